@@ -1,6 +1,6 @@
 use birli::{
-    cli::BirliContext,
-    get_durations,
+    cli::BirliCommand,
+    get_durations, BirliError,
     BirliError::{ClapError, DryRun},
 };
 use clap::ErrorKind::{DisplayHelp, DisplayVersion};
@@ -15,25 +15,41 @@ where
     T: Into<OsString> + Clone,
     I: Debug,
 {
-    let birli_ctx = match BirliContext::from_args(args) {
-        Ok(birli_ctx) => birli_ctx,
-        Err(DryRun {}) => {
-            info!("Dry run. No files will be written.");
-            return 0;
-        }
-        Err(ClapError(inner)) => {
-            // Swallow broken pipe errors
-            trace!("clap error: {:?}", inner.kind());
-            let _ = inner.print();
-            match inner.kind() {
-                DisplayHelp | DisplayVersion => return 0,
-                _ => return 1,
-            }
+    let birli_ctx = match BirliCommand::from_args(args) {
+        Ok(BirliCommand::Convert(birli_ctx)) => birli_ctx,
+        // Solve for calibration solutions if requested.
+        Ok(BirliCommand::Calibrate(calibrate_ctx)) => {
+            return match calibrate_ctx.run() {
+                Ok(_) => {
+                    info!(
+                        "total duration: {:?}",
+                        get_durations().values().sum::<Duration>()
+                    );
+                    0
+                }
+                Err(e) => {
+                    eprintln!("calibration error: {e}");
+                    1
+                }
+            };
         }
-        Err(e) => {
-            eprintln!("error parsing args: {e}");
-            return 1;
+        // Reprocess already-converted visibilities if requested.
+        Ok(BirliCommand::Reprocess(reprocess_ctx)) => {
+            return match reprocess_ctx.run() {
+                Ok(_) => {
+                    info!(
+                        "total duration: {:?}",
+                        get_durations().values().sum::<Duration>()
+                    );
+                    0
+                }
+                Err(e) => {
+                    eprintln!("reprocessing error: {e}");
+                    1
+                }
+            };
         }
+        Err(e) => return parse_error_retcode(e),
     };
 
     // Calculations for timing info
@@ -120,6 +136,29 @@ where
     }
 }
 
+/// The return code for an error which occurred while parsing arguments.
+fn parse_error_retcode(e: BirliError) -> i32 {
+    match e {
+        DryRun {} => {
+            info!("Dry run. No files will be written.");
+            0
+        }
+        ClapError(inner) => {
+            // Swallow broken pipe errors
+            trace!("clap error: {:?}", inner.kind());
+            let _ = inner.print();
+            match inner.kind() {
+                DisplayHelp | DisplayVersion => 0,
+                _ => 1,
+            }
+        }
+        e => {
+            eprintln!("error parsing args: {e}");
+            1
+        }
+    }
+}
+
 fn main() {
     env_logger::init_from_env(
         env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "info"),
@@ -133,9 +172,16 @@ fn main() {
 #[cfg(test)]
 mod tests {
     use approx::assert_abs_diff_eq;
-    use birli::mwalib::{
-        _open_fits, _open_hdu, fits_open, fits_open_hdu, get_required_fits_key, CorrelatorContext,
-        _get_required_fits_key,
+    use birli::{
+        io::{aocal::AOCalSols, uvfits::UvfitsReader, VisRead},
+        marlu::{
+            ndarray::Array3,
+            rubbl_casatables::{Table, TableOpenMode},
+        },
+        mwalib::{
+            _get_required_fits_key, _open_fits, _open_hdu, fits_open, fits_open_hdu,
+            get_required_fits_key, CorrelatorContext,
+        },
     };
     use tempfile::tempdir;
 
@@ -269,6 +315,239 @@ mod tests {
         assert_abs_diff_eq!(result_center_freq_2, expected_center_freq_2);
     }
 
+    #[test]
+    fn main_reprocesses_uvfits() {
+        let tmp_dir = tempdir().unwrap();
+        let uvfits_path = tmp_dir.path().join("1297526432.uvfits");
+        let reprocessed_path = tmp_dir.path().join("1297526432.reprocessed.uvfits");
+
+        let metafits_path = "tests/data/1297526432_mwax/1297526432.metafits";
+        let gpufits_paths = [
+            "tests/data/1297526432_mwax/1297526432_20210216160014_ch117_000.fits",
+            "tests/data/1297526432_mwax/1297526432_20210216160014_ch117_001.fits",
+            "tests/data/1297526432_mwax/1297526432_20210216160014_ch118_000.fits",
+            "tests/data/1297526432_mwax/1297526432_20210216160014_ch118_001.fits",
+        ];
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli",
+            "-m", metafits_path,
+            "-u", uvfits_path.to_str().unwrap(),
+            "--no-draw-progress",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+        assert_eq!(main_with_args(&args), 0);
+
+        #[rustfmt::skip]
+        let args = vec![
+            "birli",
+            "-m", metafits_path,
+            "--uvfits-in", uvfits_path.to_str().unwrap(),
+            "-u", reprocessed_path.to_str().unwrap(),
+            "--no-draw-progress",
+            "--avg-freq-factor", "2",
+        ];
+        assert_eq!(main_with_args(&args), 0);
+
+        let mut fptr = fits_open!(&uvfits_path).unwrap();
+        let hdu = fits_open_hdu!(&mut fptr, 0).unwrap();
+        let num_rows: usize = get_required_fits_key!(&mut fptr, &hdu, "GCOUNT").unwrap();
+        let num_chans: usize = get_required_fits_key!(&mut fptr, &hdu, "NAXIS4").unwrap();
+
+        let mut fptr = fits_open!(&reprocessed_path).unwrap();
+        let hdu = fits_open_hdu!(&mut fptr, 0).unwrap();
+        let reprocessed_rows: usize = get_required_fits_key!(&mut fptr, &hdu, "GCOUNT").unwrap();
+        let reprocessed_chans: usize = get_required_fits_key!(&mut fptr, &hdu, "NAXIS4").unwrap();
+
+        assert_eq!(reprocessed_rows, num_rows);
+        assert_eq!(reprocessed_chans, num_chans / 2);
+
+        // reading the input in chunks gives the same visibilities.
+        let chunked_path = tmp_dir.path().join("1297526432.chunked.uvfits");
+        #[rustfmt::skip]
+        let args = vec![
+            "birli",
+            "-m", metafits_path,
+            "--uvfits-in", uvfits_path.to_str().unwrap(),
+            "-u", chunked_path.to_str().unwrap(),
+            "--no-draw-progress",
+            "--avg-freq-factor", "2",
+            "--time-chunk", "1",
+        ];
+        assert_eq!(main_with_args(&args), 0);
+
+        let read_all = |path| {
+            let reader = UvfitsReader::new(path).unwrap();
+            let shape = reader.get_shape();
+            let mut jones_array = Array3::zeros(shape);
+            let mut weight_array = Array3::zeros(shape);
+            let mut flag_array = Array3::from_elem(shape, false);
+            reader
+                .read_vis(
+                    jones_array.view_mut(),
+                    weight_array.view_mut(),
+                    flag_array.view_mut(),
+                    &(0..shape.0),
+                )
+                .unwrap();
+            (jones_array, weight_array, flag_array)
+        };
+        let (jones_array, weight_array, flag_array) = read_all(&reprocessed_path);
+        let (chunked_jones, chunked_weights, chunked_flags) = read_all(&chunked_path);
+        assert_abs_diff_eq!(chunked_jones, jones_array);
+        assert_abs_diff_eq!(chunked_weights, weight_array);
+        assert_eq!(chunked_flags, flag_array);
+    }
+
+    #[test]
+    fn main_reprocesses_ms() {
+        let tmp_dir = tempdir().unwrap();
+        let ms_path = tmp_dir.path().join("1297526432.ms");
+        let reprocessed_path = tmp_dir.path().join("1297526432.reprocessed.uvfits");
+
+        let metafits_path = "tests/data/1297526432_mwax/1297526432.metafits";
+        let gpufits_paths = [
+            "tests/data/1297526432_mwax/1297526432_20210216160014_ch117_000.fits",
+            "tests/data/1297526432_mwax/1297526432_20210216160014_ch117_001.fits",
+            "tests/data/1297526432_mwax/1297526432_20210216160014_ch118_000.fits",
+            "tests/data/1297526432_mwax/1297526432_20210216160014_ch118_001.fits",
+        ];
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli",
+            "-m", metafits_path,
+            "-M", ms_path.to_str().unwrap(),
+            "--no-draw-progress",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+        assert_eq!(main_with_args(&args), 0);
+
+        #[rustfmt::skip]
+        let args = vec![
+            "birli",
+            "-m", metafits_path,
+            "--ms-in", ms_path.to_str().unwrap(),
+            "--data-column", "DATA",
+            "-u", reprocessed_path.to_str().unwrap(),
+            "--no-draw-progress",
+            "--avg-time-factor", "2",
+        ];
+        assert_eq!(main_with_args(&args), 0);
+
+        let ms_rows = Table::open(&ms_path, TableOpenMode::Read).unwrap().n_rows() as usize;
+
+        let mut fptr = fits_open!(&reprocessed_path).unwrap();
+        let hdu = fits_open_hdu!(&mut fptr, 0).unwrap();
+        let reprocessed_rows: usize = get_required_fits_key!(&mut fptr, &hdu, "GCOUNT").unwrap();
+
+        assert_eq!(reprocessed_rows * 2, ms_rows);
+    }
+
+    #[test]
+    fn main_calibrates_and_applies_solutions() {
+        let tmp_dir = tempdir().unwrap();
+        let sky_model_path = tmp_dir.path().join("sky_model.txt");
+        let calsols_path = tmp_dir.path().join("1297526432_solutions.fits");
+        let uvfits_path = tmp_dir.path().join("1297526432.calibrated.uvfits");
+        std::fs::write(
+            &sky_model_path,
+            "# ra_deg dec_deg flux_jy\n0.0 -27.0 10.0\n",
+        )
+        .unwrap();
+
+        let metafits_path = "tests/data/1297526432_mwax/1297526432.metafits";
+        let gpufits_paths = [
+            "tests/data/1297526432_mwax/1297526432_20210216160014_ch117_000.fits",
+            "tests/data/1297526432_mwax/1297526432_20210216160014_ch117_001.fits",
+            "tests/data/1297526432_mwax/1297526432_20210216160014_ch118_000.fits",
+            "tests/data/1297526432_mwax/1297526432_20210216160014_ch118_001.fits",
+        ];
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli",
+            "calibrate",
+            "-m", metafits_path,
+            "--sky-model", sky_model_path.to_str().unwrap(),
+            "--calsols-out", calsols_path.to_str().unwrap(),
+            "-u", uvfits_path.to_str().unwrap(),
+            "--no-draw-progress",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+        assert_eq!(main_with_args(&args), 0);
+
+        let calsols = AOCalSols::read(&calsols_path).unwrap();
+        // 2 fine channels for each of the 24 coarse channels in the observation.
+        assert_eq!(calsols.di_jones.dim(), (1, 2, 48));
+        assert_eq!(calsols.start_timestamps.len(), 1);
+        assert_eq!(calsols.tile_names.map(|names| names.len()), Some(2));
+
+        // the output has the solutions applied, as if they were applied with --apply-di-cal, up to
+        // the precision the solutions are written with.
+        let applied_uvfits_path = tmp_dir.path().join("1297526432.applied.uvfits");
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli",
+            "-m", metafits_path,
+            "-u", applied_uvfits_path.to_str().unwrap(),
+            "--apply-di-cal", calsols_path.to_str().unwrap(),
+            "--no-draw-progress",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+        assert_eq!(main_with_args(&args), 0);
+        let read_all = |path| {
+            let reader = UvfitsReader::new(path).unwrap();
+            let shape = reader.get_shape();
+            let mut jones_array = Array3::zeros(shape);
+            let mut weight_array = Array3::zeros(shape);
+            let mut flag_array = Array3::from_elem(shape, false);
+            reader
+                .read_vis(
+                    jones_array.view_mut(),
+                    weight_array.view_mut(),
+                    flag_array.view_mut(),
+                    &(0..shape.0),
+                )
+                .unwrap();
+            (jones_array, weight_array, flag_array)
+        };
+        let (jones_array, weight_array, flag_array) = read_all(&uvfits_path);
+        let (applied_jones, applied_weights, applied_flags) = read_all(&applied_uvfits_path);
+        assert!(jones_array.iter().all(|jones| !jones.any_nan()));
+        assert_abs_diff_eq!(applied_jones, jones_array, epsilon = 1e-4);
+        assert_abs_diff_eq!(applied_weights, weight_array);
+        assert_eq!(applied_flags, flag_array);
+
+        // the solutions can also be applied to averaged visibilities.
+        let avg_uvfits_path = tmp_dir.path().join("1297526432.avg.uvfits");
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli",
+            "-m", metafits_path,
+            "-u", avg_uvfits_path.to_str().unwrap(),
+            "--avg-time-factor", "2",
+            "--apply-di-cal", calsols_path.to_str().unwrap(),
+            "--apply-di-cal-after-avg",
+            "--no-draw-progress",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+        assert_eq!(main_with_args(&args), 0);
+        assert!(avg_uvfits_path.exists());
+
+        // calibration options need calibrate mode.
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli",
+            "-m", metafits_path,
+            "--sky-model", sky_model_path.to_str().unwrap(),
+            "--",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+        assert_ne!(main_with_args(&args), 0);
+    }
+
     #[test]
     fn main_gracefully_handle_munted_cal_file() {
         let tmp_dir = tempdir().unwrap();
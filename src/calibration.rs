@@ -1,8 +1,19 @@
 //! Calibrating visibilities.
 
-use crate::ndarray::{ArrayView2, ArrayViewMut3, Axis};
+use std::f64::consts::TAU;
+
+use crate::io::aocal::AOCalSols;
+use crate::ndarray::{
+    parallel::prelude::*, s, Array1, Array2, Array3, ArrayView1, ArrayView2, ArrayView3,
+    ArrayViewMut1, ArrayViewMut2, ArrayViewMut3, Axis,
+};
 use itertools::izip;
-use marlu::Jones;
+use log::warn;
+use marlu::{
+    average_chunk_f64,
+    mwalib::{Antenna, CoarseChannel},
+    Complex, Jones, VisContext,
+};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -29,6 +40,104 @@ pub enum CalibrationError {
         /// The shape that was received instead
         received: String,
     },
+
+    #[error("The provided calibration solutions have {calsol_tiles} tiles, but the observation has {obs_tiles} tiles, and the solutions don't name their tiles")]
+    /// When calibration solutions without tile names don't have a solution for each tile.
+    TileCountMismatch {
+        /// The number of tiles in the calibration solutions
+        calsol_tiles: usize,
+        /// The number of tiles in the observation
+        obs_tiles: usize,
+    },
+
+    #[error("Tile {tile_name} has ID {calsol_id} in the calibration solutions, but ID {obs_id} in the observation")]
+    /// When a tile with the same name has a different ID in the calibration solutions.
+    TileIdMismatch {
+        /// The name of the tile
+        tile_name: String,
+        /// The ID of the tile in the calibration solutions
+        calsol_id: u32,
+        /// The ID of the tile in the observation
+        obs_id: u32,
+    },
+
+    #[error(
+        "None of the {calsol_tiles} tiles in the calibration solutions are in the observation"
+    )]
+    /// When none of the tiles in the calibration solutions match the observation.
+    NoMatchingTiles {
+        /// The number of tiles in the calibration solutions
+        calsol_tiles: usize,
+    },
+}
+
+/// Reorder calibration solutions with dimensions `[timeblock][tile][channel]` so that the tile
+/// axis matches the antennas of the observation, which is how [`apply_di_calsol`] indexes them.
+///
+/// Solutions which name their tiles are matched by name, or by ID if only IDs are known, and
+/// tiles missing from the solutions get `NaN` solutions, so their visibilities are flagged.
+/// Solutions without names or IDs are assumed to be in the same order as the observation.
+///
+/// # Errors
+///
+/// Returns a [`CalibrationError`] if the solutions can't describe the observation's array: the
+/// tile count differs for unnamed solutions, a named tile has a different ID, or no tiles match.
+pub fn match_calsol_tiles(
+    calsols: &AOCalSols,
+    antennas: &[Antenna],
+) -> Result<Array3<Jones<f64>>, CalibrationError> {
+    let (num_timeblocks, calsol_tiles, num_chans) = calsols.di_jones.dim();
+    if calsols.tile_names.is_none() && calsols.tile_ids.is_none() {
+        if calsol_tiles != antennas.len() {
+            return Err(CalibrationError::TileCountMismatch {
+                calsol_tiles,
+                obs_tiles: antennas.len(),
+            });
+        }
+        return Ok(calsols.di_jones.clone());
+    }
+
+    let mut matched = Array3::from_elem((num_timeblocks, antennas.len(), num_chans), Jones::nan());
+    let mut missing = vec![];
+    for (antenna, mut matched) in izip!(antennas, matched.axis_iter_mut(Axis(1))) {
+        let calsol_idx = match (calsols.tile_names.as_ref(), calsols.tile_ids.as_ref()) {
+            (Some(tile_names), tile_ids) => {
+                let calsol_idx = tile_names
+                    .iter()
+                    .position(|name| name == &antenna.tile_name);
+                if let (Some(calsol_idx), Some(tile_ids)) = (calsol_idx, tile_ids) {
+                    if tile_ids[calsol_idx] != antenna.tile_id {
+                        return Err(CalibrationError::TileIdMismatch {
+                            tile_name: antenna.tile_name.clone(),
+                            calsol_id: tile_ids[calsol_idx],
+                            obs_id: antenna.tile_id,
+                        });
+                    }
+                }
+                calsol_idx
+            }
+            (None, Some(tile_ids)) => tile_ids.iter().position(|&id| id == antenna.tile_id),
+            (None, None) => unreachable!(),
+        };
+        match calsol_idx {
+            Some(calsol_idx) => {
+                matched.assign(&calsols.di_jones.index_axis(Axis(1), calsol_idx));
+            }
+            None => missing.push(antenna.tile_name.as_str()),
+        }
+    }
+
+    if missing.len() == antennas.len() {
+        return Err(CalibrationError::NoMatchingTiles { calsol_tiles });
+    }
+    if !missing.is_empty() {
+        warn!(
+            "flagging {} tiles without calibration solutions: {}",
+            missing.len(),
+            missing.join(", ")
+        );
+    }
+    Ok(matched)
 }
 
 /// apply a direction independent calibration solution for a single timeblock to the given
@@ -130,11 +239,757 @@ pub fn apply_di_calsol(
     Ok(())
 }
 
+/// How [`interpolate_calsols`] interpolates between solution channels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterpMethod {
+    /// Linear interpolation between the neighbouring solutions.
+    Linear,
+    /// A natural cubic spline through the solutions.
+    Spline,
+}
+
+/// Options for interpolating calibration solutions in frequency with [`interpolate_calsols`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CalsolInterp {
+    /// How to interpolate between solution channels
+    pub method: InterpMethod,
+    /// Interpolate the amplitude and phase of each element of the Jones matrices, instead of the
+    /// real and imaginary parts
+    pub amp_phase: bool,
+}
+
+impl std::fmt::Display for CalsolInterp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} interpolation of {}",
+            match self.method {
+                InterpMethod::Linear => "linear",
+                InterpMethod::Spline => "cubic spline",
+            },
+            if self.amp_phase {
+                "amplitude and phase"
+            } else {
+                "real and imaginary parts"
+            }
+        )
+    }
+}
+
+/// The centre frequency of each channel when every coarse channel is evenly divided into
+/// `chans_per_coarse` channels, which is the grid calibration solutions are assumed to be on.
+pub fn coarse_chan_grid_hz(coarse_chans: &[CoarseChannel], chans_per_coarse: usize) -> Vec<f64> {
+    coarse_chans
+        .iter()
+        .flat_map(|coarse_chan| {
+            let width_hz = coarse_chan.chan_width_hz as f64;
+            let start_hz = coarse_chan.chan_centre_hz as f64 - width_hz / 2.;
+            (0..chans_per_coarse).map(move |chan| {
+                start_hz + (chan as f64 + 0.5) * width_hz / chans_per_coarse as f64
+            })
+        })
+        .collect()
+}
+
+/// The centre frequency of each of `num_chans` calibration solution channels covering
+/// `coarse_chans`, for solutions which don't record their frequencies. Each coarse channel is
+/// evenly divided (see [`coarse_chan_grid_hz`]) if `num_chans` is a multiple of the number of
+/// coarse channels, otherwise the band from the lower edge of the first coarse channel to the
+/// upper edge of the last is evenly divided.
+pub fn calsol_grid_hz(coarse_chans: &[CoarseChannel], num_chans: usize) -> Vec<f64> {
+    if coarse_chans.is_empty() {
+        return vec![];
+    }
+    if num_chans % coarse_chans.len() == 0 {
+        return coarse_chan_grid_hz(coarse_chans, num_chans / coarse_chans.len());
+    }
+    let (first, last) = (&coarse_chans[0], &coarse_chans[coarse_chans.len() - 1]);
+    let start_hz = first.chan_centre_hz as f64 - first.chan_width_hz as f64 / 2.;
+    let end_hz = last.chan_centre_hz as f64 + last.chan_width_hz as f64 / 2.;
+    let width_hz = (end_hz - start_hz) / num_chans as f64;
+    (0..num_chans)
+        .map(|chan| start_hz + (chan as f64 + 0.5) * width_hz)
+        .collect()
+}
+
+/// Interpolate calibration solutions with dimensions `[tile][channel]`, at the ascending
+/// frequencies `calsol_freqs_hz`, onto any other ascending frequencies `freqs_hz`.
+///
+/// Solutions containing a `NaN` are flagged, so they are bridged by interpolating from their
+/// neighbours. Frequencies beyond the first or last good solution take the value of that
+/// solution, and tiles without any good solutions get `NaN` solutions. When interpolating
+/// amplitude and phase, the phases are unwrapped across the good solutions first.
+///
+/// # Errors
+///
+/// `calsol_freqs_hz` must have a frequency for each channel of calsols.
+pub fn interpolate_calsols(
+    // a two dimensional array of jones matrix calibration solutions with
+    // dimensions `[tile][channel]`
+    calsols: ArrayView2<Jones<f64>>,
+    calsol_freqs_hz: &[f64],
+    freqs_hz: &[f64],
+    interp: CalsolInterp,
+) -> Result<Array2<Jones<f64>>, CalibrationError> {
+    let (num_tiles, calsol_chans) = calsols.dim();
+    if calsol_freqs_hz.len() != calsol_chans {
+        return Err(CalibrationError::BadArrayShape {
+            argument: "calsol_freqs_hz".into(),
+            function: "interpolate_calsols".into(),
+            expected: format!("{calsol_chans}"),
+            received: format!("{}", calsol_freqs_hz.len()),
+        });
+    }
+
+    let mut result = Array2::from_elem((num_tiles, freqs_hz.len()), Jones::nan());
+    for (calsols, mut result) in izip!(calsols.outer_iter(), result.outer_iter_mut()) {
+        let (xs, mut components): (Vec<f64>, Vec<[f64; 8]>) = izip!(calsol_freqs_hz, calsols)
+            .filter(|(_, calsol)| !calsol.any_nan())
+            .map(|(&freq_hz, calsol)| (freq_hz, jones_components(*calsol, interp.amp_phase)))
+            .unzip();
+        if xs.is_empty() {
+            continue;
+        }
+        if interp.amp_phase {
+            for idx in (1..8).step_by(2) {
+                for chan in 1..components.len() {
+                    let prev = components[chan - 1][idx];
+                    let turns = ((components[chan][idx] - prev) / TAU).round();
+                    components[chan][idx] -= turns * TAU;
+                }
+            }
+        }
+
+        let mut interpolated = vec![[0.; 8]; freqs_hz.len()];
+        for idx in 0..8 {
+            let ys = components.iter().map(|c| c[idx]).collect::<Vec<_>>();
+            let second_derivs = match interp.method {
+                InterpMethod::Spline if xs.len() > 2 => Some(spline_second_derivs(&xs, &ys)),
+                _ => None,
+            };
+            for (&freq_hz, interpolated) in izip!(freqs_hz, interpolated.iter_mut()) {
+                interpolated[idx] = interp_sorted(&xs, &ys, second_derivs.as_deref(), freq_hz);
+            }
+        }
+        for (interpolated, result) in izip!(interpolated, result.iter_mut()) {
+            *result = jones_from_components(interpolated, interp.amp_phase);
+        }
+    }
+    Ok(result)
+}
+
+/// The real and imaginary parts, or amplitude and phase, of each element of a Jones matrix.
+fn jones_components(jones: Jones<f64>, amp_phase: bool) -> [f64; 8] {
+    let mut components = jones.to_float_array();
+    if amp_phase {
+        for (idx, element) in jones.iter().enumerate() {
+            components[2 * idx] = element.norm();
+            components[2 * idx + 1] = element.arg();
+        }
+    }
+    components
+}
+
+/// The inverse of [`jones_components`].
+fn jones_from_components(components: [f64; 8], amp_phase: bool) -> Jones<f64> {
+    if amp_phase {
+        Jones::from([
+            Complex::from_polar(components[0], components[1]),
+            Complex::from_polar(components[2], components[3]),
+            Complex::from_polar(components[4], components[5]),
+            Complex::from_polar(components[6], components[7]),
+        ])
+    } else {
+        Jones::from(components)
+    }
+}
+
+/// The second derivatives of a natural cubic spline through the points `(xs, ys)`.
+fn spline_second_derivs(xs: &[f64], ys: &[f64]) -> Vec<f64> {
+    let num_points = xs.len();
+    let mut second_derivs = vec![0.; num_points];
+    let mut upper = vec![0.; num_points];
+    // forward sweep of the tridiagonal system, with zero curvature at both ends.
+    for idx in 1..num_points - 1 {
+        let sig = (xs[idx] - xs[idx - 1]) / (xs[idx + 1] - xs[idx - 1]);
+        let p = sig * second_derivs[idx - 1] + 2.;
+        second_derivs[idx] = (sig - 1.) / p;
+        let slope_change = (ys[idx + 1] - ys[idx]) / (xs[idx + 1] - xs[idx])
+            - (ys[idx] - ys[idx - 1]) / (xs[idx] - xs[idx - 1]);
+        upper[idx] = (6. * slope_change / (xs[idx + 1] - xs[idx - 1]) - sig * upper[idx - 1]) / p;
+    }
+    second_derivs[num_points - 1] = 0.;
+    for idx in (0..num_points - 1).rev() {
+        second_derivs[idx] = second_derivs[idx] * second_derivs[idx + 1] + upper[idx];
+    }
+    second_derivs
+}
+
+/// Interpolate the points `(xs, ys)` at `x`, linearly or with the spline given by its second
+/// derivatives, holding the end values beyond the first and last points.
+fn interp_sorted(xs: &[f64], ys: &[f64], second_derivs: Option<&[f64]>, x: f64) -> f64 {
+    let upper = xs.partition_point(|&point| point < x);
+    if upper == 0 {
+        return ys[0];
+    }
+    if upper == xs.len() {
+        return ys[xs.len() - 1];
+    }
+    let lower = upper - 1;
+    let width = xs[upper] - xs[lower];
+    let b = (x - xs[lower]) / width;
+    let a = 1. - b;
+    let linear = a * ys[lower] + b * ys[upper];
+    second_derivs.map_or(linear, |d2| {
+        linear + ((a * a * a - a) * d2[lower] + (b * b * b - b) * d2[upper]) * width * width / 6.
+    })
+}
+
+/// Options for smoothing calibration solutions in frequency with [`smooth_calsols`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CalsolSmoothing {
+    /// Median filter the real and imaginary parts over a window of this many channels
+    pub median_width: Option<usize>,
+    /// Fit a polynomial of this order to the amplitude across each coarse channel
+    pub amp_poly_order: Option<usize>,
+    /// Fit a delay (a linear phase slope) across the band
+    pub fit_delay: bool,
+}
+
+impl CalsolSmoothing {
+    /// Whether no smoothing is requested.
+    pub const fn is_empty(&self) -> bool {
+        self.median_width.is_none() && self.amp_poly_order.is_none() && !self.fit_delay
+    }
+}
+
+impl std::fmt::Display for CalsolSmoothing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut steps = vec![];
+        if let Some(width) = self.median_width {
+            steps.push(format!("median filter over {width} channels"));
+        }
+        if let Some(order) = self.amp_poly_order {
+            steps.push(format!("order {order} amplitude fit per coarse channel"));
+        }
+        if self.fit_delay {
+            steps.push("delay fit".into());
+        }
+        write!(f, "{}", steps.join(", then "))
+    }
+}
+
+/// Smooth calibration solutions with dimensions `[tile][channel]` in frequency, in place.
+///
+/// Each tile is smoothed independently, first with a median filter of the real and imaginary
+/// parts, then by fitting a polynomial to the amplitude of each Jones element across each coarse
+/// channel of `chans_per_coarse` channels, and then by fitting a delay to the phase of each element
+/// across the band, as requested in `smoothing`. Flagged (`NaN`) solutions are left out of each
+/// step and stay flagged, and a fit is skipped where there are too few good solutions for it.
+///
+/// # Errors
+///
+/// `freqs_hz` must have a frequency for each channel of calsols, and the number of channels must be
+/// a multiple of `chans_per_coarse`.
+pub fn smooth_calsols(
+    // a two dimensional array of jones matrix calibration solutions with
+    // dimensions `[tile][channel]`
+    mut calsols: ArrayViewMut2<Jones<f64>>,
+    freqs_hz: &[f64],
+    chans_per_coarse: usize,
+    smoothing: &CalsolSmoothing,
+) -> Result<(), CalibrationError> {
+    let num_chans = calsols.dim().1;
+    if freqs_hz.len() != num_chans {
+        return Err(CalibrationError::BadArrayShape {
+            argument: "freqs_hz".into(),
+            function: "smooth_calsols".into(),
+            expected: format!("{num_chans}"),
+            received: format!("{}", freqs_hz.len()),
+        });
+    }
+    if chans_per_coarse == 0 || num_chans % chans_per_coarse != 0 {
+        return Err(CalibrationError::BadArrayShape {
+            argument: "chans_per_coarse".into(),
+            function: "smooth_calsols".into(),
+            expected: format!("a divisor of {num_chans}"),
+            received: format!("{chans_per_coarse}"),
+        });
+    }
+
+    calsols
+        .outer_iter_mut()
+        .into_par_iter()
+        .for_each(|mut calsols| {
+            if let Some(width) = smoothing.median_width {
+                let filtered = median_filter(calsols.view(), width);
+                calsols.assign(&filtered);
+            }
+            if let Some(order) = smoothing.amp_poly_order {
+                for calsols in calsols.axis_chunks_iter_mut(Axis(0), chans_per_coarse) {
+                    fit_amplitudes(calsols, order);
+                }
+            }
+            if smoothing.fit_delay {
+                fit_delay(calsols, freqs_hz);
+            }
+        });
+    Ok(())
+}
+
+/// The median of the real and imaginary parts of the good solutions within `width` channels
+/// centred on each good solution. For an even number of good solutions the lower median is used,
+/// so that a single outlier never leaks into its neighbours.
+fn median_filter(calsols: ArrayView1<Jones<f64>>, width: usize) -> Array1<Jones<f64>> {
+    let half_width = width / 2;
+    let num_chans = calsols.len();
+    Array1::from_shape_fn(num_chans, |chan| {
+        if calsols[chan].any_nan() {
+            return calsols[chan];
+        }
+        let window = calsols
+            .slice(s![
+                chan.saturating_sub(half_width)..(chan + half_width + 1).min(num_chans)
+            ])
+            .iter()
+            .filter(|calsol| !calsol.any_nan())
+            .map(|calsol| calsol.to_float_array())
+            .collect::<Vec<_>>();
+        let mut median = [0.; 8];
+        for (idx, median) in median.iter_mut().enumerate() {
+            let mut values = window.iter().map(|v| v[idx]).collect::<Vec<_>>();
+            values.sort_by(f64::total_cmp);
+            *median = values[(values.len() - 1) / 2];
+        }
+        Jones::from(median)
+    })
+}
+
+/// Replace the amplitude of each element of the good solutions with a least squares polynomial
+/// fit of the given order against channel, keeping the phase.
+fn fit_amplitudes(mut calsols: ArrayViewMut1<Jones<f64>>, order: usize) {
+    let good_chans = (0..calsols.len())
+        .filter(|&chan| !calsols[chan].any_nan())
+        .collect::<Vec<_>>();
+    if good_chans.len() <= order {
+        return;
+    }
+    // centre and scale the channel axis to keep the fit well conditioned.
+    let centre = (calsols.len() as f64 - 1.) / 2.;
+    let scale = centre.max(1.);
+    let xs = good_chans
+        .iter()
+        .map(|&chan| (chan as f64 - centre) / scale)
+        .collect::<Vec<_>>();
+    for element in 0..4 {
+        let amps = good_chans
+            .iter()
+            .map(|&chan| calsols[chan][element].norm())
+            .collect::<Vec<_>>();
+        let coeffs = match poly_fit(&xs, &amps, order) {
+            Some(coeffs) => coeffs,
+            None => continue,
+        };
+        for (&chan, &x, &amp) in izip!(&good_chans, &xs, &amps) {
+            if amp > 0. {
+                let fit = coeffs.iter().rev().fold(0., |acc, coeff| acc * x + coeff);
+                calsols[chan][element] *= fit.max(0.) / amp;
+            }
+        }
+    }
+}
+
+/// The coefficients, lowest order first, of the least squares polynomial fit of `ys` against `xs`,
+/// or `None` if the fit is degenerate.
+fn poly_fit(xs: &[f64], ys: &[f64], order: usize) -> Option<Vec<f64>> {
+    let size = order + 1;
+    // the normal equations, as an augmented matrix.
+    let mut matrix = vec![vec![0.; size + 1]; size];
+    for (&x, &y) in izip!(xs, ys) {
+        let powers = (0..size)
+            .map(|power| x.powi(power as i32))
+            .collect::<Vec<_>>();
+        for (row, &row_power) in izip!(matrix.iter_mut(), &powers) {
+            for (cell, &col_power) in izip!(row.iter_mut(), &powers) {
+                *cell += row_power * col_power;
+            }
+            row[size] += row_power * y;
+        }
+    }
+    // Gaussian elimination with partial pivoting.
+    for col in 0..size {
+        let pivot =
+            (col..size).max_by(|&a, &b| matrix[a][col].abs().total_cmp(&matrix[b][col].abs()))?;
+        if matrix[pivot][col].abs() < f64::EPSILON {
+            return None;
+        }
+        matrix.swap(col, pivot);
+        let (upper, lower) = matrix.split_at_mut(col + 1);
+        let pivot_row = &upper[col];
+        for row in lower.iter_mut() {
+            let factor = row[col] / pivot_row[col];
+            for (cell, &pivot_cell) in izip!(&mut row[col..], &pivot_row[col..]) {
+                *cell -= factor * pivot_cell;
+            }
+        }
+    }
+    let mut coeffs = vec![0.; size];
+    for row in (0..size).rev() {
+        let known = ((row + 1)..size)
+            .map(|idx| matrix[row][idx] * coeffs[idx])
+            .sum::<f64>();
+        coeffs[row] = (matrix[row][size] - known) / matrix[row][row];
+    }
+    Some(coeffs)
+}
+
+/// Replace the phase of each element of the good solutions with a delay (a linear phase slope)
+/// fit across the band, keeping the amplitude.
+///
+/// The slope comes from the average phase change between neighbouring good solutions, so it
+/// doesn't need the phases to be unwrapped, as long as the delay doesn't wrap the phase by more
+/// than half a turn between them.
+fn fit_delay(mut calsols: ArrayViewMut1<Jones<f64>>, freqs_hz: &[f64]) {
+    for element in 0..4 {
+        let good = izip!(calsols.iter(), freqs_hz)
+            .enumerate()
+            .filter(|(_, (calsol, _))| !calsol.any_nan() && calsol[element].norm() > 0.)
+            .map(|(chan, (calsol, &freq_hz))| {
+                (
+                    chan,
+                    freq_hz,
+                    calsol[element].unscale(calsol[element].norm()),
+                )
+            })
+            .collect::<Vec<_>>();
+        if good.len() < 2 {
+            continue;
+        }
+        let ref_freq_hz = good[0].1;
+        let (phase_steps, freq_steps) = good.windows(2).fold(
+            (Complex::new(0., 0.), 0.),
+            |(phase_steps, freq_steps), pair| {
+                (
+                    phase_steps + pair[1].2 * pair[0].2.conj(),
+                    freq_steps + pair[1].1 - pair[0].1,
+                )
+            },
+        );
+        let coarse_slope = phase_steps.arg() / (freq_steps / (good.len() - 1) as f64);
+        // refine with a least squares line through the phases, relative to the coarse slope.
+        let (xs, residuals): (Vec<f64>, Vec<f64>) = good
+            .iter()
+            .map(|&(_, freq_hz, phasor)| {
+                let x = freq_hz - ref_freq_hz;
+                (
+                    x,
+                    (phasor * Complex::from_polar(1., -coarse_slope * x)).arg(),
+                )
+            })
+            .unzip();
+        let (offset, slope) = match poly_fit(&xs, &residuals, 1) {
+            Some(coeffs) => (coeffs[0], coarse_slope + coeffs[1]),
+            None => continue,
+        };
+        for &(chan, freq_hz, _) in &good {
+            let amp = calsols[chan][element].norm();
+            calsols[chan][element] =
+                Complex::from_polar(amp, offset + slope * (freq_hz - ref_freq_hz));
+        }
+    }
+}
+
+/// Average visibilities and weights with dimensions `[timestep][channel][baselines]` by the
+/// factors in `vis_ctx`, the same way the uvfits and measurement set writers do, then apply a
+/// direction independent calibration solution to the averaged visibilities.
+///
+/// This is cheaper than calibrating before averaging, and matches solutions which were solved
+/// at the averaged resolution.
+///
+/// Returns the averaged visibilities and weights, and a [`VisContext`] which describes them
+/// without any further averaging, ready to be written. The averaged weight is the sum of the
+/// unflagged weights, and is negative when the averaged visibility is flagged, either because all
+/// of its visibilities were flagged or calibration produced a NaN.
+///
+/// # Errors
+///
+/// The number of averaged channels must be a multiple of the number of channels in calsols, and
+/// `weight_array` must have the same shape as `vis_array`, which must match `vis_ctx`.
+#[allow(clippy::type_complexity)]
+pub fn apply_di_calsol_averaged(
+    // a two dimensional array of jones matrix calibration solutions with
+    // dimensions `[tile][channel]`
+    calsols: ArrayView2<Jones<f64>>,
+    // dimensions `[timestep][channel][baselines]`
+    vis_array: ArrayView3<Jones<f32>>,
+    // dimensions `[timestep][channel][baselines]`
+    weight_array: ArrayView3<f32>,
+    // describes the visibilities, and how to average them
+    vis_ctx: &VisContext,
+) -> Result<(Array3<Jones<f32>>, Array3<f32>, VisContext), CalibrationError> {
+    let vis_dims = vis_array.dim();
+    let weight_dims = weight_array.dim();
+    if weight_dims != vis_dims {
+        return Err(CalibrationError::BadArrayShape {
+            argument: "weight_array".into(),
+            function: "apply_di_calsol_averaged".into(),
+            expected: format!("{vis_dims:?}"),
+            received: format!("{weight_dims:?}"),
+        });
+    }
+    let sel_dims = vis_ctx.sel_dims();
+    if vis_dims != sel_dims {
+        return Err(CalibrationError::BadArrayShape {
+            argument: "vis_array".into(),
+            function: "apply_di_calsol_averaged".into(),
+            expected: format!("{sel_dims:?}"),
+            received: format!("{vis_dims:?}"),
+        });
+    }
+
+    let avg_dims = vis_ctx.avg_dims();
+    let calsol_chans = calsols.dim().1;
+    if calsol_chans == 0 || avg_dims.1 % calsol_chans != 0 {
+        return Err(CalibrationError::ChannelSizeMismatch {
+            calsol_chans,
+            data_chans: avg_dims.1,
+        });
+    }
+
+    let mut avg_vis_array = Array3::<Jones<f32>>::zeros(avg_dims);
+    let mut avg_weight_array = Array3::<f32>::zeros(avg_dims);
+    for (jones_chunk, weight_chunk, mut avg_vis_array, mut avg_weight_array) in izip!(
+        vis_array.axis_chunks_iter(Axis(0), vis_ctx.avg_time),
+        weight_array.axis_chunks_iter(Axis(0), vis_ctx.avg_time),
+        avg_vis_array.outer_iter_mut(),
+        avg_weight_array.outer_iter_mut(),
+    ) {
+        for (jones_chunk, weight_chunk, mut avg_vis_array, mut avg_weight_array) in izip!(
+            jones_chunk.axis_chunks_iter(Axis(1), vis_ctx.avg_freq),
+            weight_chunk.axis_chunks_iter(Axis(1), vis_ctx.avg_freq),
+            avg_vis_array.outer_iter_mut(),
+            avg_weight_array.outer_iter_mut(),
+        ) {
+            for (jones_chunk, weight_chunk, avg_jones, avg_weight) in izip!(
+                jones_chunk.axis_iter(Axis(2)),
+                weight_chunk.axis_iter(Axis(2)),
+                avg_vis_array.iter_mut(),
+                avg_weight_array.iter_mut(),
+            ) {
+                let mut avg_flag: bool;
+                average_chunk_f64!(jones_chunk, weight_chunk, *avg_jones, *avg_weight, avg_flag);
+                if avg_flag {
+                    *avg_weight = -avg_weight.abs();
+                }
+            }
+        }
+    }
+
+    let mut avg_flag_array = avg_weight_array.mapv(f32::is_sign_negative);
+    apply_di_calsol(
+        calsols,
+        avg_vis_array.view_mut(),
+        avg_weight_array.view_mut(),
+        avg_flag_array.view_mut(),
+        &vis_ctx.sel_baselines,
+    )?;
+
+    let avg_vis_ctx = VisContext {
+        num_sel_timesteps: avg_dims.0,
+        int_time: vis_ctx.avg_int_time(),
+        num_sel_chans: avg_dims.1,
+        start_freq_hz: vis_ctx.avg_frequencies_hz()[0],
+        freq_resolution_hz: vis_ctx.avg_freq_resolution_hz(),
+        avg_time: 1,
+        avg_freq: 1,
+        ..vis_ctx.clone()
+    };
+    Ok((avg_vis_array, avg_weight_array, avg_vis_ctx))
+}
+
+/// Solve for direction independent calibration solutions, with dimensions `[tile][channel]`,
+/// which best map the model visibilities onto the data for each channel, using every timestep.
+///
+/// Each iteration updates every tile's Jones matrix from the current estimates of all the others
+/// (the `StefCal` or `MitchCal` algorithm), averaging with the previous estimate on every second
+/// iteration to help convergence. Iteration stops when the relative change in the solutions is
+/// below `stop_threshold`, or after `max_iterations`.
+///
+/// The solutions `J` model the data as `D = J1 * M * J2^H`; their inverses are what
+/// [`apply_di_calsol`] expects. Autocorrelations and visibilities without a positive weight are
+/// ignored. Tiles without any usable visibilities in a channel get NaN solutions.
+///
+/// Also returns whether each channel converged.
+///
+/// # Errors
+///
+/// Will return [`CalibrationError::BadArrayShape`] if `model_array` or `weight_array` have a
+/// different shape to `vis_array`, or `sel_baselines` doesn't match the baseline axis.
+#[allow(clippy::too_many_arguments)]
+pub fn solve_di_calsol(
+    // dimensions `[timestep][channel][baselines]`
+    vis_array: ArrayView3<Jones<f32>>,
+    // dimensions `[timestep][channel][baselines]`
+    model_array: ArrayView3<Jones<f32>>,
+    // dimensions `[timestep][channel][baselines]`
+    weight_array: ArrayView3<f32>,
+    // The tile index pairs for each selected baseline
+    sel_baselines: &[(usize, usize)],
+    num_tiles: usize,
+    max_iterations: usize,
+    stop_threshold: f64,
+) -> Result<(Array2<Jones<f64>>, Vec<bool>), CalibrationError> {
+    let vis_dims = vis_array.dim();
+    for (argument, dims) in [
+        ("model_array", model_array.dim()),
+        ("weight_array", weight_array.dim()),
+    ] {
+        if dims != vis_dims {
+            return Err(CalibrationError::BadArrayShape {
+                argument: argument.into(),
+                function: "solve_di_calsol".into(),
+                expected: format!("{vis_dims:?}"),
+                received: format!("{dims:?}"),
+            });
+        }
+    }
+    if sel_baselines.len() != vis_dims.2 {
+        return Err(CalibrationError::BadArrayShape {
+            argument: "sel_baselines".into(),
+            function: "solve_di_calsol".into(),
+            expected: format!("{}", vis_dims.2),
+            received: format!("{}", sel_baselines.len()),
+        });
+    }
+
+    let mut calsols = Array2::from_elem((num_tiles, vis_dims.1), Jones::nan());
+    let converged = calsols
+        .axis_iter_mut(Axis(1))
+        .into_par_iter()
+        .zip(vis_array.axis_iter(Axis(1)))
+        .zip(model_array.axis_iter(Axis(1)))
+        .zip(weight_array.axis_iter(Axis(1)))
+        .map(|(((mut calsols, vis_array), model_array), weight_array)| {
+            match solve_channel(
+                vis_array,
+                model_array,
+                weight_array,
+                sel_baselines,
+                num_tiles,
+                max_iterations,
+                stop_threshold,
+            ) {
+                Some((solutions, converged)) => {
+                    for (calsol, solution) in izip!(calsols.iter_mut(), solutions) {
+                        *calsol = solution;
+                    }
+                    converged
+                }
+                None => false,
+            }
+        })
+        .collect();
+
+    Ok((calsols, converged))
+}
+
+/// Solve a single channel with dimensions `[timestep][baseline]`, returning the solution for
+/// each tile and whether it converged, or `None` if there is no usable data.
+#[allow(clippy::too_many_arguments)]
+fn solve_channel(
+    vis_array: ArrayView2<Jones<f32>>,
+    model_array: ArrayView2<Jones<f32>>,
+    weight_array: ArrayView2<f32>,
+    sel_baselines: &[(usize, usize)],
+    num_tiles: usize,
+    max_iterations: usize,
+    stop_threshold: f64,
+) -> Option<(Vec<Jones<f64>>, bool)> {
+    // promote the usable cross-correlations once, up front.
+    let mut samples = vec![];
+    let mut has_data = vec![false; num_tiles];
+    for (vis_array, model_array, weight_array) in izip!(
+        vis_array.outer_iter(),
+        model_array.outer_iter(),
+        weight_array.outer_iter()
+    ) {
+        for (&(ant1, ant2), &vis, &model, &weight) in
+            izip!(sel_baselines, vis_array, model_array, weight_array)
+        {
+            if ant1 == ant2 || weight <= 0. || vis.any_nan() || model.any_nan() {
+                continue;
+            }
+            has_data[ant1] = true;
+            has_data[ant2] = true;
+            samples.push((
+                ant1,
+                ant2,
+                Jones::<f64>::from(vis),
+                Jones::<f64>::from(model),
+                weight as f64,
+            ));
+        }
+    }
+    if samples.is_empty() {
+        return None;
+    }
+
+    let mut solutions = vec![Jones::<f64>::identity(); num_tiles];
+    let mut converged = false;
+    for iteration in 0..max_iterations {
+        let mut top = vec![Jones::<f64>::default(); num_tiles];
+        let mut bot = vec![Jones::<f64>::default(); num_tiles];
+        for &(ant1, ant2, vis, model, weight) in &samples {
+            // vis = J1 * model * J2^H, so J1 = vis * z^H * (z * z^H)^-1 with z = model * J2^H
+            let z = model * solutions[ant2].h();
+            top[ant1] += vis * z.h() * weight;
+            bot[ant1] += z * z.h() * weight;
+            // and the same for J2, from vis^H = J2 * model^H * J1^H
+            let z = model.h() * solutions[ant1].h();
+            top[ant2] += vis.h() * z.h() * weight;
+            bot[ant2] += z * z.h() * weight;
+        }
+
+        let mut change = 0.;
+        let mut total = 0.;
+        for (solution, &top, &bot, &has_data) in izip!(&mut solutions, &top, &bot, &has_data) {
+            if !has_data {
+                continue;
+            }
+            let mut new = top * bot.inv();
+            if iteration % 2 == 1 {
+                new = (new + *solution) * 0.5;
+            }
+            change += (new - *solution).norm_sqr().iter().sum::<f64>();
+            total += new.norm_sqr().iter().sum::<f64>();
+            *solution = new;
+        }
+        if !(change / total).is_finite() {
+            break;
+        }
+        if change / total < stop_threshold * stop_threshold {
+            converged = true;
+            break;
+        }
+    }
+
+    for (solution, &has_data) in izip!(&mut solutions, &has_data) {
+        if !has_data {
+            *solution = Jones::nan();
+        }
+    }
+    Some((solutions, converged))
+}
+
 #[cfg(test)]
 mod tests {
     use approx::assert_abs_diff_eq;
+    use marlu::hifitime::{Duration, Epoch};
 
-    use crate::{compare_jones, Complex};
+    use crate::{
+        compare_jones, marlu::mwalib::MetafitsContext, test_common::get_1254670392_avg_paths,
+        Complex,
+    };
 
     use ndarray::{array, Array2, Array3};
 
@@ -365,4 +1220,440 @@ mod tests {
         compare_jones!(vis_array[(0, 0, 0)], exp_vis_array[(0, 0, 0)]);
         compare_jones!(vis_array[(0, 1, 0)], exp_vis_array[(0, 1, 0)]);
     }
+
+    /// Test the calsols are applied to visibilities averaged in time and frequency, and that
+    /// flagged visibilities are left out of the average.
+    #[test]
+    fn test_apply_calsols_averaged() {
+        let vis_ctx = VisContext {
+            num_sel_timesteps: 2,
+            start_timestamp: Epoch::from_gpst_seconds(1297526432.),
+            int_time: Duration::from_seconds(2.),
+            num_sel_chans: 4,
+            start_freq_hz: 167_000_000.,
+            freq_resolution_hz: 40_000.,
+            sel_baselines: vec![(0, 0), (0, 1)],
+            avg_time: 2,
+            avg_freq: 2,
+            num_vis_pols: 4,
+        };
+        let calsols = Array2::from_shape_fn((2, 2), |(i, _)| Jones::identity() * (i + 2) as f64);
+        let shape = vis_ctx.sel_dims();
+        let vis_array = Array3::from_shape_fn(shape, |(t, c, _)| {
+            Jones::<f32>::identity() * (t * 4 + c + 1) as f32
+        });
+        // the autocorrelation is flagged entirely, and one cross-correlation is flagged
+        let weight_array = Array3::from_shape_fn(shape, |(t, c, bl)| match (t, c, bl) {
+            (_, _, 0) | (1, 1, 1) => -1_f32,
+            _ => 1_f32,
+        });
+
+        let (avg_vis_array, avg_weight_array, avg_vis_ctx) = apply_di_calsol_averaged(
+            calsols.view(),
+            vis_array.view(),
+            weight_array.view(),
+            &vis_ctx,
+        )
+        .unwrap();
+
+        assert_eq!(avg_vis_array.dim(), (1, 2, 2));
+        assert_eq!(avg_vis_ctx.sel_dims(), (1, 2, 2));
+        assert_eq!((avg_vis_ctx.avg_time, avg_vis_ctx.avg_freq), (1, 1));
+        assert_abs_diff_eq!(avg_vis_ctx.freq_resolution_hz, 80_000.);
+        assert_abs_diff_eq!(avg_vis_ctx.int_time.to_seconds(), 4.);
+
+        assert!(avg_weight_array[(0, 0, 0)].is_sign_negative());
+        assert!(avg_weight_array[(0, 1, 0)].is_sign_negative());
+        assert_abs_diff_eq!(avg_weight_array[(0, 0, 1)], 3.);
+        assert_abs_diff_eq!(avg_weight_array[(0, 1, 1)], 4.);
+        // mean of 1, 2 and 5, then scaled by 2 * 3
+        compare_jones!(
+            avg_vis_array[(0, 0, 1)],
+            Jones::<f64>::identity() * (8. / 3. * 6.)
+        );
+        // mean of 3, 4, 7 and 8, then scaled by 2 * 3
+        compare_jones!(avg_vis_array[(0, 1, 1)], Jones::<f64>::identity() * 33.);
+    }
+
+    /// Test the averaged calsols are rejected when they have more channels than the averaged
+    /// visibilities.
+    #[test]
+    fn test_apply_calsols_averaged_too_many_chans() {
+        let vis_ctx = VisContext {
+            num_sel_timesteps: 1,
+            start_timestamp: Epoch::from_gpst_seconds(1297526432.),
+            int_time: Duration::from_seconds(2.),
+            num_sel_chans: 4,
+            start_freq_hz: 167_000_000.,
+            freq_resolution_hz: 40_000.,
+            sel_baselines: vec![(0, 1)],
+            avg_time: 1,
+            avg_freq: 4,
+            num_vis_pols: 4,
+        };
+        let calsols = Array2::from_elem((2, 4), Jones::identity());
+        let vis_array = Array3::from_elem(vis_ctx.sel_dims(), Jones::<f32>::identity());
+        let weight_array = Array3::from_elem(vis_ctx.sel_dims(), 1_f32);
+
+        assert!(matches!(
+            apply_di_calsol_averaged(
+                calsols.view(),
+                vis_array.view(),
+                weight_array.view(),
+                &vis_ctx,
+            ),
+            Err(CalibrationError::ChannelSizeMismatch {
+                calsol_chans: 4,
+                data_chans: 1
+            })
+        ));
+    }
+
+    /// Test the calsols are reordered to match the observation's tiles by name, and tiles missing
+    /// from the calsols get NaN solutions.
+    #[test]
+    fn test_match_calsol_tiles() {
+        let (metafits_path, _) = get_1254670392_avg_paths();
+        let antennas = MetafitsContext::new(metafits_path, None).unwrap().antennas;
+        let num_ants = antennas.len();
+
+        // solutions for all but the first tile, in reverse order.
+        let calsol_ants = antennas[1..].iter().rev().collect::<Vec<_>>();
+        let calsols = AOCalSols {
+            di_jones: Array3::from_shape_fn((1, num_ants - 1, 2), |(_, tile, _)| {
+                Jones::identity() * calsol_ants[tile].tile_id as f64
+            }),
+            start_timestamps: vec![],
+            tile_names: Some(calsol_ants.iter().map(|a| a.tile_name.clone()).collect()),
+            tile_ids: Some(calsol_ants.iter().map(|a| a.tile_id).collect()),
+            freqs_hz: None,
+        };
+
+        let matched = match_calsol_tiles(&calsols, &antennas).unwrap();
+        assert_eq!(matched.dim(), (1, num_ants, 2));
+        assert!(matched[(0, 0, 0)].any_nan());
+        for (antenna, matched) in izip!(&antennas[1..], matched.axis_iter(Axis(1)).skip(1)) {
+            compare_jones!(
+                matched[(0, 1)],
+                Jones::<f64>::identity() * antenna.tile_id as f64
+            );
+        }
+
+        // a tile ID which doesn't match the observation is an error.
+        let mut bad_ids = calsols.clone();
+        bad_ids.tile_ids.as_mut().unwrap()[0] += 1000;
+        assert!(matches!(
+            match_calsol_tiles(&bad_ids, &antennas),
+            Err(CalibrationError::TileIdMismatch { .. })
+        ));
+
+        // so are solutions which don't share any tiles with the observation.
+        let mut no_match = calsols.clone();
+        no_match.tile_names = Some(vec!["Tile999".into(); num_ants - 1]);
+        assert!(matches!(
+            match_calsol_tiles(&no_match, &antennas),
+            Err(CalibrationError::NoMatchingTiles { .. })
+        ));
+
+        // solutions without tile names must have a solution for each tile.
+        let unnamed = AOCalSols {
+            tile_names: None,
+            tile_ids: None,
+            ..calsols
+        };
+        assert!(matches!(
+            match_calsol_tiles(&unnamed, &antennas),
+            Err(CalibrationError::TileCountMismatch { .. })
+        ));
+    }
+
+    /// Test linear interpolation onto a finer, offset grid bridges a flagged solution channel,
+    /// and holds the end solutions beyond the solution grid.
+    #[test]
+    fn test_interpolate_calsols_linear() {
+        let calsol_freqs_hz = [100., 200., 300., 400.];
+        let mut calsols = Array2::from_shape_fn((2, 4), |(tile, chan)| {
+            Jones::identity() * (tile * 10 + chan) as f64
+        });
+        calsols[(0, 2)] = Jones::nan();
+        calsols[(1, 1)] = Jones::nan();
+        calsols[(1, 3)] = Jones::nan();
+        let freqs_hz = [50., 150., 250., 350., 450.];
+        let interp = CalsolInterp {
+            method: InterpMethod::Linear,
+            amp_phase: false,
+        };
+
+        let result =
+            interpolate_calsols(calsols.view(), &calsol_freqs_hz, &freqs_hz, interp).unwrap();
+
+        assert_eq!(result.dim(), (2, 5));
+        for (chan, expected) in [0., 0.5, 1.5, 2.5, 3.].iter().enumerate() {
+            compare_jones!(result[(0, chan)], Jones::<f64>::identity() * *expected);
+        }
+        for (chan, expected) in [10., 10.5, 11.5, 12., 12.].iter().enumerate() {
+            compare_jones!(result[(1, chan)], Jones::<f64>::identity() * *expected);
+        }
+
+        assert!(matches!(
+            interpolate_calsols(calsols.view(), &calsol_freqs_hz[..3], &freqs_hz, interp),
+            Err(CalibrationError::BadArrayShape { .. })
+        ));
+    }
+
+    /// Test splines pass through the solutions, and that amplitude and phase interpolation
+    /// follows the phase across a wrap instead of cutting through zero.
+    #[test]
+    fn test_interpolate_calsols_spline_amp_phase() {
+        let calsol_freqs_hz = [0., 1., 2., 3.];
+        let phases = [2.5, 3.0, -2.783_185_307_179_586_5, -2.283_185_307_179_586_5];
+        let calsols = Array2::from_shape_fn((1, 4), |(_, chan)| {
+            Jones::identity() * Complex::from_polar(2., phases[chan])
+        });
+        let freqs_hz = [0., 1., 1.5, 2., 3.];
+        let interp = CalsolInterp {
+            method: InterpMethod::Spline,
+            amp_phase: true,
+        };
+
+        let result =
+            interpolate_calsols(calsols.view(), &calsol_freqs_hz, &freqs_hz, interp).unwrap();
+
+        compare_jones!(result[(0, 0)], calsols[(0, 0)]);
+        compare_jones!(result[(0, 1)], calsols[(0, 1)]);
+        compare_jones!(result[(0, 3)], calsols[(0, 2)]);
+        compare_jones!(result[(0, 4)], calsols[(0, 3)]);
+        // the phase is linear once unwrapped, so the spline is too.
+        compare_jones!(
+            result[(0, 2)],
+            Jones::<f64>::identity() * Complex::from_polar(2., 3.25)
+        );
+        // the unflagged off-diagonals stay zero.
+        assert_abs_diff_eq!(result[(0, 2)][1].norm(), 0.);
+    }
+
+    /// Test the median filter removes a spike without leaking it into its neighbours, and keeps
+    /// flagged solutions flagged.
+    #[test]
+    fn test_smooth_calsols_median() {
+        let mut calsols =
+            Array2::from_shape_fn((1, 6), |(_, chan)| Jones::identity() * chan as f64);
+        calsols[(0, 2)] = Jones::identity() * 100.;
+        calsols[(0, 4)] = Jones::nan();
+        let smoothing = CalsolSmoothing {
+            median_width: Some(3),
+            ..CalsolSmoothing::default()
+        };
+
+        smooth_calsols(calsols.view_mut(), &[0.; 6], 6, &smoothing).unwrap();
+
+        compare_jones!(calsols[(0, 0)], Jones::<f64>::identity() * 0.);
+        compare_jones!(calsols[(0, 1)], Jones::<f64>::identity() * 1.);
+        compare_jones!(calsols[(0, 2)], Jones::<f64>::identity() * 3.);
+        // only the spike and one good neighbour are in this window, the spike is ignored.
+        compare_jones!(calsols[(0, 3)], Jones::<f64>::identity() * 3.);
+        assert!(calsols[(0, 4)].any_nan());
+        compare_jones!(calsols[(0, 5)], Jones::<f64>::identity() * 5.);
+    }
+
+    /// Test the amplitude fit is done separately for each coarse channel, and keeps the phase.
+    #[test]
+    fn test_smooth_calsols_amp_fit() {
+        // a line in each coarse channel, with alternating noise, and a flagged channel.
+        let noise = [0.1, -0.1, 0.1, -0.1];
+        let mut calsols = Array2::from_shape_fn((1, 8), |(_, chan)| {
+            let (coarse, fine) = (chan / 4, chan % 4);
+            let amp = (coarse * 10 + fine) as f64 + 1. + noise[fine];
+            Jones::identity() * Complex::from_polar(amp, 0.5)
+        });
+        calsols[(0, 6)] = Jones::nan();
+        let smoothing = CalsolSmoothing {
+            amp_poly_order: Some(1),
+            ..CalsolSmoothing::default()
+        };
+
+        smooth_calsols(calsols.view_mut(), &[0.; 8], 4, &smoothing).unwrap();
+
+        // the least squares line through the noisy first coarse channel.
+        for (chan, expected) in [1.06, 2.02, 2.98, 3.94].iter().enumerate() {
+            compare_jones!(
+                calsols[(0, chan)],
+                Jones::<f64>::identity() * Complex::from_polar(*expected, 0.5)
+            );
+        }
+        assert!(calsols[(0, 6)].any_nan());
+        assert_abs_diff_eq!(calsols[(0, 4)][0].arg(), 0.5, epsilon = 1e-12);
+        assert_abs_diff_eq!(calsols[(0, 4)][1].norm(), 0.);
+    }
+
+    /// Test the delay fit recovers a phase slope which wraps several times across the band.
+    #[test]
+    fn test_smooth_calsols_delay_fit() {
+        let freqs_hz = (0..16)
+            .map(|chan| 150e6 + chan as f64 * 40e3)
+            .collect::<Vec<_>>();
+        let delay_s = 50e-9;
+        let phase = |freq_hz: f64| 0.3 + TAU * delay_s * (freq_hz - freqs_hz[0]);
+        let noise = [0.05, -0.05];
+        let mut calsols = Array2::from_shape_fn((1, 16), |(_, chan)| {
+            Jones::identity() * Complex::from_polar(2., phase(freqs_hz[chan]) + noise[chan % 2])
+        });
+        calsols[(0, 5)] = Jones::nan();
+        let smoothing = CalsolSmoothing {
+            fit_delay: true,
+            ..CalsolSmoothing::default()
+        };
+
+        smooth_calsols(calsols.view_mut(), &freqs_hz, 16, &smoothing).unwrap();
+
+        assert!(calsols[(0, 5)].any_nan());
+        for (chan, &freq_hz) in freqs_hz.iter().enumerate().filter(|(chan, _)| *chan != 5) {
+            assert_abs_diff_eq!(
+                (calsols[(0, chan)][0] * Complex::from_polar(1., -phase(freq_hz))).arg(),
+                0.,
+                epsilon = 0.05
+            );
+            assert_abs_diff_eq!(calsols[(0, chan)][3].norm(), 2., epsilon = 1e-12);
+        }
+
+        assert!(matches!(
+            smooth_calsols(calsols.view_mut(), &freqs_hz[1..], 16, &smoothing),
+            Err(CalibrationError::BadArrayShape { .. })
+        ));
+        assert!(matches!(
+            smooth_calsols(calsols.view_mut(), &freqs_hz, 3, &smoothing),
+            Err(CalibrationError::BadArrayShape { .. })
+        ));
+    }
+
+    /// Test the grid of channel centres within each coarse channel, and across the band.
+    #[test]
+    fn test_coarse_chan_grid_hz() {
+        let (metafits_path, _) = get_1254670392_avg_paths();
+        let coarse_chans = MetafitsContext::new(metafits_path, None)
+            .unwrap()
+            .metafits_coarse_chans;
+        let grid = coarse_chan_grid_hz(&coarse_chans[..2], 2);
+        let (centre_hz, width_hz) = (
+            coarse_chans[0].chan_centre_hz as f64,
+            coarse_chans[0].chan_width_hz as f64,
+        );
+        assert_eq!(grid.len(), 4);
+        assert_abs_diff_eq!(grid[0], centre_hz - width_hz / 4.);
+        assert_abs_diff_eq!(grid[1], centre_hz + width_hz / 4.);
+        assert_abs_diff_eq!(grid[2], centre_hz + width_hz * 3. / 4.);
+
+        // solutions which evenly divide the coarse channels are on the same grid.
+        assert_eq!(calsol_grid_hz(&coarse_chans[..2], 4), grid);
+        // otherwise they evenly divide the whole band.
+        let grid = calsol_grid_hz(&coarse_chans[..2], 3);
+        assert_eq!(grid.len(), 3);
+        assert_abs_diff_eq!(grid[0], centre_hz - width_hz / 2. + width_hz / 3.);
+        assert_abs_diff_eq!(grid[1], centre_hz + width_hz / 2.);
+    }
+
+    /// Test the solutions recover the model from visibilities corrupted by known gains.
+    #[test]
+    fn test_solve_di_calsol() {
+        let num_tiles = 4;
+        let sel_baselines = (0..num_tiles)
+            .flat_map(|ant1| (ant1..num_tiles).map(move |ant2| (ant1, ant2)))
+            .collect::<Vec<_>>();
+        let gains = Array2::from_shape_fn((num_tiles, 2), |(tile, chan)| {
+            let (tile, chan) = (tile as f64, chan as f64);
+            Jones::from([
+                Complex::from_polar(1. + 0.1 * tile, 0.3 * tile + chan),
+                Complex::new(0.02 * tile, -0.01),
+                Complex::new(-0.01 * chan, 0.03),
+                Complex::from_polar(0.9 + 0.05 * tile, -0.2 * tile),
+            ])
+        });
+        let shape = (2, 2, sel_baselines.len());
+        let model_array = Array3::from_shape_fn(shape, |(t, c, bl)| {
+            Jones::<f32>::identity() * Complex::from_polar(1. + bl as f32, (t + c * bl) as f32)
+        });
+        let mut vis_array = Array3::from_shape_fn(shape, |(t, c, bl)| {
+            let (ant1, ant2) = sel_baselines[bl];
+            Jones::<f32>::from(
+                gains[(ant1, c)]
+                    * Jones::<f64>::from(model_array[(t, c, bl)])
+                    * gains[(ant2, c)].h(),
+            )
+        });
+        let mut weight_array = Array3::from_elem(shape, 1_f32);
+        let mut flag_array = Array3::from_elem(shape, false);
+
+        let (calsols, converged) = solve_di_calsol(
+            vis_array.view(),
+            model_array.view(),
+            weight_array.view(),
+            &sel_baselines,
+            num_tiles,
+            100,
+            1e-10,
+        )
+        .unwrap();
+        assert_eq!(calsols.dim(), (num_tiles, 2));
+        assert_eq!(converged, vec![true, true]);
+
+        apply_di_calsol(
+            calsols.mapv(Jones::inv).view(),
+            vis_array.view_mut(),
+            weight_array.view_mut(),
+            flag_array.view_mut(),
+            &sel_baselines,
+        )
+        .unwrap();
+        for (vis, model) in vis_array.iter().zip(model_array.iter()) {
+            assert_abs_diff_eq!(vis, model, epsilon = 1e-3);
+        }
+    }
+
+    /// Test tiles without usable data get NaN solutions, and bad shapes are rejected.
+    #[test]
+    fn test_solve_di_calsol_flagged_tile() {
+        let num_tiles = 4;
+        let sel_baselines = vec![(0, 1), (0, 2), (0, 3), (1, 2), (1, 3), (2, 3)];
+        let shape = (1, 1, sel_baselines.len());
+        let vis_array = Array3::from_elem(shape, Jones::<f32>::identity() * 4.);
+        let model_array = Array3::from_elem(shape, Jones::<f32>::identity());
+        let weight_array = Array3::from_shape_fn(shape, |(_, _, bl)| {
+            let (ant1, ant2) = sel_baselines[bl];
+            if ant1 == 3 || ant2 == 3 {
+                -1.
+            } else {
+                1.
+            }
+        });
+        let (calsols, _) = solve_di_calsol(
+            vis_array.view(),
+            model_array.view(),
+            weight_array.view(),
+            &sel_baselines,
+            num_tiles,
+            50,
+            1e-8,
+        )
+        .unwrap();
+        assert!(!calsols[(0, 0)].any_nan());
+        assert!(calsols[(3, 0)].any_nan());
+        assert_abs_diff_eq!(
+            calsols[(0, 0)] * calsols[(1, 0)].h(),
+            Jones::identity() * 4.,
+            epsilon = 1e-6
+        );
+
+        assert!(matches!(
+            solve_di_calsol(
+                vis_array.view(),
+                model_array.view(),
+                weight_array.view(),
+                &sel_baselines[1..],
+                num_tiles,
+                50,
+                1e-8,
+            ),
+            Err(CalibrationError::BadArrayShape { .. })
+        ));
+    }
 }
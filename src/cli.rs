@@ -4,28 +4,49 @@ use std::{
     convert::Into,
     ffi::OsString,
     fmt::{Debug, Display},
+    ops::Range,
+    path::{Path, PathBuf},
     time::Duration,
 };
 
 use cfg_if::cfg_if;
-use clap::{arg, command, ErrorKind::ArgumentNotFound, PossibleValue, ValueHint::FilePath};
+use clap::{
+    arg, command,
+    ErrorKind::ArgumentNotFound,
+    PossibleValue,
+    ValueHint::{DirPath, FilePath},
+};
 use indicatif::{ProgressDrawTarget, ProgressStyle};
 use itertools::{izip, Itertools};
 use log::{debug, info, trace, warn};
 use mwalib::{
     built_info::PKG_VERSION as MWALIB_PKG_VERSION, fitsio_sys::CFITSIO_VERSION, CableDelaysApplied,
-    CorrelatorContext, GeometricDelaysApplied, MWAVersion,
+    CorrelatorContext, GeometricDelaysApplied, MWAVersion, MetafitsContext, Rfinput,
 };
 use prettytable::{format as prettyformat, row, table};
 
 use crate::{
+    calibration::{
+        apply_di_calsol_averaged, calsol_grid_hz, interpolate_calsols, match_calsol_tiles,
+        smooth_calsols, solve_di_calsol, CalsolInterp, CalsolSmoothing, InterpMethod,
+    },
+    corrections::{calc_uvws, drift_phase_centres},
     error::{
         BirliError,
         BirliError::{BadMWAVersion, DryRun},
         CLIError::{InvalidCommandLineArgument, InvalidRangeSpecifier},
     },
-    flags::FlagContext,
-    io::{aocal::AOCalSols, read_mwalib, IOContext},
+    flags::{set_new_flag_reasons, FlagContext, FlagReason, FlagThresholds},
+    io::{
+        aocal::AOCalSols,
+        error::IOError,
+        layout::apply_layout,
+        ms::{write_drift_fields, MsReader, MS_DATA_COLUMNS},
+        passband::{read_passband_gains, write_passband_gains},
+        read_mwalib, read_mwalib_tolerant, read_mwalib_weights,
+        uvfits::UvfitsReader,
+        IOContext, VisRead,
+    },
     marlu::{
         built_info::PKG_VERSION as MARLU_PKG_VERSION,
         constants::{
@@ -34,12 +55,17 @@ use crate::{
         hifitime::{self, Epoch},
         io::{error::BadArrayShape, ms::MeasurementSetWriter, uvfits::UvfitsWriter, VisWrite},
         mwalib,
-        ndarray::s,
+        ndarray::{s, Array2, ArrayView3},
         precession::{precess_time, PrecessionInfo},
-        History, Jones, LatLngHeight, MwaObsContext, ObsContext, RADec, VisContext, ENH,
+        AzEl, History, Jones, LatLngHeight, MwaObsContext, ObsContext, RADec, VisContext,
+        XyzGeodetic, ENH,
     },
-    passband_gains::{PFB_COTTER_2014_10KHZ, PFB_JAKE_2022_200HZ},
-    with_increment_duration, Axis, Complex, FlagFileSet, PreprocessContext, VisSelection,
+    passband_gains::{PFB_COTTER_2014_10KHZ, PFB_JAKE_2022_200HZ, PFB_JAKE_2022_200HZ_OVERSAMPLED},
+    preprocessing::ChunkContext,
+    sky_model::SkyModel,
+    ssins::SsinsContext,
+    with_increment_duration, Array3, Axis, Complex, FlagFileSet, FlagOccupancy, FlagSource,
+    PreprocessContext, VisSelection,
 };
 
 cfg_if! {
@@ -48,12 +74,53 @@ cfg_if! {
     }
 }
 
+/// The mode to run Birli in, parsed from the command line arguments.
+pub enum BirliCommand<'a> {
+    /// Preprocess gpubox files, see [`BirliContext`]
+    Convert(BirliContext<'a>),
+    /// Reprocess visibilities which have already been converted, see [`ReprocessContext`]
+    Reprocess(ReprocessContext<'a>),
+    /// Solve for calibration solutions with `birli calibrate`, see [`CalibrateContext`]
+    Calibrate(CalibrateContext<'a>),
+}
+
+impl<'a> BirliCommand<'a> {
+    /// Parse an iterator of arguments, `args` into the context for the requested mode, parsing
+    /// the arguments only once.
+    ///
+    /// # Errors
+    ///
+    /// Can raise any error from [`BirliContext::from_args`] or [`CalibrateContext::from_args`],
+    /// or `IOError` if the visibilities to reprocess can't be read.
+    pub fn from_args<I, T>(args: I) -> Result<Self, BirliError>
+    where
+        I: IntoIterator<Item = T> + Debug,
+        T: Into<OsString> + Clone,
+    {
+        debug!("args:\n{:?}", &args);
+        let matches = BirliContext::get_matches(args)?;
+        trace!("arg matches:\n{:?}", &matches);
+        if let Some(("calibrate", calibrate_matches)) = matches.subcommand() {
+            return Ok(Self::Calibrate(CalibrateContext::from_matches(
+                calibrate_matches,
+            )?));
+        }
+        if let Some(reprocess_ctx) = ReprocessContext::from_matches(&matches)? {
+            return Ok(Self::Reprocess(reprocess_ctx));
+        }
+        Ok(Self::Convert(BirliContext::from_matches(&matches)?))
+    }
+}
+
 /// Args for preprocessing a correlator context.
 pub struct BirliContext<'a> {
     /// `mwalib::CorrelatorContext`
     pub corr_ctx: CorrelatorContext,
     /// Preprocessing parameters
     pub prep_ctx: PreprocessContext<'a>,
+    /// pfb passband gains read from `--passband-gains-in`, used instead of
+    /// `prep_ctx.passband_gains`, which can only borrow its gains
+    pub passband_gains: Option<Vec<f64>>,
     /// selected visibility indices
     pub vis_sel: VisSelection,
     /// Flagging Parameters
@@ -66,13 +133,102 @@ pub struct BirliContext<'a> {
     pub avg_freq: usize,
     /// temporal chunking factor
     pub num_timesteps_per_chunk: Option<usize>,
+    /// number of extra timesteps read and flagged either side of each chunk
+    pub num_timesteps_overlap: usize,
     /// channel selections for picket-fencing
     pub channel_range_sel: ChannelRanges,
 }
 
+/// Append the layout overrides applied from `io_ctx.layout_in`, if any, to a history message.
+fn with_layout_history(message: String, io_ctx: &IOContext) -> String {
+    match io_ctx.layout_in.as_ref() {
+        Some(layout_in) => format!(
+            "{message}; layout overrides from {}: {}",
+            layout_in.display(),
+            if io_ctx.layout_overrides.is_empty() {
+                "none".into()
+            } else {
+                io_ctx.layout_overrides.join("; ")
+            }
+        ),
+        None => message,
+    }
+}
+
+/// The path of the CSV listing unreadable HDUs, next to the flag occupancy CSV at
+/// `occupancy_out`, e.g. `occupancy.csv` becomes `occupancy_unreadable_hdus.csv`.
+fn unreadable_hdus_path(occupancy_out: &Path) -> PathBuf {
+    let stem = occupancy_out
+        .file_stem()
+        .map_or_else(Default::default, |stem| stem.to_string_lossy());
+    occupancy_out.with_file_name(format!("{stem}_unreadable_hdus.csv"))
+}
+
+/// A value given to `--phase-centre`, either `zenith` or an angle in degrees.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PhaseCentreValue {
+    Zenith,
+    Degrees(f64),
+}
+
+/// Parse a value of `--phase-centre`, as `zenith` (in any case) or an angle in degrees.
+fn parse_phase_centre_value(value: &str) -> Result<PhaseCentreValue, String> {
+    if value.eq_ignore_ascii_case("zenith") {
+        return Ok(PhaseCentreValue::Zenith);
+    }
+    value
+        .parse()
+        .map(PhaseCentreValue::Degrees)
+        .map_err(|_| "expected an RA and Dec in degrees, or `zenith`".into())
+}
+
+/// Attach `zenith` to a preceding `--phase-centre`, as in `--phase-centre=zenith`.
+///
+/// `--phase-centre` accepts hyphenated values for a negative Dec, so clap would otherwise take
+/// whatever follows `--phase-centre zenith` as the Dec.
+fn attach_phase_centre_zenith<I, T>(args: I) -> Vec<OsString>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString>,
+{
+    let mut attached = vec![];
+    let mut args = args.into_iter().map(Into::into).peekable();
+    while let Some(arg) = args.next() {
+        match args.peek().and_then(|next| next.to_str()) {
+            Some(next) if arg == "--phase-centre" && next.eq_ignore_ascii_case("zenith") => {
+                attached.push(format!("--phase-centre={next}").into());
+                args.next();
+            }
+            _ => attached.push(arg),
+        }
+    }
+    attached
+}
+
 // Add build-time information from the "built" crate.
 include!(concat!(env!("OUT_DIR"), "/built.rs"));
 
+/// Get the name and geodetic position of each antenna in the metafits, for the given array position.
+fn get_antenna_names_positions(
+    meta_ctx: &MetafitsContext,
+    array_pos: LatLngHeight,
+) -> (Vec<String>, Vec<XyzGeodetic>) {
+    let (s_lat, c_lat) = array_pos.latitude_rad.sin_cos();
+    meta_ctx
+        .antennas
+        .iter()
+        .map(|a| {
+            let enh = ENH {
+                e: a.east_m,
+                n: a.north_m,
+                h: a.height_m,
+            };
+            let xyz = enh.to_xyz_inner(s_lat, c_lat);
+            (a.tile_name.clone(), xyz)
+        })
+        .unzip()
+}
+
 /// stolen from hyperdrive
 /// Write many info-level log lines of how this executable was compiled.
 ///
@@ -252,6 +408,10 @@ impl Display for BirliContext<'_> {
 
         writeln!(f, "Array position:       {}", &self.prep_ctx.array_pos)?;
         writeln!(f, "Phase centre:         {}", &self.prep_ctx.phase_centre)?;
+        if let Some(phase_centres) = self.prep_ctx.drift_phase_centres.as_ref() {
+            let last_phase_centre = phase_centres[self.vis_sel.timestep_range.end - 1];
+            writeln!(f, "Drifting to:          {last_phase_centre}")?;
+        }
         let pointing_centre = RADec::from_mwalib_tile_pointing(&self.corr_ctx.metafits_context);
         if pointing_centre != self.prep_ctx.phase_centre {
             writeln!(f, "Pointing centre:      {}", &pointing_centre)?;
@@ -370,11 +530,16 @@ impl Display for BirliContext<'_> {
 
         writeln!(
             f,
-            "Scheduled Bandwidth:  {:.3}MHz = {:3} * {:3} * {:.3}kHz",
+            "Scheduled Bandwidth:  {:.3}MHz = {:3} * {:3} * {:.3}kHz{}",
             total_bandwidth_mhz,
             self.corr_ctx.metafits_context.num_metafits_coarse_chans,
             fine_chans_per_coarse,
-            fine_chan_width_khz
+            fine_chan_width_khz,
+            if self.corr_ctx.metafits_context.oversampled {
+                " (critically sampled band of oversampled coarse channels)"
+            } else {
+                ""
+            }
         )?;
 
         let out_bandwidth_mhz = self.vis_sel.coarse_chan_range.len() as f64
@@ -531,6 +696,17 @@ impl Display for BirliContext<'_> {
                 .count(),
             // format!("\n{}", ant_table)
         )?;
+        writeln!(
+            f,
+            "Flagged tiles: {:?}",
+            izip!(
+                &self.corr_ctx.metafits_context.antennas,
+                &self.flag_ctx.antenna_flags
+            )
+            .filter(|(_, &flag)| flag)
+            .map(|(ant, _)| ant.tile_name.as_str())
+            .collect_vec()
+        )?;
 
         writeln!(
             f,
@@ -563,6 +739,7 @@ impl Display for BirliContext<'_> {
         )?;
 
         if let Some(num_timesteps) = self.num_timesteps_per_chunk {
+            let num_timesteps = num_timesteps + 2 * self.num_timesteps_overlap;
             writeln!(
                 f,
                 "Estimated memory per chunk          = {:5}ts * {:6}ch * {:6}bl * ({}<Jones<f32>> + {}<f32> + {}<bool>) = {:7.02} GiB",
@@ -609,7 +786,11 @@ impl Display for BirliContext<'_> {
             avg_mem_per_timestep_gib * num_avg_timesteps as f64,
         )?;
 
-        writeln!(f, "Preprocessing Context: \n{}", &self.prep_ctx)?;
+        writeln!(
+            f,
+            "Preprocessing Context: \n{}",
+            &self.prep_ctx_with_gains(None)
+        )?;
 
         Ok(())
     }
@@ -639,15 +820,50 @@ impl<'a> BirliContext<'a> {
                 arg!(fits_paths: <PATHS>... "GPUBox files to process")
                     .help_heading("INPUT")
                     .value_hint(FilePath)
-                    .required(true),
+                    .required_unless_present_any(["uvfits-in", "ms-in"]),
+                arg!(--"tolerate-read-errors" "Flag HDUs which can't be read instead of stopping")
+                    .help_heading("INPUT"),
+                arg!(--"no-mwax-weights" "Ignore the MWAX weights HDUs")
+                    .help_heading("INPUT"),
+                arg!(--"uvfits-in" <PATH> "Read visibilities from a uvfits file instead of GPUBox files")
+                    .help_heading("INPUT")
+                    .value_hint(FilePath)
+                    .conflicts_with("fits_paths")
+                    .required(false),
+                arg!(--"ms-in" <PATH> "Read visibilities from a measurement set instead of GPUBox files")
+                    .help_heading("INPUT")
+                    .value_hint(DirPath)
+                    .conflicts_with_all(&["fits_paths", "uvfits-in"])
+                    .required(false),
+                arg!(--layout <PATH> "Override tile positions, cable lengths and flags from a CSV or JSON file")
+                    .help_heading("INPUT")
+                    .value_hint(FilePath)
+                    .required(false),
+                arg!(--"data-column" <COLUMN> "The measurement set column to read visibilities from")
+                    .help_heading("INPUT")
+                    .possible_values(MS_DATA_COLUMNS)
+                    .default_value("DATA")
+                    .requires("ms-in")
+                    .required(false),
 
                 // processing options
-                arg!(--"phase-centre" "Override Phase centre from metafits (degrees)")
-                    .value_names(&["RA", "DEC"])
+                arg!(--"phase-centre" "Override Phase centre from metafits (degrees), or `zenith` drift scan")
+                    .help_heading("PHASE CENTRE")
+                    .value_name("RA DEC")
+                    .min_values(1)
+                    .max_values(2)
+                    .allow_hyphen_values(true)
+                    .value_parser(parse_phase_centre_value)
+                    .required(false),
+                arg!(--"phase-centre-azel" "Drift scan phased to a fixed Az/El (degrees)")
+                    .help_heading("PHASE CENTRE")
+                    .value_names(&["AZ", "EL"])
                     .allow_hyphen_values(true)
+                    .conflicts_with("phase-centre")
                     .required(false),
                 arg!(--"pointing-centre" "Use pointing instead phase centre")
-                    .conflicts_with("phase-centre"),
+                    .help_heading("PHASE CENTRE")
+                    .conflicts_with_all(&["phase-centre", "phase-centre-azel"]),
                 arg!(--"emulate-cotter" "Use Cotter's array position, not MWAlib's"),
                 arg!(--"dry-run" "Just print the summary and exit"),
                 arg!(--"no-draw-progress" "do not show progress bars"),
@@ -681,6 +897,9 @@ impl<'a> BirliContext<'a> {
                 arg!(--"max-memory" <GIBIBYTES> "Estimate --time-chunk with <GIBIBYTES> GiB each chunk.")
                     .help_heading("RESOURCE LIMITS")
                     .required(false),
+                arg!(--"chunk-overlap" <STEPS> "Also read and flag <STEPS> timesteps either side of each chunk.")
+                    .help_heading("RESOURCE LIMITS")
+                    .required(false),
 
                 // flagging options
                 // -> timesteps
@@ -728,13 +947,57 @@ impl<'a> BirliContext<'a> {
                 // -> antennas
                 arg!(--"no-flag-metafits" "Ignore antenna flags in metafits")
                     .help_heading("FLAGGING"),
+                arg!(--"no-flag-bad-values" "Do not flag zero, NaN or infinite visibilities")
+                    .help_heading("FLAGGING"),
                 arg!(--"flag-antennas" <ANTS>... "Flag antenna indices")
                     .help_heading("FLAGGING")
                     .multiple_values(true)
                     .required(false),
+                arg!(--"flag-receivers" <RECEIVERS>... "Flag tiles on receiver numbers")
+                    .help_heading("FLAGGING")
+                    .multiple_values(true)
+                    .required(false),
+                arg!(--"flag-slots" <SLOTS>... "Flag tiles in receiver slots, as RX:SLOT")
+                    .help_heading("FLAGGING")
+                    .multiple_values(true)
+                    .required(false),
+                arg!(--"flag-rfinputs" <INPUTS>... "Flag tiles with metafits rfinput numbers")
+                    .help_heading("FLAGGING")
+                    .multiple_values(true)
+                    .required(false),
                 // -> baselines
                 arg!(--"flag-autos" "Flag auto correlations")
                     .help_heading("FLAGGING"),
+                arg!(--"flag-min-weight" <PERCENT> "Flag baselines with under <PERCENT>% data received")
+                    .help_heading("FLAGGING")
+                    .conflicts_with("no-mwax-weights")
+                    .required(false),
+                // -> elevation
+                arg!(--"flag-min-elevation" <DEG> "Flag times with phase centre below <DEG>")
+                    .help_heading("FLAGGING")
+                    .allow_hyphen_values(true)
+                    .required(false),
+                arg!(--"flag-sun-above" <DEG> "Flag times with the Sun above <DEG>")
+                    .help_heading("FLAGGING")
+                    .allow_hyphen_values(true)
+                    .required(false),
+                // -> occupancy thresholds
+                arg!(--"flag-timestep-occupancy" <PERCENT> "Flag timesteps over <PERCENT>% flagged")
+                    .help_heading("FLAGGING")
+                    .required(false),
+                arg!(--"flag-chan-occupancy" <PERCENT> "Flag fine chans over <PERCENT>% flagged")
+                    .help_heading("FLAGGING")
+                    .required(false),
+                arg!(--"flag-antenna-occupancy" <PERCENT> "Flag antennas over <PERCENT>% flagged")
+                    .help_heading("FLAGGING")
+                    .required(false),
+                // -> ssins
+                arg!(--"ssins" "Flag faint broadband RFI with SSINS")
+                    .help_heading("FLAGGING"),
+                arg!(--"ssins-sigma" <SIGMA> "SSINS flagging threshold [default: 5]")
+                    .help_heading("FLAGGING")
+                    .requires("ssins")
+                    .required(false),
 
                 // corrections
                 arg!(--"no-cable-delay" "Do not perform cable length corrections")
@@ -751,21 +1014,58 @@ impl<'a> BirliContext<'a> {
                         PossibleValue::new("cotter")
                             .help(
                                 "_sb128ChannelSubbandValue2014FromMemo from
-                                    subbandpassband.cpp in Cotter. Can only be used with resolutions of
-                                    n * 10kHz"
+                                    subbandpassband.cpp in Cotter, at a resolution of 10kHz"
                             ),
                         PossibleValue::new("jake")
                             .help("see: PFB_JAKE_2022_200HZ in src/passband_gains.rs"),
+                        PossibleValue::new("jake-oversampled")
+                            .help("Unmeasured model, see: PFB_JAKE_2022_200HZ_OVERSAMPLED in src/passband_gains.rs"),
                         PossibleValue::new("auto")
-                            .help("MWAX => jake, legacy => cotter"),
+                            .help("MWAX => jake (none if oversampled), legacy => cotter"),
+                        PossibleValue::new("empirical")
+                            .help("Measure the passband from the autocorrelations of the observation"),
                     ])
                     .default_value("auto")
                     .alias("pfb-gains")
                     .help_heading("CORRECTION"),
+                arg!(--"passband-gains-in" <PATH> "Read PFB passband filter gains from a subband passband file instead")
+                    .required(false)
+                    .conflicts_with("passband-gains")
+                    .help_heading("CORRECTION"),
+                arg!(--"normalise-by-autos" "Divide cross-correlations by autocorrelation amplitudes")
+                    .help_heading("CORRECTION"),
+                arg!(--"radiometer-weights" "Derive weights from autocorrelations")
+                    .help_heading("CORRECTION"),
 
                 // calibration
-                arg!(--"apply-di-cal" <PATH> "Apply DI calibration solutions before averaging")
+                arg!(--"apply-di-cal" <PATH> "Apply DI calibration solutions, before averaging unless --apply-di-cal-after-avg")
+                    .required(false)
+                    .value_hint(FilePath),
+                arg!(--"apply-di-cal-after-avg" "Apply DI calibration solutions after averaging instead")
+                    .requires("apply-di-cal"),
+                arg!(--"interp-di-cal" <METHOD> "Interpolate DI calibration solutions in frequency")
+                    .required(false)
+                    .possible_values([
+                        PossibleValue::new("none")
+                            .help("Use each solution for the channels it covers"),
+                        PossibleValue::new("linear").help("Linear interpolation"),
+                        PossibleValue::new("spline").help("Natural cubic spline interpolation"),
+                    ])
+                    .default_value("none")
+                    .requires("apply-di-cal"),
+                arg!(--"interp-di-cal-amp-phase" "Interpolate amplitude and phase instead of real and imaginary, with linear interpolation unless --interp-di-cal is given")
+                    .requires("apply-di-cal"),
+                arg!(--"median-di-cal" <WIDTH> "Median filter DI calibration solutions over <WIDTH> channels")
+                    .required(false)
+                    .requires("apply-di-cal"),
+                arg!(--"fit-di-cal-amp" <ORDER> "Fit a polynomial of <ORDER> to the amplitude of DI calibration solutions across each coarse channel")
+                    .required(false)
+                    .requires("apply-di-cal"),
+                arg!(--"fit-di-cal-delay" "Fit a delay to the phase of DI calibration solutions across the band")
+                    .requires("apply-di-cal"),
+                arg!(--"smoothed-di-cal-out" <PATH> "Write the smoothed DI calibration solutions to <PATH> (.bin or .fits)")
                     .required(false)
+                    .requires("apply-di-cal")
                     .value_hint(FilePath),
 
                 // averaging
@@ -796,6 +1096,13 @@ impl<'a> BirliContext<'a> {
                 arg!(-M --"ms-out" <PATH> "Path for measurement set output")
                     .help_heading("OUTPUT")
                     .required(false),
+                arg!(--"passband-gains-out" <PATH> "Path for subband passband file output of empirical passband gains")
+                    .help_heading("OUTPUT")
+                    .required(false),
+                arg!(--"occupancy-out" <PATH> "Path for flag occupancy CSV output. HDUs flagged with --tolerate-read-errors are listed in a sibling file ending in _unreadable_hdus.csv")
+                    .help_heading("OUTPUT")
+                    .value_hint(FilePath)
+                    .required(false),
             ]);
         cfg_if! {
             if #[cfg(feature = "aoflagger")] {
@@ -809,23 +1116,142 @@ impl<'a> BirliContext<'a> {
                 ]);
             }
         };
-        let matches = app.try_get_matches_from_mut(args)?;
+        // calibrate takes every preprocessing option, as well as the solver options.
+        let calibrate = app
+            .clone()
+            .name("calibrate")
+            .about("Solve for DI calibration solutions against a sky model, and apply them")
+            .args(&[
+                arg!(--"sky-model" <PATH> "Sky model of point and Gaussian components to solve \
+                        against, as text, JSON or YAML")
+                .help_heading("CALIBRATION")
+                .value_hint(FilePath),
+                arg!(--"calsols-out" <PATH> "Path for DI calibration solutions output")
+                    .help_heading("CALIBRATION")
+                    .value_hint(FilePath),
+                arg!(--"max-iterations" <COUNT> "Maximum number of solver iterations per channel")
+                    .help_heading("CALIBRATION")
+                    .default_value("50")
+                    .required(false),
+                arg!(--"stop-threshold" <THRESHOLD> "Relative change in the solutions at which \
+                        the solver stops iterating")
+                .help_heading("CALIBRATION")
+                .default_value("1e-6")
+                .required(false),
+            ]);
+        let matches = app
+            .subcommand_negates_reqs(true)
+            .subcommand(calibrate)
+            .try_get_matches_from_mut(attach_phase_centre_zenith(args))?;
         Ok(matches)
     }
 
-    fn parse_io_matches(matches: &clap::ArgMatches) -> IOContext {
-        IOContext {
+    fn parse_io_matches(matches: &clap::ArgMatches) -> Result<IOContext, BirliError> {
+        let min_mwax_weight = if matches.is_present("no-mwax-weights") {
+            None
+        } else {
+            match matches.value_of_t::<f32>("flag-min-weight") {
+                Ok(percent) if (0.0..=100.0).contains(&percent) => Some(percent / 100.),
+                Ok(percent) => {
+                    return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                        option: "--flag-min-weight <PERCENT>".into(),
+                        expected: "a percentage between 0 and 100".into(),
+                        received: format!("{percent}"),
+                    }))
+                }
+                Err(err) if err.kind() != ArgumentNotFound => return Err(err.into()),
+                Err(_) => Some(0.),
+            }
+        };
+        let aocalsols_smoothing = CalsolSmoothing {
+            median_width: match matches.value_of_t::<usize>("median-di-cal") {
+                Ok(width) if width > 0 => Some(width),
+                Ok(width) => {
+                    return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                        option: "--median-di-cal <WIDTH>".into(),
+                        expected: "a positive number of channels".into(),
+                        received: format!("{width}"),
+                    }))
+                }
+                Err(err) if err.kind() != ArgumentNotFound => return Err(err.into()),
+                Err(_) => None,
+            },
+            amp_poly_order: match matches.value_of_t::<usize>("fit-di-cal-amp") {
+                Ok(order) => Some(order),
+                Err(err) if err.kind() != ArgumentNotFound => return Err(err.into()),
+                Err(_) => None,
+            },
+            fit_delay: matches.is_present("fit-di-cal-delay"),
+        };
+        let amp_phase = matches.is_present("interp-di-cal-amp-phase");
+        let aocalsols_interp = match matches.value_of("interp-di-cal") {
+            // the default method, which amplitude and phase interpolation upgrades to linear.
+            None | Some("none") if amp_phase && matches.occurrences_of("interp-di-cal") == 0 => {
+                Some(CalsolInterp {
+                    method: InterpMethod::Linear,
+                    amp_phase,
+                })
+            }
+            None | Some("none") if amp_phase => {
+                return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                    option: "--interp-di-cal-amp-phase".into(),
+                    expected: "an --interp-di-cal method other than none".into(),
+                    received: "--interp-di-cal none".into(),
+                }))
+            }
+            None | Some("none") => None,
+            Some(method) => Some(CalsolInterp {
+                method: if method == "spline" {
+                    InterpMethod::Spline
+                } else {
+                    InterpMethod::Linear
+                },
+                amp_phase,
+            }),
+        };
+        Ok(IOContext {
             metafits_in: matches
                 .value_of_t("metafits")
                 .unwrap_or_else(|_| panic!("--metafits <PATH> is required, enforced by clap")),
-            gpufits_in: matches
-                .values_of_t("fits_paths")
-                .unwrap_or_else(|_| panic!("<PATHS> is required, enforced by clap")),
+            // <PATHS> is required unless --uvfits-in or --ms-in is present, enforced by clap
+            gpufits_in: matches.values_of_t("fits_paths").unwrap_or_default(),
+            tolerate_read_errors: matches.is_present("tolerate-read-errors"),
+            min_mwax_weight,
+            uvfits_in: matches.value_of("uvfits-in").map(Into::into),
+            ms_in: matches.value_of("ms-in").map(Into::into),
             aocalsols_in: matches.value_of("apply-di-cal").map(Into::into),
+            aocalsols_after_avg: matches.is_present("apply-di-cal-after-avg"),
+            aocalsols_interp,
+            aocalsols_smoothing,
+            aocalsols_smoothed_out: matches.value_of("smoothed-di-cal-out").map(Into::into),
+            layout_in: matches.value_of("layout").map(Into::into),
+            layout_overrides: vec![],
             uvfits_out: matches.value_of("uvfits-out").map(Into::into),
             ms_out: matches.value_of("ms-out").map(Into::into),
             flag_template: matches.value_of("flag-template").map(Into::into),
+            occupancy_out: matches.value_of("occupancy-out").map(Into::into),
+            passband_gains_out: matches.value_of("passband-gains-out").map(Into::into),
+        })
+    }
+
+    /// Override the tile positions, cable lengths and flags in `meta_ctx` with the layout file
+    /// in `io_ctx.layout_in`, if any, keeping a description of the overrides for the history.
+    fn override_layout(
+        io_ctx: &mut IOContext,
+        meta_ctx: &mut MetafitsContext,
+    ) -> Result<(), BirliError> {
+        if let Some(layout_in) = io_ctx.layout_in.as_ref() {
+            io_ctx.layout_overrides = apply_layout(layout_in, meta_ctx)?;
+            info!(
+                "Applied {} tile overrides from layout {}",
+                io_ctx.layout_overrides.len(),
+                layout_in.display()
+            );
+            for description in &io_ctx.layout_overrides {
+                info!("layout override {}", description);
+            }
         }
+        Ok(())
     }
 
     fn parse_vis_sel_matches(
@@ -1011,6 +1437,9 @@ impl<'a> BirliContext<'a> {
                 _ => return Err(err.into()),
             },
         };
+        for antenna_idx in Self::parse_rfinput_flag_matches(matches, &corr_ctx.metafits_context)? {
+            flag_ctx.antenna_flags[antenna_idx] = true;
+        }
         if matches.is_present("flag-autos") {
             flag_ctx.autos = true;
         }
@@ -1131,7 +1560,8 @@ impl<'a> BirliContext<'a> {
 
     fn parse_avg_matches(
         matches: &clap::ArgMatches,
-        corr_ctx: &CorrelatorContext,
+        int_time_s: f64,
+        fine_chan_width_khz: f64,
     ) -> Result<(usize, usize), BirliError> {
         let avg_time: usize = match (
             matches.value_of_t::<usize>("avg-time-factor"),
@@ -1155,7 +1585,6 @@ impl<'a> BirliContext<'a> {
                 factor
             }
             (_, Ok(res)) => {
-                let int_time_s = corr_ctx.metafits_context.corr_int_time_ms as f64 / 1e3;
                 let ratio = res / int_time_s;
                 if ratio.is_infinite() || ratio.fract() > 1e-6 || ratio < 1.0 {
                     return Err(BirliError::CLIError(InvalidCommandLineArgument {
@@ -1190,8 +1619,6 @@ impl<'a> BirliContext<'a> {
                 factor
             }
             (_, Ok(res)) => {
-                let fine_chan_width_khz =
-                    corr_ctx.metafits_context.corr_fine_chan_width_hz as f64 / 1e3;
                 let ratio = res / fine_chan_width_khz;
                 if ratio.is_infinite() || ratio.fract() > 1e-6 || ratio < 1.0 {
                     return Err(BirliError::CLIError(InvalidCommandLineArgument {
@@ -1209,13 +1636,19 @@ impl<'a> BirliContext<'a> {
         Ok((avg_time, avg_freq))
     }
 
+    /// Parse the chunking options for a selection of `num_timesteps` timesteps, which would take
+    /// `bytes_selected` bytes to read at once.
     fn parse_chunk_matches(
-        corr_ctx: &CorrelatorContext,
         matches: &clap::ArgMatches,
         avg_time: usize,
-        vis_sel: &VisSelection,
-    ) -> Result<Option<usize>, BirliError> {
-        let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+        num_timesteps: usize,
+        bytes_selected: usize,
+    ) -> Result<(Option<usize>, usize), BirliError> {
+        let num_timesteps_overlap = match matches.value_of_t::<usize>("chunk-overlap") {
+            Ok(steps) => steps,
+            Err(err) if err.kind() != ArgumentNotFound => return Err(err.into()),
+            Err(_) => 0,
+        };
         let num_timesteps_per_chunk: Option<usize> = match (
             matches.value_of_t::<usize>("time-chunk"),
             matches.value_of_t::<f64>("max-memory"),
@@ -1248,18 +1681,23 @@ impl<'a> BirliContext<'a> {
                         received: format!("{max_mem_bytes}B"),
                     }));
                 }
-                let bytes_selected = vis_sel.estimate_bytes_best(fine_chans_per_coarse);
-                let bytes_per_timestep = bytes_selected / vis_sel.timestep_range.len();
+                let bytes_per_timestep = bytes_selected / num_timesteps;
                 let bytes_per_avg_time = bytes_per_timestep * avg_time;
+                // each chunk also holds the overlapping timesteps either side of it.
+                let bytes_overlap = bytes_per_timestep * 2 * num_timesteps_overlap;
                 if max_mem_bytes < bytes_selected as f64 {
-                    if max_mem_bytes < bytes_per_avg_time as f64 {
+                    if max_mem_bytes < (bytes_per_avg_time + bytes_overlap) as f64 {
                         return Err(BirliError::CLIError(InvalidCommandLineArgument {
                             option: "--max-memory <GIBIBYTES>".into(),
-                            expected: format!("at least enough memory for an averaged timestep ({} * {:.02} = {:.02} GiB)", avg_time, bytes_per_timestep as f64 / 1024.0_f64.powi(3), bytes_per_avg_time as f64 / 1024.0_f64.powi(3)),
+                            expected: format!("at least enough memory for an averaged timestep and overlap ({} * {:.02} = {:.02} GiB)", avg_time + 2 * num_timesteps_overlap, bytes_per_timestep as f64 / 1024.0_f64.powi(3), (bytes_per_avg_time + bytes_overlap) as f64 / 1024.0_f64.powi(3)),
                             received: format!("{}GiB", max_mem_bytes / 1024.0_f64.powi(3)),
                         }));
                     }
-                    Some((max_mem_bytes / bytes_per_avg_time as f64).floor() as usize * avg_time)
+                    Some(
+                        ((max_mem_bytes - bytes_overlap as f64) / bytes_per_avg_time as f64).floor()
+                            as usize
+                            * avg_time,
+                    )
                 } else {
                     None
                 }
@@ -1270,9 +1708,216 @@ impl<'a> BirliContext<'a> {
         // validate chunk size
         if let Some(chunk_size) = num_timesteps_per_chunk {
             info!("chunking output to {} timesteps per chunk", chunk_size);
+            if num_timesteps_overlap > 0 {
+                info!(
+                    "reading and flagging {} timesteps either side of each chunk",
+                    num_timesteps_overlap
+                );
+            }
+        } else if num_timesteps_overlap > 0 {
+            warn!("--chunk-overlap is ignored without --time-chunk or --max-memory");
+        }
+
+        Ok((num_timesteps_per_chunk, num_timesteps_overlap))
+    }
+
+    /// Find the antenna indices selected by `--flag-receivers`, `--flag-slots` and
+    /// `--flag-rfinputs`, using the rfinputs of each antenna in the metafits.
+    fn parse_rfinput_flag_matches(
+        matches: &clap::ArgMatches,
+        meta_ctx: &MetafitsContext,
+    ) -> Result<Vec<usize>, BirliError> {
+        let rfinputs = || {
+            meta_ctx
+                .antennas
+                .iter()
+                .flat_map(|ant| [&ant.rfinput_x, &ant.rfinput_y])
+        };
+        // each option, and whether it selects an rfinput
+        type Selector = Box<dyn Fn(&Rfinput) -> bool>;
+        let mut selectors: Vec<(&str, Selector)> = vec![];
+
+        match matches.values_of_t::<u32>("flag-receivers") {
+            Ok(receivers) => {
+                for &receiver in &receivers {
+                    if !rfinputs().any(|rf| rf.rec_number == receiver) {
+                        return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                            option: "--flag-receivers <RECEIVERS>...".into(),
+                            expected: format!(
+                                "one of the receivers in the metafits: {:?}",
+                                rfinputs()
+                                    .map(|rf| rf.rec_number)
+                                    .unique()
+                                    .sorted()
+                                    .collect_vec()
+                            ),
+                            received: format!("{receiver}"),
+                        }));
+                    }
+                }
+                selectors.push((
+                    "--flag-receivers",
+                    Box::new(move |rf| receivers.contains(&rf.rec_number)),
+                ));
+            }
+            Err(err) if err.kind() != ArgumentNotFound => return Err(err.into()),
+            Err(_) => {}
+        };
+
+        match matches.values_of_t::<String>("flag-slots") {
+            Ok(values) => {
+                let mut slots = vec![];
+                for value in &values {
+                    let slot = value
+                        .split_once(':')
+                        .and_then(|(rx, slot)| Some((rx.parse().ok()?, slot.parse().ok()?)))
+                        .filter(|&(rx, slot)| {
+                            rfinputs().any(|rf| (rf.rec_number, rf.rec_slot_number) == (rx, slot))
+                        });
+                    match slot {
+                        Some(slot) => slots.push(slot),
+                        None => {
+                            return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                                option: "--flag-slots <SLOTS>...".into(),
+                                expected: "a receiver and slot in the metafits, as RX:SLOT".into(),
+                                received: value.clone(),
+                            }))
+                        }
+                    }
+                }
+                selectors.push((
+                    "--flag-slots",
+                    Box::new(move |rf| slots.contains(&(rf.rec_number, rf.rec_slot_number))),
+                ));
+            }
+            Err(err) if err.kind() != ArgumentNotFound => return Err(err.into()),
+            Err(_) => {}
+        };
+
+        match matches.values_of_t::<u32>("flag-rfinputs") {
+            Ok(inputs) => {
+                for &input in &inputs {
+                    if !rfinputs().any(|rf| rf.input == input) {
+                        return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                            option: "--flag-rfinputs <INPUTS>...".into(),
+                            expected: format!(
+                                "one of the rfinputs in the metafits: {:?}",
+                                rfinputs().map(|rf| rf.input).sorted().collect_vec()
+                            ),
+                            received: format!("{input}"),
+                        }));
+                    }
+                }
+                selectors.push((
+                    "--flag-rfinputs",
+                    Box::new(move |rf| inputs.contains(&rf.input)),
+                ));
+            }
+            Err(err) if err.kind() != ArgumentNotFound => return Err(err.into()),
+            Err(_) => {}
+        };
+
+        let mut antenna_idxs = vec![];
+        for (option, selector) in &selectors {
+            let selected = meta_ctx
+                .antennas
+                .iter()
+                .enumerate()
+                .filter(|(_, ant)| selector(&ant.rfinput_x) || selector(&ant.rfinput_y))
+                .collect_vec();
+            info!(
+                "{} selected tiles: {:?}",
+                option,
+                selected
+                    .iter()
+                    .map(|(_, ant)| ant.tile_name.as_str())
+                    .collect_vec()
+            );
+            antenna_idxs.extend(selected.into_iter().map(|(idx, _)| idx));
+        }
+        Ok(antenna_idxs.into_iter().unique().sorted().collect())
+    }
+
+    /// Flag timesteps with `--flag-min-elevation` and `--flag-sun-above`, given the centroid of
+    /// each timestep in `flag_ctx`.
+    #[allow(clippy::too_many_arguments)]
+    fn parse_elevation_flag_matches(
+        matches: &clap::ArgMatches,
+        flag_ctx: &mut FlagContext,
+        centroid_timestamps: &[Epoch],
+        dut1: hifitime::Duration,
+        array_pos: LatLngHeight,
+        phase_centre: RADec,
+        drift_azel: Option<AzEl>,
+    ) -> Result<(), BirliError> {
+        let parse_elevation = |option: &str| -> Result<Option<f64>, BirliError> {
+            match matches.value_of_t::<f64>(option) {
+                Ok(deg) if (-90.0..=90.0).contains(&deg) => Ok(Some(deg)),
+                Ok(deg) => Err(BirliError::CLIError(InvalidCommandLineArgument {
+                    option: format!("--{option} <DEG>"),
+                    expected: "an elevation between -90 and 90 degrees".into(),
+                    received: format!("{deg}"),
+                })),
+                Err(err) if err.kind() != ArgumentNotFound => Err(err.into()),
+                Err(_) => Ok(None),
+            }
+        };
+        if let Some(min_elevation_deg) = parse_elevation("flag-min-elevation")? {
+            let count = match drift_azel {
+                // the phase centre of a drift scan stays at the same elevation.
+                Some(azel) if azel.el.to_degrees() < min_elevation_deg => {
+                    let count = flag_ctx
+                        .timestep_flags
+                        .iter()
+                        .filter(|&&flag| !flag)
+                        .count();
+                    flag_ctx.timestep_flags.fill(true);
+                    count
+                }
+                Some(_) => 0,
+                None => flag_ctx.flag_min_elevation(
+                    centroid_timestamps,
+                    dut1,
+                    array_pos,
+                    phase_centre,
+                    min_elevation_deg,
+                ),
+            };
+            info!("--flag-min-elevation {min_elevation_deg} flagged {count} timesteps");
+        }
+        if let Some(max_elevation_deg) = parse_elevation("flag-sun-above")? {
+            let count =
+                flag_ctx.flag_sun_above(centroid_timestamps, dut1, array_pos, max_elevation_deg);
+            info!("--flag-sun-above {max_elevation_deg} flagged {count} timesteps");
         }
+        Ok(())
+    }
+
+    /// The values of `--phase-centre`, if any.
+    fn phase_centre_values(matches: &clap::ArgMatches) -> Option<Vec<PhaseCentreValue>> {
+        matches
+            .get_many::<PhaseCentreValue>("phase-centre")
+            .map(|values| values.copied().collect())
+    }
 
-        Ok(num_timesteps_per_chunk)
+    /// The Az/El of the phase centre of a drift scan, from `--phase-centre zenith` or
+    /// `--phase-centre-azel <AZ> <EL>`.
+    fn parse_drift_azel_matches(matches: &clap::ArgMatches) -> Result<Option<AzEl>, BirliError> {
+        if Self::phase_centre_values(matches).as_deref() == Some(&[PhaseCentreValue::Zenith]) {
+            return Ok(Some(AzEl::from_degrees(0., 90.)));
+        }
+        match matches.values_of_t::<f64>("phase-centre-azel") {
+            Ok(azel) if (-90.0..=90.0).contains(&azel[1]) => {
+                Ok(Some(AzEl::from_degrees(azel[0], azel[1])))
+            }
+            Ok(azel) => Err(BirliError::CLIError(InvalidCommandLineArgument {
+                option: "--phase-centre-azel <AZ> <EL>".into(),
+                expected: "an elevation between -90 and 90 degrees".into(),
+                received: format!("{} {}", azel[0], azel[1]),
+            })),
+            Err(err) if err.kind() != ArgumentNotFound => Err(err.into()),
+            Err(_) => Ok(None),
+        }
     }
 
     fn parse_prep_matches(
@@ -1283,30 +1928,32 @@ impl<'a> BirliContext<'a> {
             draw_progress: !matches.is_present("no-draw-progress"),
             ..PreprocessContext::default()
         };
-        prep_ctx.array_pos = if matches.is_present("emulate-cotter") {
-            info!("Using array position from Cotter.");
-            LatLngHeight {
-                longitude_rad: COTTER_MWA_LONGITUDE_RADIANS,
-                latitude_rad: COTTER_MWA_LATITUDE_RADIANS,
-                height_metres: COTTER_MWA_HEIGHT_METRES,
-            }
-        } else {
-            info!("Using default MWA array position.");
-            LatLngHeight::mwa()
-        };
+        prep_ctx.flag_bad_values = !matches.is_present("no-flag-bad-values");
+        prep_ctx.array_pos = Self::parse_array_pos_matches(matches);
         prep_ctx.phase_centre = match (
-            matches
-                .values_of_t::<f64>("phase-centre")
-                .map(|v| (v[0], v[1])),
+            Self::phase_centre_values(matches).as_deref(),
             matches.is_present("pointing-centre"),
         ) {
-            (Err(err), _) if err.kind() != ArgumentNotFound => return Err(err.into()),
-            (Ok(_), true) => {
+            (Some(_), true) => {
                 unreachable!("--phase-centre conflicts with --pointing-centre, enforced by clap");
             }
-            (Ok((ra, dec)), _) => RADec::from_degrees(ra, dec),
-            (_, true) => RADec::from_mwalib_tile_pointing(&corr_ctx.metafits_context),
-            _ => RADec::from_mwalib_phase_or_pointing(&corr_ctx.metafits_context),
+            (Some(&[PhaseCentreValue::Degrees(ra), PhaseCentreValue::Degrees(dec)]), _) => {
+                RADec::from_degrees(ra, dec)
+            }
+            // drift scans are phased to a separate centre at each timestep once the timesteps
+            // are known, see `BirliContext::from_matches`.
+            (Some(&[PhaseCentreValue::Zenith]), _) => {
+                RADec::from_mwalib_phase_or_pointing(&corr_ctx.metafits_context)
+            }
+            (Some(values), _) => {
+                return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                    option: "--phase-centre <RA> <DEC>".into(),
+                    expected: "an RA and Dec in degrees, or `zenith`".into(),
+                    received: format!("{values:?}"),
+                }))
+            }
+            (None, true) => RADec::from_mwalib_tile_pointing(&corr_ctx.metafits_context),
+            (None, false) => RADec::from_mwalib_phase_or_pointing(&corr_ctx.metafits_context),
         };
         prep_ctx.correct_cable_lengths = {
             let cable_delays_disabled = matches.is_present("no-cable-delay");
@@ -1321,18 +1968,55 @@ impl<'a> BirliContext<'a> {
             ) && !cable_delays_disabled
         };
         prep_ctx.correct_digital_gains = !matches.is_present("no-digital-gains");
-        prep_ctx.passband_gains = match matches.value_of("passband-gains") {
-            None | Some("none") => None,
-            Some("jake") => Some(PFB_JAKE_2022_200HZ),
-            Some("cotter") => Some(PFB_COTTER_2014_10KHZ),
-            Some("auto") => match corr_ctx.mwa_version {
+        prep_ctx.normalise_by_autos = matches.is_present("normalise-by-autos");
+        prep_ctx.passband_gains = match (
+            matches.value_of("passband-gains-in"),
+            matches.value_of("passband-gains"),
+        ) {
+            // owned by `BirliContext::passband_gains`
+            (Some(_), _) | (_, None | Some("none" | "empirical")) => None,
+            (_, Some("jake")) => Some(PFB_JAKE_2022_200HZ),
+            (_, Some("jake-oversampled")) => Some(PFB_JAKE_2022_200HZ_OVERSAMPLED.as_slice()),
+            (_, Some("cotter")) => Some(PFB_COTTER_2014_10KHZ),
+            (_, Some("auto")) => match corr_ctx.mwa_version {
+                // there are no measured gains for the oversampled channeliser yet.
+                MWAVersion::CorrMWAXv2 if corr_ctx.metafits_context.oversampled => None,
                 MWAVersion::CorrMWAXv2 => Some(PFB_JAKE_2022_200HZ),
                 MWAVersion::CorrLegacy | MWAVersion::CorrOldLegacy => Some(PFB_COTTER_2014_10KHZ),
                 #[rustfmt::skip]
                 ver => { return Err(BadMWAVersion { message: "unknown mwa version".into(), version: ver.to_string() }) },
             },
-            Some(option) => panic!("unknown option for --passband-gains: {option}"),
+            (_, Some(option)) => panic!("unknown option for --passband-gains: {option}"),
         };
+        prep_ctx.empirical_passband = !matches.is_present("passband-gains-in")
+            && matches.value_of("passband-gains") == Some("empirical");
+        match (
+            matches.value_of("passband-gains"),
+            corr_ctx.metafits_context.oversampled,
+        ) {
+            (Some("auto"), true) if !matches.is_present("passband-gains-in") => warn!(
+                "there are no measured passband gains for oversampled coarse channels, so no \
+                passband correction will be applied. Use --passband-gains empirical to measure \
+                the passband from this observation, or --passband-gains jake-oversampled for gains \
+                modelled from the critically sampled ones"
+            ),
+            (Some(option @ ("jake" | "cotter")), true) => warn!(
+                "--passband-gains {option} models a critically sampled coarse channeliser, but \
+                this observation used oversampled coarse channels"
+            ),
+            (Some(option @ "jake-oversampled"), false) => warn!(
+                "--passband-gains {option} models an oversampled coarse channeliser, but this \
+                observation used critically sampled coarse channels"
+            ),
+            _ => {}
+        }
+        if matches.is_present("passband-gains-out") && !prep_ctx.empirical_passband {
+            return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                option: "--passband-gains-out <PATH>".into(),
+                expected: "--passband-gains empirical".into(),
+                received: "passband gains which aren't measured".into(),
+            }));
+        }
         prep_ctx.correct_geometry = {
             let geometric_delays_disabled = matches.is_present("no-geometric-delay");
             let geometric_delays_applied = corr_ctx.metafits_context.geometric_delays_applied;
@@ -1343,43 +2027,115 @@ impl<'a> BirliContext<'a> {
             matches!(geometric_delays_applied, GeometricDelaysApplied::No)
                 && !geometric_delays_disabled
         };
-        cfg_if! {
-            if #[cfg(feature = "aoflagger")] {
-                prep_ctx.aoflagger_strategy = if matches.is_present("no-rfi") {
-                    None
-                } else {
-                    match matches.value_of_t("aoflagger-strategy") {
-                        Err(err) if err.kind() != ArgumentNotFound => return Err(err.into()),
-                        Ok(strategy) => Some(strategy),
-                        Err(_) => Some(unsafe {
-                            cxx_aoflagger_new().FindStrategyFileMWA()
-                        }),
-                    }
-                };
-            }
+        #[cfg(feature = "aoflagger")]
+        {
+            prep_ctx.aoflagger_strategy = Self::parse_aoflagger_strategy_matches(matches)?;
         }
+        prep_ctx.ssins = Self::parse_ssins_matches(matches)?;
+        prep_ctx.radiometer_weights = matches.is_present("radiometer-weights");
+        prep_ctx.flag_thresholds = Self::parse_flag_threshold_matches(matches)?;
         Ok(prep_ctx)
     }
 
-    /// Parse an iterator of arguments, `args` into a `BirliContext`.
-    ///
-    /// # Errors
-    ///
-    /// Can raise:
-    /// - `clap::Error` if clap cannot parse `args`
-    /// - `mwalib::MwalibError` if mwalib can't open the input files.
-    /// - `BirliError::CLIError` if the arguments are invalid.
-    pub fn from_args<I, T>(args: I) -> Result<Self, BirliError>
-    where
-        I: IntoIterator<Item = T> + Debug,
-        T: Into<OsString> + Clone,
-    {
-        debug!("args:\n{:?}", &args);
+    fn parse_ssins_matches(matches: &clap::ArgMatches) -> Result<Option<SsinsContext>, BirliError> {
+        if !matches.is_present("ssins") {
+            return Ok(None);
+        }
+        let mut ssins = SsinsContext::default();
+        match matches.value_of_t::<f64>("ssins-sigma") {
+            Ok(sigma) if sigma > 0.0 => ssins.sigma = sigma,
+            Ok(sigma) => {
+                return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                    option: "--ssins-sigma <SIGMA>".into(),
+                    expected: "a positive number".into(),
+                    received: format!("{sigma}"),
+                }))
+            }
+            Err(err) if err.kind() != ArgumentNotFound => return Err(err.into()),
+            Err(_) => {}
+        }
+        Ok(Some(ssins))
+    }
 
-        let matches = Self::get_matches(args)?;
-        trace!("arg matches:\n{:?}", &matches);
+    fn parse_flag_threshold_matches(
+        matches: &clap::ArgMatches,
+    ) -> Result<FlagThresholds, BirliError> {
+        let parse_threshold = |option: &str| -> Result<Option<f32>, BirliError> {
+            match matches.value_of_t::<f32>(option) {
+                Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(Some(percent / 100.)),
+                Ok(percent) => Err(BirliError::CLIError(InvalidCommandLineArgument {
+                    option: format!("--{option} <PERCENT>"),
+                    expected: "a percentage between 0 and 100".into(),
+                    received: format!("{percent}"),
+                })),
+                Err(err) if err.kind() != ArgumentNotFound => Err(err.into()),
+                Err(_) => Ok(None),
+            }
+        };
+        Ok(FlagThresholds {
+            timestep: parse_threshold("flag-timestep-occupancy")?,
+            chan: parse_threshold("flag-chan-occupancy")?,
+            antenna: parse_threshold("flag-antenna-occupancy")?,
+        })
+    }
 
-        for unimplemented_option in &["no-sel-autos", "no-sel-flagged-ants"] {
+    fn parse_array_pos_matches(matches: &clap::ArgMatches) -> LatLngHeight {
+        if matches.is_present("emulate-cotter") {
+            info!("Using array position from Cotter.");
+            LatLngHeight {
+                longitude_rad: COTTER_MWA_LONGITUDE_RADIANS,
+                latitude_rad: COTTER_MWA_LATITUDE_RADIANS,
+                height_metres: COTTER_MWA_HEIGHT_METRES,
+            }
+        } else {
+            info!("Using default MWA array position.");
+            LatLngHeight::mwa()
+        }
+    }
+
+    #[cfg(feature = "aoflagger")]
+    fn parse_aoflagger_strategy_matches(
+        matches: &clap::ArgMatches,
+    ) -> Result<Option<String>, BirliError> {
+        if matches.is_present("no-rfi") {
+            return Ok(None);
+        }
+        match matches.value_of_t("aoflagger-strategy") {
+            Err(err) if err.kind() != ArgumentNotFound => Err(err.into()),
+            Ok(strategy) => Ok(Some(strategy)),
+            Err(_) => Ok(Some(unsafe { cxx_aoflagger_new().FindStrategyFileMWA() })),
+        }
+    }
+
+    /// Parse an iterator of arguments, `args` into a `BirliContext`.
+    ///
+    /// # Errors
+    ///
+    /// Can raise:
+    /// - `clap::Error` if clap cannot parse `args`
+    /// - `mwalib::MwalibError` if mwalib can't open the input files.
+    /// - `BirliError::CLIError` if the arguments are invalid.
+    pub fn from_args<I, T>(args: I) -> Result<Self, BirliError>
+    where
+        I: IntoIterator<Item = T> + Debug,
+        T: Into<OsString> + Clone,
+    {
+        debug!("args:\n{:?}", &args);
+
+        let matches = Self::get_matches(args)?;
+        trace!("arg matches:\n{:?}", &matches);
+        if let Some((subcommand, _)) = matches.subcommand() {
+            return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                option: subcommand.into(),
+                expected: "no subcommand, see `BirliCommand::from_args`".into(),
+                received: subcommand.into(),
+            }));
+        }
+        Self::from_matches(&matches)
+    }
+
+    fn from_matches(matches: &clap::ArgMatches) -> Result<Self, BirliError> {
+        for unimplemented_option in &["no-sel-autos", "no-sel-flagged-ants"] {
             assert!(
                 !matches.is_present(unimplemented_option),
                 "option not yet implemented: --{unimplemented_option}"
@@ -1395,26 +2151,78 @@ impl<'a> BirliContext<'a> {
             }
         }
 
-        let io_ctx = Self::parse_io_matches(&matches);
-        let corr_ctx = io_ctx.get_corr_ctx()?;
+        let mut io_ctx = Self::parse_io_matches(matches)?;
+        let mut corr_ctx = io_ctx.get_corr_ctx()?;
+        Self::override_layout(&mut io_ctx, &mut corr_ctx.metafits_context)?;
         debug!("mwalib correlator context:\n{}", &corr_ctx);
-        let vis_sel = Self::parse_vis_sel_matches(&corr_ctx, &matches)?;
-        let mut flag_ctx = Self::parse_flag_matches(&corr_ctx, &matches)?;
-        let prep_ctx = Self::parse_prep_matches(&matches, &corr_ctx)?;
-        let (avg_time, avg_freq) = Self::parse_avg_matches(&matches, &corr_ctx)?;
-        let num_timesteps_per_chunk =
-            Self::parse_chunk_matches(&corr_ctx, &matches, avg_time, &vis_sel)?;
+        let vis_sel = Self::parse_vis_sel_matches(&corr_ctx, matches)?;
+        let mut flag_ctx = Self::parse_flag_matches(&corr_ctx, matches)?;
+        let mut prep_ctx = Self::parse_prep_matches(matches, &corr_ctx)?;
+        let (avg_time, avg_freq) = Self::parse_avg_matches(
+            matches,
+            corr_ctx.metafits_context.corr_int_time_ms as f64 / 1e3,
+            corr_ctx.metafits_context.corr_fine_chan_width_hz as f64 / 1e3,
+        )?;
+        // drift scans are phased to a separate centre at each timestep.
+        let drift_azel = Self::parse_drift_azel_matches(matches)?;
+        if let Some(azel) = drift_azel {
+            if io_ctx.uvfits_out.is_some() {
+                return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                    option: "--uvfits-out <PATH>".into(),
+                    expected: "measurement set output for a drift scan, uvfits files only have a \
+                        single phase centre"
+                        .into(),
+                    received: format!("a drift scan phase centre at Az/El {azel}"),
+                }));
+            }
+            let phase_centres = drift_phase_centres(
+                &corr_ctx,
+                &vis_sel.timestep_range,
+                avg_time,
+                prep_ctx.array_pos,
+                azel,
+            );
+            prep_ctx.phase_centre = phase_centres[vis_sel.timestep_range.start];
+            prep_ctx.drift_phase_centres = Some(phase_centres);
+        }
+        let int_time_s = corr_ctx.metafits_context.corr_int_time_ms as f64 / 1e3;
+        let centroid_timestamps = corr_ctx
+            .timesteps
+            .iter()
+            .map(|t| Epoch::from_gpst_seconds(t.gps_time_ms as f64 / 1e3 + int_time_s / 2.0))
+            .collect_vec();
+        Self::parse_elevation_flag_matches(
+            matches,
+            &mut flag_ctx,
+            &centroid_timestamps,
+            hifitime::Duration::from_seconds(corr_ctx.metafits_context.dut1.unwrap_or(0.0)),
+            prep_ctx.array_pos,
+            prep_ctx.phase_centre,
+            drift_azel,
+        )?;
+        let (num_timesteps_per_chunk, num_timesteps_overlap) = Self::parse_chunk_matches(
+            matches,
+            avg_time,
+            vis_sel.timestep_range.len(),
+            vis_sel.estimate_bytes_best(corr_ctx.metafits_context.num_corr_fine_chans_per_coarse),
+        )?;
         flag_ctx.finalise_flag_settings(&corr_ctx);
-        let channel_range_sel = Self::parse_sel_chan_ranges(&corr_ctx, &matches)?;
+        let channel_range_sel = Self::parse_sel_chan_ranges(&corr_ctx, matches)?;
+        let passband_gains = matches
+            .value_of("passband-gains-in")
+            .map(read_passband_gains)
+            .transpose()?;
         let result = Self {
             corr_ctx,
             prep_ctx,
+            passband_gains,
             vis_sel,
             flag_ctx,
             io_ctx,
             avg_time,
             avg_freq,
             num_timesteps_per_chunk,
+            num_timesteps_overlap,
             channel_range_sel,
         };
 
@@ -1438,12 +2246,14 @@ impl<'a> BirliContext<'a> {
         let mut ranged_context: BirliContext = BirliContext {
             corr_ctx: self.corr_ctx,
             prep_ctx: self.prep_ctx,
+            passband_gains: self.passband_gains,
             vis_sel: self.vis_sel,
             flag_ctx: self.flag_ctx,
             io_ctx: self.io_ctx,
             avg_time: self.avg_time,
             avg_freq: self.avg_freq,
             num_timesteps_per_chunk: self.num_timesteps_per_chunk,
+            num_timesteps_overlap: self.num_timesteps_overlap,
             channel_range_sel: self.channel_range_sel,
         };
         for &(range_start, range_end) in &ranges {
@@ -1478,11 +2288,84 @@ impl<'a> BirliContext<'a> {
                     path.extension().unwrap().to_str().unwrap()
                 ));
             }
+            if let Some(path) = ranged_context.io_ctx.occupancy_out.as_mut() {
+                path.set_file_name(format!(
+                    "{}{}.{}",
+                    path.file_stem().unwrap().to_str().unwrap(),
+                    suffix,
+                    path.extension().unwrap().to_str().unwrap()
+                ));
+            }
+            // the passband is measured from each range separately.
+            if let Some(path) = ranged_context.io_ctx.passband_gains_out.as_mut() {
+                path.set_file_name(format!(
+                    "{}{}.{}",
+                    path.file_stem().unwrap().to_str().unwrap(),
+                    suffix,
+                    path.extension().unwrap().to_str().unwrap()
+                ));
+            }
+            if let Some(path) = ranged_context.io_ctx.aocalsols_smoothed_out.as_mut() {
+                path.set_file_name(format!(
+                    "{}{}.{}",
+                    path.file_stem().unwrap().to_str().unwrap(),
+                    suffix,
+                    path.extension().unwrap().to_str().unwrap()
+                ));
+            }
             ranged_context.run()?;
         }
         Ok(())
     }
 
+    /// Read the calibration solutions in `io_ctx.aocalsols_in`, if any, for the selected
+    /// channels, see [`read_input_calsols`].
+    fn read_calsols(&self) -> Result<Option<Array2<Jones<f64>>>, BirliError> {
+        read_input_calsols(
+            &self.io_ctx,
+            &VisInput::Gpubox {
+                corr_ctx: &self.corr_ctx,
+                vis_sel: &self.vis_sel,
+            },
+            self.avg_time,
+            self.avg_freq,
+        )
+    }
+
+    /// Measure the passband gains from the autocorrelations of the selection if
+    /// `prep_ctx.empirical_passband` is set, writing them to `io_ctx.passband_gains_out` if given.
+    fn measure_passband_gains(&self) -> Result<Option<Vec<f64>>, BirliError> {
+        if !self.prep_ctx.empirical_passband {
+            return Ok(None);
+        }
+        let passband_gains = with_increment_duration!(
+            "correct_passband",
+            self.prep_ctx.measure_passband_gains(
+                &self.corr_ctx,
+                &self.vis_sel,
+                &self.flag_ctx.antenna_flags
+            )?
+        );
+        if let Some(path) = self.io_ctx.passband_gains_out.as_ref() {
+            write_passband_gains(path, &passband_gains)?;
+            info!("Wrote empirical passband gains to {}", path.display());
+        }
+        Ok(Some(passband_gains))
+    }
+
+    /// The preprocessing parameters to use, borrowing `measured_passband_gains` (see
+    /// [`BirliContext::measure_passband_gains`]) or the passband gains read from a file.
+    fn prep_ctx_with_gains<'b>(
+        &'b self,
+        measured_passband_gains: Option<&'b [f64]>,
+    ) -> PreprocessContext<'b> {
+        let mut prep_ctx: PreprocessContext<'b> = self.prep_ctx.clone();
+        if let Some(passband_gains) = measured_passband_gains.or(self.passband_gains.as_deref()) {
+            prep_ctx.passband_gains = Some(passband_gains);
+        }
+        prep_ctx
+    }
+
     /// Read, Preprocess and write corrected visibilities chunks.
     ///
     /// # Errors
@@ -1492,124 +2375,628 @@ impl<'a> BirliContext<'a> {
     ///     is incompatible with the visibility shape.
     /// - preprocessing errors
     pub fn run(&self) -> Result<(), BirliError> {
-        let Self {
-            corr_ctx,
-            vis_sel,
-            flag_ctx,
-            io_ctx,
-            avg_time,
-            avg_freq,
-            num_timesteps_per_chunk,
-            ..
-        } = self;
-        let mut prep_ctx = self.prep_ctx.clone();
+        self.run_with_solver(None)
+    }
 
-        // ////////// //
-        // Prepare IO //
-        // ////////// //
+    /// Like [`BirliContext::run`], but if a `solver` is given, solve for calibration solutions
+    /// once the visibilities are phased, and apply those instead. The whole selection is then
+    /// read in a single chunk, so the solutions see every timestep.
+    fn run_with_solver(&self, solver: Option<&CalibrateContext>) -> Result<(), BirliError> {
+        let measured_passband_gains = self.measure_passband_gains()?;
+        let mut prep_ctx = self.prep_ctx_with_gains(measured_passband_gains.as_deref());
+        prep_ctx.calsols = self.read_calsols()?;
+        let history_message = prep_ctx.as_comment();
+        let (num_timesteps_per_chunk, num_timesteps_overlap) = if solver.is_some() {
+            (None, 0)
+        } else {
+            (self.num_timesteps_per_chunk, self.num_timesteps_overlap)
+        };
+        ChunkRunner {
+            input: VisInput::Gpubox {
+                corr_ctx: &self.corr_ctx,
+                vis_sel: &self.vis_sel,
+            },
+            prep_ctx,
+            flag_ctx: &self.flag_ctx,
+            io_ctx: &self.io_ctx,
+            avg_time: self.avg_time,
+            avg_freq: self.avg_freq,
+            num_timesteps_per_chunk,
+            num_timesteps_overlap,
+            history_message,
+            solver,
+        }
+        .run()
+    }
+}
 
-        let vis_ctx = VisContext::from_mwalib(
-            corr_ctx,
-            &vis_sel.timestep_range,
-            &vis_sel.coarse_chan_range,
-            &vis_sel.baseline_idxs,
-            *avg_time,
-            *avg_freq,
-        );
+/// Args for reprocessing visibilities which have already been converted, with `--uvfits-in` or
+/// `--ms-in`.
+///
+/// Only the stages which make sense for converted data are available: flagging, applying
+/// calibration solutions, averaging and writing. Corrections are assumed to have been applied
+/// when the data was converted.
+pub struct ReprocessContext<'a> {
+    /// `mwalib::MetafitsContext` of the observation
+    pub meta_ctx: MetafitsContext,
+    /// Reader for the input visibilities
+    pub vis_reader: Box<dyn VisRead>,
+    /// Preprocessing parameters. Only the array position, phase centre, aoflagger strategy and
+    /// progress bar settings are used.
+    pub prep_ctx: PreprocessContext<'a>,
+    /// Range of timestep indices in the input file to process
+    pub timestep_range: Range<usize>,
+    /// Flagging Parameters, with a single coarse channel containing every channel in the input
+    pub flag_ctx: FlagContext,
+    /// Input / output paths
+    pub io_ctx: IOContext,
+    /// temporal averaging factor
+    pub avg_time: usize,
+    /// spectral averaging factor
+    pub avg_freq: usize,
+    /// temporal chunking factor
+    pub num_timesteps_per_chunk: Option<usize>,
+    /// number of extra timesteps read and flagged either side of each chunk
+    pub num_timesteps_overlap: usize,
+}
 
-        let num_avg_timesteps = vis_ctx.num_avg_timesteps();
-        let draw_target = if prep_ctx.draw_progress {
-            ProgressDrawTarget::stderr()
+impl Display for ReprocessContext<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{PKG_NAME} version {PKG_VERSION}")?;
+        writeln!(f, "observation name:     {}", self.meta_ctx.obs_name)?;
+        writeln!(
+            f,
+            "Reprocessing:         {}",
+            self.vis_reader.get_path().display()
+        )?;
+        writeln!(f, "Array position:       {}", &self.prep_ctx.array_pos)?;
+        writeln!(f, "Phase centre:         {}", &self.prep_ctx.phase_centre)?;
+        let (num_timesteps, num_chans, num_baselines) = self.vis_reader.get_shape();
+        let vis_ctx = self.vis_reader.get_vis_ctx(&self.timestep_range, 1, 1);
+        writeln!(
+            f,
+            "Timesteps:            {:?} of {} @ {}",
+            self.timestep_range, num_timesteps, vis_ctx.int_time
+        )?;
+        writeln!(
+            f,
+            "Channels:             {} @ {} kHz",
+            num_chans,
+            vis_ctx.freq_resolution_hz / 1e3
+        )?;
+        writeln!(f, "Baselines:            {num_baselines}")?;
+        let antenna_flag_idxs = self
+            .flag_ctx
+            .antenna_flags
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, &flag)| if flag { Some(idx) } else { None })
+            .collect_vec();
+        writeln!(f, "Flagged antennas:     {antenna_flag_idxs:?}")?;
+        if self.flag_ctx.autos {
+            writeln!(f, "Will flag autocorrelations.")?;
+        }
+        writeln!(
+            f,
+            "{} flag zero-valued and non-finite visibilities.",
+            if self.prep_ctx.flag_bad_values {
+                "Will"
+            } else {
+                "Will not"
+            }
+        )?;
+        cfg_if! {
+            if #[cfg(feature = "aoflagger")] {
+                if let Some(strategy) = &self.prep_ctx.aoflagger_strategy {
+                    writeln!(f, "Will flag with aoflagger strategy {strategy}")?;
+                } else {
+                    writeln!(f, "Will not flag with aoflagger")?;
+                }
+            }
+        }
+        if let Some(ssins) = &self.prep_ctx.ssins {
+            writeln!(f, "Will flag with {ssins}")?;
         } else {
-            ProgressDrawTarget::hidden()
-        };
-        let write_progress =
-            indicatif::ProgressBar::with_draw_target(Some(num_avg_timesteps as u64), draw_target);
-        write_progress.set_style(
-            ProgressStyle::default_bar()
-                .template(
-                    "{msg:16}: [{elapsed_precise}] [{wide_bar:.cyan/blue}] {percent:3}% ({eta:5})",
-                )
-                .unwrap()
-                .progress_chars("=> "),
-        );
-        write_progress.set_message("write vis");
+            writeln!(f, "Will not flag with SSINS")?;
+        }
+        write!(f, "{}", self.prep_ctx.flag_thresholds)?;
+        if let Some(calsols_in) = self.io_ctx.aocalsols_in.as_ref() {
+            writeln!(
+                f,
+                "Will apply calibration solutions {} {} averaging",
+                calsols_in.display(),
+                if self.io_ctx.aocalsols_after_avg {
+                    "after"
+                } else {
+                    "before"
+                }
+            )?;
+        }
+        if !self.io_ctx.aocalsols_smoothing.is_empty() {
+            writeln!(
+                f,
+                "Will smooth calibration solutions with {}",
+                self.io_ctx.aocalsols_smoothing
+            )?;
+        }
+        if let Some(interp) = self.io_ctx.aocalsols_interp.as_ref() {
+            writeln!(f, "Will interpolate calibration solutions with {interp}")?;
+        }
+        if self.prep_ctx.normalise_by_autos {
+            writeln!(f, "Will normalise by autocorrelation amplitudes.")?;
+        }
+        if self.prep_ctx.radiometer_weights {
+            writeln!(f, "Will derive weights from the radiometer equation.")?;
+        }
+        writeln!(
+            f,
+            "Averaging:            {} timesteps, {} channels",
+            self.avg_time, self.avg_freq
+        )?;
+        if let Some(uvfits_out) = self.io_ctx.uvfits_out.as_ref() {
+            writeln!(f, "Will write uvfits to: {}", uvfits_out.display())?;
+        }
+        if let Some(ms_out) = self.io_ctx.ms_out.as_ref() {
+            writeln!(f, "Will write ms to:     {}", ms_out.display())?;
+        }
+        Ok(())
+    }
+}
 
-        // TODO: move phase_centre, array_pos out of prep_ctx
-        let obs_ctx = ObsContext {
-            phase_centre: prep_ctx.phase_centre,
-            array_pos: prep_ctx.array_pos,
-            ..ObsContext::from_mwalib(&corr_ctx.metafits_context)
-        };
+impl<'a> ReprocessContext<'a> {
+    /// Parse the arguments into a `ReprocessContext`, or `None` if they don't ask for
+    /// already-converted visibilities to be reprocessed.
+    ///
+    /// # Errors
+    ///
+    /// Can raise:
+    /// - `mwalib::MwalibError` if mwalib can't open the metafits.
+    /// - `IOError` if the input visibilities can't be read.
+    /// - `BirliError::CLIError` if the arguments are invalid.
+    fn from_matches(matches: &clap::ArgMatches) -> Result<Option<Self>, BirliError> {
+        let mut io_ctx = BirliContext::parse_io_matches(matches)?;
+        let (vis_reader, input_option): (Box<dyn VisRead>, _) =
+            match (io_ctx.uvfits_in.as_ref(), io_ctx.ms_in.as_ref()) {
+                (Some(uvfits_in), _) => (Box::new(UvfitsReader::new(uvfits_in)?), "--uvfits-in"),
+                (_, Some(ms_in)) => {
+                    let data_column = matches.value_of("data-column").unwrap_or("DATA");
+                    (Box::new(MsReader::new(ms_in, data_column)?), "--ms-in")
+                }
+                _ => return Ok(None),
+            };
 
-        let mwa_ctx = MwaObsContext::from_mwalib(&corr_ctx.metafits_context);
+        for ignored_option in &[
+            "tolerate-read-errors",
+            "no-mwax-weights",
+            "flag-min-weight",
+            "phase-centre",
+            "phase-centre-azel",
+            "pointing-centre",
+            "sel-ants",
+            "sel-chan-ranges",
+            "provided-chan-ranges",
+            "flag-init",
+            "flag-init-steps",
+            "flag-end",
+            "flag-end-steps",
+            "flag-coarse-chans",
+            "flag-edge-width",
+            "flag-edge-chans",
+            "flag-fine-chans",
+            "flag-dc",
+            "flag-template",
+            "occupancy-out",
+        ] {
+            if matches.is_present(ignored_option) {
+                warn!(
+                    "option is ignored when reprocessing converted visibilities: --{}",
+                    ignored_option
+                );
+            }
+        }
 
-        prep_ctx.calsols = if let Some(ref calsol_file) = io_ctx.aocalsols_in {
-            let calsols = AOCalSols::read_andre_binary(calsol_file).unwrap();
-            assert!(
-                calsols.di_jones.dim().0 == 1,
-                "only 1 timeblock must be supplied for calsols. \
-                Instead found {} timeblocks. dimensions {:?}",
-                calsols.di_jones.dim().1,
-                calsols.di_jones.dim()
-            );
-            let calsol_chans = calsols.di_jones.dim().2;
-            if calsol_chans % corr_ctx.num_coarse_chans != 0 {
-                return Err(BirliError::BadArrayShape(BadArrayShape {
-                    argument: "input AO calibration solutions",
-                    function: "BirliContext::run",
-                    expected: format!(
-                        "a multiple of metafits_num_coarse_chans={}",
-                        corr_ctx.metafits_context.num_metafits_coarse_chans
-                    ),
-                    received: format!("{calsol_chans}"),
-                }));
+        let mut meta_ctx = MetafitsContext::new(&io_ctx.metafits_in, None)?;
+        BirliContext::override_layout(&mut io_ctx, &mut meta_ctx)?;
+        debug!("mwalib metafits context:\n{}", &meta_ctx);
+
+        if let Some(&(ant1, ant2)) = vis_reader
+            .get_ant_pairs()
+            .iter()
+            .find(|&&(ant1, ant2)| ant1.max(ant2) >= meta_ctx.num_ants)
+        {
+            return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                option: format!("{input_option} <PATH>"),
+                expected: format!("antenna indices < num_ants={}", meta_ctx.num_ants),
+                received: format!("baseline ({ant1}, {ant2})"),
+            }));
+        }
+
+        let (num_timesteps, num_chans, _) = vis_reader.get_shape();
+        let timestep_range = match matches.values_of_t::<usize>("sel-time") {
+            Ok(sel_time) => {
+                let (min, max) = (sel_time[0], sel_time[1]);
+                if min > max || max >= num_timesteps {
+                    return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                        option: "--sel-time <MIN> <MAX>".into(),
+                        expected: format!("0 <= MIN <= MAX < num_timesteps={num_timesteps}"),
+                        received: format!("{min} {max}"),
+                    }));
+                }
+                min..max + 1
             }
-            let num_calsol_fine_chans_per_coarse = calsol_chans / corr_ctx.num_coarse_chans;
-            Some(
-                calsols
-                    .di_jones
-                    .index_axis(Axis(0), 0)
-                    .slice(s![
-                        ..,
-                        (vis_sel.coarse_chan_range.start * num_calsol_fine_chans_per_coarse)
-                            ..(vis_sel.coarse_chan_range.end * num_calsol_fine_chans_per_coarse)
-                    ])
-                    .to_owned(),
-            )
-        } else {
-            None
+            Err(err) if err.kind() != ArgumentNotFound => return Err(err.into()),
+            Err(_) => 0..num_timesteps,
         };
 
-        let args_strings = std::env::args().collect_vec();
-        let cmd_line = shlex::try_join(args_strings.iter().map(String::as_str))?;
-        let application = format!("{PKG_NAME} {PKG_VERSION}");
-        let message = prep_ctx.as_comment();
-        let history = History {
-            cmd_line: Some(&cmd_line),
-            application: Some(&application),
-            message: Some(&message),
+        let mut flag_ctx =
+            FlagContext::blank_from_dimensions(num_timesteps, 1, num_chans, meta_ctx.num_ants);
+        if matches.is_present("no-flag-metafits") {
+            info!("Ignoring antenna flags from metafits.");
+        } else {
+            for (antenna, flag) in
+                izip!(meta_ctx.antennas.iter(), flag_ctx.antenna_flags.iter_mut())
+            {
+                *flag = antenna.rfinput_x.flagged || antenna.rfinput_y.flagged;
+            }
+        }
+        match matches.values_of_t::<usize>("flag-antennas") {
+            Ok(antenna_idxs) => {
+                for (value_idx, &antenna_idx) in antenna_idxs.iter().enumerate() {
+                    if antenna_idx >= meta_ctx.num_ants {
+                        return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                            option: "--flag-antennas <ANTS>...".into(),
+                            expected: format!("antenna_idx < num_ants={}", meta_ctx.num_ants),
+                            received: format!(
+                                "antenna_idxs[{value_idx}]={antenna_idx}. all:{antenna_idxs:?}"
+                            ),
+                        }));
+                    }
+                    flag_ctx.antenna_flags[antenna_idx] = true;
+                }
+            }
+            Err(err) if err.kind() != ArgumentNotFound => return Err(err.into()),
+            Err(_) => {}
         };
-        let (s_lat, c_lat) = obs_ctx.array_pos.latitude_rad.sin_cos();
-        let (antenna_names, antenna_positions): (Vec<String>, Vec<marlu::XyzGeodetic>) = corr_ctx
-            .metafits_context
-            .antennas
-            .iter()
-            .map(|a| {
-                let enh = ENH {
-                    e: a.east_m,
-                    n: a.north_m,
-                    h: a.height_m,
-                };
-                let xyz = enh.to_xyz_inner(s_lat, c_lat);
-                (a.tile_name.clone(), xyz)
-            })
-            .unzip();
-        let dut1 = hifitime::Duration::from_seconds(corr_ctx.metafits_context.dut1.unwrap_or(0.0));
-        let mut uvfits_writer = io_ctx.uvfits_out.as_ref().map(|uvfits_out| {
-            with_increment_duration!("init", {
-                UvfitsWriter::from_marlu(
-                    uvfits_out,
+        for antenna_idx in BirliContext::parse_rfinput_flag_matches(matches, &meta_ctx)? {
+            flag_ctx.antenna_flags[antenna_idx] = true;
+        }
+        match matches.values_of_t::<usize>("flag-times") {
+            Ok(timestep_idxs) => {
+                for (value_idx, &timestep_idx) in timestep_idxs.iter().enumerate() {
+                    if timestep_idx >= num_timesteps {
+                        return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                            option: "--flag-times <TIMESTEPS>...".into(),
+                            expected: format!("timestep_idx < num_timesteps={num_timesteps}"),
+                            received: format!(
+                                "timestep_idxs[{value_idx}]={timestep_idx}. all:{timestep_idxs:?}"
+                            ),
+                        }));
+                    }
+                    flag_ctx.timestep_flags[timestep_idx] = true;
+                }
+            }
+            Err(err) if err.kind() != ArgumentNotFound => return Err(err.into()),
+            Err(_) => {}
+        };
+        flag_ctx.autos = matches.is_present("flag-autos");
+
+        #[allow(unused_mut)]
+        let mut prep_ctx = PreprocessContext {
+            array_pos: BirliContext::parse_array_pos_matches(matches),
+            phase_centre: vis_reader.get_phase_centre(),
+            flag_bad_values: !matches.is_present("no-flag-bad-values"),
+            normalise_by_autos: matches.is_present("normalise-by-autos"),
+            radiometer_weights: matches.is_present("radiometer-weights"),
+            draw_progress: !matches.is_present("no-draw-progress"),
+            ..PreprocessContext::default()
+        };
+        #[cfg(feature = "aoflagger")]
+        {
+            prep_ctx.aoflagger_strategy = BirliContext::parse_aoflagger_strategy_matches(matches)?;
+        }
+        prep_ctx.ssins = BirliContext::parse_ssins_matches(matches)?;
+        prep_ctx.flag_thresholds = BirliContext::parse_flag_threshold_matches(matches)?;
+        for (enabled, option) in [
+            (prep_ctx.normalise_by_autos, "--normalise-by-autos"),
+            (prep_ctx.radiometer_weights, "--radiometer-weights"),
+        ] {
+            let ant_pairs = vis_reader.get_ant_pairs();
+            let has_autos = ant_pairs
+                .iter()
+                .flat_map(|&(ant1, ant2)| [ant1, ant2])
+                .all(|ant| ant_pairs.contains(&(ant, ant)));
+            if enabled && !has_autos {
+                return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                    option: option.into(),
+                    expected: "autocorrelations for every antenna in the input".into(),
+                    received: format!("no autocorrelations in {input_option}"),
+                }));
+            }
+        }
+
+        let centroid_timestamps = vis_reader
+            .get_vis_ctx(&(0..num_timesteps), 1, 1)
+            .timeseries(false, true)
+            .collect_vec();
+        BirliContext::parse_elevation_flag_matches(
+            matches,
+            &mut flag_ctx,
+            &centroid_timestamps,
+            hifitime::Duration::from_seconds(meta_ctx.dut1.unwrap_or(0.0)),
+            prep_ctx.array_pos,
+            prep_ctx.phase_centre,
+            None,
+        )?;
+
+        let vis_ctx = vis_reader.get_vis_ctx(&timestep_range, 1, 1);
+        let (avg_time, avg_freq) = BirliContext::parse_avg_matches(
+            matches,
+            vis_ctx.int_time.to_seconds(),
+            vis_ctx.freq_resolution_hz / 1e3,
+        )?;
+        let (_, num_chans, num_baselines) = vis_reader.get_shape();
+        let (num_timesteps_per_chunk, num_timesteps_overlap) = BirliContext::parse_chunk_matches(
+            matches,
+            avg_time,
+            timestep_range.len(),
+            timestep_range.len()
+                * num_chans
+                * num_baselines
+                * (std::mem::size_of::<Jones<f32>>()
+                    + std::mem::size_of::<f32>()
+                    + std::mem::size_of::<bool>()),
+        )?;
+
+        let result = Self {
+            meta_ctx,
+            vis_reader,
+            prep_ctx,
+            timestep_range,
+            flag_ctx,
+            io_ctx,
+            avg_time,
+            avg_freq,
+            num_timesteps_per_chunk,
+            num_timesteps_overlap,
+        };
+
+        info!("{}", &result);
+
+        if matches.is_present("dry-run") {
+            return Err(DryRun {});
+        }
+
+        Ok(Some(result))
+    }
+
+    /// Read the calibration solutions in `io_ctx.aocalsols_in`, if any, for the channels of the
+    /// input, see [`read_input_calsols`].
+    fn read_calsols(&self) -> Result<Option<Array2<Jones<f64>>>, BirliError> {
+        read_input_calsols(
+            &self.io_ctx,
+            &VisInput::Converted {
+                meta_ctx: &self.meta_ctx,
+                vis_reader: self.vis_reader.as_ref(),
+                timestep_range: &self.timestep_range,
+            },
+            self.avg_time,
+            self.avg_freq,
+        )
+    }
+
+    /// Read, flag, calibrate, average and write the converted visibilities in chunks.
+    ///
+    /// # Errors
+    ///
+    /// can raise:
+    /// - `IOError` if the visibilities can't be read.
+    /// - `CalibrationError` if the calibration solutions are incompatible with the visibilities.
+    pub fn run(&self) -> Result<(), BirliError> {
+        let mut prep_ctx = self.prep_ctx.clone();
+        prep_ctx.calsols = self.read_calsols()?;
+        ChunkRunner {
+            input: VisInput::Converted {
+                meta_ctx: &self.meta_ctx,
+                vis_reader: self.vis_reader.as_ref(),
+                timestep_range: &self.timestep_range,
+            },
+            prep_ctx,
+            flag_ctx: &self.flag_ctx,
+            io_ctx: &self.io_ctx,
+            avg_time: self.avg_time,
+            avg_freq: self.avg_freq,
+            num_timesteps_per_chunk: self.num_timesteps_per_chunk,
+            num_timesteps_overlap: self.num_timesteps_overlap,
+            history_message: format!("reprocessed {}", self.vis_reader.get_path().display()),
+            solver: None,
+        }
+        .run()
+    }
+}
+
+/// The visibilities read by a [`ChunkRunner`].
+enum VisInput<'i> {
+    /// The selected visibilities of the gpubox files in a correlator context.
+    Gpubox {
+        /// `mwalib::CorrelatorContext`
+        corr_ctx: &'i CorrelatorContext,
+        /// selected visibility indices
+        vis_sel: &'i VisSelection,
+    },
+    /// Visibilities which have already been converted, treated as a single coarse channel.
+    Converted {
+        /// `mwalib::MetafitsContext` of the observation
+        meta_ctx: &'i MetafitsContext,
+        /// Reader for the input visibilities
+        vis_reader: &'i dyn VisRead,
+        /// Range of timestep indices in the input file to process
+        timestep_range: &'i Range<usize>,
+    },
+}
+
+impl VisInput<'_> {
+    const fn meta_ctx(&self) -> &MetafitsContext {
+        match self {
+            Self::Gpubox { corr_ctx, .. } => &corr_ctx.metafits_context,
+            Self::Converted { meta_ctx, .. } => meta_ctx,
+        }
+    }
+
+    const fn corr_ctx(&self) -> Option<&CorrelatorContext> {
+        match self {
+            Self::Gpubox { corr_ctx, .. } => Some(corr_ctx),
+            Self::Converted { .. } => None,
+        }
+    }
+
+    /// The layout of every selected visibility.
+    fn chunk_ctx(&self) -> ChunkContext {
+        match self {
+            Self::Gpubox { corr_ctx, vis_sel } => ChunkContext::from_mwalib(corr_ctx, vis_sel),
+            Self::Converted {
+                vis_reader,
+                timestep_range,
+                ..
+            } => {
+                let (_, num_chans, num_baselines) = vis_reader.get_shape();
+                ChunkContext {
+                    vis_sel: VisSelection {
+                        timestep_range: (*timestep_range).clone(),
+                        coarse_chan_range: 0..1,
+                        baseline_idxs: (0..num_baselines).collect(),
+                    },
+                    fine_chans_per_coarse: num_chans,
+                    ant_pairs: vis_reader.get_ant_pairs().to_vec(),
+                    freqs_hz: vis_reader
+                        .get_vis_ctx(timestep_range, 1, 1)
+                        .frequencies_hz(),
+                }
+            }
+        }
+    }
+
+    /// A [`VisContext`] for a range of timesteps of the selection, with the given averaging
+    /// factors.
+    fn vis_ctx(
+        &self,
+        timestep_range: &Range<usize>,
+        avg_time: usize,
+        avg_freq: usize,
+    ) -> VisContext {
+        match self {
+            Self::Gpubox { corr_ctx, vis_sel } => VisContext::from_mwalib(
+                corr_ctx,
+                timestep_range,
+                &vis_sel.coarse_chan_range,
+                &vis_sel.baseline_idxs,
+                avg_time,
+                avg_freq,
+            ),
+            Self::Converted { vis_reader, .. } => {
+                vis_reader.get_vis_ctx(timestep_range, avg_time, avg_freq)
+            }
+        }
+    }
+}
+
+/// Reads, preprocesses, averages and writes the visibilities of a [`VisInput`] in chunks of
+/// timesteps.
+struct ChunkRunner<'r> {
+    /// The visibilities to process
+    input: VisInput<'r>,
+    /// Preprocessing parameters, including any calibration solutions to apply
+    prep_ctx: PreprocessContext<'r>,
+    /// Flagging Parameters
+    flag_ctx: &'r FlagContext,
+    /// Input / output paths
+    io_ctx: &'r IOContext,
+    /// temporal averaging factor
+    avg_time: usize,
+    /// spectral averaging factor
+    avg_freq: usize,
+    /// temporal chunking factor
+    num_timesteps_per_chunk: Option<usize>,
+    /// number of extra timesteps read and flagged either side of each chunk
+    num_timesteps_overlap: usize,
+    /// The message for the history of the outputs
+    history_message: String,
+    /// Solves for the calibration solutions to apply, instead of any in `prep_ctx`
+    solver: Option<&'r CalibrateContext<'r>>,
+}
+
+impl ChunkRunner<'_> {
+    #[allow(clippy::cognitive_complexity)]
+    fn run(self) -> Result<(), BirliError> {
+        let Self {
+            input,
+            mut prep_ctx,
+            flag_ctx,
+            io_ctx,
+            avg_time,
+            avg_freq,
+            num_timesteps_per_chunk,
+            num_timesteps_overlap,
+            history_message,
+            solver,
+        } = self;
+        let sel_ctx = input.chunk_ctx();
+        let vis_sel = &sel_ctx.vis_sel;
+        let meta_ctx = input.meta_ctx();
+
+        // ////////// //
+        // Prepare IO //
+        // ////////// //
+
+        let vis_ctx = input.vis_ctx(&vis_sel.timestep_range, avg_time, avg_freq);
+
+        let num_avg_timesteps = vis_ctx.num_avg_timesteps();
+        let draw_target = if prep_ctx.draw_progress {
+            ProgressDrawTarget::stderr()
+        } else {
+            ProgressDrawTarget::hidden()
+        };
+        let write_progress =
+            indicatif::ProgressBar::with_draw_target(Some(num_avg_timesteps as u64), draw_target);
+        write_progress.set_style(
+            ProgressStyle::default_bar()
+                .template(
+                    "{msg:16}: [{elapsed_precise}] [{wide_bar:.cyan/blue}] {percent:3}% ({eta:5})",
+                )
+                .unwrap()
+                .progress_chars("=> "),
+        );
+        write_progress.set_message("write vis");
+
+        // TODO: move phase_centre, array_pos out of prep_ctx
+        let obs_ctx = ObsContext {
+            phase_centre: prep_ctx.phase_centre,
+            array_pos: prep_ctx.array_pos,
+            ..ObsContext::from_mwalib(meta_ctx)
+        };
+
+        // solutions to apply after averaging are kept out of preprocessing
+        let avg_calsols = if io_ctx.aocalsols_after_avg {
+            prep_ctx.calsols.take()
+        } else {
+            None
+        };
+
+        let args_strings = std::env::args().collect_vec();
+        let cmd_line = shlex::try_join(args_strings.iter().map(String::as_str))?;
+        let application = format!("{PKG_NAME} {PKG_VERSION}");
+        let message = with_layout_history(history_message, io_ctx);
+        let history = History {
+            cmd_line: Some(&cmd_line),
+            application: Some(&application),
+            message: Some(&message),
+        };
+        let (antenna_names, antenna_positions) =
+            get_antenna_names_positions(meta_ctx, obs_ctx.array_pos);
+        let dut1 = hifitime::Duration::from_seconds(meta_ctx.dut1.unwrap_or(0.0));
+        let mut uvfits_writer = io_ctx.uvfits_out.as_ref().map(|uvfits_out| {
+            with_increment_duration!("init", {
+                UvfitsWriter::from_marlu(
+                    uvfits_out,
                     &vis_ctx,
                     obs_ctx.array_pos,
                     obs_ctx.phase_centre,
@@ -1628,7 +3015,7 @@ impl<'a> BirliContext<'a> {
                 ms_out,
                 obs_ctx.phase_centre,
                 obs_ctx.array_pos,
-                antenna_positions,
+                antenna_positions.clone(),
                 dut1,
                 true,
             );
@@ -1638,15 +3025,20 @@ impl<'a> BirliContext<'a> {
                 vis_ctx.num_sel_chans
             );
             with_increment_duration!("init", {
-                writer
-                    .initialize_mwa(
+                match input {
+                    VisInput::Gpubox { .. } => writer.initialize_mwa(
                         &vis_ctx,
                         &obs_ctx,
-                        &mwa_ctx,
+                        &MwaObsContext::from_mwalib(meta_ctx),
                         Some(&history),
                         &vis_sel.coarse_chan_range,
-                    )
-                    .expect("unable to initialize ms writer");
+                    ),
+                    // the coarse channels of converted visibilities aren't known.
+                    VisInput::Converted { .. } => {
+                        writer.initialize(&vis_ctx, &obs_ctx, Some(&history))
+                    }
+                }
+                .expect("unable to initialize ms writer");
             });
             writer
         });
@@ -1671,47 +3063,65 @@ impl<'a> BirliContext<'a> {
         #[cfg(not(feature = "aoflagger"))]
         let (aoflagger_version, aoflagger_strategy) = (None, None);
 
-        let mut flag_file_set = io_ctx.flag_template.as_ref().map(|flag_template| {
-            FlagFileSet::new(
-                flag_template,
-                corr_ctx,
-                vis_sel,
-                aoflagger_version,
-                aoflagger_strategy,
-            )
-            .expect("cannot create flag file writer")
+        // mwaf files and occupancy are only written for gpubox files, whose flags can be
+        // attributed to the correlator metadata.
+        let mut flag_file_set = match (io_ctx.flag_template.as_ref(), input.corr_ctx()) {
+            (Some(flag_template), Some(corr_ctx)) => Some(
+                FlagFileSet::new(
+                    flag_template,
+                    corr_ctx,
+                    vis_sel,
+                    aoflagger_version,
+                    aoflagger_strategy,
+                )
+                .expect("cannot create flag file writer"),
+            ),
+            _ => None,
+        };
+        let mut occupancy = input
+            .corr_ctx()
+            .map(|corr_ctx| FlagOccupancy::new(corr_ctx, vis_sel));
+        // the flags which would have been set from the observation metadata alone.
+        let metafits_flag_ctx = input.corr_ctx().map(|corr_ctx| {
+            let mut metafits_flag_ctx = FlagContext::from_mwalib(corr_ctx);
+            metafits_flag_ctx.finalise_flag_settings(corr_ctx);
+            metafits_flag_ctx
         });
 
         // //////// //
         // Chunking //
         // //////// //
 
-        let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
-        let chunk_size = num_timesteps_per_chunk.map_or_else(
-            || {
-                let num_timesteps: usize = vis_sel.timestep_range.len();
-                num_timesteps
-            },
-            |steps| steps,
-        );
+        let fine_chans_per_coarse = sel_ctx.fine_chans_per_coarse;
+        let chunk_size = num_timesteps_per_chunk.unwrap_or(vis_sel.timestep_range.len());
+        // Extra timesteps are read and flagged either side of each chunk, so that aoflagger sees
+        // the same time series at the edges of a chunk as it would without chunking.
+        let num_timesteps_overlap = if num_timesteps_per_chunk.is_some() {
+            num_timesteps_overlap
+        } else {
+            0
+        };
 
         // Allocate our big arrays once, reuse them for each chunk unless the chunk shape changes
         let chunk_vis_sel = VisSelection {
             timestep_range: (vis_sel.timestep_range.start
-                ..vis_sel.timestep_range.start + chunk_size),
+                ..vis_sel.timestep_range.start
+                    + (chunk_size + 2 * num_timesteps_overlap).min(vis_sel.timestep_range.len())),
             ..vis_sel.clone()
         };
         let mut jones_array = chunk_vis_sel.allocate_jones(fine_chans_per_coarse)?;
         let mut flag_array = chunk_vis_sel.allocate_flags(fine_chans_per_coarse)?;
         let mut weight_array = chunk_vis_sel.allocate_weights(fine_chans_per_coarse)?;
+        // a copy of the flags before each stage, used to attribute new flags to that stage.
+        let mut prev_flag_array = chunk_vis_sel.allocate_flags(fine_chans_per_coarse)?;
+        let mut flag_reason_array = Array3::zeros(prev_flag_array.dim());
 
         for mut timestep_chunk in &vis_sel.timestep_range.clone().chunks(chunk_size) {
             let chunk_first_timestep = timestep_chunk.next().expect("zero-sized chunk");
-            let chunk_vis_sel = VisSelection {
-                timestep_range: (chunk_first_timestep
-                    ..(timestep_chunk.last().unwrap_or(chunk_first_timestep) + 1)),
-                ..vis_sel.clone()
-            };
+            let chunk_ctx = sel_ctx.with_timestep_range(
+                chunk_first_timestep..(timestep_chunk.last().unwrap_or(chunk_first_timestep) + 1),
+            );
+            let chunk_vis_sel = &chunk_ctx.vis_sel;
             if num_timesteps_per_chunk.is_some() {
                 info!(
                     "processing timestep chunk {:?} of {:?} % {}",
@@ -1721,54 +3131,228 @@ impl<'a> BirliContext<'a> {
                 );
             }
 
+            // the chunk, along with the overlapping timesteps either side of it.
+            let read_start = chunk_vis_sel
+                .timestep_range
+                .start
+                .saturating_sub(num_timesteps_overlap)
+                .max(vis_sel.timestep_range.start);
+            let read_end = (chunk_vis_sel.timestep_range.end + num_timesteps_overlap)
+                .min(vis_sel.timestep_range.end);
+            let read_ctx = sel_ctx.with_timestep_range(read_start..read_end);
+            let read_vis_sel = &read_ctx.vis_sel;
+            // the timesteps of the chunk within the read arrays.
+            let overlap_before =
+                chunk_vis_sel.timestep_range.start - read_vis_sel.timestep_range.start;
+            let central = s![
+                overlap_before..overlap_before + chunk_vis_sel.timestep_range.len(),
+                ..,
+                ..
+            ];
+
             // only reallocate arrays if the chunk dimensions have changed.
-            let chunk_dims = chunk_vis_sel.get_shape(fine_chans_per_coarse);
-            let (mut jones_array, mut flag_array, mut weight_array) = if jones_array.dim()
-                == chunk_dims
-            {
+            let chunk_dims = read_vis_sel.get_shape(fine_chans_per_coarse);
+            let (
+                mut jones_array,
+                mut flag_array,
+                mut weight_array,
+                mut prev_flag_array,
+                mut flag_reason_array,
+            ) = if jones_array.dim() == chunk_dims {
                 (
                     jones_array.view_mut(),
                     flag_array.view_mut(),
                     weight_array.view_mut(),
+                    prev_flag_array.view_mut(),
+                    flag_reason_array.view_mut(),
                 )
             } else {
+                let chunk_slice = s![0..chunk_dims.0, 0..chunk_dims.1, 0..chunk_dims.2];
                 (
-                    jones_array.slice_mut(s![0..chunk_dims.0, 0..chunk_dims.1, 0..chunk_dims.2]),
-                    flag_array.slice_mut(s![0..chunk_dims.0, 0..chunk_dims.1, 0..chunk_dims.2]),
-                    weight_array.slice_mut(s![0..chunk_dims.0, 0..chunk_dims.1, 0..chunk_dims.2]),
+                    jones_array.slice_mut(chunk_slice),
+                    flag_array.slice_mut(chunk_slice),
+                    weight_array.slice_mut(chunk_slice),
+                    prev_flag_array.slice_mut(chunk_slice),
+                    flag_reason_array.slice_mut(chunk_slice),
                 )
             };
 
             // populate flags
             flag_ctx.set_flags(
                 flag_array.view_mut(),
-                &chunk_vis_sel.timestep_range,
-                &chunk_vis_sel.coarse_chan_range,
-                &chunk_vis_sel.get_ant_pairs(&corr_ctx.metafits_context),
+                &read_vis_sel.timestep_range,
+                &read_vis_sel.coarse_chan_range,
+                &read_ctx.ant_pairs,
             )?;
+            flag_ctx.set_flag_reasons(
+                flag_reason_array.view_mut(),
+                &read_vis_sel.timestep_range,
+                &read_vis_sel.coarse_chan_range,
+                &read_ctx.ant_pairs,
+            )?;
+            if let (Some(occupancy), Some(metafits_flag_ctx)) =
+                (occupancy.as_mut(), metafits_flag_ctx.as_ref())
+            {
+                metafits_flag_ctx.set_flags(
+                    prev_flag_array.view_mut(),
+                    &read_vis_sel.timestep_range,
+                    &read_vis_sel.coarse_chan_range,
+                    &read_ctx.ant_pairs,
+                )?;
+                prev_flag_array.zip_mut_with(&flag_array, |prev, &flag| *prev &= flag);
+                occupancy.add(
+                    FlagSource::Metafits,
+                    prev_flag_array.slice(central),
+                    chunk_first_timestep,
+                );
+                occupancy.add_new(
+                    FlagSource::User,
+                    prev_flag_array.slice(central),
+                    flag_array.slice(central),
+                    chunk_first_timestep,
+                );
+            }
+            prev_flag_array.assign(&flag_array);
 
-            // populate visibilities
-            with_increment_duration!(
-                "read",
-                read_mwalib(
-                    &chunk_vis_sel,
-                    corr_ctx,
-                    jones_array.view_mut(),
-                    flag_array.view_mut(),
-                    prep_ctx.draw_progress
-                )?
-            );
-
-            // populate weights
-            weight_array.fill(vis_ctx.weight_factor() as f32);
+            // populate visibilities and weights
+            match input {
+                VisInput::Gpubox { corr_ctx, .. } => {
+                    with_increment_duration!(
+                        "read",
+                        if io_ctx.tolerate_read_errors {
+                            let mut unreadable_hdus = read_mwalib_tolerant(
+                                read_vis_sel,
+                                corr_ctx,
+                                jones_array.view_mut(),
+                                flag_array.view_mut(),
+                                prep_ctx.draw_progress,
+                            )?;
+                            // overlapping timesteps are reported with the chunk they belong to.
+                            unreadable_hdus.retain(|hdu| {
+                                chunk_vis_sel.timestep_range.contains(&hdu.timestep_idx)
+                            });
+                            if let Some(occupancy) = occupancy.as_mut() {
+                                occupancy.add_unreadable_hdus(&unreadable_hdus);
+                            }
+                        } else {
+                            read_mwalib(
+                                read_vis_sel,
+                                corr_ctx,
+                                jones_array.view_mut(),
+                                flag_array.view_mut(),
+                                prep_ctx.draw_progress,
+                            )?;
+                        }
+                    );
+                    if let Some(occupancy) = occupancy.as_mut() {
+                        occupancy.add_new(
+                            FlagSource::MissingHdu,
+                            prev_flag_array.slice(central),
+                            flag_array.slice(central),
+                            chunk_first_timestep,
+                        );
+                    }
+                    set_new_flag_reasons(
+                        flag_reason_array.view_mut(),
+                        prev_flag_array.view(),
+                        flag_array.view(),
+                        FlagReason::MissingHdu,
+                    )?;
+                    prev_flag_array.assign(&flag_array);
+
+                    weight_array.fill(vis_ctx.weight_factor() as f32);
+                    if let Some(min_mwax_weight) = io_ctx.min_mwax_weight {
+                        let num_flagged = with_increment_duration!(
+                            "read",
+                            read_mwalib_weights(
+                                read_vis_sel,
+                                corr_ctx,
+                                weight_array.view_mut(),
+                                flag_array.view_mut(),
+                                min_mwax_weight,
+                                io_ctx.tolerate_read_errors,
+                            )?
+                        );
+                        if num_flagged > 0 {
+                            warn!(
+                                "Flagged {num_flagged} baselines with packet loss from MWAX weights"
+                            );
+                        }
+                        if let Some(occupancy) = occupancy.as_mut() {
+                            occupancy.add_new(
+                                FlagSource::PacketLoss,
+                                prev_flag_array.slice(central),
+                                flag_array.slice(central),
+                                chunk_first_timestep,
+                            );
+                        }
+                        set_new_flag_reasons(
+                            flag_reason_array.view_mut(),
+                            prev_flag_array.view(),
+                            flag_array.view(),
+                            FlagReason::PacketLoss,
+                        )?;
+                        prev_flag_array.assign(&flag_array);
+                    }
+                }
+                VisInput::Converted { vis_reader, .. } => {
+                    with_increment_duration!(
+                        "read",
+                        vis_reader.read_vis(
+                            jones_array.view_mut(),
+                            weight_array.view_mut(),
+                            flag_array.view_mut(),
+                            &read_vis_sel.timestep_range,
+                        )?
+                    );
+                    // flags are kept in the flag array until they are baked into the weights.
+                    weight_array.mapv_inplace(f32::abs);
+                    prev_flag_array.assign(&flag_array);
+                }
+            }
 
-            prep_ctx.preprocess(
-                corr_ctx,
+            // the overlap is only needed for flagging, so discard it before calibration.
+            let base_weight_array = prep_ctx.correct_and_flag(
+                input.corr_ctx(),
+                jones_array.view_mut(),
+                weight_array.view_mut(),
+                flag_array.view_mut(),
+                Some(flag_reason_array.view_mut()),
+                &read_ctx,
+            )?;
+            let mut jones_array = jones_array.slice_mut(central);
+            let mut flag_array = flag_array.slice_mut(central);
+            let mut weight_array = weight_array.slice_mut(central);
+            let mut flag_reason_array = flag_reason_array.slice_mut(central);
+            prep_ctx.phase(input.corr_ctx(), jones_array.view_mut(), &chunk_ctx);
+            if let (Some(solver), Some(corr_ctx)) = (solver, input.corr_ctx()) {
+                prep_ctx.calsols = Some(solver.solve(
+                    corr_ctx,
+                    jones_array.view(),
+                    weight_array.view(),
+                    flag_array.view(),
+                    &chunk_ctx,
+                )?);
+            }
+            prep_ctx.calibrate_and_weight(
                 jones_array.view_mut(),
                 weight_array.view_mut(),
                 flag_array.view_mut(),
-                &chunk_vis_sel,
+                Some(flag_reason_array.view_mut()),
+                &chunk_ctx,
+                base_weight_array
+                    .as_ref()
+                    .map(|base_weight_array| base_weight_array.slice(central)),
             )?;
+            if let Some(occupancy) = occupancy.as_mut() {
+                occupancy.add_new_preprocessing(
+                    prev_flag_array.slice(central),
+                    flag_array.view(),
+                    flag_reason_array.view(),
+                    chunk_first_timestep,
+                );
+                occupancy.add_reasons(flag_reason_array.view(), chunk_first_timestep);
+            }
 
             // output flags (before averaging)
             if let Some(flag_file_set) = flag_file_set.as_mut() {
@@ -1789,27 +3373,35 @@ impl<'a> BirliContext<'a> {
                 };
             }
 
-            izip!(
-                &chunk_vis_sel.timestep_range.clone().chunks(*avg_time),
-                jones_array.axis_chunks_iter(Axis(0), *avg_time),
-                weight_array.axis_chunks_iter(Axis(0), *avg_time),
-            )
-            .for_each(|(mut out_times, jones_array, weight_array)| {
+            for (mut out_times, jones_array, weight_array) in izip!(
+                &chunk_vis_sel.timestep_range.clone().chunks(avg_time),
+                jones_array.axis_chunks_iter(Axis(0), avg_time),
+                weight_array.axis_chunks_iter(Axis(0), avg_time),
+            ) {
                 let first_avg_timestep = out_times.next().expect("zero-sized chunk");
                 let last_avg_timestep = out_times.last().unwrap_or(first_avg_timestep) + 1;
-                let avg_timestep_range = first_avg_timestep..last_avg_timestep;
-                let avg_vis_sel = VisSelection {
-                    timestep_range: avg_timestep_range,
-                    ..chunk_vis_sel.clone()
-                };
-
-                let avg_vis_ctx = VisContext::from_mwalib(
-                    corr_ctx,
-                    &avg_vis_sel.timestep_range,
-                    &avg_vis_sel.coarse_chan_range,
-                    &avg_vis_sel.baseline_idxs,
-                    *avg_time,
-                    *avg_freq,
+                let avg_vis_ctx =
+                    input.vis_ctx(&(first_avg_timestep..last_avg_timestep), avg_time, avg_freq);
+
+                let calibrated = avg_calsols
+                    .as_ref()
+                    .map(|calsols| {
+                        with_increment_duration!(
+                            "calibrate",
+                            apply_di_calsol_averaged(
+                                calsols.view(),
+                                jones_array,
+                                weight_array,
+                                &avg_vis_ctx,
+                            )
+                        )
+                    })
+                    .transpose()?;
+                let (jones_array, weight_array, avg_vis_ctx) = calibrated.as_ref().map_or(
+                    (jones_array.view(), weight_array.view(), &avg_vis_ctx),
+                    |(jones_array, weight_array, avg_vis_ctx)| {
+                        (jones_array.view(), weight_array.view(), avg_vis_ctx)
+                    },
                 );
 
                 // output uvfits
@@ -1817,7 +3409,7 @@ impl<'a> BirliContext<'a> {
                     with_increment_duration!(
                         "write",
                         uvfits_writer
-                            .write_vis(jones_array.view(), weight_array.view(), &avg_vis_ctx)
+                            .write_vis(jones_array.view(), weight_array.view(), avg_vis_ctx)
                             .expect("unable to write uvfits")
                     );
                 }
@@ -1827,13 +3419,13 @@ impl<'a> BirliContext<'a> {
                     with_increment_duration!(
                         "write",
                         ms_writer
-                            .write_vis(jones_array.view(), weight_array.view(), &avg_vis_ctx)
+                            .write_vis(jones_array.view(), weight_array.view(), avg_vis_ctx)
                             .expect("unable to write ms")
                     );
                 }
 
                 write_progress.inc(1);
-            });
+            }
         }
         // Finalise the uvfits writer.
         if let Some(uvfits_writer) = uvfits_writer.as_mut() {
@@ -1849,6 +3441,29 @@ impl<'a> BirliContext<'a> {
         if let Some(ms_writer) = ms_writer.as_mut() {
             with_increment_duration!("write", ms_writer.finalise().expect("couldn't finalise MS"));
         };
+        // The MS writer only knows a single phase centre, give each timestep of a drift scan its
+        // own field.
+        if let (Some(ms_out), Some(phase_centres)) = (
+            io_ctx.ms_out.as_ref(),
+            prep_ctx.drift_phase_centres.as_ref(),
+        ) {
+            let avg_phase_centres = phase_centres[vis_sel.timestep_range.clone()]
+                .iter()
+                .step_by(avg_time)
+                .copied()
+                .collect_vec();
+            with_increment_duration!(
+                "write",
+                write_drift_fields(
+                    ms_out,
+                    &vis_ctx,
+                    &avg_phase_centres,
+                    obs_ctx.array_pos,
+                    &antenna_positions,
+                    dut1,
+                )?
+            );
+        }
 
         write_progress.finish();
 
@@ -1859,23 +3474,459 @@ impl<'a> BirliContext<'a> {
                 .expect("couldn't finalise mwaf files");
         }
 
+        if let Some(occupancy) = occupancy {
+            info!("{}", &occupancy);
+            if let Some(occupancy_out) = io_ctx.occupancy_out.as_ref() {
+                occupancy.write_csv(occupancy_out)?;
+                if !occupancy.unreadable_hdus().is_empty() {
+                    occupancy.write_unreadable_hdus_csv(unreadable_hdus_path(occupancy_out))?;
+                }
+            }
+        }
+
         Ok(())
     }
 }
 
-#[cfg(test)]
-#[cfg(feature = "aoflagger")]
-mod tests {
-    use approx::{assert_abs_diff_eq, assert_abs_diff_ne};
-    use tempfile::tempdir;
+/// Read the calibration solutions in `io_ctx.aocalsols_in`, if any, for a single timeblock, with
+/// tiles matched to the metafits and the frequency of each channel. The solutions are smoothed
+/// with `io_ctx.aocalsols_smoothing`, assuming they evenly divide every coarse channel in the
+/// metafits, and written to `io_ctx.aocalsols_smoothed_out` if requested.
+fn read_obs_calsols(
+    io_ctx: &IOContext,
+    meta_ctx: &MetafitsContext,
+) -> Result<Option<AOCalSols>, BirliError> {
+    let calsol_file = match io_ctx.aocalsols_in.as_ref() {
+        Some(calsol_file) => calsol_file,
+        None => return Ok(None),
+    };
+    let calsols = AOCalSols::read(calsol_file).map_err(IOError::from)?;
+    if calsols.di_jones.dim().0 != 1 {
+        return Err(BirliError::BadArrayShape(BadArrayShape {
+            argument: "input AO calibration solutions",
+            function: "read_obs_calsols",
+            expected: "a single timeblock, with dimensions (1, tiles, channels)".into(),
+            received: format!("{:?}", calsols.di_jones.dim()),
+        }));
+    }
+    let mut di_jones = match_calsol_tiles(&calsols, &meta_ctx.antennas)?;
+    let calsol_chans = di_jones.dim().2;
+    let freqs_hz = calsols.freqs_hz.clone().unwrap_or_else(|| {
+        // solutions at the correlator resolution are on the same grid as the visibilities.
+        if calsol_chans == meta_ctx.metafits_fine_chan_freqs_hz.len() {
+            meta_ctx.metafits_fine_chan_freqs_hz.clone()
+        } else {
+            calsol_grid_hz(&meta_ctx.metafits_coarse_chans, calsol_chans)
+        }
+    });
+
+    let smoothing = &io_ctx.aocalsols_smoothing;
+    if !smoothing.is_empty() {
+        let coarse_chans = &meta_ctx.metafits_coarse_chans;
+        if calsol_chans % coarse_chans.len() != 0 {
+            return Err(BirliError::BadArrayShape(BadArrayShape {
+                argument: "input AO calibration solutions",
+                function: "read_obs_calsols",
+                expected: format!(
+                    "a multiple of metafits_num_coarse_chans={}",
+                    coarse_chans.len()
+                ),
+                received: format!("{calsol_chans}"),
+            }));
+        }
+        let chans_per_coarse = calsol_chans / coarse_chans.len();
+        with_increment_duration!(
+            "calibrate",
+            smooth_calsols(
+                di_jones.index_axis_mut(Axis(0), 0),
+                &freqs_hz,
+                chans_per_coarse,
+                smoothing,
+            )?
+        );
+    }
 
-    use crate::{
-        passband_gains::{PFB_COTTER_2014_10KHZ, PFB_JAKE_2022_200HZ},
-        test_common::{get_1254670392_avg_paths, get_mwax_data_paths},
-        BirliContext, BirliError, VisSelection,
+    let matched = AOCalSols {
+        di_jones,
+        start_timestamps: calsols.start_timestamps,
+        tile_names: Some(
+            meta_ctx
+                .antennas
+                .iter()
+                .map(|ant| ant.tile_name.clone())
+                .collect(),
+        ),
+        tile_ids: Some(meta_ctx.antennas.iter().map(|ant| ant.tile_id).collect()),
+        freqs_hz: Some(freqs_hz),
     };
+    if let Some(smoothed_out) = io_ctx.aocalsols_smoothed_out.as_ref() {
+        matched.write(smoothed_out)?;
+        info!(
+            "wrote smoothed calibration solutions to {}",
+            smoothed_out.display()
+        );
+    }
+    Ok(Some(matched))
+}
 
-    #[test]
+/// Read the calibration solutions in `io_ctx.aocalsols_in`, if any, matched to the tiles of
+/// the observation, for the channels of `input` they will be applied to: the averaged channels
+/// if they are to be applied after averaging, otherwise the selected channels.
+///
+/// The solution frequencies are read from the solutions if they record them, otherwise the
+/// solutions are assumed to cover every coarse channel in the metafits, on its fine channel grid
+/// if they have as many channels, or evenly otherwise (see [`calsol_grid_hz`]). If
+/// `io_ctx.aocalsols_interp` is set, the solutions are interpolated onto the frequencies of the
+/// channels, otherwise only the solutions within the band of the channels are kept.
+fn read_input_calsols(
+    io_ctx: &IOContext,
+    input: &VisInput,
+    avg_time: usize,
+    avg_freq: usize,
+) -> Result<Option<Array2<Jones<f64>>>, BirliError> {
+    let (calsols, calsol_freqs_hz) = match read_obs_calsols(io_ctx, input.meta_ctx())? {
+        Some(AOCalSols {
+            di_jones,
+            freqs_hz: Some(freqs_hz),
+            ..
+        }) => (di_jones.index_axis_move(Axis(0), 0), freqs_hz),
+        _ => return Ok(None),
+    };
+    let timestep_range = input.chunk_ctx().vis_sel.timestep_range;
+    let vis_ctx = input.vis_ctx(&timestep_range, avg_time, avg_freq);
+    let (freqs_hz, freq_resolution_hz) = if io_ctx.aocalsols_after_avg {
+        (
+            vis_ctx.avg_frequencies_hz(),
+            vis_ctx.avg_freq as f64 * vis_ctx.freq_resolution_hz,
+        )
+    } else {
+        (vis_ctx.frequencies_hz(), vis_ctx.freq_resolution_hz)
+    };
+    if let Some(interp) = io_ctx.aocalsols_interp {
+        return Ok(Some(interpolate_calsols(
+            calsols.view(),
+            &calsol_freqs_hz,
+            &freqs_hz,
+            interp,
+        )?));
+    }
+    let (band_start_hz, band_end_hz) = match (freqs_hz.first(), freqs_hz.last()) {
+        (Some(first), Some(last)) => (
+            first - freq_resolution_hz / 2.,
+            last + freq_resolution_hz / 2.,
+        ),
+        _ => return Ok(Some(calsols)),
+    };
+    let band_chans = calsol_freqs_hz
+        .iter()
+        .enumerate()
+        .filter(|(_, &freq_hz)| freq_hz >= band_start_hz && freq_hz < band_end_hz)
+        .map(|(chan, _)| chan)
+        .collect_vec();
+    Ok(Some(calsols.select(Axis(1), &band_chans)))
+}
+
+/// Args for solving direction independent calibration solutions with `birli calibrate`.
+///
+/// The selected visibilities are read and preprocessed as they would be for conversion, and
+/// solved against model visibilities predicted from a sky model once they are phased. The new
+/// solutions are then applied in place of `--apply-di-cal`, and any outputs written.
+pub struct CalibrateContext<'a> {
+    /// Preprocessing context of the observation to calibrate
+    pub birli_ctx: BirliContext<'a>,
+    /// The sky model to solve against
+    pub sky_model: SkyModel,
+    /// Path for the calibration solutions
+    pub calsols_out: PathBuf,
+    /// Maximum number of solver iterations per channel
+    pub max_iterations: usize,
+    /// Relative change in the solutions at which the solver stops iterating
+    pub stop_threshold: f64,
+}
+
+impl Display for CalibrateContext<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Will solve against {} sky model components.",
+            self.sky_model.components.len()
+        )?;
+        writeln!(
+            f,
+            "Solver:               {} iterations, stop threshold {:e}",
+            self.max_iterations, self.stop_threshold
+        )?;
+        writeln!(
+            f,
+            "Will write calibration solutions to: {}",
+            self.calsols_out.display()
+        )?;
+        Ok(())
+    }
+}
+
+impl<'a> CalibrateContext<'a> {
+    /// Parse an iterator of arguments, `args` into a `CalibrateContext`, or `None` if the
+    /// arguments don't use the `calibrate` subcommand.
+    ///
+    /// # Errors
+    ///
+    /// Can raise:
+    /// - `clap::Error` if clap cannot parse `args`
+    /// - any error from [`CalibrateContext::from_matches`].
+    pub fn from_args<I, T>(args: I) -> Result<Option<Self>, BirliError>
+    where
+        I: IntoIterator<Item = T> + Debug,
+        T: Into<OsString> + Clone,
+    {
+        debug!("args:\n{:?}", &args);
+        let matches = BirliContext::get_matches(args)?;
+        trace!("arg matches:\n{:?}", &matches);
+        match matches.subcommand() {
+            Some(("calibrate", calibrate_matches)) => {
+                Self::from_matches(calibrate_matches).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Parse the matches of the `calibrate` subcommand into a `CalibrateContext`.
+    ///
+    /// # Errors
+    ///
+    /// Can raise:
+    /// - `SkyModelError` if the sky model can't be read.
+    /// - `BirliError::CLIError` if the arguments are invalid.
+    /// - any error from [`BirliContext::from_args`].
+    fn from_matches(matches: &clap::ArgMatches) -> Result<Self, BirliError> {
+        for reprocess_option in ["uvfits-in", "ms-in"] {
+            if matches.is_present(reprocess_option) {
+                return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                    option: format!("--{reprocess_option}"),
+                    expected: "gpubox files with `birli calibrate`".into(),
+                    received: "already-converted visibilities".into(),
+                }));
+            }
+        }
+        if matches.is_present("apply-di-cal") {
+            return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                option: "--apply-di-cal".into(),
+                expected: "no existing solutions with `birli calibrate`".into(),
+                received: matches.value_of("apply-di-cal").unwrap_or_default().into(),
+            }));
+        }
+        // the sky model is predicted relative to a single phase centre.
+        if let Some(azel) = BirliContext::parse_drift_azel_matches(matches)? {
+            return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                option: if matches.is_present("phase-centre") {
+                    "--phase-centre zenith".into()
+                } else {
+                    "--phase-centre-azel <AZ> <EL>".into()
+                },
+                expected: "a fixed phase centre with `birli calibrate`".into(),
+                received: format!("a drift scan phase centre at Az/El {azel}"),
+            }));
+        }
+        // the solutions are solved over the whole selection at once.
+        for chunk_option in ["time-chunk", "max-memory", "chunk-overlap"] {
+            if matches.is_present(chunk_option) {
+                warn!("--{chunk_option} is ignored with `birli calibrate`");
+            }
+        }
+        let sky_model = SkyModel::read(
+            matches
+                .value_of("sky-model")
+                .unwrap_or_else(|| panic!("--sky-model <PATH> is required, enforced by clap")),
+        )?;
+        let calsols_out = matches
+            .value_of("calsols-out")
+            .unwrap_or_else(|| panic!("--calsols-out <PATH> is required, enforced by clap"))
+            .into();
+        let max_iterations = matches.value_of_t::<usize>("max-iterations")?;
+        let stop_threshold = matches.value_of_t::<f64>("stop-threshold")?;
+        if max_iterations == 0 || stop_threshold.is_nan() || stop_threshold <= 0. {
+            return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                option: "--max-iterations <COUNT> --stop-threshold <THRESHOLD>".into(),
+                expected: "a positive number of iterations and threshold".into(),
+                received: format!("{max_iterations} {stop_threshold}"),
+            }));
+        }
+
+        let birli_ctx = BirliContext::from_matches(matches)?;
+        let ranges = &birli_ctx.channel_range_sel.ranges;
+        if ranges.len() > 1 {
+            return Err(BirliError::CLIError(InvalidCommandLineArgument {
+                option: "--sel-chan-ranges <RANGES>".into(),
+                expected: "a single channel range with `birli calibrate`".into(),
+                received: format!("{ranges:?}"),
+            }));
+        }
+
+        let calibrate_ctx = Self {
+            birli_ctx,
+            sky_model,
+            calsols_out,
+            max_iterations,
+            stop_threshold,
+        };
+        info!("{}", &calibrate_ctx);
+
+        Ok(calibrate_ctx)
+    }
+
+    /// Read and preprocess the selected visibilities, solving for calibration solutions once
+    /// they are phased and writing them to `calsols_out`, then apply the solutions and write any
+    /// requested outputs.
+    ///
+    /// The whole selection is read at once, `--time-chunk` and `--max-memory` are ignored.
+    ///
+    /// # Errors
+    ///
+    /// can raise:
+    /// - preprocessing errors
+    /// - `CalibrationError` if the solver can't be run
+    /// - `IOError` if the solutions can't be written
+    pub fn run(&self) -> Result<(), BirliError> {
+        if !self.birli_ctx.prep_ctx.correct_geometry
+            && matches!(
+                self.birli_ctx
+                    .corr_ctx
+                    .metafits_context
+                    .geometric_delays_applied,
+                GeometricDelaysApplied::No
+            )
+        {
+            warn!("geometric corrections are disabled, the data may not be phased to the model");
+        }
+        self.birli_ctx.run_with_solver(Some(self))
+    }
+
+    /// Solve for calibration solutions against the sky model, given preprocessed and phased
+    /// visibilities for the selection in `chunk_ctx`, and write them to `calsols_out`.
+    ///
+    /// Returns the inverse of the solutions for the selected channels, to be applied to the
+    /// visibilities.
+    fn solve(
+        &self,
+        corr_ctx: &CorrelatorContext,
+        jones_array: ArrayView3<Jones<f32>>,
+        weight_array: ArrayView3<f32>,
+        flag_array: ArrayView3<bool>,
+        chunk_ctx: &ChunkContext,
+    ) -> Result<Array2<Jones<f64>>, BirliError> {
+        let ChunkContext {
+            vis_sel,
+            fine_chans_per_coarse,
+            ant_pairs,
+            freqs_hz,
+        } = chunk_ctx;
+        let prep_ctx = &self.birli_ctx.prep_ctx;
+
+        // the solver ignores visibilities with non-positive weights.
+        let mut weight_array = weight_array.to_owned();
+        weight_array.zip_mut_with(&flag_array, |weight, &flag| {
+            if flag {
+                *weight = -weight.abs();
+            }
+        });
+
+        // predict the model at the same UVWs the geometric correction phased the data with.
+        let uvws = calc_uvws(
+            corr_ctx,
+            vis_sel,
+            Some(prep_ctx.array_pos),
+            Some(prep_ctx.phase_centre),
+        );
+        let mut model_array = vis_sel.allocate_jones(*fine_chans_per_coarse)?;
+        with_increment_duration!(
+            "predict",
+            self.sky_model.predict(
+                model_array.view_mut(),
+                uvws.view(),
+                freqs_hz,
+                prep_ctx.phase_centre,
+            )?
+        );
+
+        let num_tiles = corr_ctx.metafits_context.num_ants;
+        let (solutions, converged) = with_increment_duration!(
+            "calibrate",
+            solve_di_calsol(
+                jones_array,
+                model_array.view(),
+                weight_array.view(),
+                ant_pairs,
+                num_tiles,
+                self.max_iterations,
+                self.stop_threshold,
+            )?
+        );
+        let num_converged = converged.iter().filter(|&&converged| converged).count();
+        if num_converged < converged.len() {
+            warn!(
+                "calibration converged in {} of {} channels",
+                num_converged,
+                converged.len()
+            );
+        } else {
+            info!("calibration converged in all {} channels", converged.len());
+        }
+
+        // the solutions cover every provided coarse channel, and are inverted to correct the data.
+        let solutions = solutions.mapv(Jones::inv);
+        let mut di_jones = Array3::from_elem(
+            (
+                1,
+                num_tiles,
+                corr_ctx.num_coarse_chans * fine_chans_per_coarse,
+            ),
+            Jones::nan(),
+        );
+        di_jones
+            .slice_mut(s![
+                0,
+                ..,
+                (vis_sel.coarse_chan_range.start * fine_chans_per_coarse)
+                    ..(vis_sel.coarse_chan_range.end * fine_chans_per_coarse)
+            ])
+            .assign(&solutions);
+        let antennas = &corr_ctx.metafits_context.antennas;
+        let calsols = AOCalSols {
+            di_jones,
+            start_timestamps: vec![Epoch::from_gpst_seconds(
+                corr_ctx.timesteps[vis_sel.timestep_range.start].gps_time_ms as f64 / 1e3,
+            )],
+            tile_names: Some(antennas.iter().map(|ant| ant.tile_name.clone()).collect()),
+            tile_ids: Some(antennas.iter().map(|ant| ant.tile_id).collect()),
+            freqs_hz: Some(
+                corr_ctx
+                    .get_fine_chan_freqs_hz_array(&(0..corr_ctx.num_coarse_chans).collect_vec()),
+            ),
+        };
+        calsols.write(&self.calsols_out)?;
+        info!(
+            "wrote calibration solutions to {}",
+            self.calsols_out.display()
+        );
+        Ok(solutions)
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "aoflagger")]
+mod tests {
+    use approx::{assert_abs_diff_eq, assert_abs_diff_ne};
+    use ndarray::prelude::*;
+    use tempfile::tempdir;
+
+    use crate::{
+        io::{uvfits::UvfitsReader, VisRead},
+        passband_gains::{PFB_COTTER_2014_10KHZ, PFB_JAKE_2022_200HZ},
+        test_common::{get_1254670392_avg_paths, get_mwax_data_paths},
+        BirliContext, BirliError, VisSelection,
+    };
+
+    #[test]
     fn test_birli_context_display_doesnt_crash() {
         let tmp_dir = tempdir().unwrap();
         let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
@@ -2291,13 +4342,84 @@ mod tests {
             Err(BirliError::CLIError(_))
         ));
     }
+
+    /// Test that chunks with overlapping timesteps give the same result as no chunking.
+    #[test]
+    fn test_chunk_overlap_matches_unchunked() {
+        let tmp_dir = tempdir().unwrap();
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+
+        let read_uvfits = |extra_args: &[&str]| {
+            let uvfits_path = tmp_dir.path().join(format!("{}.uvfits", extra_args.len()));
+            #[rustfmt::skip]
+            let mut args = vec![
+                "birli",
+                "-m", metafits_path,
+                "-u", uvfits_path.to_str().unwrap(),
+                "--no-draw-progress",
+            ];
+            args.extend_from_slice(extra_args);
+            args.extend_from_slice(&gpufits_paths);
+            BirliContext::from_args(&args).unwrap().run().unwrap();
+
+            let reader = UvfitsReader::new(&uvfits_path).unwrap();
+            let shape = reader.get_shape();
+            let mut jones_array = Array3::zeros(shape);
+            let mut weight_array = Array3::zeros(shape);
+            let mut flag_array = Array3::from_elem(shape, false);
+            reader
+                .read_vis(
+                    jones_array.view_mut(),
+                    weight_array.view_mut(),
+                    flag_array.view_mut(),
+                    &(0..shape.0),
+                )
+                .unwrap();
+            (jones_array, weight_array, flag_array)
+        };
+
+        let (jones_array, weight_array, flag_array) = read_uvfits(&[]);
+        // the overlap covers the whole observation, so aoflagger sees the same time series for
+        // every chunk, but only the chunk itself is kept.
+        let (chunked_jones, chunked_weights, chunked_flags) =
+            read_uvfits(&["--time-chunk", "1", "--chunk-overlap", "3"]);
+
+        assert_eq!(chunked_jones.dim(), jones_array.dim());
+        assert_eq!(chunked_jones.dim().0, 4);
+        assert_abs_diff_eq!(chunked_jones, jones_array);
+        assert_abs_diff_eq!(chunked_weights, weight_array);
+        assert_eq!(chunked_flags, flag_array);
+    }
 }
 
 #[cfg(test)]
 mod argparse_tests {
-    use marlu::RADec;
+    use approx::{assert_abs_diff_eq, assert_relative_eq};
+    use marlu::{
+        mwalib::{GpuboxError, MwalibError},
+        rubbl_casatables::{Table, TableOpenMode},
+        RADec, VisContext,
+    };
+    use tempfile::tempdir;
 
-    use crate::{error::BirliError, test_common::get_1254670392_avg_paths, BirliContext};
+    use crate::{
+        calibration::{CalsolInterp, CalsolSmoothing, InterpMethod},
+        cli::{with_layout_history, CalibrateContext},
+        corrections::{calc_uvws, resample_gains, ScrunchType},
+        error::BirliError,
+        io::{aocal::AOCalSols, error::IOError, passband::read_passband_gains, read_mwalib},
+        marlu::{
+            ndarray::{Array2, Array3},
+            Complex, Jones,
+        },
+        passband_gains::{PFB_JAKE_2022_200HZ, PFB_JAKE_2022_200HZ_OVERSAMPLED},
+        preprocessing::ChunkContext,
+        test_common::{
+            get_1254670392_avg_paths, get_mwax_data_paths, get_mwax_oversampled_layout_paths,
+            get_mwax_oversampled_metafits,
+        },
+        BirliContext, FlagThresholds, SsinsContext,
+    };
 
     #[test]
     fn test_parse_missing_input() {
@@ -2493,89 +4615,1093 @@ mod argparse_tests {
     fn test_parse_flag_end_steps() {
         let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
 
-        #[rustfmt::skip]
-        let mut args = vec!["birli", "-m", metafits_path, "--flag-init", "0", "--flag-end-steps", "1", "--"];
+        #[rustfmt::skip]
+        let mut args = vec!["birli", "-m", metafits_path, "--flag-init", "0", "--flag-end-steps", "1", "--"];
+        args.extend_from_slice(&gpufits_paths);
+
+        let BirliContext {
+            corr_ctx, flag_ctx, ..
+        } = BirliContext::from_args(&args).unwrap();
+        let n = corr_ctx.num_common_timesteps;
+
+        assert!(flag_ctx.timestep_flags[n - 1]);
+        assert!(!flag_ctx.timestep_flags[n - 2]);
+    }
+
+    #[test]
+    fn test_parse_flag_end_invalid_indivisible() {
+        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli",
+            "-m", metafits_path,
+            "--flag-end", "1",
+            "--",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::CLIError(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_invalid_coarse_chan_flag() {
+        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli",
+            "-m", metafits_path,
+            "--flag-coarse-chans", "0", "9999",
+            "--",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::CLIError(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_valid_coarse_chan_flag() {
+        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli",
+            "-m", metafits_path,
+            "--flag-coarse-chans", "2",
+            "--",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+
+        let BirliContext { flag_ctx, .. } = BirliContext::from_args(&args).unwrap();
+
+        assert!(flag_ctx.coarse_chan_flags[2]);
+        assert!(!flag_ctx.coarse_chan_flags[1]);
+    }
+
+    #[test]
+    fn test_parse_invalid_fine_chan_flag() {
+        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli",
+            "-m", metafits_path,
+            "--flag-fine-chans", "0", "9999",
+            "--",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::CLIError(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_valid_fine_chan_flag() {
+        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+
+        #[rustfmt::skip]
+        let mut args = vec!["birli", "-m", metafits_path, "--flag-fine-chans", "2", "--"];
+        args.extend_from_slice(&gpufits_paths);
+
+        let BirliContext { flag_ctx, .. } = BirliContext::from_args(&args).unwrap();
+
+        assert!(flag_ctx.fine_chan_flags[2]);
+        assert!(!flag_ctx.fine_chan_flags[1]);
+    }
+
+    #[test]
+    fn test_parse_invalid_antenna_flag() {
+        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli",
+            "-m", metafits_path,
+            "--flag-antennas", "0", "9999",
+            "--",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::CLIError(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_valid_antenna_flag() {
+        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+
+        #[rustfmt::skip]
+        let mut args = vec!["birli", "-m", metafits_path, "--flag-antennas", "2", "--"];
+        args.extend_from_slice(&gpufits_paths);
+
+        let BirliContext { flag_ctx, .. } = BirliContext::from_args(&args).unwrap();
+
+        assert!(flag_ctx.antenna_flags[2]);
+        assert!(!flag_ctx.antenna_flags[1]);
+    }
+
+    #[test]
+    fn test_parse_no_flag_metafits_and_valid_antenna_flag() {
+        let (_, gpufits_paths) = get_1254670392_avg_paths();
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli",
+            // in this metafits file, all ants are flagged.
+            "-m", "tests/data/1254670392_avg/1254670392.metafits",
+            "--no-flag-metafits",
+            "--flag-antennas", "2",
+            "--",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+
+        let BirliContext { flag_ctx, .. } = BirliContext::from_args(&args).unwrap();
+
+        assert!(flag_ctx.antenna_flags[2]);
+        assert!(!flag_ctx.antenna_flags[1]);
+    }
+
+    #[test]
+    fn test_parse_flag_autos() {
+        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+
+        #[rustfmt::skip]
+        let mut args = vec!["birli", "-m", metafits_path, "--flag-autos"];
+        args.extend_from_slice(&gpufits_paths);
+
+        let BirliContext { flag_ctx, .. } = BirliContext::from_args(&args).unwrap();
+
+        assert!(flag_ctx.autos);
+    }
+
+    #[test]
+    fn test_parse_flag_occupancy_thresholds() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli",
+            "-m", metafits_path,
+            "--flag-timestep-occupancy", "90",
+            "--flag-antenna-occupancy", "50.5",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+
+        let BirliContext { prep_ctx, .. } = BirliContext::from_args(&args).unwrap();
+
+        assert_eq!(
+            prep_ctx.flag_thresholds,
+            FlagThresholds {
+                timestep: Some(0.9),
+                chan: None,
+                antenna: Some(0.505),
+            }
+        );
+        assert!(prep_ctx
+            .to_string()
+            .contains("Will flag antennas with more than 50.5% flagged."));
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli",
+            "-m", metafits_path,
+            "--flag-chan-occupancy", "101",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::CLIError(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_ssins() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+
+        let mut args = vec!["birli", "-m", metafits_path];
+        args.extend_from_slice(&gpufits_paths);
+        let BirliContext { prep_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        assert_eq!(prep_ctx.ssins, None);
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli",
+            "-m", metafits_path,
+            "--ssins",
+            "--ssins-sigma", "4.5",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+
+        let BirliContext { prep_ctx, .. } = BirliContext::from_args(&args).unwrap();
+
+        assert_eq!(
+            prep_ctx.ssins,
+            Some(SsinsContext {
+                sigma: 4.5,
+                ..SsinsContext::default()
+            })
+        );
+        assert!(prep_ctx
+            .to_string()
+            .contains("Will flag with SSINS at 4.5 sigma"));
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli",
+            "-m", metafits_path,
+            "--ssins",
+            "--ssins-sigma", "0",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::CLIError(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_no_flag_bad_values() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+
+        let mut args = vec!["birli", "-m", metafits_path];
+        args.extend_from_slice(&gpufits_paths);
+        let BirliContext { prep_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        assert!(prep_ctx.flag_bad_values);
+
+        let mut args = vec!["birli", "-m", metafits_path, "--no-flag-bad-values"];
+        args.extend_from_slice(&gpufits_paths);
+        let BirliContext { prep_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        assert!(!prep_ctx.flag_bad_values);
+        assert!(prep_ctx
+            .to_string()
+            .contains("Will not flag zero-valued and non-finite visibilities."));
+    }
+
+    #[test]
+    fn test_parse_normalise_by_autos() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+
+        let mut args = vec!["birli", "-m", metafits_path, "--normalise-by-autos"];
+        args.extend_from_slice(&gpufits_paths);
+        let BirliContext { prep_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        assert!(prep_ctx.normalise_by_autos);
+        assert!(prep_ctx
+            .to_string()
+            .contains("Will normalise by autocorrelation amplitudes."));
+    }
+
+    #[test]
+    fn test_parse_radiometer_weights() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+
+        let mut args = vec!["birli", "-m", metafits_path];
+        args.extend_from_slice(&gpufits_paths);
+        let BirliContext { prep_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        assert!(!prep_ctx.radiometer_weights);
+
+        let mut args = vec!["birli", "-m", metafits_path, "--radiometer-weights"];
+        args.extend_from_slice(&gpufits_paths);
+        let BirliContext { prep_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        assert!(prep_ctx.radiometer_weights);
+        assert!(prep_ctx.as_comment().contains("radiometer weights"));
+    }
+
+    #[test]
+    fn test_parse_apply_di_cal_after_avg() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+
+        let mut args = vec!["birli", "-m", metafits_path, "--apply-di-cal", "cal.bin"];
+        args.extend_from_slice(&gpufits_paths);
+        let BirliContext { io_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        assert!(!io_ctx.aocalsols_after_avg);
+
+        args.insert(5, "--apply-di-cal-after-avg");
+        let BirliContext { io_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        assert!(io_ctx.aocalsols_after_avg);
+
+        // solutions are required
+        let mut args = vec!["birli", "-m", metafits_path, "--apply-di-cal-after-avg"];
+        args.extend_from_slice(&gpufits_paths);
+        match BirliContext::from_args(&args) {
+            Err(BirliError::ClapError(inner)) => assert!(matches!(
+                inner.kind(),
+                clap::error::ErrorKind::MissingRequiredArgument { .. }
+            )),
+            Err(e) => panic!("expected missing required argument error, not {e}"),
+            Ok(_) => panic!("expected error, but got Ok(_)"),
+        }
+    }
+
+    #[test]
+    fn test_parse_interp_di_cal() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+
+        for (options, expected) in [
+            (vec![], None),
+            (vec!["--interp-di-cal", "none"], None),
+            (
+                vec!["--interp-di-cal", "linear"],
+                Some(CalsolInterp {
+                    method: InterpMethod::Linear,
+                    amp_phase: false,
+                }),
+            ),
+            (
+                vec!["--interp-di-cal", "spline", "--interp-di-cal-amp-phase"],
+                Some(CalsolInterp {
+                    method: InterpMethod::Spline,
+                    amp_phase: true,
+                }),
+            ),
+            (
+                vec!["--interp-di-cal-amp-phase"],
+                Some(CalsolInterp {
+                    method: InterpMethod::Linear,
+                    amp_phase: true,
+                }),
+            ),
+        ] {
+            let mut args = vec!["birli", "-m", metafits_path, "--apply-di-cal", "cal.bin"];
+            args.extend_from_slice(&options);
+            args.extend_from_slice(&gpufits_paths);
+            let BirliContext { io_ctx, .. } = BirliContext::from_args(&args).unwrap();
+            assert_eq!(io_ctx.aocalsols_interp, expected);
+        }
+
+        // amplitude and phase interpolation needs interpolation.
+        let mut args = vec![
+            "birli",
+            "-m",
+            metafits_path,
+            "--apply-di-cal",
+            "cal.bin",
+            "--interp-di-cal",
+            "none",
+            "--interp-di-cal-amp-phase",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::CLIError(_))
+        ));
+    }
+
+    /// empirical pfb gains are measured from the autocorrelations when the context is run.
+    #[test]
+    fn test_parse_empirical_passband() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+        let tmp_dir = tempdir().unwrap();
+        let passband_path = tmp_dir.path().join("passband.txt");
+
+        #[rustfmt::skip]
+        let args = vec![
+            "birli",
+            "-m", metafits_path,
+            "--no-draw-progress",
+            "--passband-gains", "empirical",
+            "--passband-gains-out", passband_path.to_str().unwrap(),
+            gpufits_paths[0],
+            gpufits_paths[1],
+        ];
+
+        let birli_ctx = BirliContext::from_args(&args).unwrap();
+        assert!(birli_ctx.prep_ctx.empirical_passband);
+        assert!(birli_ctx.prep_ctx.passband_gains.is_none());
+
+        let passband_gains = birli_ctx.measure_passband_gains().unwrap().unwrap();
+        assert_eq!(
+            passband_gains.len(),
+            birli_ctx
+                .corr_ctx
+                .metafits_context
+                .num_corr_fine_chans_per_coarse
+        );
+
+        // the written gains can be read back in
+        #[rustfmt::skip]
+        let args = vec![
+            "birli",
+            "-m", metafits_path,
+            "--no-draw-progress",
+            "--passband-gains-in", passband_path.to_str().unwrap(),
+            gpufits_paths[0],
+            gpufits_paths[1],
+        ];
+        let birli_ctx = BirliContext::from_args(&args).unwrap();
+        assert!(!birli_ctx.prep_ctx.empirical_passband);
+        assert!(birli_ctx.prep_ctx.passband_gains.is_none());
+        let prep_ctx = birli_ctx.prep_ctx_with_gains(None);
+        for (&gain, &expected) in prep_ctx
+            .passband_gains
+            .unwrap()
+            .iter()
+            .zip(passband_gains.iter())
+        {
+            assert_abs_diff_eq!(gain, expected, epsilon = 1e-10);
+        }
+
+        // each channel range measures and writes its own gains.
+        let corr_ctx = &birli_ctx.corr_ctx;
+        let chan_ranges = corr_ctx
+            .provided_coarse_chan_indices
+            .iter()
+            .map(|cc_idx| format!("{cc_idx}-{cc_idx}"))
+            .collect::<Vec<_>>()
+            .join(",");
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli",
+            "-m", metafits_path,
+            "--no-draw-progress",
+            "--passband-gains", "empirical",
+            "--passband-gains-out", passband_path.to_str().unwrap(),
+            "--sel-chan-ranges", &chan_ranges,
+        ];
+        args.extend_from_slice(&gpufits_paths);
+        std::fs::remove_file(&passband_path).unwrap();
+        BirliContext::from_args(&args)
+            .unwrap()
+            .run_ranges()
+            .unwrap();
+        assert!(!passband_path.exists());
+        for &cc_idx in &corr_ctx.provided_coarse_chan_indices {
+            let rec_chan_number = corr_ctx.coarse_chans[cc_idx].rec_chan_number;
+            let range_path = tmp_dir
+                .path()
+                .join(format!("passband_ch{rec_chan_number}.txt"));
+            assert_eq!(
+                read_passband_gains(&range_path).unwrap().len(),
+                passband_gains.len()
+            );
+        }
+
+        // only measured gains can be written
+        #[rustfmt::skip]
+        let args = vec![
+            "birli",
+            "-m", metafits_path,
+            "--no-draw-progress",
+            "--passband-gains", "jake",
+            "--passband-gains-out", passband_path.to_str().unwrap(),
+            gpufits_paths[0],
+            gpufits_paths[1],
+        ];
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::CLIError(_))
+        ));
+    }
+
+    /// Observations with oversampled coarse channels don't get a passband correction by default,
+    /// and their channels are the critically sampled band mwax keeps.
+    #[test]
+    fn test_parse_oversampled() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+        let tmp_dir = tempdir().unwrap();
+        let oversampled_path = get_mwax_oversampled_metafits(tmp_dir.path());
+        let oversampled_path = oversampled_path.to_str().unwrap();
+
+        let mut args = vec!["birli", "-m", oversampled_path];
+        args.extend_from_slice(&gpufits_paths);
+        let birli_ctx = BirliContext::from_args(&args).unwrap();
+        assert!(birli_ctx.corr_ctx.metafits_context.oversampled);
+        assert!(birli_ctx.prep_ctx.passband_gains.is_none());
+        assert!(!birli_ctx.prep_ctx.empirical_passband);
+        assert!(format!("{birli_ctx}").contains("oversampled coarse channels"));
+
+        let mut args = vec!["birli", "-m", metafits_path];
+        args.extend_from_slice(&gpufits_paths);
+        let critical_ctx = BirliContext::from_args(&args).unwrap();
+        assert!(!critical_ctx.corr_ctx.metafits_context.oversampled);
+        assert_eq!(
+            critical_ctx.prep_ctx.passband_gains,
+            Some(PFB_JAKE_2022_200HZ)
+        );
+        assert_eq!(
+            birli_ctx.flag_ctx.fine_chan_flags,
+            critical_ctx.flag_ctx.fine_chan_flags
+        );
+        let vis_ctx = |ctx: &BirliContext| {
+            VisContext::from_mwalib(
+                &ctx.corr_ctx,
+                &ctx.vis_sel.timestep_range,
+                &ctx.vis_sel.coarse_chan_range,
+                &ctx.vis_sel.baseline_idxs,
+                1,
+                1,
+            )
+        };
+        assert_eq!(
+            vis_ctx(&birli_ctx).frequencies_hz(),
+            vis_ctx(&critical_ctx).frequencies_hz()
+        );
+
+        // the built-in gains can still be chosen explicitly
+        let mut args = vec!["birli", "-m", oversampled_path, "--passband-gains", "jake"];
+        args.extend_from_slice(&gpufits_paths);
+        let BirliContext { prep_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        assert_eq!(prep_ctx.passband_gains, Some(PFB_JAKE_2022_200HZ));
+
+        let mut args = vec![
+            "birli",
+            "-m",
+            oversampled_path,
+            "--passband-gains",
+            "jake-oversampled",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+        let BirliContext { prep_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        assert_eq!(
+            prep_ctx.passband_gains,
+            Some(PFB_JAKE_2022_200HZ_OVERSAMPLED.as_slice())
+        );
+    }
+
+    /// `--passband-gains jake-oversampled` is applied to the visibilities of oversampled
+    /// observations.
+    #[test]
+    fn test_oversampled_passband_gains_applied() {
+        let (_, gpufits_paths) = get_mwax_data_paths();
+        let tmp_dir = tempdir().unwrap();
+        let oversampled_path = get_mwax_oversampled_metafits(tmp_dir.path());
+        let oversampled_path = oversampled_path.to_str().unwrap();
+
+        let preprocess = |passband_gains: &str| {
+            let mut args = vec![
+                "birli",
+                "-m",
+                oversampled_path,
+                "--passband-gains",
+                passband_gains,
+            ];
+            args.extend_from_slice(&gpufits_paths);
+            let BirliContext {
+                corr_ctx,
+                prep_ctx,
+                vis_sel,
+                ..
+            } = BirliContext::from_args(&args).unwrap();
+            let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+            let mut jones_array = vis_sel.allocate_jones(fine_chans_per_coarse).unwrap();
+            let mut flag_array = vis_sel.allocate_flags(fine_chans_per_coarse).unwrap();
+            let mut weight_array = vis_sel.allocate_weights(fine_chans_per_coarse).unwrap();
+            read_mwalib(
+                &vis_sel,
+                &corr_ctx,
+                jones_array.view_mut(),
+                flag_array.view_mut(),
+                false,
+            )
+            .unwrap();
+            prep_ctx
+                .preprocess(
+                    &corr_ctx,
+                    jones_array.view_mut(),
+                    weight_array.view_mut(),
+                    flag_array.view_mut(),
+                    &vis_sel,
+                )
+                .unwrap();
+            (jones_array, fine_chans_per_coarse)
+        };
+        let (corrected_jones, fine_chans_per_coarse) = preprocess("jake-oversampled");
+        let (uncorrected_jones, _) = preprocess("none");
+
+        let gains = resample_gains(
+            &PFB_JAKE_2022_200HZ_OVERSAMPLED,
+            fine_chans_per_coarse,
+            &ScrunchType::CenterSymmetric,
+        );
+        let mut num_compared = 0;
+        for (((_, chan_idx, _), corrected), uncorrected) in
+            corrected_jones.indexed_iter().zip(uncorrected_jones.iter())
+        {
+            let gain = gains[chan_idx % fine_chans_per_coarse] as f32;
+            for (&corrected, &uncorrected) in corrected.iter().zip(uncorrected.iter()) {
+                assert_relative_eq!(corrected * gain, uncorrected, max_relative = 1e-4);
+                if uncorrected.norm() > 0. {
+                    num_compared += 1;
+                }
+            }
+        }
+        assert!(num_compared > 0);
+    }
+
+    /// mwalib only reads gpubox files of an oversampled observation with the channel layout of
+    /// the critically sampled band, which is what MWAX writes, so there are never oversampled edge
+    /// channels to discard.
+    #[test]
+    fn test_oversampled_channel_layout() {
+        let args = |metafits_path: &std::path::Path, gpufits_paths: &[std::path::PathBuf]| {
+            let mut args = vec![
+                "birli".to_string(),
+                "-m".into(),
+                metafits_path.display().to_string(),
+                "--flag-edge-chans".into(),
+                "8".into(),
+            ];
+            args.extend(gpufits_paths.iter().map(|path| path.display().to_string()));
+            args
+        };
+
+        // the oversampled 1.6384MHz band
+        let tmp_dir = tempdir().unwrap();
+        let (metafits_path, gpufits_paths) = get_mwax_oversampled_layout_paths(tmp_dir.path(), 160);
+        assert!(matches!(
+            BirliContext::from_args(args(&metafits_path, &gpufits_paths)),
+            Err(BirliError::MwalibError(MwalibError::Gpubox(
+                GpuboxError::MwaxNaxis1Mismatch { .. }
+            )))
+        ));
+
+        // the critically sampled 1.28MHz band
+        let tmp_dir = tempdir().unwrap();
+        let (metafits_path, gpufits_paths) = get_mwax_oversampled_layout_paths(tmp_dir.path(), 125);
+        let birli_ctx = BirliContext::from_args(args(&metafits_path, &gpufits_paths)).unwrap();
+        let meta_ctx = &birli_ctx.corr_ctx.metafits_context;
+        assert!(meta_ctx.oversampled);
+        assert_eq!(meta_ctx.num_corr_fine_chans_per_coarse, 125);
+        assert!(birli_ctx.prep_ctx.passband_gains.is_none());
+        // the edges of the critically sampled band are flagged.
+        let fine_chan_flags = &birli_ctx.flag_ctx.fine_chan_flags;
+        assert_eq!(fine_chan_flags.len(), 125);
+        for (chan_idx, &flag) in fine_chan_flags.iter().enumerate() {
+            assert_eq!(flag, !(8..117).contains(&chan_idx), "channel {chan_idx}");
+        }
+        // and the channels are spread across it.
+        let coarse_chan =
+            &birli_ctx.corr_ctx.coarse_chans[birli_ctx.vis_sel.coarse_chan_range.start];
+        let vis_ctx = VisContext::from_mwalib(
+            &birli_ctx.corr_ctx,
+            &birli_ctx.vis_sel.timestep_range,
+            &(birli_ctx.vis_sel.coarse_chan_range.start
+                ..birli_ctx.vis_sel.coarse_chan_range.start + 1),
+            &birli_ctx.vis_sel.baseline_idxs,
+            1,
+            1,
+        );
+        let freqs_hz = vis_ctx.frequencies_hz();
+        assert_eq!(freqs_hz.len(), 125);
+        assert_abs_diff_eq!(vis_ctx.freq_resolution_hz, 10_240.);
+        assert!(freqs_hz[0] >= coarse_chan.chan_start_hz as f64);
+        assert!(freqs_hz[124] < coarse_chan.chan_end_hz as f64);
+        birli_ctx.run().unwrap();
+    }
+
+    /// Solutions with any extension other than .fits are read as MWAOCAL, and errors reading
+    /// them are returned rather than panicking.
+    #[test]
+    fn test_read_calsols_any_ext() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+        let tmp_dir = tempdir().unwrap();
+        let calsols_path = tmp_dir.path().join("sols.dat");
+        let mut args = vec![
+            "birli",
+            "-m",
+            metafits_path,
+            "--apply-di-cal",
+            calsols_path.to_str().unwrap(),
+        ];
+        args.extend_from_slice(&gpufits_paths);
+        let birli_ctx = BirliContext::from_args(&args).unwrap();
+        let num_tiles = birli_ctx.corr_ctx.metafits_context.num_ants;
+        let num_chans = birli_ctx.corr_ctx.num_coarse_chans
+            * birli_ctx
+                .corr_ctx
+                .metafits_context
+                .num_corr_fine_chans_per_coarse;
+
+        assert!(matches!(
+            birli_ctx.read_calsols(),
+            Err(BirliError::IOError(IOError::ReadSolutionsError(_)))
+        ));
+
+        AOCalSols::new(
+            Array3::from_elem((1, num_tiles, num_chans), Jones::identity()),
+            vec![],
+        )
+        .write(&calsols_path)
+        .unwrap();
+        let calsols = birli_ctx.read_calsols().unwrap().unwrap();
+        assert_eq!(calsols.dim().0, num_tiles);
+
+        AOCalSols::new(
+            Array3::from_elem((2, num_tiles, num_chans), Jones::identity()),
+            vec![],
+        )
+        .write(&calsols_path)
+        .unwrap();
+        assert!(matches!(
+            birli_ctx.read_calsols(),
+            Err(BirliError::BadArrayShape(_))
+        ));
+    }
+
+    /// Solutions on any grid can be interpolated, using the frequencies they record if any, and
+    /// the same channel frequencies as the data.
+    #[test]
+    fn test_read_calsols_frequencies() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+        let tmp_dir = tempdir().unwrap();
+        let fits_path = tmp_dir.path().join("sols.fits");
+        let bin_path = tmp_dir.path().join("sols.bin");
+
+        let args = vec!["birli", "-m", metafits_path, "--apply-di-cal"];
+        let parse = |calsols_path: &std::path::Path, options: &[&str]| {
+            let mut args = args.clone();
+            args.push(calsols_path.to_str().unwrap());
+            args.extend_from_slice(options);
+            args.extend_from_slice(&gpufits_paths);
+            BirliContext::from_args(&args).unwrap()
+        };
+        let birli_ctx = parse(&fits_path, &[]);
+        let BirliContext {
+            corr_ctx, vis_sel, ..
+        } = &birli_ctx;
+        let num_tiles = corr_ctx.metafits_context.num_ants;
+        let freqs_hz = VisContext::from_mwalib(
+            corr_ctx,
+            &vis_sel.timestep_range,
+            &vis_sel.coarse_chan_range,
+            &vis_sel.baseline_idxs,
+            1,
+            1,
+        )
+        .frequencies_hz();
+
+        // 5 solutions across the selected band, which is linear in frequency.
+        let (first_hz, last_hz) = (freqs_hz[0] - 1e6, freqs_hz[freqs_hz.len() - 1] + 1e6);
+        let calsol_freqs_hz = (0..5)
+            .map(|chan| first_hz + chan as f64 * (last_hz - first_hz) / 4.)
+            .collect::<Vec<_>>();
+        let mut calsols = AOCalSols::new(
+            Array3::from_shape_fn((1, num_tiles, 5), |(_, _, chan)| {
+                Jones::identity() * (calsol_freqs_hz[chan] / 1e8)
+            }),
+            vec![],
+        );
+        calsols.freqs_hz = Some(calsol_freqs_hz);
+        calsols.write(&fits_path).unwrap();
+        let interpolated = parse(&fits_path, &["--interp-di-cal", "linear"])
+            .read_calsols()
+            .unwrap()
+            .unwrap();
+        assert_eq!(interpolated.dim(), (num_tiles, freqs_hz.len()));
+        for ((_, chan), jones) in interpolated.indexed_iter() {
+            assert_abs_diff_eq!(
+                *jones,
+                Jones::identity() * (freqs_hz[chan] / 1e8),
+                epsilon = 1e-9
+            );
+        }
+
+        // without frequencies, the solutions are spread across the metafits band.
+        calsols.write(&bin_path).unwrap();
+        let interpolated = parse(&bin_path, &["--interp-di-cal", "linear"])
+            .read_calsols()
+            .unwrap()
+            .unwrap();
+        assert_eq!(interpolated.dim(), (num_tiles, freqs_hz.len()));
+
+        // without interpolation, only the solutions for the selected channels are kept.
+        let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+        let num_chans = corr_ctx.num_coarse_chans * fine_chans_per_coarse;
+        AOCalSols::new(
+            Array3::from_shape_fn((1, num_tiles, num_chans), |(_, _, chan)| {
+                Jones::identity() * chan as f64
+            }),
+            vec![],
+        )
+        .write(&bin_path)
+        .unwrap();
+        let sliced = parse(&bin_path, &[]).read_calsols().unwrap().unwrap();
+        assert_eq!(sliced.dim(), (num_tiles, freqs_hz.len()));
+        let first_chan = vis_sel.coarse_chan_range.start * fine_chans_per_coarse;
+        for ((_, chan), jones) in sliced.indexed_iter() {
+            assert_abs_diff_eq!(*jones, Jones::identity() * (first_chan + chan) as f64);
+        }
+    }
+
+    #[test]
+    fn test_parse_layout() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+        let mut args = vec!["birli", "-m", metafits_path];
         args.extend_from_slice(&gpufits_paths);
+        let original_ctx = BirliContext::from_args(&args).unwrap();
+        let original_antenna = &original_ctx.corr_ctx.metafits_context.antennas[1];
+        assert!(!original_ctx.flag_ctx.antenna_flags[1]);
+        assert!(original_ctx.io_ctx.layout_overrides.is_empty());
 
+        let tmp_dir = tempdir().unwrap();
+        let layout_path = tmp_dir.path().join("layout.json");
+        std::fs::write(
+            &layout_path,
+            format!(
+                r#"{{"{}": {{"north_m": 12.5, "electrical_length_m": 100.0, "flagged": true}}}}"#,
+                original_antenna.tile_name
+            ),
+        )
+        .unwrap();
+        args.extend_from_slice(&["--layout", layout_path.to_str().unwrap()]);
         let BirliContext {
-            corr_ctx, flag_ctx, ..
+            corr_ctx,
+            flag_ctx,
+            io_ctx,
+            ..
         } = BirliContext::from_args(&args).unwrap();
-        let n = corr_ctx.num_common_timesteps;
-
-        assert!(flag_ctx.timestep_flags[n - 1]);
-        assert!(!flag_ctx.timestep_flags[n - 2]);
+        let antenna = &corr_ctx.metafits_context.antennas[1];
+        assert_abs_diff_eq!(antenna.north_m, 12.5);
+        assert_abs_diff_eq!(antenna.east_m, original_antenna.east_m);
+        assert_abs_diff_eq!(antenna.rfinput_y.electrical_length_m, 100.0);
+        assert!(flag_ctx.antenna_flags[1]);
+        assert_eq!(io_ctx.layout_overrides.len(), 1);
+        let history = with_layout_history("message".into(), &io_ctx);
+        assert!(history.starts_with("message; layout overrides from "));
+        assert!(history.contains(&format!("{}: north_m ", original_antenna.tile_name)));
+
+        std::fs::write(&layout_path, r#"{"Tile999": {"flagged": true}}"#).unwrap();
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::IOError(IOError::BadLayout { .. }))
+        ));
     }
 
     #[test]
-    fn test_parse_flag_end_invalid_indivisible() {
-        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+    fn test_parse_drift_scan() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+        let tmp_dir = tempdir().unwrap();
+        let ms_path = tmp_dir.path().join("drift.ms");
+        let uvfits_path = tmp_dir.path().join("drift.uvfits");
+
+        for drift_args in [
+            vec!["--phase-centre", "zenith"],
+            vec!["--phase-centre-azel", "0", "90"],
+        ] {
+            let mut args = vec!["birli", "-m", metafits_path, "--no-draw-progress"];
+            args.extend_from_slice(&drift_args);
+            args.extend_from_slice(&["-M", ms_path.to_str().unwrap()]);
+            args.extend_from_slice(&gpufits_paths);
+            let birli_ctx = BirliContext::from_args(&args).unwrap();
+            let phase_centres = birli_ctx.prep_ctx.drift_phase_centres.as_ref().unwrap();
+            assert_eq!(phase_centres.len(), birli_ctx.corr_ctx.timesteps.len());
+            let vis_sel = birli_ctx.vis_sel.clone();
+            assert_eq!(
+                birli_ctx.prep_ctx.phase_centre,
+                phase_centres[vis_sel.timestep_range.start]
+            );
+            // at zenith, the declination is the latitude of the array.
+            assert_abs_diff_eq!(
+                phase_centres[vis_sel.timestep_range.start].dec,
+                birli_ctx.prep_ctx.array_pos.latitude_rad,
+                epsilon = 1e-2
+            );
+            birli_ctx.run().unwrap();
 
-        #[rustfmt::skip]
-        let mut args = vec![
-            "birli",
-            "-m", metafits_path,
-            "--flag-end", "1",
-            "--",
-        ];
-        args.extend_from_slice(&gpufits_paths);
+            let field_table = Table::open(ms_path.join("FIELD"), TableOpenMode::Read).unwrap();
+            assert_eq!(field_table.n_rows(), vis_sel.timestep_range.len() as u64);
+            std::fs::remove_dir_all(&ms_path).unwrap();
+        }
 
+        // uvfits files only have a single phase centre.
+        let mut args = vec!["birli", "-m", metafits_path, "--phase-centre", "zenith"];
+        args.extend_from_slice(&["-u", uvfits_path.to_str().unwrap()]);
+        args.extend_from_slice(&gpufits_paths);
         assert!(matches!(
             BirliContext::from_args(&args),
             Err(BirliError::CLIError(_))
         ));
+
+        // `zenith` is the only value of --phase-centre, even before other options or files.
+        let mut args = vec!["birli", "-m", metafits_path, "--phase-centre", "ZENITH"];
+        args.extend_from_slice(&gpufits_paths);
+        let birli_ctx = BirliContext::from_args(&args).unwrap();
+        assert!(birli_ctx.prep_ctx.drift_phase_centres.is_some());
+
+        // the phase centre needs both an RA and a Dec, or `zenith` on its own.
+        for phase_centre_args in [
+            vec!["--phase-centre", "10"],
+            vec!["--phase-centre", "10", "zenith"],
+        ] {
+            let mut args = vec!["birli", "-m", metafits_path];
+            args.extend_from_slice(&gpufits_paths);
+            args.extend_from_slice(&phase_centre_args);
+            assert!(matches!(
+                BirliContext::from_args(&args),
+                Err(BirliError::CLIError(_))
+            ));
+        }
+        let mut args = vec!["birli", "-m", metafits_path];
+        args.extend_from_slice(&gpufits_paths);
+        args.extend_from_slice(&["--phase-centre", "10", "south"]);
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::ClapError(_))
+        ));
+
+        let mut args = vec!["birli", "-m", metafits_path, "--phase-centre", "zenith"];
+        args.extend_from_slice(&["--phase-centre-azel", "0", "90"]);
+        args.extend_from_slice(&gpufits_paths);
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::ClapError(_))
+        ));
+
+        let mut args = vec![
+            "birli",
+            "-m",
+            metafits_path,
+            "--phase-centre-azel",
+            "0",
+            "91",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+        assert!(BirliContext::from_args(&args).is_err());
     }
 
     #[test]
-    fn test_parse_invalid_coarse_chan_flag() {
-        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+    fn test_parse_smooth_di_cal() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+
+        for (options, expected) in [
+            (vec![], CalsolSmoothing::default()),
+            (
+                vec!["--median-di-cal", "5", "--fit-di-cal-delay"],
+                CalsolSmoothing {
+                    median_width: Some(5),
+                    amp_poly_order: None,
+                    fit_delay: true,
+                },
+            ),
+            (
+                vec!["--fit-di-cal-amp", "2"],
+                CalsolSmoothing {
+                    median_width: None,
+                    amp_poly_order: Some(2),
+                    fit_delay: false,
+                },
+            ),
+        ] {
+            let mut args = vec!["birli", "-m", metafits_path, "--apply-di-cal", "cal.bin"];
+            args.extend_from_slice(&options);
+            args.extend_from_slice(&gpufits_paths);
+            let BirliContext { io_ctx, .. } = BirliContext::from_args(&args).unwrap();
+            assert_eq!(io_ctx.aocalsols_smoothing, expected);
+        }
 
-        #[rustfmt::skip]
         let mut args = vec![
             "birli",
-            "-m", metafits_path,
-            "--flag-coarse-chans", "0", "9999",
-            "--",
+            "-m",
+            metafits_path,
+            "--apply-di-cal",
+            "cal.bin",
+            "--median-di-cal",
+            "0",
         ];
         args.extend_from_slice(&gpufits_paths);
-
         assert!(matches!(
             BirliContext::from_args(&args),
             Err(BirliError::CLIError(_))
         ));
     }
 
+    /// Each channel range writes the smoothed solutions to its own file, rather than overwriting
+    /// the same one. The solutions cover the whole metafits band, so they can be applied again.
     #[test]
-    fn test_parse_valid_coarse_chan_flag() {
-        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+    fn test_smoothed_di_cal_out_ranges() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+        let tmp_dir = tempdir().unwrap();
+        let calsols_path = tmp_dir.path().join("sols.bin");
+        let smoothed_path = tmp_dir.path().join("smoothed.bin");
+
+        let mut args = vec!["birli", "-m", metafits_path];
+        args.extend_from_slice(&gpufits_paths);
+        let BirliContext { corr_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        let num_tiles = corr_ctx.metafits_context.num_ants;
+        let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+        AOCalSols::new(
+            Array3::from_elem(
+                (
+                    1,
+                    num_tiles,
+                    corr_ctx.num_coarse_chans * fine_chans_per_coarse,
+                ),
+                Jones::identity(),
+            ),
+            vec![],
+        )
+        .write(&calsols_path)
+        .unwrap();
 
+        let chan_ranges = corr_ctx
+            .provided_coarse_chan_indices
+            .iter()
+            .map(|cc_idx| format!("{cc_idx}-{cc_idx}"))
+            .collect::<Vec<_>>()
+            .join(",");
         #[rustfmt::skip]
         let mut args = vec![
             "birli",
             "-m", metafits_path,
-            "--flag-coarse-chans", "2",
-            "--",
+            "--no-draw-progress",
+            "--apply-di-cal", calsols_path.to_str().unwrap(),
+            "--median-di-cal", "3",
+            "--smoothed-di-cal-out", smoothed_path.to_str().unwrap(),
+            "--sel-chan-ranges", &chan_ranges,
         ];
         args.extend_from_slice(&gpufits_paths);
-
-        let BirliContext { flag_ctx, .. } = BirliContext::from_args(&args).unwrap();
-
-        assert!(flag_ctx.coarse_chan_flags[2]);
-        assert!(!flag_ctx.coarse_chan_flags[1]);
+        BirliContext::from_args(&args)
+            .unwrap()
+            .run_ranges()
+            .unwrap();
+        assert!(!smoothed_path.exists());
+        for &cc_idx in &corr_ctx.provided_coarse_chan_indices {
+            let rec_chan_number = corr_ctx.coarse_chans[cc_idx].rec_chan_number;
+            let range_path = tmp_dir
+                .path()
+                .join(format!("smoothed_ch{rec_chan_number}.bin"));
+            let smoothed = AOCalSols::read(&range_path).unwrap();
+            assert_eq!(
+                smoothed.di_jones.dim(),
+                (
+                    1,
+                    num_tiles,
+                    corr_ctx.num_coarse_chans * fine_chans_per_coarse
+                )
+            );
+        }
     }
 
     #[test]
-    fn test_parse_invalid_fine_chan_flag() {
-        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+    fn test_parse_mwax_weights() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
 
-        #[rustfmt::skip]
-        let mut args = vec![
-            "birli",
-            "-m", metafits_path,
-            "--flag-fine-chans", "0", "9999",
-            "--",
-        ];
-        args.extend_from_slice(&gpufits_paths);
+        for (options, expected) in [
+            (vec![], Some(0.0)),
+            (vec!["--flag-min-weight", "90"], Some(0.9)),
+            (vec!["--no-mwax-weights"], None),
+        ] {
+            let mut args = vec!["birli", "-m", metafits_path];
+            args.extend_from_slice(&options);
+            args.extend_from_slice(&gpufits_paths);
+            let BirliContext { io_ctx, .. } = BirliContext::from_args(&args).unwrap();
+            assert_eq!(io_ctx.min_mwax_weight, expected);
+        }
 
+        let mut args = vec!["birli", "-m", metafits_path, "--flag-min-weight", "150"];
+        args.extend_from_slice(&gpufits_paths);
         assert!(matches!(
             BirliContext::from_args(&args),
             Err(BirliError::CLIError(_))
@@ -2583,84 +5709,287 @@ mod argparse_tests {
     }
 
     #[test]
-    fn test_parse_valid_fine_chan_flag() {
-        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+    fn test_parse_calibrate() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let sky_model_path = tmp_dir.path().join("sky_model.json");
+        std::fs::write(
+            &sky_model_path,
+            r#"[{"ra_deg": 0.0, "dec_deg": -27.0, "flux_jy": 10.0}]"#,
+        )
+        .unwrap();
+        let sky_model = sky_model_path.to_str().unwrap();
 
-        #[rustfmt::skip]
-        let mut args = vec!["birli", "-m", metafits_path, "--flag-fine-chans", "2", "--"];
+        let mut args = vec!["birli", "-m", metafits_path];
         args.extend_from_slice(&gpufits_paths);
+        assert!(CalibrateContext::from_args(&args).unwrap().is_none());
 
-        let BirliContext { flag_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        let mut args = vec![
+            "birli",
+            "calibrate",
+            "-m",
+            metafits_path,
+            "--sky-model",
+            sky_model,
+            "--calsols-out",
+            "sols.bin",
+            "--max-iterations",
+            "10",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+        let calibrate_ctx = CalibrateContext::from_args(&args).unwrap().unwrap();
+        assert_eq!(calibrate_ctx.sky_model.components.len(), 1);
+        assert_eq!(calibrate_ctx.max_iterations, 10);
+        assert_eq!(calibrate_ctx.calsols_out.to_str(), Some("sols.bin"));
+
+        // the sky model and output path are required by clap.
+        for options in [
+            vec!["calibrate", "--calsols-out", "sols.bin"],
+            vec!["calibrate", "--sky-model", sky_model],
+        ] {
+            let mut args = vec!["birli"];
+            args.extend_from_slice(&options);
+            args.extend_from_slice(&["-m", metafits_path]);
+            args.extend_from_slice(&gpufits_paths);
+            assert!(matches!(
+                CalibrateContext::from_args(&args),
+                Err(BirliError::ClapError(_))
+            ));
+        }
 
-        assert!(flag_ctx.fine_chan_flags[2]);
-        assert!(!flag_ctx.fine_chan_flags[1]);
+        for options in [
+            vec!["--stop-threshold", "0"],
+            vec!["--sel-chan-ranges", "0-0,1-1"],
+        ] {
+            let mut args = vec![
+                "birli",
+                "calibrate",
+                "--sky-model",
+                sky_model,
+                "--calsols-out",
+                "sols.bin",
+                "-m",
+                metafits_path,
+            ];
+            args.extend_from_slice(&options);
+            args.extend_from_slice(&gpufits_paths);
+            assert!(matches!(
+                CalibrateContext::from_args(&args),
+                Err(BirliError::CLIError(_))
+            ));
+        }
+
+        // solver options are only known to the calibrate subcommand.
+        let mut args = vec!["birli", "-m", metafits_path, "--max-iterations", "10"];
+        args.extend_from_slice(&gpufits_paths);
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::ClapError(_))
+        ));
     }
 
+    /// Test the solutions of `birli calibrate` match the gains which corrupted the model.
     #[test]
-    fn test_parse_invalid_antenna_flag() {
-        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+    fn test_calibrate_solves_known_gains() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let sky_model_path = tmp_dir.path().join("sky_model.txt");
+        std::fs::write(&sky_model_path, "0.0 -27.0 10.0\n1.0 -26.0 5.0\n").unwrap();
+        let calsols_path = tmp_dir.path().join("sols.bin");
 
+        let metafits_path = "tests/data/1196175296_mwa_ord/1196175296.metafits";
         #[rustfmt::skip]
-        let mut args = vec![
+        let args = vec![
             "birli",
+            "calibrate",
             "-m", metafits_path,
-            "--flag-antennas", "0", "9999",
-            "--",
+            "--sky-model", sky_model_path.to_str().unwrap(),
+            "--calsols-out", calsols_path.to_str().unwrap(),
+            "--max-iterations", "100",
+            "--stop-threshold", "1e-10",
+            "tests/data/1196175296_mwa_ord/1196175296_20171201145440_gpubox01_00.fits",
+            "tests/data/1196175296_mwa_ord/1196175296_20171201145440_gpubox02_00.fits",
         ];
-        args.extend_from_slice(&gpufits_paths);
+        let calibrate_ctx = CalibrateContext::from_args(&args).unwrap().unwrap();
+        let BirliContext {
+            corr_ctx,
+            vis_sel,
+            prep_ctx,
+            ..
+        } = &calibrate_ctx.birli_ctx;
+        let chunk_ctx = ChunkContext::from_mwalib(corr_ctx, vis_sel);
+        let ant_pairs = &chunk_ctx.ant_pairs;
+        let num_tiles = corr_ctx.metafits_context.num_ants;
+
+        // corrupt the model visibilities with known gains.
+        let mut jones_array = vis_sel
+            .allocate_jones(chunk_ctx.fine_chans_per_coarse)
+            .unwrap();
+        let uvws = calc_uvws(
+            corr_ctx,
+            vis_sel,
+            Some(prep_ctx.array_pos),
+            Some(prep_ctx.phase_centre),
+        );
+        calibrate_ctx
+            .sky_model
+            .predict(
+                jones_array.view_mut(),
+                uvws.view(),
+                &chunk_ctx.freqs_hz,
+                prep_ctx.phase_centre,
+            )
+            .unwrap();
+        let num_chans = chunk_ctx.freqs_hz.len();
+        let gains = Array2::from_shape_fn((num_tiles, num_chans), |(tile, chan)| {
+            let (tile, chan) = (tile as f64 / num_tiles as f64, chan as f64);
+            Jones::from([
+                Complex::from_polar(1. + 0.2 * tile, 3. * tile + 0.1 * chan),
+                Complex::new(0.02 * tile, -0.01),
+                Complex::new(-0.01 * chan, 0.03 * tile),
+                Complex::from_polar(0.9 + 0.1 * tile, -2. * tile),
+            ])
+        });
+        for ((_, chan, bl), jones) in jones_array.indexed_iter_mut() {
+            let (ant1, ant2) = ant_pairs[bl];
+            *jones = Jones::<f32>::from(
+                gains[(ant1, chan)] * Jones::<f64>::from(*jones) * gains[(ant2, chan)].h(),
+            );
+        }
+        let weight_array = Array3::from_elem(jones_array.dim(), 1_f32);
+        let flag_array = Array3::from_elem(jones_array.dim(), false);
 
-        assert!(matches!(
-            BirliContext::from_args(&args),
-            Err(BirliError::CLIError(_))
-        ));
+        let corrections = calibrate_ctx
+            .solve(
+                corr_ctx,
+                jones_array.view(),
+                weight_array.view(),
+                flag_array.view(),
+                &chunk_ctx,
+            )
+            .unwrap();
+        assert_eq!(corrections.dim(), (num_tiles, num_chans));
+
+        // the solutions are only unique up to a unitary matrix shared by every tile, which
+        // cancels in the gains of each baseline.
+        for chan in 0..num_chans {
+            for &(ant1, ant2) in ant_pairs.iter().filter(|(ant1, ant2)| ant1 != ant2) {
+                let solved = corrections[(ant1, chan)].inv() * corrections[(ant2, chan)].inv().h();
+                let known = gains[(ant1, chan)] * gains[(ant2, chan)].h();
+                assert_abs_diff_eq!(solved, known, epsilon = 1e-3);
+            }
+        }
+
+        // the solutions are written for every coarse channel in the observation.
+        let calsols = AOCalSols::read(&calsols_path).unwrap();
+        assert_eq!(
+            calsols.di_jones.dim(),
+            (
+                1,
+                num_tiles,
+                corr_ctx.num_coarse_chans * chunk_ctx.fine_chans_per_coarse
+            )
+        );
+        let sel_start = vis_sel.coarse_chan_range.start * chunk_ctx.fine_chans_per_coarse;
+        for ((tile, chan), correction) in corrections.indexed_iter() {
+            assert_abs_diff_eq!(
+                calsols.di_jones[(0, tile, sel_start + chan)],
+                *correction,
+                epsilon = 1e-6
+            );
+        }
     }
 
     #[test]
-    fn test_parse_valid_antenna_flag() {
-        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+    fn test_parse_flag_rfinput_selectors() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
 
-        #[rustfmt::skip]
-        let mut args = vec!["birli", "-m", metafits_path, "--flag-antennas", "2", "--"];
-        args.extend_from_slice(&gpufits_paths);
+        // Tile051 is antenna 0 on receiver 5 slot 1 with rfinput 246, and Tile052 is
+        // antenna 1 on receiver 5 slot 2.
+        for (selector, expected) in [
+            (vec!["--flag-receivers", "5"], [true, true]),
+            (vec!["--flag-slots", "5:2"], [false, true]),
+            (vec!["--flag-rfinputs", "246"], [true, false]),
+        ] {
+            let mut args = vec!["birli", "-m", metafits_path];
+            args.extend_from_slice(&selector);
+            args.push("--");
+            args.extend_from_slice(&gpufits_paths);
+
+            let birli_ctx = BirliContext::from_args(&args).unwrap();
+
+            assert_eq!(birli_ctx.flag_ctx.antenna_flags, expected, "{selector:?}");
+            assert!(birli_ctx.to_string().contains(&format!(
+                "Flagged tiles: {:?}",
+                [(expected[0], "Tile051"), (expected[1], "Tile052")]
+                    .iter()
+                    .filter(|(flag, _)| *flag)
+                    .map(|(_, name)| *name)
+                    .collect::<Vec<_>>()
+            )));
+        }
 
-        let BirliContext { flag_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        for selector in [
+            ["--flag-receivers", "99"],
+            ["--flag-slots", "5:9"],
+            ["--flag-slots", "5"],
+            ["--flag-rfinputs", "0"],
+        ] {
+            let mut args = vec!["birli", "-m", metafits_path];
+            args.extend_from_slice(&selector);
+            args.push("--");
+            args.extend_from_slice(&gpufits_paths);
 
-        assert!(flag_ctx.antenna_flags[2]);
-        assert!(!flag_ctx.antenna_flags[1]);
+            assert!(
+                matches!(BirliContext::from_args(&args), Err(BirliError::CLIError(_))),
+                "{selector:?}"
+            );
+        }
     }
 
     #[test]
-    fn test_parse_no_flag_metafits_and_valid_antenna_flag() {
-        let (_, gpufits_paths) = get_1254670392_avg_paths();
+    fn test_parse_flag_elevation() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
 
+        // the observation is around local midnight, with the Sun about 50 degrees below the
+        // horizon, and the phase centre well above it.
         #[rustfmt::skip]
         let mut args = vec![
             "birli",
-            // in this metafits file, all ants are flagged.
-            "-m", "tests/data/1254670392_avg/1254670392.metafits",
-            "--no-flag-metafits",
-            "--flag-antennas", "2",
-            "--",
+            "-m", metafits_path,
+            "--flag-sun-above", "-60",
+            "--flag-min-elevation", "30",
         ];
         args.extend_from_slice(&gpufits_paths);
 
-        let BirliContext { flag_ctx, .. } = BirliContext::from_args(&args).unwrap();
-
-        assert!(flag_ctx.antenna_flags[2]);
-        assert!(!flag_ctx.antenna_flags[1]);
-    }
-
-    #[test]
-    fn test_parse_flag_autos() {
-        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+        let BirliContext {
+            flag_ctx, vis_sel, ..
+        } = BirliContext::from_args(&args).unwrap();
+        assert!(vis_sel
+            .timestep_range
+            .clone()
+            .all(|timestep_idx| flag_ctx.timestep_flags[timestep_idx]));
 
         #[rustfmt::skip]
-        let mut args = vec!["birli", "-m", metafits_path, "--flag-autos"];
+        let mut args = vec![
+            "birli",
+            "-m", metafits_path,
+            "--flag-sun-above", "-30",
+            "--flag-min-elevation", "30",
+        ];
         args.extend_from_slice(&gpufits_paths);
 
         let BirliContext { flag_ctx, .. } = BirliContext::from_args(&args).unwrap();
+        let mut args = vec!["birli", "-m", metafits_path];
+        args.extend_from_slice(&gpufits_paths);
+        let default_flag_ctx = BirliContext::from_args(&args).unwrap().flag_ctx;
+        assert_eq!(flag_ctx.timestep_flags, default_flag_ctx.timestep_flags);
 
-        assert!(flag_ctx.autos);
+        let mut args = vec!["birli", "-m", metafits_path, "--flag-min-elevation", "91"];
+        args.extend_from_slice(&gpufits_paths);
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::CLIError(_))
+        ));
     }
 
     #[test]
@@ -2832,6 +6161,50 @@ mod argparse_tests {
         assert_eq!(num_timesteps_per_chunk, Some(2));
     }
 
+    #[test]
+    fn test_parse_valid_chunk_overlap() {
+        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli",
+            "-m", metafits_path,
+            "--time-chunk", "2",
+            "--chunk-overlap", "1",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+
+        let BirliContext {
+            num_timesteps_per_chunk,
+            num_timesteps_overlap,
+            ..
+        } = BirliContext::from_args(&args).unwrap();
+
+        assert_eq!(num_timesteps_per_chunk, Some(2));
+        assert_eq!(num_timesteps_overlap, 1);
+    }
+
+    #[test]
+    fn test_parse_max_memory_with_chunk_overlap() {
+        let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
+
+        // 0.64 GiB fits two timesteps, but not a timestep with one either side of it.
+        #[rustfmt::skip]
+        let mut args = vec![
+            "birli",
+            "-m", metafits_path,
+            "--max-memory", "0.64",
+            "--sel-time", "0", "2",
+            "--chunk-overlap", "1",
+        ];
+        args.extend_from_slice(&gpufits_paths);
+
+        assert!(matches!(
+            BirliContext::from_args(&args),
+            Err(BirliError::CLIError(_))
+        ));
+    }
+
     #[test]
     fn test_parse_custom_phase_negative() {
         let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
@@ -5,14 +5,14 @@ use crate::{
 };
 use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
 use itertools::{izip, Itertools};
-use log::trace;
+use log::{trace, warn};
 use marlu::{
     constants::VEL_C,
     hifitime::{Duration, Epoch},
     io::error::BadArrayShape,
     mwalib::{CorrelatorContext, MWAVersion},
     precession::precess_time,
-    Complex, LatLngHeight, RADec, VisSelection, XyzGeodetic, UVW,
+    AzEl, Complex, LatLngHeight, RADec, VisSelection, XyzGeodetic, UVW,
 };
 use std::{f64::consts::TAU, ops::Range};
 use thiserror::Error;
@@ -206,48 +206,48 @@ pub fn correct_cable_lengths(
 /// ```
 pub fn correct_geometry(
     corr_ctx: &CorrelatorContext,
-    mut jones_array: ArrayViewMut3<Jones<f32>>,
+    jones_array: ArrayViewMut3<Jones<f32>>,
     vis_sel: &VisSelection,
     array_pos: Option<LatLngHeight>,
     phase_centre: Option<RADec>,
     draw_progress: bool,
 ) {
-    trace!("start correct_geometry");
-
-    let array_pos = array_pos.unwrap_or_else(|| {
-        // The results here are slightly different to those given by cotter.
-        // This is at least partly due to different constants (the altitude is
-        // definitely slightly different), but possibly also because ERFA is
-        // more accurate than cotter's "homebrewed" Geodetic2XYZ.
-        LatLngHeight::mwa()
-    });
+    let phase_centre = phase_centre
+        .unwrap_or_else(|| RADec::from_mwalib_phase_or_pointing(&corr_ctx.metafits_context));
+    correct_geometry_per_timestep(
+        corr_ctx,
+        jones_array,
+        vis_sel,
+        array_pos,
+        &vec![phase_centre; vis_sel.timestep_range.len()],
+        draw_progress,
+    );
+}
 
-    let timesteps = &corr_ctx.timesteps[vis_sel.timestep_range.clone()];
+/// Perform geometric corrections like [`correct_geometry`], but with a separate phase centre for
+/// each timestep in `vis_sel.timestep_range`, e.g. for drift scans (see
+/// [`drift_phase_centres`]).
+///
+/// # Panics
+///
+/// Will panic if `phase_centres` doesn't have a phase centre for each selected timestep.
+pub fn correct_geometry_per_timestep(
+    corr_ctx: &CorrelatorContext,
+    mut jones_array: ArrayViewMut3<Jones<f32>>,
+    vis_sel: &VisSelection,
+    array_pos: Option<LatLngHeight>,
+    phase_centres: &[RADec],
+    draw_progress: bool,
+) {
+    trace!("start correct_geometry");
 
     let all_freqs_hz = corr_ctx
         .get_fine_chan_freqs_hz_array(&vis_sel.coarse_chan_range.clone().collect::<Vec<_>>());
     let jones_dims = jones_array.dim();
 
-    let integration_time_s = corr_ctx.metafits_context.corr_int_time_ms as f64 / 1000.0;
-
-    let phase_centre = phase_centre
-        .unwrap_or_else(|| RADec::from_mwalib_phase_or_pointing(&corr_ctx.metafits_context));
-    let tiles_xyz_geod = XyzGeodetic::get_tiles(&corr_ctx.metafits_context, array_pos.latitude_rad);
-
     // use baseline_idxs to select antpairs out of corr_ctx.metafits_context.baselines
     let ant_pairs = vis_sel.get_ant_pairs(&corr_ctx.metafits_context);
-    let centroid_timestamps = timesteps
-        .iter()
-        .map(|t| Epoch::from_gpst_seconds(t.gps_time_ms as f64 / 1000.0 + integration_time_s / 2.0))
-        .collect::<Vec<_>>();
-    let dut1 = Duration::from_seconds(corr_ctx.metafits_context.dut1.unwrap_or(0.0));
-    let part_uvws = calc_part_uvws(
-        &centroid_timestamps,
-        dut1,
-        phase_centre,
-        array_pos,
-        &tiles_xyz_geod,
-    );
+    let part_uvws = calc_sel_part_uvws(corr_ctx, vis_sel, array_pos, phase_centres);
 
     // Create a progress bar to show the status of the correction
     let draw_target = if draw_progress {
@@ -309,10 +309,15 @@ pub enum DigitalGainCorrection {
 ///
 /// - `corr_ctx` - The correlator [`marlu::mwalib::CorrelatorContext`].
 /// - `jones_array` - The array of Jones matrices to be corrected, `[timestep][channel][baseleine]`.
+/// - `flag_array` - The array of flags, same dimensions as `jones_array`.
 /// - `coarse_chan_range` - The range of mwalib coarse channels which are used in in the channel
 ///     dimension of the jones array.
 /// - `ant_pairs` - a slice of tuples of antenna indices for each baseline in the visibilities.
 ///
+/// If a digital gain is zero, negative or not finite, the visibilities of that antenna and coarse
+/// channel are flagged instead of corrected, and a warning is shown with the tile name and
+/// receiver channel.
+///
 /// # Assumptions
 /// - the digital gains are provided in [`marlu::mwalib::Rfinput.digital_gains`] in the same order as the
 ///   coarse channel indices (increasing sky frequency)
@@ -321,9 +326,11 @@ pub enum DigitalGainCorrection {
 /// - Will throw [`BadArrayShape`] if:
 ///     - `jones_array.dim().1 != num_fine_chans_per_coarse * coarse_chan_range.len()`
 ///     - `jones_array.dim().2 != ant_pairs.len()`
+///     - `flag_array.dim() != jones_array.dim()`
 pub fn correct_digital_gains(
     corr_ctx: &CorrelatorContext,
     jones_array: ArrayViewMut3<Jones<f32>>,
+    flag_array: ArrayViewMut3<bool>,
     // TODO: take a VisSelection
     coarse_chan_range: &Range<usize>,
     ant_pairs: &[(usize, usize)],
@@ -365,16 +372,47 @@ pub fn correct_digital_gains(
         },
     );
 
+    let sel_ant_idxs = ant_pairs
+        .iter()
+        .flat_map(|&(ant1, ant2)| [ant1, ant2])
+        .unique()
+        .sorted();
+    for ant_idx in sel_ant_idxs {
+        let ant = &corr_ctx.metafits_context.antennas[ant_idx];
+        for (&(gain_x, gain_y), coarse_chan_idx) in izip!(
+            gains.index_axis(Axis(0), ant_idx),
+            coarse_chan_range.clone()
+        ) {
+            if !is_good_gain(gain_x) || !is_good_gain(gain_y) {
+                warn!(
+                    "Flagging tile {} @ rec_chan={}, bad digital gains: x={}, y={}",
+                    ant.tile_name,
+                    corr_ctx.metafits_context.metafits_coarse_chans[coarse_chan_idx]
+                        .rec_chan_number,
+                    gain_x,
+                    gain_y
+                );
+            }
+        }
+    }
+
     _correct_digital_gains(
         jones_array,
+        flag_array,
         gains.view(),
         ant_pairs,
         num_fine_chans_per_coarse,
     )
 }
 
+/// Whether a digital gain can be corrected for: positive and finite.
+fn is_good_gain(gain: f64) -> bool {
+    gain.is_finite() && gain > 0.0
+}
+
 fn _correct_digital_gains(
     mut jones_array: ArrayViewMut3<Jones<f32>>,
+    mut flag_array: ArrayViewMut3<bool>,
     gains: ArrayView2<(f64, f64)>,
     ant_pairs: &[(usize, usize)],
     num_fine_chans_per_coarse: usize,
@@ -395,19 +433,37 @@ fn _correct_digital_gains(
         }));
     }
     assert!(vis_dims.1 == gain_dims.1 * num_fine_chans_per_coarse);
+    if flag_array.dim() != vis_dims {
+        return Err(DigitalGainCorrection::BadArrayShape(BadArrayShape {
+            argument: "flag_array",
+            function: "_correct_digital_gains",
+            expected: format!("{vis_dims:?}"),
+            received: format!("{:?}", flag_array.dim()),
+        }));
+    }
 
     // iterate through the selected baselines
     jones_array
         .axis_iter_mut(Axis(2))
         .into_par_iter()
+        .zip_eq(flag_array.axis_iter_mut(Axis(2)))
         .zip_eq(ant_pairs)
-        .for_each(|(mut jones_array, &(ant1_idx, ant2_idx))| {
+        .for_each(|((mut jones_array, mut flag_array), &(ant1_idx, ant2_idx))| {
             // iterate through the selected coarse channels
-            for (mut jones_array, &(gain1x, gain1y), &(gain2x, gain2y)) in izip!(
+            for (mut jones_array, mut flag_array, &(gain1x, gain1y), &(gain2x, gain2y)) in izip!(
                 jones_array.axis_chunks_iter_mut(Axis(1), num_fine_chans_per_coarse),
+                flag_array.axis_chunks_iter_mut(Axis(1), num_fine_chans_per_coarse),
                 gains.index_axis(Axis(0), ant1_idx),
                 gains.index_axis(Axis(0), ant2_idx),
             ) {
+                // flag instead of dividing by a bad gain
+                if ![gain1x, gain1y, gain2x, gain2y]
+                    .into_iter()
+                    .all(is_good_gain)
+                {
+                    flag_array.fill(true);
+                    continue;
+                }
                 // for all visibilities in the selected coarse channel, for all timesteps
                 for jones in &mut jones_array {
                     // promote, correct, demote
@@ -430,6 +486,9 @@ pub enum PassbandCorrection {
     #[error(transparent)]
     /// Error for bad array shape in provided argument
     BadArrayShape(#[from] BadArrayShape),
+    #[error("No usable autocorrelations to derive passband gains from")]
+    /// Error when every autocorrelation spectrum is flagged, zero or non-finite
+    NoAutocorrelations,
 }
 
 /// Correct for coarse pfb bandpass shape in each coarse channel by scaling `passband_gains` to
@@ -449,7 +508,10 @@ pub enum PassbandCorrection {
 /// - `num_fine_chans_per_coarse` is zero
 /// - The length of the channel axis in `jones_array` is not a multiple of `num_fine_chans_per_coarse`.
 /// - `jones_array` and `weight_array` have different shapes.
-/// - The length of the coarse band gains is not a multiple of `num_fine_chans_per_coarse`, or vice versa.
+/// - `passband_gains` is empty.
+///
+/// The gains don't need to be at a multiple of the fine channel resolution, they are resampled
+/// onto the fine channels by [`resample_gains`].
 ///
 pub fn correct_coarse_passband_gains(
     mut jones_array: ArrayViewMut3<Jones<f32>>,
@@ -487,20 +549,16 @@ pub fn correct_coarse_passband_gains(
         }));
     };
 
-    let fscrunch = if passband_gains.len() % num_fine_chans_per_coarse == 0 {
-        passband_gains.len() / num_fine_chans_per_coarse
-    } else {
+    if passband_gains.is_empty() {
         return Err(PassbandCorrection::BadArrayShape(BadArrayShape {
             argument: "passband_gains",
             function: "correct_coarse_passband_gains",
-            expected: format!(
-                "n, where n is a multiple of num_fine_chans_per_coarse={num_fine_chans_per_coarse}"
-            ),
+            expected: "a non-empty slice".into(),
             received: format!("{:?}", passband_gains.len()),
         }));
     };
 
-    let scrunched_gains = scrunch_gains(passband_gains, fscrunch, scrunch_type);
+    let scrunched_gains = resample_gains(passband_gains, num_fine_chans_per_coarse, scrunch_type);
 
     for (mut jones_array, mut weight_array) in izip!(
         jones_array.axis_chunks_iter_mut(Axis(1), num_fine_chans_per_coarse),
@@ -522,6 +580,224 @@ pub fn correct_coarse_passband_gains(
     Ok(())
 }
 
+/// Derive the coarse pfb passband shape empirically from the autocorrelations of an observation.
+///
+/// The XX and YY powers of each antenna, timestep and coarse channel are normalised by their
+/// mean across the coarse channel, then these spectra are averaged together. Spectra containing a
+/// zero or non-finite power are skipped. The result is normalised to a median of one, so the flat
+/// centre of the passband is close to unity like the built-in gains, and can be given to
+/// [`correct_coarse_passband_gains`] as `passband_gains`.
+///
+/// # Arguments
+///
+/// - `auto_array` - The autocorrelations, `[timestep][channel][antenna]`, e.g. from
+///   [`select_autos`], with digital gains corrected but no passband correction.
+/// - `num_fine_chans_per_coarse` - The number of fine channels in each coarse.
+///
+/// # Errors
+///
+/// Will throw `BadArrayShape` if:
+/// - `num_fine_chans_per_coarse` is zero
+/// - The length of the channel axis in `auto_array` is not a multiple of `num_fine_chans_per_coarse`.
+///
+/// Will throw `NoAutocorrelations` if no spectrum is usable.
+pub fn derive_passband_gains(
+    auto_array: ArrayView3<Jones<f32>>,
+    num_fine_chans_per_coarse: usize,
+) -> Result<Vec<f64>, PassbandCorrection> {
+    if num_fine_chans_per_coarse == 0 {
+        return Err(PassbandCorrection::BadArrayShape(BadArrayShape {
+            argument: "num_fine_chans_per_coarse",
+            function: "derive_passband_gains",
+            expected: "a number greater than zero".into(),
+            received: format!("{num_fine_chans_per_coarse:?}"),
+        }));
+    }
+    if auto_array.dim().1 % num_fine_chans_per_coarse != 0 {
+        return Err(PassbandCorrection::BadArrayShape(BadArrayShape {
+            argument: "auto_array",
+            function: "derive_passband_gains",
+            expected: format!(
+                "(_, n, _), where n is a multiple of num_fine_chans_per_coarse={num_fine_chans_per_coarse}"
+            ),
+            received: format!("{:?}", auto_array.dim()),
+        }));
+    }
+
+    let mut gains = vec![0.; num_fine_chans_per_coarse];
+    let mut num_spectra = 0;
+    // autos: [timestep][channel][antenna] for each coarse channel
+    for autos in auto_array.axis_chunks_iter(Axis(1), num_fine_chans_per_coarse) {
+        for autos in autos.outer_iter() {
+            for autos in autos.axis_iter(Axis(1)) {
+                for pol_idx in [0, 3] {
+                    let powers = autos
+                        .iter()
+                        .map(|auto| auto[pol_idx].re as f64)
+                        .collect_vec();
+                    if !powers.iter().all(|&power| power.is_finite() && power > 0.) {
+                        continue;
+                    }
+                    let mean = powers.iter().sum::<f64>() / powers.len() as f64;
+                    for (gain, power) in izip!(gains.iter_mut(), powers) {
+                        *gain += power / mean;
+                    }
+                    num_spectra += 1;
+                }
+            }
+        }
+    }
+    if num_spectra == 0 {
+        return Err(PassbandCorrection::NoAutocorrelations);
+    }
+
+    let sorted = gains
+        .iter()
+        .copied()
+        .sorted_by(f64::total_cmp)
+        .collect_vec();
+    let median = if sorted.len() % 2 == 0 {
+        (sorted[sorted.len() / 2 - 1] + sorted[sorted.len() / 2]) / 2.
+    } else {
+        sorted[sorted.len() / 2]
+    };
+    Ok(gains.into_iter().map(|gain| gain / median).collect())
+}
+
+/// Copy the autocorrelations out of `jones_array` as `[timestep][channel][antenna]`, along with
+/// the antenna index of each one, or `None` if any antenna in `ant_pairs` has no autocorrelation.
+pub fn select_autos(
+    jones_array: ArrayView3<Jones<f32>>,
+    ant_pairs: &[(usize, usize)],
+) -> Option<(Array3<Jones<f32>>, Vec<usize>)> {
+    let auto_ants = ant_pairs
+        .iter()
+        .flat_map(|&(ant1, ant2)| [ant1, ant2])
+        .unique()
+        .sorted()
+        .collect_vec();
+    let auto_bl_idxs = auto_ants
+        .iter()
+        .map(|&ant| ant_pairs.iter().position(|&pair| pair == (ant, ant)))
+        .collect::<Option<Vec<_>>>()?;
+    Some((jones_array.select(Axis(2), &auto_bl_idxs), auto_ants))
+}
+
+/// Normalise visibilities by the amplitude of the autocorrelations of each baseline's antennas.
+///
+/// Each polarisation `pq` of a visibility between antennas `i` and `j` is divided by
+/// `sqrt(A_i,pp A_j,qq)`, where `A` is the real part of an autocorrelation in the same timestep and
+/// channel. This flattens tile-based bandpass shapes and gain drifts when absolute calibration
+/// isn't available. Autocorrelations are normalised too, so their XX and YY become 1.
+///
+/// # Arguments
+///
+/// - `jones_array` - The array of Jones matrices to be normalised, `[timestep][channel][baseline]`.
+/// - `flag_array` - The array of flags, same dimensions as `jones_array`.
+/// - `ant_pairs` - a slice of tuples of antenna indices for each baseline in the visibilities.
+/// - `auto_array` - The autocorrelations to normalise by, `[timestep][channel][antenna]`, e.g.
+///   from [`select_autos`].
+/// - `auto_ants` - The antenna index of each autocorrelation in `auto_array`.
+///
+/// Visibilities where either autocorrelation is not positive and finite are flagged instead.
+/// Returns the number of newly flagged visibilities.
+///
+/// # Errors
+/// - Will throw [`BadArrayShape`] if:
+///     - `flag_array.dim() != jones_array.dim()`
+///     - `jones_array.dim().2 != ant_pairs.len()`
+///     - `auto_array` does not have the same timesteps and channels as `jones_array`, and an
+///       autocorrelation for each of `auto_ants`
+///     - an antenna in `ant_pairs` is not in `auto_ants`
+pub fn normalise_by_autos(
+    mut jones_array: ArrayViewMut3<Jones<f32>>,
+    mut flag_array: ArrayViewMut3<bool>,
+    ant_pairs: &[(usize, usize)],
+    auto_array: ArrayView3<Jones<f32>>,
+    auto_ants: &[usize],
+) -> Result<usize, BadArrayShape> {
+    let vis_dims = jones_array.dim();
+    if flag_array.dim() != vis_dims {
+        return Err(BadArrayShape {
+            argument: "flag_array",
+            function: "normalise_by_autos",
+            expected: format!("{vis_dims:?}"),
+            received: format!("{:?}", flag_array.dim()),
+        });
+    }
+    if vis_dims.2 != ant_pairs.len() {
+        return Err(BadArrayShape {
+            argument: "ant_pairs",
+            function: "normalise_by_autos",
+            expected: format!("vis_dims.2={}", vis_dims.2),
+            received: format!("{:?}", ant_pairs.len()),
+        });
+    }
+    let auto_dims = (vis_dims.0, vis_dims.1, auto_ants.len());
+    if auto_array.dim() != auto_dims {
+        return Err(BadArrayShape {
+            argument: "auto_array",
+            function: "normalise_by_autos",
+            expected: format!("{auto_dims:?}"),
+            received: format!("{:?}", auto_array.dim()),
+        });
+    }
+    let auto_idx = |ant: usize| {
+        auto_ants
+            .iter()
+            .position(|&auto_ant| auto_ant == ant)
+            .ok_or_else(|| BadArrayShape {
+                argument: "auto_ants",
+                function: "normalise_by_autos",
+                expected: format!("an autocorrelation for antenna {ant}"),
+                received: format!("{auto_ants:?}"),
+            })
+    };
+    let auto_idxs = ant_pairs
+        .iter()
+        .map(|&(ant1, ant2)| Ok((auto_idx(ant1)?, auto_idx(ant2)?)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut count = 0;
+    // arrays: [chan][baseline]
+    for (mut jones_array, mut flag_array, auto_array) in izip!(
+        jones_array.outer_iter_mut(),
+        flag_array.outer_iter_mut(),
+        auto_array.outer_iter(),
+    ) {
+        // arrays: [baseline]
+        for (mut jones_array, mut flag_array, autos) in izip!(
+            jones_array.outer_iter_mut(),
+            flag_array.outer_iter_mut(),
+            auto_array.outer_iter(),
+        ) {
+            for (jones, flag, &(auto1_idx, auto2_idx)) in izip!(
+                jones_array.iter_mut(),
+                flag_array.iter_mut(),
+                auto_idxs.iter()
+            ) {
+                // the XX and YY powers of each antenna
+                let powers1 = [autos[auto1_idx][0].re, autos[auto1_idx][3].re];
+                let powers2 = [autos[auto2_idx][0].re, autos[auto2_idx][3].re];
+                if powers1
+                    .iter()
+                    .chain(powers2.iter())
+                    .all(|&power| power.is_finite() && power > 0.0)
+                {
+                    for (pol_idx, pol) in jones.iter_mut().enumerate() {
+                        let power = powers1[pol_idx / 2] as f64 * powers2[pol_idx % 2] as f64;
+                        *pol /= power.sqrt() as f32;
+                    }
+                } else if !*flag {
+                    *flag = true;
+                    count += 1;
+                }
+            }
+        }
+    }
+    Ok(count)
+}
+
 #[derive(Clone)]
 /// Possible types of scrunching that a correlator might do.
 pub enum ScrunchType {
@@ -534,6 +810,10 @@ pub enum ScrunchType {
 impl ScrunchType {
     /// Return the corresponding scrunch version from the [`marlu::mwalib::MWAVersion`].
     ///
+    /// Observations with oversampled coarse channels are also
+    /// [`marlu::mwalib::MWAVersion::CorrMWAXv2`], since MWAX discards the oversampled edges of
+    /// each coarse channel and scrunches the critically sampled band that remains the same way.
+    ///
     /// # Errors
     ///
     /// Will throw [`BirliError::BadMWAVersion`] If you provide something other than
@@ -573,6 +853,9 @@ impl ScrunchType {
 /// 3 (o->o)|     0*    |     1     |     2     |     3     |     4     |
 /// 5 (o->o)|           0       |         1         |         2         |
 ///
+/// For gains which aren't a whole number of ultrafine channels per fine channel, see
+/// [`resample_gains`].
+///
 /// For more details see: <https://wiki.mwatelescope.org/display/MP/MWA+Fine+Channel+Centre+Frequencies>
 pub fn scrunch_gains(
     ultrafine_gains: &[f64],
@@ -646,6 +929,188 @@ pub fn scrunch_gains(
     }
 }
 
+/// Given a set of gains across a coarse channel, compute the gains for `num_scrunched_chans` fine
+/// channels, as if these were averaged by the given correlator type.
+///
+/// When the number of gains is a multiple of `num_scrunched_chans`, this is [`scrunch_gains`].
+/// Otherwise, e.g. 10kHz gains for 15kHz channels, or 40kHz gains for 10kHz channels, each
+/// scrunched channel is the average of the gains across its bandwidth, with the gains at its
+/// edges weighted by how much of them it overlaps. The scrunched channels are placed the same way
+/// as [`scrunch_gains`].
+pub fn resample_gains(
+    ultrafine_gains: &[f64],
+    num_scrunched_chans: usize,
+    scrunch_type: &ScrunchType,
+) -> Vec<f64> {
+    if ultrafine_gains.len() % num_scrunched_chans == 0 {
+        scrunch_gains(
+            ultrafine_gains,
+            ultrafine_gains.len() / num_scrunched_chans,
+            scrunch_type,
+        )
+    } else {
+        integrate_gains(ultrafine_gains, num_scrunched_chans, scrunch_type)
+    }
+}
+
+/// Resample `ultrafine_gains` onto `num_scrunched_chans` channels by integrating them across the
+/// bandwidth of each scrunched channel (see [`resample_gains`]).
+///
+/// The gains are treated as constant across each ultrafine channel, and periodic across the
+/// coarse channel, so scrunched channels which extend past either edge of the coarse channel wrap
+/// around, like the MWAX scrunched channel zero.
+fn integrate_gains(
+    ultrafine_gains: &[f64],
+    num_scrunched_chans: usize,
+    scrunch_type: &ScrunchType,
+) -> Vec<f64> {
+    let num_ultrafine_chans = ultrafine_gains.len() as f64;
+    let fscrunch = num_ultrafine_chans / num_scrunched_chans as f64;
+    // the integral of the gains from the start of ultrafine channel zero to each channel boundary
+    let boundary_integrals = std::iter::once(0.)
+        .chain(ultrafine_gains.iter().scan(0., |acc, &gain| {
+            *acc += gain;
+            Some(*acc)
+        }))
+        .collect::<Vec<f64>>();
+    let total = boundary_integrals[ultrafine_gains.len()];
+    // the integral of the gains from the start of ultrafine channel zero to `position`, in
+    // units of ultrafine channels, wrapping around the coarse channel
+    let integral = |position: f64| {
+        let periods = (position / num_ultrafine_chans).floor();
+        let position = position - periods * num_ultrafine_chans;
+        let chan = (position.floor() as usize).min(ultrafine_gains.len() - 1);
+        periods * total
+            + boundary_integrals[chan]
+            + (position - chan as f64) * ultrafine_gains[chan]
+    };
+    // the start of scrunched channel zero, relative to the start of ultrafine channel zero.
+    let first_start = match (scrunch_type, num_scrunched_chans % 2) {
+        (ScrunchType::Simple, _) => 0.,
+        // mwax ultrafine channel zero is centred on the centre of the coarse channel, and so is
+        // scrunched channel zero for an even number of scrunched channels.
+        (ScrunchType::CenterSymmetric, 0) => 0.5 - fscrunch / 2.,
+        // for an odd number of scrunched channels, scrunched channel zero starts at the centre.
+        (ScrunchType::CenterSymmetric, _) => 0.5,
+    };
+    (0..num_scrunched_chans)
+        .map(|scrunched_chan| {
+            let start = first_start + scrunched_chan as f64 * fscrunch;
+            (integral(start + fscrunch) - integral(start)) / fscrunch
+        })
+        .collect()
+}
+
+/// Calculate the UVWs of each selected baseline at the centroid of each selected timestep, the
+/// same UVWs [`correct_geometry`] uses to phase the visibilities to `phase_centre`.
+///
+/// UVWs are in units of meters, with dimensions `[timestep][baseline]`. `array_pos` and
+/// `phase_centre` default to the same values as [`correct_geometry`].
+pub fn calc_uvws(
+    corr_ctx: &CorrelatorContext,
+    vis_sel: &VisSelection,
+    array_pos: Option<LatLngHeight>,
+    phase_centre: Option<RADec>,
+) -> Array2<UVW> {
+    let ant_pairs = vis_sel.get_ant_pairs(&corr_ctx.metafits_context);
+    let phase_centre = phase_centre
+        .unwrap_or_else(|| RADec::from_mwalib_phase_or_pointing(&corr_ctx.metafits_context));
+    let part_uvws = calc_sel_part_uvws(
+        corr_ctx,
+        vis_sel,
+        array_pos,
+        &vec![phase_centre; vis_sel.timestep_range.len()],
+    );
+    Array2::from_shape_fn(
+        (part_uvws.dim().0, ant_pairs.len()),
+        |(timestep_idx, baseline_idx)| {
+            let (ant1, ant2) = ant_pairs[baseline_idx];
+            part_uvws[[timestep_idx, ant1]] - part_uvws[[timestep_idx, ant2]]
+        },
+    )
+}
+
+/// Calculate the phase centre of each timestep of the observation for a drift scan, where the
+/// phase centre stays at a fixed azimuth and elevation (`azel`) instead of a fixed RA/Dec.
+///
+/// Timesteps are grouped into averaging windows of `avg_time` timesteps from the start of
+/// `timestep_range`, and each timestep is given the J2000 RA/Dec at `azel` at the centroid of its
+/// window, so that each averaged timestep has a single phase centre. The result has a phase
+/// centre for every timestep in `corr_ctx.timesteps`, timesteps before `timestep_range` share the
+/// first window.
+pub fn drift_phase_centres(
+    corr_ctx: &CorrelatorContext,
+    timestep_range: &Range<usize>,
+    avg_time: usize,
+    array_pos: LatLngHeight,
+    azel: AzEl,
+) -> Vec<RADec> {
+    let int_time_s = corr_ctx.metafits_context.corr_int_time_ms as f64 / 1000.0;
+    let dut1 = Duration::from_seconds(corr_ctx.metafits_context.dut1.unwrap_or(0.0));
+    let start_s = corr_ctx.timesteps[timestep_range.start].gps_time_ms as f64 / 1000.0;
+    let avg_int_time_s = avg_time as f64 * int_time_s;
+    (0..corr_ctx.timesteps.len())
+        .map(|timestep_idx| {
+            let window_idx = timestep_idx.saturating_sub(timestep_range.start) / avg_time;
+            let centroid =
+                Epoch::from_gpst_seconds(start_s + (window_idx as f64 + 0.5) * avg_int_time_s);
+            // precession of the array, not of the phase centre, is needed here.
+            let prec = precess_time(
+                array_pos.longitude_rad,
+                array_pos.latitude_rad,
+                RADec::from_radians(0., array_pos.latitude_rad),
+                centroid,
+                dut1,
+            );
+            let radec = azel
+                .to_hadec(prec.array_latitude_j2000)
+                .to_radec(prec.lmst_j2000);
+            RADec::from_radians(radec.ra.rem_euclid(TAU), radec.dec)
+        })
+        .collect()
+}
+
+// Calculate partial uvw components for each antenna at the centroid of each selected timestep,
+// phased to the phase centre of that timestep.
+fn calc_sel_part_uvws(
+    corr_ctx: &CorrelatorContext,
+    vis_sel: &VisSelection,
+    array_pos: Option<LatLngHeight>,
+    phase_centres: &[RADec],
+) -> Array2<UVW> {
+    let array_pos = array_pos.unwrap_or_else(|| {
+        // The results here are slightly different to those given by cotter.
+        // This is at least partly due to different constants (the altitude is
+        // definitely slightly different), but possibly also because ERFA is
+        // more accurate than cotter's "homebrewed" Geodetic2XYZ.
+        LatLngHeight::mwa()
+    });
+    assert_eq!(
+        phase_centres.len(),
+        vis_sel.timestep_range.len(),
+        "expected a phase centre for each selected timestep"
+    );
+
+    let timesteps = &corr_ctx.timesteps[vis_sel.timestep_range.clone()];
+
+    let integration_time_s = corr_ctx.metafits_context.corr_int_time_ms as f64 / 1000.0;
+
+    let tiles_xyz_geod = XyzGeodetic::get_tiles(&corr_ctx.metafits_context, array_pos.latitude_rad);
+
+    let centroid_timestamps = timesteps
+        .iter()
+        .map(|t| Epoch::from_gpst_seconds(t.gps_time_ms as f64 / 1000.0 + integration_time_s / 2.0))
+        .collect::<Vec<_>>();
+    let dut1 = Duration::from_seconds(corr_ctx.metafits_context.dut1.unwrap_or(0.0));
+    calc_part_uvws(
+        &centroid_timestamps,
+        dut1,
+        phase_centres,
+        array_pos,
+        &tiles_xyz_geod,
+    )
+}
+
 // Calculate partial uvw components for each antenna and timestep.
 //
 // UVWs are in units of meters. To get the UVWs in units of wavelengths, divide by the wavelength.
@@ -653,13 +1118,13 @@ pub fn scrunch_gains(
 fn calc_part_uvws(
     centroid_timestamps: &[Epoch],
     dut1: Duration,
-    phase_centre: RADec,
+    phase_centres: &[RADec],
     array_pos: LatLngHeight,
     tile_xyzs: &[XyzGeodetic],
 ) -> Array2<UVW> {
     let nants = tile_xyzs.len();
     let mut part_uvws = Array2::from_elem((centroid_timestamps.len(), nants), UVW::default());
-    for (t, &epoch) in centroid_timestamps.iter().enumerate() {
+    for (t, (&epoch, &phase_centre)) in izip!(centroid_timestamps, phase_centres).enumerate() {
         let prec = precess_time(
             array_pos.longitude_rad,
             array_pos.latitude_rad,
@@ -682,7 +1147,8 @@ mod tests {
 
     use super::{
         _correct_digital_gains, correct_cable_lengths, correct_coarse_passband_gains,
-        correct_digital_gains, correct_geometry, scrunch_gains, VEL_C,
+        correct_digital_gains, correct_geometry, derive_passband_gains, integrate_gains,
+        normalise_by_autos, resample_gains, scrunch_gains, select_autos, VEL_C,
     };
     use float_cmp::assert_approx_eq;
     use itertools::izip;
@@ -1333,11 +1799,14 @@ mod tests {
         // ts 0, last chan, baseline 1 (0 vs 1)
         let jones_0_max_1 = jones_array[(0, max_chan, 1)];
 
-        let mut out_jones_array = jones_array.slice_mut(s![.., .., sel_baseline_range]);
+        let mut out_jones_array = jones_array.slice_mut(s![.., .., sel_baseline_range.clone()]);
+
+        let mut out_flag_array = flag_array.slice_mut(s![.., .., sel_baseline_range]);
 
         correct_digital_gains(
             &corr_ctx,
             out_jones_array.view_mut(),
+            out_flag_array.view_mut(),
             &vis_sel.coarse_chan_range,
             &ant_pairs,
         )
@@ -1361,12 +1830,62 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_correct_digital_gains_flags_bad_gains() {
+        let mut corr_ctx = get_mwax_context();
+        let vis_sel = VisSelection::from_mwalib(&corr_ctx).unwrap();
+        let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+        let ant_pairs = vis_sel.get_ant_pairs(&corr_ctx.metafits_context);
+        let first_coarse_chan_idx = vis_sel.coarse_chan_range.start;
+
+        // antenna 0 has a zero gain in the first coarse channel, antenna 1 a NaN in the last.
+        let last_coarse_chan_idx = vis_sel.coarse_chan_range.end - 1;
+        corr_ctx.metafits_context.antennas[0]
+            .rfinput_x
+            .digital_gains[first_coarse_chan_idx] = 0.0;
+        corr_ctx.metafits_context.antennas[1]
+            .rfinput_y
+            .digital_gains[last_coarse_chan_idx] = f64::NAN;
+
+        let shape = vis_sel.get_shape(fine_chans_per_coarse);
+        let mut jones_array = Array3::from_elem(shape, Jones::<f32>::identity());
+        let mut flag_array = Array3::from_elem(shape, false);
+
+        correct_digital_gains(
+            &corr_ctx,
+            jones_array.view_mut(),
+            flag_array.view_mut(),
+            &vis_sel.coarse_chan_range,
+            &ant_pairs,
+        )
+        .unwrap();
+
+        let last_chans = shape.1 - fine_chans_per_coarse..shape.1;
+        for (bl_idx, &(ant1, ant2)) in ant_pairs.iter().enumerate() {
+            for (chans, bad_ant) in [(0..fine_chans_per_coarse, 0), (last_chans.clone(), 1)] {
+                let flags = flag_array.slice(s![.., chans.clone(), bl_idx]);
+                let jones = jones_array.slice(s![.., chans, bl_idx]);
+                if ant1 == bad_ant || ant2 == bad_ant {
+                    assert!(flags.iter().all(|&f| f));
+                    // not corrected, so no infinities or NaNs
+                    assert!(jones.iter().all(|j| *j == Jones::identity()));
+                } else {
+                    assert!(!flags.iter().any(|&f| f));
+                }
+            }
+        }
+        assert!(jones_array
+            .iter()
+            .all(|j| j.iter().all(|pol| pol.is_finite())));
+    }
+
     #[test]
     fn test_correct_digital_gains_bad_array_shape() {
         let corr_ctx = get_mwa_ord_context();
         let mut vis_sel = VisSelection::from_mwalib(&corr_ctx).unwrap();
 
         let mut jones_array = Array3::from_shape_fn((1, 1, 1), |_| Jones::nan());
+        let mut flag_array = Array3::from_elem((1, 1, 1), false);
 
         let sel_baseline_range = 0..2;
         vis_sel.baseline_idxs = sel_baseline_range.collect();
@@ -1377,6 +1896,7 @@ mod tests {
             correct_digital_gains(
                 &corr_ctx,
                 jones_array.view_mut(),
+                flag_array.view_mut(),
                 &vis_sel.coarse_chan_range,
                 &ant_pairs,
             ),
@@ -1387,11 +1907,13 @@ mod tests {
             * corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
 
         let mut jones_array = Array3::from_shape_fn((1, channels, 1), |_| Jones::nan());
+        let mut flag_array = Array3::from_elem((1, channels, 1), false);
 
         assert!(matches!(
             correct_digital_gains(
                 &corr_ctx,
                 jones_array.view_mut(),
+                flag_array.view_mut(),
                 &vis_sel.coarse_chan_range,
                 &ant_pairs,
             ),
@@ -1401,11 +1923,88 @@ mod tests {
         let gains = Array2::from_shape_fn((1, 1), |_| (1., 1.));
 
         assert!(matches!(
-            _correct_digital_gains(jones_array.view_mut(), gains.view(), &ant_pairs, 2),
+            _correct_digital_gains(
+                jones_array.view_mut(),
+                flag_array.view_mut(),
+                gains.view(),
+                &ant_pairs,
+                2
+            ),
             Err(DigitalGainCorrection::BadArrayShape { .. })
         ));
     }
 
+    #[test]
+    fn test_normalise_by_autos() {
+        let ant_pairs = vec![(0, 0), (0, 1), (1, 1)];
+        let mut jones_array: Array3<Jones<f32>> = Array3::from_elem((2, 1, 3), Jones::identity());
+        let mut flag_array = Array3::from_elem((2, 1, 3), false);
+        for timestep_idx in 0..2 {
+            jones_array[(timestep_idx, 0, 0)] = Jones::from([
+                Complex::new(4.0, 0.0),
+                Complex::new(1.0, 1.0),
+                Complex::new(1.0, -1.0),
+                Complex::new(9.0, 0.0),
+            ]);
+            jones_array[(timestep_idx, 0, 1)] = Jones::from([
+                Complex::new(8.0, 8.0),
+                Complex::new(2.0, 0.0),
+                Complex::new(0.0, 12.0),
+                Complex::new(3.0, -3.0),
+            ]);
+            jones_array[(timestep_idx, 0, 2)] = Jones::from([
+                Complex::new(16.0, 0.0),
+                Complex::new(0.0, 0.0),
+                Complex::new(0.0, 0.0),
+                Complex::new(1.0, 0.0),
+            ]);
+        }
+        // a bad autocorrelation for antenna 1 in the second timestep
+        jones_array[(1, 0, 2)][3] = Complex::new(0.0, 0.0);
+
+        let (auto_array, auto_ants) = select_autos(jones_array.view(), &ant_pairs).unwrap();
+        assert_eq!(auto_ants, vec![0, 1]);
+        let count = normalise_by_autos(
+            jones_array.view_mut(),
+            flag_array.view_mut(),
+            &ant_pairs,
+            auto_array.view(),
+            &auto_ants,
+        )
+        .unwrap();
+
+        // each polarisation is divided by sqrt(4 * 16), sqrt(4 * 1), sqrt(9 * 16), sqrt(9 * 1)
+        compare_jones!(
+            jones_array[(0, 0, 1)],
+            Jones::from([
+                Complex::new(1.0, 1.0),
+                Complex::new(1.0, 0.0),
+                Complex::new(0.0, 1.0),
+                Complex::new(1.0, -1.0),
+            ])
+        );
+        // autocorrelations are normalised too
+        assert_abs_diff_eq!(jones_array[(0, 0, 0)][0].re, 1.0);
+        assert_abs_diff_eq!(jones_array[(0, 0, 0)][3].re, 1.0);
+        assert_abs_diff_eq!(jones_array[(0, 0, 2)][3].re, 1.0);
+        assert_eq!(count, 2);
+        assert_eq!(
+            flag_array.iter().copied().collect::<Vec<_>>(),
+            vec![false, false, false, false, true, true]
+        );
+
+        // antennas without autocorrelations
+        assert!(select_autos(jones_array.view(), &ant_pairs[1..]).is_none());
+        assert!(normalise_by_autos(
+            jones_array.view_mut(),
+            flag_array.view_mut(),
+            &ant_pairs,
+            auto_array.slice(s![.., .., ..1]),
+            &auto_ants[..1],
+        )
+        .is_err());
+    }
+
     #[test]
     fn test_scrunch_gains_legacy() {
         let base: i32 = 2;
@@ -1493,6 +2092,59 @@ mod tests {
         assert_eq!(scrunched_gains, expected_gains);
     }
 
+    /// Integrating places the scrunched channels the same way as scrunching by a whole number.
+    #[test]
+    fn test_integrate_gains_matches_scrunch_gains() {
+        let ultrafine_gains: Vec<f64> = (0..60).map(|x| (x as f64 * 0.3).sin() + 2.).collect();
+        for scrunch_type in [ScrunchType::Simple, ScrunchType::CenterSymmetric] {
+            for num_scrunched_chans in [6, 10, 12, 15, 20, 30] {
+                let scrunched_gains = scrunch_gains(
+                    &ultrafine_gains,
+                    ultrafine_gains.len() / num_scrunched_chans,
+                    &scrunch_type,
+                );
+                let resampled_gains =
+                    integrate_gains(&ultrafine_gains, num_scrunched_chans, &scrunch_type);
+                for (&scrunched, &resampled) in izip!(&scrunched_gains, &resampled_gains) {
+                    assert_abs_diff_eq!(scrunched, resampled, epsilon = 1e-12);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_resample_gains_non_integer() {
+        let ultrafine_gains = [1., 2., 3., 4.];
+        let scrunched_gains = resample_gains(&ultrafine_gains, 3, &ScrunchType::Simple);
+        for (&gain, expected) in izip!(&scrunched_gains, [1.25, 2.5, 3.75]) {
+            assert_abs_diff_eq!(gain, expected, epsilon = 1e-12);
+        }
+
+        // an odd number of channels starts at the centre of ultrafine channel zero, and the last
+        // channel wraps around the edge of the coarse channel
+        let scrunched_gains = resample_gains(&ultrafine_gains, 3, &ScrunchType::CenterSymmetric);
+        for (&gain, expected) in izip!(
+            &scrunched_gains,
+            [
+                (1. / 2. * 1. + 5. / 6. * 2.) / (4. / 3.),
+                (1. / 6. * 2. + 1. * 3. + 1. / 6. * 4.) / (4. / 3.),
+                (5. / 6. * 4. + 1. / 2. * 1.) / (4. / 3.),
+            ]
+        ) {
+            assert_abs_diff_eq!(gain, expected, epsilon = 1e-12);
+        }
+
+        // fewer ultrafine gains than scrunched channels
+        let scrunched_gains = resample_gains(&ultrafine_gains[..2], 4, &ScrunchType::Simple);
+        assert_eq!(scrunched_gains, vec![1., 1., 2., 2.]);
+
+        // a whole number of gains per channel is the same as scrunching
+        assert_eq!(
+            resample_gains(&ultrafine_gains, 2, &ScrunchType::Simple),
+            scrunch_gains(&ultrafine_gains, 2, &ScrunchType::Simple)
+        );
+    }
+
     #[test]
     fn test_correct_coarse_passband_gains() {
         let num_fine_chans_per_coarse = 2;
@@ -1525,6 +2177,42 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_derive_passband_gains() {
+        let num_fine_chans_per_coarse = 4;
+        let shape = [0.5_f32, 1., 1., 0.5];
+        // 2 timesteps, 2 coarse channels, 3 antennas, with different power levels
+        let mut auto_array = Array3::from_shape_fn((2, 8, 3), |(timestep, chan, ant)| {
+            let power = shape[chan % num_fine_chans_per_coarse]
+                * (1 + timestep + chan / num_fine_chans_per_coarse + ant) as f32;
+            Jones::from([power, 0., 0., 0., 0., 0., 2. * power, 0.])
+        });
+        // bad spectra are skipped
+        auto_array[(0, 1, 2)] = Jones::nan();
+        auto_array[(1, 6, 0)] = Jones::default();
+
+        let gains = derive_passband_gains(auto_array.view(), num_fine_chans_per_coarse).unwrap();
+
+        assert_eq!(gains.len(), num_fine_chans_per_coarse);
+        for (&gain, &expected) in izip!(gains.iter(), shape.iter()) {
+            assert_abs_diff_eq!(gain, (expected / 0.75) as f64, epsilon = 1e-6);
+        }
+
+        assert!(matches!(
+            derive_passband_gains(auto_array.view(), 3),
+            Err(PassbandCorrection::BadArrayShape(_))
+        ));
+        assert!(matches!(
+            derive_passband_gains(auto_array.view(), 0),
+            Err(PassbandCorrection::BadArrayShape(_))
+        ));
+        auto_array.fill(Jones::default());
+        assert!(matches!(
+            derive_passband_gains(auto_array.view(), num_fine_chans_per_coarse),
+            Err(PassbandCorrection::NoAutocorrelations)
+        ));
+    }
+
     #[test]
     fn test_correct_coarse_passband_gains_good_fscrunch() {
         let num_fine_chans_per_coarse = 2;
@@ -1634,17 +2322,27 @@ mod tests {
             Err(PassbandCorrection::BadArrayShape { .. })
         ));
 
-        // test bad gain shape
-        let bad_passband_gains = vec![0.1, 0.2, 0.3];
+        // test empty gains
         assert!(matches!(
             correct_coarse_passband_gains(
                 jones_array.view_mut(),
                 weight_array.view_mut(),
-                &bad_passband_gains,
+                &[],
                 num_fine_chans_per_coarse,
                 &ScrunchType::Simple,
             ),
             Err(PassbandCorrection::BadArrayShape { .. })
         ));
+
+        // gains which aren't a multiple of the fine channels are resampled
+        let uneven_passband_gains = vec![0.1, 0.2, 0.3];
+        assert!(correct_coarse_passband_gains(
+            jones_array.view_mut(),
+            weight_array.view_mut(),
+            &uneven_passband_gains,
+            num_fine_chans_per_coarse,
+            &ScrunchType::Simple,
+        )
+        .is_ok());
     }
 }
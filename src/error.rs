@@ -43,6 +43,10 @@ pub enum BirliError {
     /// Error derived from [`crate::calibration::CalibrationError`]
     CalibrationError(#[from] crate::calibration::CalibrationError),
 
+    #[error(transparent)]
+    /// Error derived from [`crate::sky_model::SkyModelError`]
+    SkyModelError(#[from] crate::sky_model::SkyModelError),
+
     #[cfg(feature = "cli")]
     #[error(transparent)]
     /// Error derived from [`QuoteError`]
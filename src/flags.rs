@@ -1,19 +1,22 @@
 //! Methods for manipulating flagmasks and flagging imagesets
 
-use std::ops::Range;
+use std::{f64::consts::TAU, fmt::Display, ops::Range};
 
 use crate::{
     io::error::IOError,
     marlu::{
+        hifitime::{Duration, Epoch},
         mwalib::{CorrelatorContext, MWAVersion},
-        ndarray::prelude::*,
+        ndarray::{prelude::*, Zip},
+        precession::{get_lmst, precess_time},
+        LatLngHeight, RADec,
     },
     BirliError, FlagFileSet,
 };
 use cfg_if::cfg_if;
 use derive_builder::Builder;
-use itertools::izip;
-use log::trace;
+use itertools::{izip, Itertools};
+use log::{info, trace};
 use marlu::{io::error::BadArrayShape, VisSelection};
 
 cfg_if! {
@@ -25,6 +28,204 @@ cfg_if! {
     }
 }
 
+/// The reasons a visibility can be flagged, used as bits in a flag reason array.
+///
+/// A flag reason array is a `[timestep][channel][baseline]` array of `u16` kept alongside the
+/// boolean flag array. Each stage which flags a visibility sets its bit, so a visibility may have
+/// several reasons.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum FlagReason {
+    /// The timestep is flagged in the [`FlagContext`], e.g. quack time or not provided.
+    Timestep = 1 << 0,
+    /// The coarse or fine channel is flagged in the [`FlagContext`], e.g. edge or DC channels.
+    Channel = 1 << 1,
+    /// An antenna in the baseline is flagged in the [`FlagContext`], or it is a flagged
+    /// autocorrelation.
+    Antenna = 1 << 2,
+    /// The HDU for this timestep and coarse channel was not provided in the gpubox files, or
+    /// could not be read.
+    MissingHdu = 1 << 3,
+    /// Flagged by `AOFlagger`.
+    AOFlagger = 1 << 4,
+    /// The calibration solution for this visibility contains a `NaN`.
+    Calibration = 1 << 5,
+    /// The timestep, channel or antenna was mostly flagged, and exceeded a [`FlagThresholds`].
+    Occupancy = 1 << 6,
+    /// Flagged by Sky-Subtracted Incoherent Noise Spectra (see [`crate::ssins`]).
+    Ssins = 1 << 7,
+    /// The visibility was zero in every polarisation, or not finite in any, when it was read.
+    BadValue = 1 << 8,
+    /// The digital gain of an antenna in the baseline was zero, negative or not finite.
+    DigitalGains = 1 << 9,
+    /// The MWAX weights HDU recorded too little data received for this baseline and timestep.
+    PacketLoss = 1 << 10,
+    /// The autocorrelation of an antenna in the baseline, needed to normalise the visibility or
+    /// for its radiometer weight, was not positive and finite.
+    BadAutos = 1 << 11,
+}
+
+impl FlagReason {
+    /// All the flag reasons, in the order of their bits.
+    pub const ALL: [Self; 12] = [
+        Self::Timestep,
+        Self::Channel,
+        Self::Antenna,
+        Self::MissingHdu,
+        Self::AOFlagger,
+        Self::Calibration,
+        Self::Occupancy,
+        Self::Ssins,
+        Self::BadValue,
+        Self::DigitalGains,
+        Self::PacketLoss,
+        Self::BadAutos,
+    ];
+
+    /// The bit for this reason in a flag reason array.
+    pub const fn bit(self) -> u16 {
+        self as u16
+    }
+
+    /// Whether the bit for this reason is set in `reasons`.
+    pub const fn is_set(self, reasons: u16) -> bool {
+        reasons & self.bit() != 0
+    }
+
+    /// A short name for this flag reason, used in table headers.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Timestep => "timestep",
+            Self::Channel => "channel",
+            Self::Antenna => "antenna",
+            Self::MissingHdu => "missing_hdu",
+            Self::AOFlagger => "aoflagger",
+            Self::Calibration => "calibration",
+            Self::Occupancy => "occupancy",
+            Self::Ssins => "ssins",
+            Self::BadValue => "bad_value",
+            Self::DigitalGains => "digital_gains",
+            Self::PacketLoss => "packet_loss",
+            Self::BadAutos => "bad_autos",
+        }
+    }
+}
+
+/// Set the bit for `reason` in `flag_reason_array` wherever a flag is set in `flag_array`, but
+/// not in `prev_flag_array`.
+///
+/// This is used to record the reason for flags raised by a stage of processing, given a copy of
+/// the flags from before that stage.
+///
+/// # Errors
+///
+/// Can throw error if the arrays are not all the same shape.
+pub fn set_new_flag_reasons(
+    mut flag_reason_array: ArrayViewMut3<u16>,
+    prev_flag_array: ArrayView3<bool>,
+    flag_array: ArrayView3<bool>,
+    reason: FlagReason,
+) -> Result<(), BirliError> {
+    let shape = flag_array.dim();
+    for (argument, received) in [
+        ("flag_reason_array", flag_reason_array.dim()),
+        ("prev_flag_array", prev_flag_array.dim()),
+    ] {
+        if received != shape {
+            return Err(BirliError::BadArrayShape(BadArrayShape {
+                argument,
+                function: "set_new_flag_reasons",
+                expected: format!("{shape:?}"),
+                received: format!("{received:?}"),
+            }));
+        }
+    }
+    Zip::from(&mut flag_reason_array)
+        .and(&prev_flag_array)
+        .and(&flag_array)
+        .for_each(|reasons, &prev_flag, &flag| {
+            if flag && !prev_flag {
+                *reasons |= reason.bit();
+            }
+        });
+    Ok(())
+}
+
+/// Flag visibilities which are zero in every polarisation, or not finite in any polarisation,
+/// e.g. from correlator dropouts. Non-finite visibilities are also set to zero, so that they
+/// can't contaminate corrections or averaging.
+///
+/// Returns the number of newly flagged visibilities in each coarse channel.
+///
+/// # Errors
+///
+/// Can throw error if the arrays are not the same shape, or the number of channels is not a
+/// multiple of `fine_chans_per_coarse`.
+pub fn flag_bad_values(
+    mut jones_array: ArrayViewMut3<marlu::Jones<f32>>,
+    mut flag_array: ArrayViewMut3<bool>,
+    fine_chans_per_coarse: usize,
+) -> Result<Vec<usize>, BirliError> {
+    let shape = jones_array.dim();
+    if flag_array.dim() != shape {
+        return Err(BirliError::BadArrayShape(BadArrayShape {
+            argument: "flag_array",
+            function: "flag_bad_values",
+            expected: format!("{shape:?}"),
+            received: format!("{:?}", flag_array.dim()),
+        }));
+    }
+    if fine_chans_per_coarse == 0 || shape.1 % fine_chans_per_coarse != 0 {
+        return Err(BirliError::BadArrayShape(BadArrayShape {
+            argument: "fine_chans_per_coarse",
+            function: "flag_bad_values",
+            expected: format!("a factor of {}", shape.1),
+            received: format!("{fine_chans_per_coarse}"),
+        }));
+    }
+    let counts = izip!(
+        jones_array.axis_chunks_iter_mut(Axis(1), fine_chans_per_coarse),
+        flag_array.axis_chunks_iter_mut(Axis(1), fine_chans_per_coarse),
+    )
+    .map(|(mut jones_array, mut flag_array)| {
+        let mut count = 0;
+        Zip::from(&mut jones_array)
+            .and(&mut flag_array)
+            .for_each(|jones, flag| {
+                let finite = jones.iter().all(|pol| pol.is_finite());
+                if !finite {
+                    *jones = marlu::Jones::default();
+                }
+                if (!finite || jones.iter().all(|pol| pol.re == 0.0 && pol.im == 0.0)) && !*flag {
+                    *flag = true;
+                    count += 1;
+                }
+            });
+        count
+    })
+    .collect();
+    Ok(counts)
+}
+
+/// The apparent position of the Sun at `epoch`, from the low precision formulae in the
+/// Astronomical Almanac, which are accurate to about 0.01 degrees between 1950 and 2050.
+fn sun_radec(epoch: Epoch) -> RADec {
+    // days since J2000.0
+    let n = epoch.to_jde_utc_days() - 2_451_545.0;
+    let mean_longitude = (280.460 + 0.985_647_4 * n).to_radians();
+    let mean_anomaly = (357.528 + 0.985_600_3 * n).to_radians();
+    let ecliptic_longitude = mean_longitude
+        + 1.915_f64.to_radians() * mean_anomaly.sin()
+        + 0.020_f64.to_radians() * (2.0 * mean_anomaly).sin();
+    let obliquity = (23.439 - 0.000_000_4 * n).to_radians();
+    RADec::from_radians(
+        (obliquity.cos() * ecliptic_longitude.sin())
+            .atan2(ecliptic_longitude.cos())
+            .rem_euclid(TAU),
+        (obliquity.sin() * ecliptic_longitude.sin()).asin(),
+    )
+}
+
 /// Which timesteps, channels and baselines are flagged in a given observation
 #[derive(Builder, Debug, Default)]
 pub struct FlagContext {
@@ -162,6 +363,71 @@ impl FlagContext {
         }
     }
 
+    /// Flag timesteps where the elevation of `phase_centre` is below `min_elevation_deg`.
+    ///
+    /// `centroid_timestamps` are the centroids of each timestep in `timestep_flags`. Returns the
+    /// number of timesteps which were newly flagged.
+    pub fn flag_min_elevation(
+        &mut self,
+        centroid_timestamps: &[Epoch],
+        dut1: Duration,
+        array_pos: LatLngHeight,
+        phase_centre: RADec,
+        min_elevation_deg: f64,
+    ) -> usize {
+        self.flag_timesteps_where(centroid_timestamps, |&epoch| {
+            let prec_info = precess_time(
+                array_pos.longitude_rad,
+                array_pos.latitude_rad,
+                phase_centre,
+                epoch,
+                dut1,
+            );
+            let elevation = prec_info
+                .hadec_j2000
+                .to_azel(prec_info.array_latitude_j2000)
+                .el;
+            elevation.to_degrees() < min_elevation_deg
+        })
+    }
+
+    /// Flag timesteps where the elevation of the Sun is above `max_elevation_deg`.
+    ///
+    /// `centroid_timestamps` are the centroids of each timestep in `timestep_flags`. Returns the
+    /// number of timesteps which were newly flagged.
+    pub fn flag_sun_above(
+        &mut self,
+        centroid_timestamps: &[Epoch],
+        dut1: Duration,
+        array_pos: LatLngHeight,
+        max_elevation_deg: f64,
+    ) -> usize {
+        self.flag_timesteps_where(centroid_timestamps, |&epoch| {
+            let lmst = get_lmst(array_pos.longitude_rad, epoch, dut1);
+            let elevation = sun_radec(epoch)
+                .to_hadec(lmst)
+                .to_azel(array_pos.latitude_rad)
+                .el;
+            elevation.to_degrees() > max_elevation_deg
+        })
+    }
+
+    /// Flag each timestep where `predicate` is true of its centroid, returning the number of
+    /// timesteps which were newly flagged.
+    fn flag_timesteps_where<F>(&mut self, centroid_timestamps: &[Epoch], predicate: F) -> usize
+    where
+        F: Fn(&Epoch) -> bool,
+    {
+        let mut count = 0;
+        for (flag, epoch) in izip!(self.timestep_flags.iter_mut(), centroid_timestamps) {
+            if !*flag && predicate(epoch) {
+                *flag = true;
+                count += 1;
+            }
+        }
+        count
+    }
+
     /// Set flags from this context in an existing array.
     ///
     /// # Errors
@@ -175,15 +441,83 @@ impl FlagContext {
         ant_pairs: &[(usize, usize)],
     ) -> Result<(), BirliError> {
         let timestep_flags = &self.timestep_flags[timestep_range.clone()];
-        let coarse_chan_flags = &self.coarse_chan_flags[coarse_chan_range.clone()];
+        let chan_flags = self.get_chan_flags(coarse_chan_range);
+        let baseline_flags = self.get_baseline_flags(ant_pairs);
+        let shape = (timestep_range.len(), chan_flags.len(), ant_pairs.len());
+
+        let flag_shape = flag_array.dim();
+        if flag_shape.0 > shape.0 || flag_shape.1 > shape.1 || flag_shape.2 > shape.2 {
+            return Err(BirliError::BadArrayShape(BadArrayShape {
+                argument: "flag_array",
+                function: "FlagContext::set_flags",
+                expected: format!("dims less than {shape:?}"),
+                received: format!("{flag_shape:?}"),
+            }));
+        };
+
+        flag_array
+            .indexed_iter_mut()
+            .for_each(|((ts_idx, ch_idx, bl_idx), flag)| {
+                *flag = timestep_flags[ts_idx] || chan_flags[ch_idx] || baseline_flags[bl_idx];
+            });
+
+        Ok(())
+    }
+
+    /// Set the reasons for the flags from this context in an existing flag reason array, as
+    /// [`FlagReason::Timestep`], [`FlagReason::Channel`] and [`FlagReason::Antenna`] bits.
+    ///
+    /// Like [`FlagContext::set_flags`], this overwrites any existing values in the array.
+    ///
+    /// # Errors
+    ///
+    /// Can throw error if array is not the correct shape.
+    pub fn set_flag_reasons(
+        &self,
+        mut flag_reason_array: ArrayViewMut3<u16>,
+        timestep_range: &Range<usize>,
+        coarse_chan_range: &Range<usize>,
+        ant_pairs: &[(usize, usize)],
+    ) -> Result<(), BirliError> {
+        let timestep_flags = &self.timestep_flags[timestep_range.clone()];
+        let chan_flags = self.get_chan_flags(coarse_chan_range);
         let baseline_flags = self.get_baseline_flags(ant_pairs);
+        let shape = (timestep_range.len(), chan_flags.len(), ant_pairs.len());
+
+        let reason_shape = flag_reason_array.dim();
+        if reason_shape.0 > shape.0 || reason_shape.1 > shape.1 || reason_shape.2 > shape.2 {
+            return Err(BirliError::BadArrayShape(BadArrayShape {
+                argument: "flag_reason_array",
+                function: "FlagContext::set_flag_reasons",
+                expected: format!("dims less than {shape:?}"),
+                received: format!("{reason_shape:?}"),
+            }));
+        };
+
+        flag_reason_array
+            .indexed_iter_mut()
+            .for_each(|((ts_idx, ch_idx, bl_idx), reasons)| {
+                *reasons = [
+                    (timestep_flags[ts_idx], FlagReason::Timestep),
+                    (chan_flags[ch_idx], FlagReason::Channel),
+                    (baseline_flags[bl_idx], FlagReason::Antenna),
+                ]
+                .iter()
+                .filter(|(flag, _)| *flag)
+                .fold(0, |reasons, (_, reason)| reasons | reason.bit());
+            });
+
+        Ok(())
+    }
 
+    /// Produce a vector of flags for every fine channel in the given coarse channel range.
+    fn get_chan_flags(&self, coarse_chan_range: &Range<usize>) -> Vec<bool> {
         let fine_chan_count = self.fine_chan_flags.len();
         let mut fine_chan_flags = self.fine_chan_flags.clone();
         if self.flag_dc {
             fine_chan_flags[fine_chan_count / 2] = true;
         }
-        let chan_flags: Vec<_> = coarse_chan_flags
+        self.coarse_chan_flags[coarse_chan_range.clone()]
             .iter()
             .flat_map(|coarse_chan_flag| {
                 if *coarse_chan_flag {
@@ -192,26 +526,206 @@ impl FlagContext {
                     fine_chan_flags.clone()
                 }
             })
-            .collect();
-        let shape = (timestep_range.len(), chan_flags.len(), ant_pairs.len());
+            .collect()
+    }
+}
 
-        let flag_shape = flag_array.dim();
-        if flag_shape.0 > shape.0 || flag_shape.1 > shape.1 || flag_shape.2 > shape.2 {
+/// Thresholds on the fraction of flagged visibilities, above which a whole timestep, channel or
+/// antenna is flagged.
+///
+/// These are applied after RFI flagging, so that the remaining visibilities of a timestep, channel
+/// or tile which is mostly flagged are not let through. Each threshold is a fraction between 0
+/// and 1, and the occupancy includes flags from all earlier stages, but not the timesteps,
+/// channels and antennas which were already entirely flagged (e.g. flagged tiles and edge
+/// channels).
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct FlagThresholds {
+    /// Flag a timestep if more than this fraction of its channels and baselines are flagged
+    pub timestep: Option<f32>,
+    /// Flag a fine channel if more than this fraction of its timesteps and baselines are flagged
+    pub chan: Option<f32>,
+    /// Flag an antenna if more than this fraction of the visibilities on its baselines are
+    /// flagged
+    pub antenna: Option<f32>,
+}
+
+/// The timesteps, channels and antennas which were flagged by [`FlagThresholds::extend_flags`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ExtendedFlags {
+    /// The mwalib timestep indices which were flagged
+    pub timestep_idxs: Vec<usize>,
+    /// The fine channel indices which were flagged, counted from the first fine channel of the
+    /// first correlator coarse channel
+    pub chan_idxs: Vec<usize>,
+    /// The mwalib antenna indices which were flagged
+    pub antenna_idxs: Vec<usize>,
+}
+
+impl Display for FlagThresholds {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (threshold, axis) in [
+            (self.timestep, "timesteps"),
+            (self.chan, "channels"),
+            (self.antenna, "antennas"),
+        ] {
+            if let Some(threshold) = threshold {
+                writeln!(
+                    f,
+                    "Will flag {axis} with more than {}% flagged.",
+                    threshold * 100.
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FlagThresholds {
+    /// Whether no thresholds are set.
+    pub const fn is_empty(&self) -> bool {
+        self.timestep.is_none() && self.chan.is_none() && self.antenna.is_none()
+    }
+
+    /// Flag every timestep, channel and antenna in `flag_array` whose occupancy exceeds its
+    /// threshold, logging what was flagged.
+    ///
+    /// The occupancy of each timestep, channel and antenna is measured from the flags as they
+    /// were before any of the thresholds are applied, so the order of the rules doesn't matter.
+    /// Timesteps, channels and antennas which were already entirely flagged are not reported,
+    /// and are left out of the occupancy of everything else.
+    /// `timestep_range` is the mwalib timestep indices of the chunk, and `first_chan_idx` is the
+    /// index of its first fine channel, counted from the first fine channel of the first
+    /// correlator coarse channel. These are only used to report what was flagged.
+    ///
+    /// # Errors
+    ///
+    /// Can throw error if the baseline dimension of `flag_array` doesn't match `ant_pairs`.
+    pub fn extend_flags(
+        &self,
+        mut flag_array: ArrayViewMut3<bool>,
+        timestep_range: &Range<usize>,
+        first_chan_idx: usize,
+        ant_pairs: &[(usize, usize)],
+    ) -> Result<ExtendedFlags, BirliError> {
+        let (num_timesteps, num_chans, num_baselines) = flag_array.dim();
+        if num_baselines != ant_pairs.len() {
             return Err(BirliError::BadArrayShape(BadArrayShape {
                 argument: "flag_array",
-                function: "FlagContext::set_flags",
-                expected: format!("dims less than {shape:?}"),
-                received: format!("{flag_shape:?}"),
+                function: "FlagThresholds::extend_flags",
+                expected: format!("(_, _, ant_pairs.len()={})", ant_pairs.len()),
+                received: format!("{:?}", flag_array.dim()),
             }));
+        }
+        let num_ants = ant_pairs
+            .iter()
+            .map(|&(ant1, ant2)| ant1.max(ant2) + 1)
+            .max()
+            .unwrap_or(0);
+
+        // find the timesteps, channels and antennas which are already entirely flagged.
+        let timestep_done = flag_array
+            .axis_iter(Axis(0))
+            .map(|flags| flags.iter().all(|&flag| flag))
+            .collect_vec();
+        let chan_done = flag_array
+            .axis_iter(Axis(1))
+            .map(|flags| flags.iter().all(|&flag| flag))
+            .collect_vec();
+        let mut antenna_done = vec![true; num_ants];
+        for (flags, &(ant1, ant2)) in flag_array.axis_iter(Axis(2)).zip(ant_pairs) {
+            if !flags.iter().all(|&flag| flag) {
+                antenna_done[ant1] = false;
+                antenna_done[ant2] = false;
+            }
+        }
+
+        // count the flagged visibilities of each timestep, channel and antenna, out of those
+        // which are not in an entirely flagged timestep, channel or antenna.
+        let mut timestep_counts = vec![0_usize; num_timesteps];
+        let mut timestep_totals = vec![0_usize; num_timesteps];
+        let mut chan_counts = vec![0_usize; num_chans];
+        let mut chan_totals = vec![0_usize; num_chans];
+        let mut antenna_counts = vec![0_usize; num_ants];
+        let mut antenna_totals = vec![0_usize; num_ants];
+        for ((timestep_idx, chan_idx, baseline_idx), &flag) in flag_array.indexed_iter() {
+            if timestep_done[timestep_idx] || chan_done[chan_idx] {
+                continue;
+            }
+            let (ant1, ant2) = ant_pairs[baseline_idx];
+            if !antenna_done[ant1] && !antenna_done[ant2] {
+                timestep_totals[timestep_idx] += 1;
+                chan_totals[chan_idx] += 1;
+                if flag {
+                    timestep_counts[timestep_idx] += 1;
+                    chan_counts[chan_idx] += 1;
+                }
+            }
+            // each antenna's occupancy is over its baselines to antennas which aren't done.
+            if ant1 == ant2 || !antenna_done[ant2] {
+                antenna_totals[ant1] += 1;
+                antenna_counts[ant1] += usize::from(flag);
+            }
+            if ant1 != ant2 && !antenna_done[ant1] {
+                antenna_totals[ant2] += 1;
+                antenna_counts[ant2] += usize::from(flag);
+            }
+        }
+
+        let exceeds = |threshold: Option<f32>, counts: &[usize], totals: &[usize]| -> Vec<usize> {
+            threshold.map_or_else(Vec::new, |threshold| {
+                izip!(counts, totals)
+                    .enumerate()
+                    // skip anything which is already entirely flagged.
+                    .filter(|(_, (&count, &total))| {
+                        count < total && count as f32 / total as f32 > threshold
+                    })
+                    .map(|(idx, _)| idx)
+                    .collect()
+            })
         };
+        let flag_timestep_idxs = exceeds(self.timestep, &timestep_counts, &timestep_totals);
+        let flag_chan_idxs = exceeds(self.chan, &chan_counts, &chan_totals);
+        let flag_antenna_idxs = exceeds(self.antenna, &antenna_counts, &antenna_totals);
 
-        flag_array
-            .indexed_iter_mut()
-            .for_each(|((ts_idx, ch_idx, bl_idx), flag)| {
-                *flag = timestep_flags[ts_idx] || chan_flags[ch_idx] || baseline_flags[bl_idx];
-            });
+        for &timestep_idx in &flag_timestep_idxs {
+            flag_array.index_axis_mut(Axis(0), timestep_idx).fill(true);
+        }
+        for &chan_idx in &flag_chan_idxs {
+            flag_array.index_axis_mut(Axis(1), chan_idx).fill(true);
+        }
+        for (baseline_idx, &(ant1, ant2)) in ant_pairs.iter().enumerate() {
+            if flag_antenna_idxs.contains(&ant1) || flag_antenna_idxs.contains(&ant2) {
+                flag_array.index_axis_mut(Axis(2), baseline_idx).fill(true);
+            }
+        }
 
-        Ok(())
+        let result = ExtendedFlags {
+            timestep_idxs: flag_timestep_idxs
+                .iter()
+                .map(|&timestep_idx| timestep_range.start + timestep_idx)
+                .collect(),
+            chan_idxs: flag_chan_idxs
+                .iter()
+                .map(|&chan_idx| first_chan_idx + chan_idx)
+                .collect(),
+            antenna_idxs: flag_antenna_idxs,
+        };
+        for (threshold, axis, idxs) in [
+            (self.timestep, "timesteps", &result.timestep_idxs),
+            (self.chan, "channels", &result.chan_idxs),
+            (self.antenna, "antennas", &result.antenna_idxs),
+        ] {
+            if let (Some(threshold), false) = (threshold, idxs.is_empty()) {
+                info!(
+                    "flagged {} {} above occupancy threshold {}%: {:?}",
+                    idxs.len(),
+                    axis,
+                    threshold * 100.,
+                    idxs
+                );
+            }
+        }
+        Ok(result)
     }
 }
 
@@ -554,8 +1068,13 @@ pub fn write_flags(
 
 #[cfg(test)]
 mod tests {
-    use super::write_flags;
+    use super::{
+        apply_radiometer_weights, flag_bad_values, set_new_flag_reasons, sun_radec, write_flags,
+        ExtendedFlags, FlagReason, FlagThresholds,
+    };
+    use approx::assert_abs_diff_eq;
     use glob::glob;
+    use ndarray::s;
     use std::ffi::c_char;
     use tempfile::tempdir;
 
@@ -566,8 +1085,217 @@ mod tests {
             get_mwa_ord_dodgy_context, get_mwa_ord_no_overlap_context,
             get_mwa_ord_no_timesteps_context,
         },
-        FlagFileSet, VisSelection,
+        Array3, Complex, FlagContext, FlagFileSet, Jones, VisSelection,
     };
+    use marlu::{
+        hifitime::{Duration, Epoch},
+        precession::get_lmst,
+        LatLngHeight, RADec,
+    };
+
+    #[test]
+    fn test_flag_bad_values() {
+        let mut jones_array = Array3::from_elem((2, 4, 3), Jones::identity());
+        let mut flag_array = Array3::from_elem((2, 4, 3), false);
+        // a correlator dropout in coarse channel 0
+        jones_array[(0, 1, 0)] = Jones::default();
+        // a single zero polarisation is fine
+        jones_array[(0, 1, 1)][1] = Complex::new(0.0, 0.0);
+        // non-finite values in coarse channel 1
+        jones_array[(1, 2, 2)][3] = Complex::new(f32::NAN, 0.0);
+        jones_array[(1, 3, 0)][0] = Complex::new(0.0, f32::INFINITY);
+        // already flagged values aren't counted, but are still zeroed.
+        jones_array[(0, 3, 1)][2] = Complex::new(f32::NEG_INFINITY, 0.0);
+        flag_array[(0, 3, 1)] = true;
+
+        let counts = flag_bad_values(jones_array.view_mut(), flag_array.view_mut(), 2).unwrap();
+
+        assert_eq!(counts, vec![1, 2]);
+        for idx in [(0, 1, 0), (1, 2, 2), (1, 3, 0), (0, 3, 1)] {
+            assert!(flag_array[idx]);
+            assert_eq!(jones_array[idx], Jones::default());
+        }
+        assert_eq!(flag_array.iter().filter(|&&f| f).count(), 4);
+        assert!(jones_array
+            .iter()
+            .all(|jones| jones.iter().all(|pol| pol.is_finite())));
+
+        // the number of channels must be a multiple of the fine channels per coarse.
+        assert!(flag_bad_values(jones_array.view_mut(), flag_array.view_mut(), 3).is_err());
+    }
+
+    #[test]
+    fn test_apply_radiometer_weights() {
+        let ant_pairs = vec![(0, 0), (0, 1), (1, 1)];
+        let mut jones_array = Array3::from_elem((2, 1, 3), Jones::identity());
+        let mut weight_array = Array3::from_elem((2, 1, 3), 1.0);
+        let mut flag_array = Array3::from_elem((2, 1, 3), false);
+        for timestep_idx in 0..2 {
+            jones_array[(timestep_idx, 0, 0)] = Jones::identity() * 2.0;
+            jones_array[(timestep_idx, 0, 2)][0] = Complex::new(4.0, 0.0);
+            jones_array[(timestep_idx, 0, 2)][3] = Complex::new(8.0, 0.0);
+        }
+        // a bad autocorrelation for antenna 1 in the second timestep
+        jones_array[(1, 0, 2)][0] = Complex::new(f32::NAN, 0.0);
+
+        let count = apply_radiometer_weights(
+            jones_array.view(),
+            weight_array.view_mut(),
+            flag_array.view_mut(),
+            &ant_pairs,
+        )
+        .unwrap();
+
+        // weights of 1 are 10kHz * 1s, divided by the mean of XX and YY variance.
+        assert_abs_diff_eq!(weight_array[(0, 0, 0)], 1e4 / 4.0);
+        assert_abs_diff_eq!(weight_array[(0, 0, 1)], 1e4 / 12.0);
+        assert_abs_diff_eq!(weight_array[(0, 0, 2)], 1e4 / 40.0);
+        assert_abs_diff_eq!(weight_array[(1, 0, 0)], 1e4 / 4.0);
+        assert_eq!(count, 2);
+        assert_eq!(
+            flag_array.iter().copied().collect::<Vec<_>>(),
+            vec![false, false, false, false, true, true]
+        );
+
+        // every antenna needs an autocorrelation.
+        assert!(apply_radiometer_weights(
+            jones_array.slice(s![.., .., 1..]),
+            weight_array.slice_mut(s![.., .., 1..]),
+            flag_array.slice_mut(s![.., .., 1..]),
+            &ant_pairs[1..],
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_sun_radec_at_equinox() {
+        // the March equinox of 2021 was at 09:37 UTC
+        let sun = sun_radec(Epoch::from_gregorian_utc(2021, 3, 20, 9, 37, 0, 0));
+        assert!(sun.dec.to_degrees().abs() < 0.05, "{sun:?}");
+        let ra_deg = sun.ra.to_degrees();
+        assert!(!(0.05..=359.95).contains(&ra_deg), "{sun:?}");
+    }
+
+    #[test]
+    fn test_flag_elevation() {
+        let array_pos = LatLngHeight::mwa();
+        let dut1 = Duration::from_seconds(0.0);
+        // local noon and midnight at the MWA in summer
+        let noon = Epoch::from_gregorian_utc(2021, 2, 16, 4, 0, 0, 0);
+        let midnight = Epoch::from_gregorian_utc(2021, 2, 16, 16, 0, 0, 0);
+        let timestamps = [noon, midnight];
+
+        let mut flag_ctx = FlagContext::blank_from_dimensions(2, 1, 1, 1);
+        assert_eq!(
+            flag_ctx.flag_sun_above(&timestamps, dut1, array_pos, 0.0),
+            1
+        );
+        assert_eq!(flag_ctx.timestep_flags, vec![true, false]);
+        // already flagged timesteps aren't counted again
+        assert_eq!(
+            flag_ctx.flag_sun_above(&timestamps, dut1, array_pos, -90.0),
+            1
+        );
+
+        // a phase centre at the zenith at noon is below the horizon at midnight
+        let zenith = RADec::from_radians(
+            get_lmst(array_pos.longitude_rad, noon, dut1),
+            array_pos.latitude_rad,
+        );
+        let mut flag_ctx = FlagContext::blank_from_dimensions(2, 1, 1, 1);
+        assert_eq!(
+            flag_ctx.flag_min_elevation(&timestamps, dut1, array_pos, zenith, 80.0),
+            1
+        );
+        assert_eq!(flag_ctx.timestep_flags, vec![false, true]);
+    }
+
+    #[test]
+    fn test_extend_flags_above_thresholds() {
+        // 3 antennas, with autos
+        let ant_pairs = vec![(0, 0), (0, 1), (0, 2), (1, 1), (1, 2), (2, 2)];
+        let mut flag_array = Array3::from_elem((4, 5, ant_pairs.len()), false);
+        // timestep 1 is mostly flagged
+        flag_array.slice_mut(ndarray::s![1, 0..4, ..]).fill(true);
+        // channel 3 is half flagged
+        flag_array.slice_mut(ndarray::s![0..2, 3, ..]).fill(true);
+        // baseline (1, 2) is flagged on every other timestep
+        flag_array.slice_mut(ndarray::s![0..;2, .., 4]).fill(true);
+
+        let thresholds = FlagThresholds {
+            timestep: Some(0.75),
+            chan: Some(0.45),
+            antenna: Some(0.5),
+        };
+        let extended = thresholds
+            .extend_flags(flag_array.view_mut(), &(10..14), 20, &ant_pairs)
+            .unwrap();
+
+        // antennas 1 and 2 are 40% flagged, so only the timestep and channel are extended.
+        assert_eq!(
+            extended,
+            ExtendedFlags {
+                timestep_idxs: vec![11],
+                chan_idxs: vec![23],
+                antenna_idxs: vec![],
+            }
+        );
+        assert!(flag_array.slice(ndarray::s![1, .., ..]).iter().all(|&f| f));
+        assert!(flag_array.slice(ndarray::s![.., 3, ..]).iter().all(|&f| f));
+        assert!(!flag_array[(2, 0, 0)]);
+
+        // no thresholds, nothing changes.
+        let prev_flag_array = flag_array.clone();
+        let extended = FlagThresholds::default()
+            .extend_flags(flag_array.view_mut(), &(10..14), 20, &ant_pairs)
+            .unwrap();
+        assert_eq!(extended, ExtendedFlags::default());
+        assert_eq!(flag_array, prev_flag_array);
+
+        // flag an antenna
+        let mut flag_array = Array3::from_elem((2, 2, ant_pairs.len()), false);
+        flag_array.slice_mut(ndarray::s![.., .., 1..3]).fill(true);
+        let extended = FlagThresholds {
+            antenna: Some(0.5),
+            ..FlagThresholds::default()
+        }
+        .extend_flags(flag_array.view_mut(), &(0..2), 0, &ant_pairs)
+        .unwrap();
+        assert_eq!(extended.antenna_idxs, vec![0]);
+        assert!(flag_array.slice(ndarray::s![.., .., 0]).iter().all(|&f| f));
+        assert!(!flag_array[(0, 0, 3)]);
+    }
+
+    #[test]
+    fn test_extend_flags_ignores_preflagged() {
+        let ant_pairs = vec![(0, 0), (0, 1), (0, 2), (1, 1), (1, 2), (2, 2)];
+        let mut flag_array = Array3::from_elem((2, 4, ant_pairs.len()), false);
+        // antenna 2 and channel 0 are already flagged
+        flag_array.slice_mut(ndarray::s![.., .., 2]).fill(true);
+        flag_array.slice_mut(ndarray::s![.., .., 4..6]).fill(true);
+        flag_array.slice_mut(ndarray::s![.., 0, ..]).fill(true);
+        // timestep 0 is flagged on 2 of the 3 remaining channels
+        flag_array.slice_mut(ndarray::s![0, 1..3, ..]).fill(true);
+
+        // counting the flagged antenna and channel, timestep 1 would be 62.5% flagged and
+        // channel 1 would be 75% flagged.
+        let extended = FlagThresholds {
+            timestep: Some(0.6),
+            chan: Some(0.6),
+            antenna: Some(0.5),
+        }
+        .extend_flags(flag_array.view_mut(), &(0..2), 0, &ant_pairs)
+        .unwrap();
+        assert_eq!(
+            extended,
+            ExtendedFlags {
+                timestep_idxs: vec![0],
+                chan_idxs: vec![],
+                antenna_idxs: vec![],
+            }
+        );
+        assert!(!flag_array[(1, 1, 0)]);
+    }
 
     #[test]
     fn test_get_flaggable_timesteps_handles_no_overlap() {
@@ -602,6 +1330,72 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_set_flag_reasons() {
+        let corr_ctx = get_mwax_context();
+        let vis_sel = VisSelection::from_mwalib(&corr_ctx).unwrap();
+        let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+        let ant_pairs = vis_sel.get_ant_pairs(&corr_ctx.metafits_context);
+
+        let mut flag_ctx = FlagContext::blank_from_dimensions(
+            corr_ctx.num_timesteps,
+            corr_ctx.num_coarse_chans,
+            fine_chans_per_coarse,
+            corr_ctx.metafits_context.num_ants,
+        );
+        flag_ctx.timestep_flags[vis_sel.timestep_range.start] = true;
+        flag_ctx.fine_chan_flags[1] = true;
+        flag_ctx.antenna_flags[1] = true;
+
+        let mut flag_reason_array = Array3::from_elem(
+            vis_sel.get_shape(fine_chans_per_coarse),
+            FlagReason::Calibration.bit(),
+        );
+        flag_ctx
+            .set_flag_reasons(
+                flag_reason_array.view_mut(),
+                &vis_sel.timestep_range,
+                &vis_sel.coarse_chan_range,
+                &ant_pairs,
+            )
+            .unwrap();
+
+        // baseline 0 is (0, 0), baseline 1 is (0, 1)
+        assert_eq!(flag_reason_array[(1, 0, 0)], 0);
+        assert_eq!(flag_reason_array[(0, 0, 0)], FlagReason::Timestep.bit());
+        assert_eq!(flag_reason_array[(1, 1, 0)], FlagReason::Channel.bit());
+        assert_eq!(flag_reason_array[(1, 0, 1)], FlagReason::Antenna.bit());
+        assert_eq!(
+            flag_reason_array[(0, 1, 1)],
+            FlagReason::Timestep.bit() | FlagReason::Channel.bit() | FlagReason::Antenna.bit()
+        );
+
+        // only new flags get the new reason.
+        let mut flag_array = vis_sel.allocate_flags(fine_chans_per_coarse).unwrap();
+        flag_ctx
+            .set_flags(
+                flag_array.view_mut(),
+                &vis_sel.timestep_range,
+                &vis_sel.coarse_chan_range,
+                &ant_pairs,
+            )
+            .unwrap();
+        let prev_flag_array = flag_array.clone();
+        flag_array[(0, 0, 0)] = true;
+        flag_array[(1, 0, 0)] = true;
+        set_new_flag_reasons(
+            flag_reason_array.view_mut(),
+            prev_flag_array.view(),
+            flag_array.view(),
+            FlagReason::AOFlagger,
+        )
+        .unwrap();
+        assert_eq!(flag_reason_array[(0, 0, 0)], FlagReason::Timestep.bit());
+        assert_eq!(flag_reason_array[(1, 0, 0)], FlagReason::AOFlagger.bit());
+        assert!(FlagReason::AOFlagger.is_set(flag_reason_array[(1, 0, 0)]));
+        assert!(!FlagReason::Timestep.is_set(flag_reason_array[(1, 0, 0)]));
+    }
+
     #[test]
     fn test_write_flags_mwax_minimal() {
         let flag_timestep = 1;
@@ -724,6 +1518,105 @@ where
     flag_array.map(|f| if *f { -weight_factor } else { weight_factor } as f32)
 }
 
+/// Scale the weights by the inverse noise variance of each visibility, estimated from the
+/// autocorrelations of the baseline's two antennas with the radiometer equation.
+///
+/// For antennas `i` and `j`, the noise variance of polarisation `pq` is `A_i,pp A_j,qq / (Δν Δt)`,
+/// where `A` is the real part of an autocorrelation. The weights are expected to be `Δν Δt` in
+/// units of 10kHz and 1s (see [`get_weight_factor`]), so each one is scaled to the inverse of the
+/// noise variance, averaged over the XX and YY polarisations.
+///
+/// Visibilities where either autocorrelation is not positive and finite are flagged instead.
+/// Returns the number of newly flagged visibilities.
+///
+/// # Errors
+///
+/// Can throw error if the arrays are not the same shape, or `ant_pairs` does not include an
+/// autocorrelation for every antenna.
+pub fn apply_radiometer_weights(
+    jones_array: ArrayView3<marlu::Jones<f32>>,
+    mut weight_array: ArrayViewMut3<f32>,
+    mut flag_array: ArrayViewMut3<bool>,
+    ant_pairs: &[(usize, usize)],
+) -> Result<usize, BirliError> {
+    let shape = jones_array.dim();
+    if weight_array.dim() != shape {
+        return Err(BirliError::BadArrayShape(BadArrayShape {
+            argument: "weight_array",
+            function: "apply_radiometer_weights",
+            expected: format!("{shape:?}"),
+            received: format!("{:?}", weight_array.dim()),
+        }));
+    }
+    if flag_array.dim() != shape {
+        return Err(BirliError::BadArrayShape(BadArrayShape {
+            argument: "flag_array",
+            function: "apply_radiometer_weights",
+            expected: format!("{shape:?}"),
+            received: format!("{:?}", flag_array.dim()),
+        }));
+    }
+    if ant_pairs.len() != shape.2 {
+        return Err(BirliError::BadArrayShape(BadArrayShape {
+            argument: "ant_pairs",
+            function: "apply_radiometer_weights",
+            expected: format!("{} baselines", shape.2),
+            received: format!("{}", ant_pairs.len()),
+        }));
+    }
+
+    // the baseline index of each antenna's autocorrelation
+    let auto_idx = |ant: usize| ant_pairs.iter().position(|&pair| pair == (ant, ant));
+    let auto_idxs = ant_pairs
+        .iter()
+        .map(|&(ant1, ant2)| match (auto_idx(ant1), auto_idx(ant2)) {
+            (Some(auto1_idx), Some(auto2_idx)) => Ok((auto1_idx, auto2_idx)),
+            _ => Err(BirliError::BadArrayShape(BadArrayShape {
+                argument: "ant_pairs",
+                function: "apply_radiometer_weights",
+                expected: "an autocorrelation for every antenna".into(),
+                received: format!("no autocorrelation for baseline ({ant1}, {ant2})"),
+            })),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let bandwidth_time =
+        marlu::constants::FREQ_WEIGHT_FACTOR * marlu::constants::TIME_WEIGHT_FACTOR;
+    let mut count = 0;
+    // arrays: [chan][baseline]
+    for (jones_array, mut weight_array, mut flag_array) in izip!(
+        jones_array.outer_iter(),
+        weight_array.outer_iter_mut(),
+        flag_array.outer_iter_mut(),
+    ) {
+        // arrays: [baseline]
+        for (jones_array, mut weight_array, mut flag_array) in izip!(
+            jones_array.outer_iter(),
+            weight_array.outer_iter_mut(),
+            flag_array.outer_iter_mut(),
+        ) {
+            for (weight, flag, &(auto1_idx, auto2_idx)) in izip!(
+                weight_array.iter_mut(),
+                flag_array.iter_mut(),
+                auto_idxs.iter()
+            ) {
+                let (auto1, auto2) = (jones_array[auto1_idx], jones_array[auto2_idx]);
+                let powers = [auto1[0].re, auto1[3].re, auto2[0].re, auto2[3].re];
+                if powers.iter().all(|&power| power.is_finite() && power > 0.0) {
+                    let variance = (powers[0] as f64 * powers[2] as f64
+                        + powers[1] as f64 * powers[3] as f64)
+                        / 2.0;
+                    *weight = (*weight as f64 * bandwidth_time / variance) as f32;
+                } else if !*flag {
+                    *flag = true;
+                    count += 1;
+                }
+            }
+        }
+    }
+    Ok(count)
+}
+
 #[cfg(test)]
 #[cfg(feature = "aoflagger")]
 /// Tests which require the use of the aoflagger feature
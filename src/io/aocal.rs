@@ -1,20 +1,39 @@
 //! IO for the AOCAL format, a binary calibration solutions format used by
-//! Andre Offringa's Calibrate software.
+//! Andre Offringa's Calibrate software, and the FITS calibration solutions
+//! format used by hyperdrive.
 
 pub(crate) use super::error::ReadSolutionsError;
 
-use std::{fs::File, io::BufReader, path::Path};
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::Path,
+};
 
 use crate::ndarray::{prelude::*, Array3};
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use marlu::{
+    fitsio::{
+        self,
+        hdu::HduInfo,
+        images::{ImageDescription, ImageType},
+        tables::{ColumnDataType, ColumnDescription},
+        FitsFile,
+    },
     hifitime::{Duration, Epoch},
     num_complex::Complex,
     Jones,
 };
 
-/// All of the relevant information contained within an MWAOCAL .bin file.
+use super::error::IOError;
+
+/// All of the relevant information contained within an MWAOCAL .bin file, or a
+/// hyperdrive .fits file.
+///
+/// Use [`AOCalSols::new`] to construct solutions outside of this crate, since
+/// more fields may be added.
 #[derive(Clone)]
+#[non_exhaustive]
 pub struct AOCalSols {
     /// a three dimensional array of jones matrix calibration solutions with
     /// dimensions `[timestep][tile][frequency]`
@@ -24,9 +43,69 @@ pub struct AOCalSols {
     /// solutions.
     pub start_timestamps: Vec<Epoch>,
     // pub obsid: Option<u32>,
+    /// The name of the tile for each solution, if the format records it. The
+    /// .bin format doesn't, so its solutions must be in metafits tile order.
+    pub tile_names: Option<Vec<String>>,
+
+    /// The ID of the tile for each solution, if the format records it.
+    pub tile_ids: Option<Vec<u32>>,
+
+    /// The centre frequency of each solution channel \[Hz\], if the format
+    /// records it.
+    pub freqs_hz: Option<Vec<f64>>,
+}
+
+/// Interpret 8 floats as the real and imaginary parts of each polarisation.
+fn jones_from_floats(view: ArrayView1<f64>) -> Jones<f64> {
+    Jones::from([
+        Complex::new(view[0], view[1]),
+        Complex::new(view[2], view[3]),
+        Complex::new(view[4], view[5]),
+        Complex::new(view[6], view[7]),
+    ])
 }
 
 impl AOCalSols {
+    /// Create calibration solutions with dimensions `[timestep][tile][frequency]`,
+    /// without any tile names or IDs, so they must be in metafits tile order.
+    pub const fn new(di_jones: Array3<Jones<f64>>, start_timestamps: Vec<Epoch>) -> Self {
+        Self {
+            di_jones,
+            start_timestamps,
+            tile_names: None,
+            tile_ids: None,
+            freqs_hz: None,
+        }
+    }
+
+    /// Reads calibration solutions, as hyperdrive (see
+    /// [`AOCalSols::read_hyperdrive_fits`]) if the file extension is `.fits`,
+    /// otherwise as MWAOCAL (see [`AOCalSols::read_andre_binary`]).
+    ///
+    /// # Errors
+    ///
+    /// Can throw [`ReadSolutionsError`] if the file format is not valid.
+    pub fn read<T: AsRef<Path>>(file: T) -> Result<Self, ReadSolutionsError> {
+        match file.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("fits") => Self::read_hyperdrive_fits(file),
+            _ => Self::read_andre_binary(file),
+        }
+    }
+
+    /// Writes calibration solutions, as hyperdrive (see
+    /// [`AOCalSols::write_hyperdrive_fits`]) if the file extension is `.fits`,
+    /// otherwise as MWAOCAL (see [`AOCalSols::write_andre_binary`]).
+    ///
+    /// # Errors
+    ///
+    /// Can throw [`IOError`] if the file can't be written.
+    pub fn write<T: AsRef<Path>>(&self, file: T) -> Result<(), IOError> {
+        match file.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("fits") => Ok(self.write_hyperdrive_fits(file)?),
+            _ => Ok(self.write_andre_binary(file)?),
+        }
+    }
+
     /// Reads an MWAOCAL .bin file and returns a struct of its' contents.
     ///
     /// # Errors
@@ -102,14 +181,7 @@ impl AOCalSols {
             di_jones_vec,
         )
         .unwrap();
-        let di_jones = di_jones_a4.map_axis(Axis(3), |view| {
-            Jones::from([
-                Complex::new(view[0], view[1]),
-                Complex::new(view[2], view[3]),
-                Complex::new(view[4], view[5]),
-                Complex::new(view[6], view[7]),
-            ])
-        });
+        let di_jones = di_jones_a4.map_axis(Axis(3), jones_from_floats);
 
         Ok(Self {
             di_jones,
@@ -137,8 +209,228 @@ impl AOCalSols {
                 }
             },
             // obsid: None,
+            tile_names: None,
+            tile_ids: None,
+            freqs_hz: None,
         })
     }
+
+    /// Reads a hyperdrive .fits calibration solutions file and returns a struct
+    /// of its' contents.
+    ///
+    /// hyperdrive solutions model the data as `J1 * M * J2^H`, so they are
+    /// inverted to match the MWAOCAL convention of solutions which correct the
+    /// data. Tile names come from the `TileName` column of the `TILES` HDU, and
+    /// tile IDs from its `TileID` column, if present. Channel frequencies come
+    /// from the `Freq` column of the `CHANBLOCKS` HDU, if every channel has one.
+    ///
+    /// # Errors
+    ///
+    /// Can throw [`ReadSolutionsError`] if the file format is not valid.
+    pub fn read_hyperdrive_fits<T: AsRef<Path>>(file: T) -> Result<Self, ReadSolutionsError> {
+        let file_str = file.as_ref().display().to_string();
+        let mut fptr = FitsFile::open(file)?;
+        let hdu = fptr.hdu("SOLUTIONS")?;
+        let shape = match &hdu.info {
+            HduInfo::ImageInfo { shape, .. } if shape.len() == 4 && shape[3] == 8 => shape.clone(),
+            info => {
+                return Err(ReadSolutionsError::HyperdriveShape {
+                    file: file_str,
+                    expected: "a SOLUTIONS image with dimensions [timeblock][tile][chanblock][8]",
+                    got: format!("{info:?}"),
+                })
+            }
+        };
+        let di_jones_vec: Vec<f64> = hdu.read_image(&mut fptr)?;
+        let di_jones = Array4::from_shape_vec((shape[0], shape[1], shape[2], 8), di_jones_vec)
+            .unwrap()
+            .map_axis(Axis(3), |view| jones_from_floats(view).inv());
+
+        let (tile_names, tile_ids) = match fptr.hdu("TILES") {
+            Ok(hdu) => {
+                let tile_names: Vec<String> = hdu.read_col(&mut fptr, "TileName")?;
+                let tile_ids: Option<Vec<u32>> = hdu.read_col(&mut fptr, "TileID").ok();
+                if tile_names.len() != shape[1]
+                    || matches!(&tile_ids, Some(tile_ids) if tile_ids.len() != shape[1])
+                {
+                    return Err(ReadSolutionsError::HyperdriveShape {
+                        file: file_str,
+                        expected: "a TILES row for each tile in SOLUTIONS",
+                        got: format!("{} rows for {} tiles", tile_names.len(), shape[1]),
+                    });
+                }
+                (
+                    Some(
+                        tile_names
+                            .into_iter()
+                            .map(|name| name.trim().to_string())
+                            .collect(),
+                    ),
+                    tile_ids,
+                )
+            }
+            Err(_) => (None, None),
+        };
+
+        let start_timestamps = fptr
+            .hdu("TIMEBLOCKS")
+            .and_then(|hdu| hdu.read_col::<f64>(&mut fptr, "Start"))
+            .map(|starts| starts.into_iter().map(Epoch::from_gpst_seconds).collect())
+            .unwrap_or_default();
+
+        // hyperdrive writes NaN for the frequencies it doesn't know.
+        let freqs_hz = match fptr
+            .hdu("CHANBLOCKS")
+            .and_then(|hdu| hdu.read_col::<f64>(&mut fptr, "Freq"))
+        {
+            Ok(freqs_hz) if freqs_hz.len() != shape[2] => {
+                return Err(ReadSolutionsError::HyperdriveShape {
+                    file: file_str,
+                    expected: "a CHANBLOCKS row for each channel in SOLUTIONS",
+                    got: format!("{} rows for {} channels", freqs_hz.len(), shape[2]),
+                });
+            }
+            Ok(freqs_hz) if freqs_hz.iter().all(|freq_hz| freq_hz.is_finite()) => Some(freqs_hz),
+            _ => None,
+        };
+
+        Ok(Self {
+            di_jones,
+            start_timestamps,
+            tile_names,
+            tile_ids,
+            freqs_hz,
+        })
+    }
+
+    /// Writes the solutions to a hyperdrive .fits file, inverting them as
+    /// described in [`AOCalSols::read_hyperdrive_fits`]. The `TILES`,
+    /// `TIMEBLOCKS` and `CHANBLOCKS` HDUs are only written when there is
+    /// something to put in them.
+    ///
+    /// # Errors
+    ///
+    /// Can throw [`fitsio::errors::Error`] if the file can't be written.
+    pub fn write_hyperdrive_fits<T: AsRef<Path>>(
+        &self,
+        file: T,
+    ) -> Result<(), fitsio::errors::Error> {
+        let mut fptr = FitsFile::create(file).overwrite().open()?;
+        let (num_timeblocks, num_tiles, num_chans) = self.di_jones.dim();
+        let hdu = fptr.create_image(
+            "SOLUTIONS",
+            &ImageDescription {
+                data_type: ImageType::Double,
+                dimensions: &[num_timeblocks, num_tiles, num_chans, 8],
+            },
+        )?;
+        let di_jones_vec = self
+            .di_jones
+            .iter()
+            .flat_map(|jones| jones.inv().to_float_array())
+            .collect::<Vec<_>>();
+        hdu.write_image(&mut fptr, &di_jones_vec)?;
+
+        if let Some(tile_names) = self.tile_names.as_ref() {
+            let name_width = tile_names.iter().map(String::len).max().unwrap_or(1);
+            let mut columns = vec![
+                ColumnDescription::new("Antenna")
+                    .with_type(ColumnDataType::Int)
+                    .create()?,
+                ColumnDescription::new("TileName")
+                    .with_type(ColumnDataType::String)
+                    .that_repeats(name_width)
+                    .create()?,
+            ];
+            if self.tile_ids.is_some() {
+                columns.push(
+                    ColumnDescription::new("TileID")
+                        .with_type(ColumnDataType::Int)
+                        .create()?,
+                );
+            }
+            let hdu = fptr.create_table("TILES", &columns)?;
+            hdu.write_col(
+                &mut fptr,
+                "Antenna",
+                &(0..num_tiles as i32).collect::<Vec<_>>(),
+            )?;
+            hdu.write_col(&mut fptr, "TileName", tile_names)?;
+            if let Some(tile_ids) = self.tile_ids.as_ref() {
+                hdu.write_col(&mut fptr, "TileID", tile_ids)?;
+            }
+        }
+
+        if !self.start_timestamps.is_empty() {
+            let hdu = fptr.create_table(
+                "TIMEBLOCKS",
+                &[ColumnDescription::new("Start")
+                    .with_type(ColumnDataType::Double)
+                    .create()?],
+            )?;
+            let starts = self
+                .start_timestamps
+                .iter()
+                .map(Epoch::to_gpst_seconds)
+                .collect::<Vec<_>>();
+            hdu.write_col(&mut fptr, "Start", &starts)?;
+        }
+
+        if let Some(freqs_hz) = self.freqs_hz.as_ref() {
+            let hdu = fptr.create_table(
+                "CHANBLOCKS",
+                &[
+                    ColumnDescription::new("Index")
+                        .with_type(ColumnDataType::Int)
+                        .create()?,
+                    ColumnDescription::new("Freq")
+                        .with_type(ColumnDataType::Double)
+                        .create()?,
+                ],
+            )?;
+            hdu.write_col(
+                &mut fptr,
+                "Index",
+                &(0..num_chans as i32).collect::<Vec<_>>(),
+            )?;
+            hdu.write_col(&mut fptr, "Freq", freqs_hz)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the solutions to an MWAOCAL .bin file.
+    ///
+    /// The start time written is the first of `start_timestamps`, and the end time is the last.
+    ///
+    /// # Errors
+    ///
+    /// Can throw [`std::io::Error`] if the file can't be written.
+    pub fn write_andre_binary<T: AsRef<Path>>(&self, file: T) -> Result<(), std::io::Error> {
+        let file_str = file.as_ref().display().to_string();
+        let mut bin_file = BufWriter::new(File::create(file).map_err(|e| {
+            std::io::Error::new(e.kind(), format!("{e} when accessing {file_str}"))
+        })?);
+        bin_file.write_all(b"MWAOCAL")?;
+        bin_file.write_all(&[0; 9])?;
+        let (num_timeblocks, total_num_tiles, total_num_fine_freq_chans) = self.di_jones.dim();
+        for value in [
+            num_timeblocks,
+            total_num_tiles,
+            total_num_fine_freq_chans,
+            4,
+        ] {
+            bin_file.write_u32::<LittleEndian>(value as u32)?;
+        }
+        for timestamp in [self.start_timestamps.first(), self.start_timestamps.last()] {
+            bin_file.write_f64::<LittleEndian>(timestamp.map_or(0., Epoch::to_gpst_seconds))?;
+        }
+        for jones in &self.di_jones {
+            for value in jones.to_float_array() {
+                bin_file.write_f64::<LittleEndian>(value)?;
+            }
+        }
+        bin_file.flush()
+    }
 }
 
 #[cfg(test)]
@@ -156,6 +448,77 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_write_andre_binary_round_trip() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let out_file = tmp_dir.path().join("sols.bin");
+        let sols = AOCalSols::new(
+            Array3::from_shape_fn((1, 2, 3), |(_, tile, chan)| {
+                Jones::from([
+                    Complex::new((tile * 3 + chan) as f64, 0.5),
+                    Complex::new(0., -0.25),
+                    Complex::new(0.125, 0.),
+                    Complex::new(-1., (tile + chan) as f64),
+                ])
+            }),
+            vec![Epoch::from_gpst_seconds(1090008640.)],
+        );
+        sols.write_andre_binary(&out_file).unwrap();
+        let read_sols = AOCalSols::read_andre_binary(&out_file).unwrap();
+        assert_eq!(read_sols.di_jones, sols.di_jones);
+        assert_eq!(read_sols.start_timestamps, sols.start_timestamps);
+
+        // any extension other than .fits is read as MWAOCAL, like `AOCalSols::write`.
+        let dat_file = tmp_dir.path().join("sols.dat");
+        sols.write(&dat_file).unwrap();
+        let read_sols = AOCalSols::read(&dat_file).unwrap();
+        assert_eq!(read_sols.di_jones, sols.di_jones);
+        assert_eq!(read_sols.tile_names, None);
+    }
+
+    #[test]
+    fn test_write_hyperdrive_fits_round_trip() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let out_file = tmp_dir.path().join("hyp_solutions.fits");
+        let mut di_jones = Array3::from_shape_fn((1, 2, 3), |(_, tile, chan)| {
+            Jones::from([
+                Complex::new((tile * 3 + chan + 1) as f64, 0.5),
+                Complex::new(0., 0.),
+                Complex::new(0., 0.),
+                Complex::new(-1., (tile + chan) as f64),
+            ])
+        });
+        di_jones[(0, 1, 2)] = Jones::nan();
+        let sols = AOCalSols {
+            di_jones,
+            start_timestamps: vec![Epoch::from_gpst_seconds(1090008640.)],
+            tile_names: Some(vec!["Tile011".into(), "Tile012".into()]),
+            tile_ids: Some(vec![11, 12]),
+            freqs_hz: Some(vec![150e6, 150.04e6, 150.08e6]),
+        };
+        sols.write(&out_file).unwrap();
+        let read_sols = AOCalSols::read(&out_file).unwrap();
+        assert_abs_diff_eq!(
+            read_sols.di_jones.slice(s![.., .., ..2]),
+            sols.di_jones.slice(s![.., .., ..2]),
+            epsilon = 1e-12
+        );
+        assert!(read_sols.di_jones[(0, 1, 2)].any_nan());
+        assert_eq!(read_sols.start_timestamps, sols.start_timestamps);
+        assert_eq!(read_sols.tile_names, sols.tile_names);
+        assert_eq!(read_sols.tile_ids, sols.tile_ids);
+        assert_eq!(read_sols.freqs_hz, sols.freqs_hz);
+    }
+
+    #[test]
+    fn test_read_other_ext_as_andre_binary() {
+        let file = "tests/data/1254670392_avg/1254670392.metafits";
+        assert!(matches!(
+            AOCalSols::read(file),
+            Err(ReadSolutionsError::AndreBinaryStr { .. })
+        ));
+    }
+
     #[test]
     fn test_read_valid_andre_binary() {
         let file = "tests/data/1254670392_avg/1254690096.bin";
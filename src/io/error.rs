@@ -47,6 +47,44 @@ pub enum IOError {
         source_line: u32,
     },
 
+    /// Error when the contents of a uvfits file can't be interpreted as visibilities.
+    #[error(
+        "{source_file}:{source_line}\nCouldn't read visibilities from {uvfits_filename}: {message}"
+    )]
+    BadUvfits {
+        /// The filename of the uvfits file
+        uvfits_filename: PathBuf,
+        /// A description of what was wrong with the file
+        message: String,
+        /// The file where the error originated (usually `file!()`)
+        source_file: &'static str,
+        /// The line number where the error originated (usually `line!()`)
+        source_line: u32,
+    },
+
+    /// Error when the contents of a measurement set can't be interpreted as visibilities.
+    #[error(
+        "{source_file}:{source_line}\nCouldn't read visibilities from {ms_filename}: {message}"
+    )]
+    BadMs {
+        /// The filename of the measurement set
+        ms_filename: PathBuf,
+        /// A description of what was wrong with the measurement set
+        message: String,
+        /// The file where the error originated (usually `file!()`)
+        source_file: &'static str,
+        /// The line number where the error originated (usually `line!()`)
+        source_line: u32,
+    },
+
+    #[error(transparent)]
+    /// Error derived from [`marlu::rubbl_casatables::TableError`]
+    TableError(#[from] marlu::rubbl_casatables::TableError),
+
+    #[error(transparent)]
+    /// Error derived from [`marlu::rubbl_casatables::CasacoreError`]
+    CasacoreError(#[from] marlu::rubbl_casatables::CasacoreError),
+
     #[error(transparent)]
     /// Error derived from [`marlu::mwalib::FitsError`]
     FitsError(#[from] mwalib::FitsError),
@@ -73,6 +111,30 @@ pub enum IOError {
         expected: u64,
     },
 
+    /// Error when a passband gains file can't be interpreted.
+    #[error("{}:{line}\nCouldn't read passband gains: {message}", path.display())]
+    BadPassbandGains {
+        /// The path of the passband gains file
+        path: PathBuf,
+        /// The line number where the error occurred
+        line: usize,
+        /// A description of what was wrong with the line
+        message: String,
+    },
+
+    /// Error when a layout file can't be interpreted, or doesn't match the metafits.
+    #[error("{}\nCouldn't read layout: {message}", path.display())]
+    BadLayout {
+        /// The path of the layout file
+        path: PathBuf,
+        /// A description of what was wrong with the layout
+        message: String,
+    },
+
+    #[error(transparent)]
+    /// Error reading calibration solutions
+    ReadSolutionsError(#[from] ReadSolutionsError),
+
     #[error(transparent)]
     /// Error for bad array shape in provided argument
     BadArrayShape(#[from] BadArrayShape),
@@ -113,10 +175,22 @@ pub enum ReadSolutionsError {
         got: String,
     },
 
+    #[error("When reading {file}, expected {expected}, but got {got} instead!")]
+    #[allow(missing_docs)]
+    HyperdriveShape {
+        file: String,
+        expected: &'static str,
+        got: String,
+    },
+
     #[error(transparent)]
     #[allow(missing_docs)]
     Fits(#[from] FitsError),
 
+    #[error(transparent)]
+    #[allow(missing_docs)]
+    Fitsio(#[from] fitsio::errors::Error),
+
     #[error("IO error: {0}")]
     #[allow(missing_docs)]
     IO(#[from] std::io::Error),
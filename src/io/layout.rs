@@ -0,0 +1,347 @@
+//! IO for array layout files, which override the positions, cable lengths and flags of tiles in
+//! the metafits.
+//!
+//! A layout is keyed by tile name, and every other field is optional. As a CSV file, the header
+//! names the columns, and empty cells are left as they are in the metafits:
+//!
+//! ```text
+//! tile_name,east_m,north_m,height_m,electrical_length_m,flagged
+//! Tile011,-149.785,265.814,377.011,,
+//! Tile012,,,,150.2,true
+//! ```
+//!
+//! As a JSON file (identified by the `.json` extension), the same fields are given in an object
+//! for each tile name:
+//!
+//! ```text
+//! {
+//!     "Tile011": { "east_m": -149.785, "north_m": 265.814, "height_m": 377.011 },
+//!     "Tile012": { "electrical_length_m": 150.2, "flagged": true }
+//! }
+//! ```
+
+use std::{collections::BTreeMap, fmt::Display, fs::File, io::BufReader, path::Path};
+
+use marlu::mwalib::{MetafitsContext, Rfinput};
+use serde::{
+    de::{MapAccess, Visitor},
+    Deserialize, Deserializer,
+};
+
+use super::error::IOError;
+
+/// The properties of a tile to override in the metafits. Fields which are `None` are left as
+/// they are.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TileOverride {
+    /// East position of the tile from the array centre, in metres
+    pub east_m: Option<f64>,
+    /// North position of the tile from the array centre, in metres
+    pub north_m: Option<f64>,
+    /// Height of the tile above sea level, in metres
+    pub height_m: Option<f64>,
+    /// Electrical length of the cables of both polarisations, in metres
+    pub electrical_length_m: Option<f64>,
+    /// Whether both polarisations of the tile are flagged
+    pub flagged: Option<bool>,
+}
+
+/// A row of a CSV layout file. `csv` can't deserialize flattened structs, so the fields of
+/// [`TileOverride`] are repeated here.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LayoutRow {
+    tile_name: String,
+    #[serde(default)]
+    east_m: Option<f64>,
+    #[serde(default)]
+    north_m: Option<f64>,
+    #[serde(default)]
+    height_m: Option<f64>,
+    #[serde(default)]
+    electrical_length_m: Option<f64>,
+    #[serde(default)]
+    flagged: Option<bool>,
+}
+
+/// The tiles of a JSON layout file, in the order they appear. Deserializing straight into a map
+/// would silently keep the last of any duplicate tile names.
+struct JsonLayout(Vec<(String, TileOverride)>);
+
+impl<'de> Deserialize<'de> for JsonLayout {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct JsonLayoutVisitor;
+
+        impl<'de> Visitor<'de> for JsonLayoutVisitor {
+            type Value = JsonLayout;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("an object of tile overrides keyed by tile name")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut tiles = Vec::with_capacity(map.size_hint().unwrap_or(0));
+                while let Some(tile) = map.next_entry()? {
+                    tiles.push(tile);
+                }
+                Ok(JsonLayout(tiles))
+            }
+        }
+
+        deserializer.deserialize_map(JsonLayoutVisitor)
+    }
+}
+
+/// Read the tile overrides from a CSV or JSON layout file, keyed by tile name.
+///
+/// # Errors
+///
+/// Will throw [`IOError::BadLayout`] if the file can't be parsed, has unknown fields, or a tile
+/// name appears more than once.
+pub fn read_layout<P: AsRef<Path>>(path: P) -> Result<BTreeMap<String, TileOverride>, IOError> {
+    let path = path.as_ref();
+    let bad_layout = |message: String| IOError::BadLayout {
+        path: path.to_path_buf(),
+        message,
+    };
+    let is_json = path
+        .extension()
+        .map_or(false, |ext| ext.eq_ignore_ascii_case("json"));
+    let rows = if is_json {
+        let JsonLayout(rows) = serde_json::from_reader(BufReader::new(File::open(path)?))
+            .map_err(|err| bad_layout(err.to_string()))?;
+        rows
+    } else {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .comment(Some(b'#'))
+            .from_path(path)
+            .map_err(|err| bad_layout(err.to_string()))?;
+        reader
+            .deserialize::<LayoutRow>()
+            .map(|row| {
+                let row = row.map_err(|err| bad_layout(err.to_string()))?;
+                let tile = TileOverride {
+                    east_m: row.east_m,
+                    north_m: row.north_m,
+                    height_m: row.height_m,
+                    electrical_length_m: row.electrical_length_m,
+                    flagged: row.flagged,
+                };
+                Ok((row.tile_name, tile))
+            })
+            .collect::<Result<Vec<_>, IOError>>()?
+    };
+
+    let mut tiles = BTreeMap::new();
+    for (tile_name, tile) in rows {
+        if tiles.contains_key(&tile_name) {
+            return Err(bad_layout(format!(
+                "tile {tile_name} appears more than once"
+            )));
+        }
+        tiles.insert(tile_name, tile);
+    }
+    Ok(tiles)
+}
+
+/// Apply the overrides of a single tile to an rf input of that tile.
+fn override_rfinput(rfinput: &mut Rfinput, tile: &TileOverride) {
+    if let Some(east_m) = tile.east_m {
+        rfinput.east_m = east_m;
+    }
+    if let Some(north_m) = tile.north_m {
+        rfinput.north_m = north_m;
+    }
+    if let Some(height_m) = tile.height_m {
+        rfinput.height_m = height_m;
+    }
+    if let Some(electrical_length_m) = tile.electrical_length_m {
+        rfinput.electrical_length_m = electrical_length_m;
+    }
+    if let Some(flagged) = tile.flagged {
+        rfinput.flagged = flagged;
+    }
+}
+
+/// Describe the change of a field, if it is overridden.
+fn describe_change<T: Display>(field: &str, old: T, new: Option<T>) -> Option<String> {
+    new.map(|new| format!("{field} {old} -> {new}"))
+}
+
+/// Override the positions, cable lengths and flags of the tiles in `meta_ctx` with the layout
+/// file at `path` (see [`read_layout`]). Both the antennas and their rf inputs are updated, so
+/// that everything downstream of the metafits sees the new values.
+///
+/// Returns a description of the overrides applied to each tile, e.g.
+/// `Tile011: east_m -149.9 -> -149.785`.
+///
+/// # Errors
+///
+/// Will throw [`IOError::BadLayout`] if the layout can't be read, or has a tile which isn't in
+/// the metafits.
+pub fn apply_layout<P: AsRef<Path>>(
+    path: P,
+    meta_ctx: &mut MetafitsContext,
+) -> Result<Vec<String>, IOError> {
+    let path = path.as_ref();
+    let tiles = read_layout(path)?;
+    let mut descriptions = Vec::with_capacity(tiles.len());
+    for (tile_name, tile) in &tiles {
+        let antenna = meta_ctx
+            .antennas
+            .iter_mut()
+            .find(|antenna| &antenna.tile_name == tile_name)
+            .ok_or_else(|| IOError::BadLayout {
+                path: path.to_path_buf(),
+                message: format!("tile {tile_name} is not in the metafits"),
+            })?;
+        let changes = [
+            describe_change("east_m", antenna.east_m, tile.east_m),
+            describe_change("north_m", antenna.north_m, tile.north_m),
+            describe_change("height_m", antenna.height_m, tile.height_m),
+            describe_change(
+                "electrical_length_m",
+                antenna.electrical_length_m,
+                tile.electrical_length_m,
+            ),
+            describe_change(
+                "flagged",
+                antenna.rfinput_x.flagged || antenna.rfinput_y.flagged,
+                tile.flagged,
+            ),
+        ];
+        let changes = changes.into_iter().flatten().collect::<Vec<_>>();
+        if changes.is_empty() {
+            continue;
+        }
+        descriptions.push(format!("{tile_name}: {}", changes.join(", ")));
+
+        if let Some(east_m) = tile.east_m {
+            antenna.east_m = east_m;
+        }
+        if let Some(north_m) = tile.north_m {
+            antenna.north_m = north_m;
+        }
+        if let Some(height_m) = tile.height_m {
+            antenna.height_m = height_m;
+        }
+        if let Some(electrical_length_m) = tile.electrical_length_m {
+            antenna.electrical_length_m = electrical_length_m;
+        }
+        override_rfinput(&mut antenna.rfinput_x, tile);
+        override_rfinput(&mut antenna.rfinput_y, tile);
+        let ant = antenna.ant;
+        for rfinput in meta_ctx.rf_inputs.iter_mut().filter(|rf| rf.ant == ant) {
+            override_rfinput(rfinput, tile);
+        }
+    }
+    Ok(descriptions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_common::get_mwax_data_paths;
+    use approx::assert_abs_diff_eq;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_read_layout_csv_json() {
+        let tmp_dir = tempdir().unwrap();
+        let csv_path = tmp_dir.path().join("layout.csv");
+        std::fs::write(
+            &csv_path,
+            "# a comment\ntile_name, east_m, flagged\nTile011, 1.5,\nTile012, , true\n",
+        )
+        .unwrap();
+        let json_path = tmp_dir.path().join("layout.json");
+        std::fs::write(
+            &json_path,
+            r#"{"Tile011": {"east_m": 1.5}, "Tile012": {"flagged": true}}"#,
+        )
+        .unwrap();
+
+        for path in [csv_path, json_path] {
+            let tiles = read_layout(&path).unwrap();
+            assert_eq!(tiles.len(), 2);
+            assert_eq!(
+                tiles["Tile011"],
+                TileOverride {
+                    east_m: Some(1.5),
+                    ..TileOverride::default()
+                }
+            );
+            assert_eq!(
+                tiles["Tile012"],
+                TileOverride {
+                    flagged: Some(true),
+                    ..TileOverride::default()
+                }
+            );
+        }
+
+        let path = tmp_dir.path().join("bad.csv");
+        std::fs::write(&path, "tile_name,eats_m\nTile011,1.5\n").unwrap();
+        assert!(matches!(read_layout(&path), Err(IOError::BadLayout { .. })));
+        std::fs::write(&path, "tile_name,east_m\nTile011,1.5\nTile011,2.5\n").unwrap();
+        assert!(matches!(read_layout(&path), Err(IOError::BadLayout { .. })));
+        let path = tmp_dir.path().join("bad.json");
+        std::fs::write(&path, r#"{"Tile011": {"flagged": "yes"}}"#).unwrap();
+        assert!(matches!(read_layout(&path), Err(IOError::BadLayout { .. })));
+        std::fs::write(
+            &path,
+            r#"{"Tile011": {"east_m": 1.5}, "Tile011": {"east_m": 2.5}}"#,
+        )
+        .unwrap();
+        assert!(matches!(read_layout(&path), Err(IOError::BadLayout { .. })));
+    }
+
+    #[test]
+    fn test_apply_layout() {
+        let (metafits_path, _) = get_mwax_data_paths();
+        let mut meta_ctx = MetafitsContext::new(metafits_path, None).unwrap();
+        let tile_name = meta_ctx.antennas[1].tile_name.clone();
+        let old_north_m = meta_ctx.antennas[1].north_m;
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("layout.csv");
+        std::fs::write(
+            &path,
+            format!("tile_name,east_m,height_m,electrical_length_m,flagged\n{tile_name},1.5,,123.4,true\n"),
+        )
+        .unwrap();
+
+        let descriptions = apply_layout(&path, &mut meta_ctx).unwrap();
+        assert_eq!(descriptions.len(), 1);
+        assert!(descriptions[0].starts_with(&format!("{tile_name}: east_m ")));
+        assert!(descriptions[0].contains(" -> 1.5, electrical_length_m "));
+        assert!(descriptions[0].ends_with(" -> 123.4, flagged false -> true"));
+
+        let antenna = &meta_ctx.antennas[1];
+        assert_abs_diff_eq!(antenna.east_m, 1.5);
+        assert_abs_diff_eq!(antenna.north_m, old_north_m);
+        assert_abs_diff_eq!(antenna.electrical_length_m, 123.4);
+        for rfinput in [&antenna.rfinput_x, &antenna.rfinput_y] {
+            assert_abs_diff_eq!(rfinput.east_m, 1.5);
+            assert_abs_diff_eq!(rfinput.electrical_length_m, 123.4);
+            assert!(rfinput.flagged);
+        }
+        let rf_inputs = meta_ctx
+            .rf_inputs
+            .iter()
+            .filter(|rf| rf.tile_name == tile_name)
+            .collect::<Vec<_>>();
+        assert_eq!(rf_inputs.len(), 2);
+        for rfinput in rf_inputs {
+            assert_abs_diff_eq!(rfinput.east_m, 1.5);
+            assert!(rfinput.flagged);
+        }
+
+        std::fs::write(&path, "tile_name,east_m\nTile999,1.5\n").unwrap();
+        assert!(matches!(
+            apply_layout(&path, &mut meta_ctx),
+            Err(IOError::BadLayout { .. })
+        ));
+    }
+}
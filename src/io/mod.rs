@@ -4,7 +4,11 @@
 
 pub mod aocal;
 pub mod error;
+pub mod layout;
+pub mod ms;
 pub mod mwaf;
+pub mod passband;
+pub mod uvfits;
 
 use std::{
     ops::Range,
@@ -14,7 +18,7 @@ use std::{
 use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
 use itertools::izip;
 use log::{trace, warn};
-use marlu::{mwalib, SelectionError, VisSelection};
+use marlu::{io::error::BadArrayShape, mwalib, SelectionError, VisSelection};
 
 use crate::{
     marlu::{
@@ -29,6 +33,7 @@ use crate::{
 };
 
 use self::error::IOError;
+use crate::calibration::{CalsolInterp, CalsolSmoothing};
 
 /// Groups together parameters related to I/O
 #[derive(Debug, Default, Clone)]
@@ -38,8 +43,33 @@ pub struct IOContext {
     pub metafits_in: PathBuf,
     /// A vector of gpufits .fits input paths
     pub gpufits_in: Vec<PathBuf>,
+    /// Whether to flag HDUs which can't be read, instead of stopping (see [`read_mwalib_tolerant`])
+    pub tolerate_read_errors: bool,
+    /// Scale weights by the MWAX weights HDUs, and flag baselines with less than this fraction of
+    /// data received (see [`read_mwalib_weights`]). `None` to ignore the weights HDUs.
+    pub min_mwax_weight: Option<f32>,
+    /// Optional .uvfits input path, read instead of the gpufits files (see [`uvfits::UvfitsReader`])
+    pub uvfits_in: Option<PathBuf>,
+    /// Optional measurement set input path, read instead of the gpufits files (see [`ms::MsReader`])
+    pub ms_in: Option<PathBuf>,
     /// Optional path to a .bin ao calibration solutions input file
     pub aocalsols_in: Option<PathBuf>,
+    /// Whether to apply `aocalsols_in` to the averaged visibilities instead of before averaging
+    pub aocalsols_after_avg: bool,
+    /// How to interpolate `aocalsols_in` onto the channels they are applied to, instead of using
+    /// each solution for the channels it covers (see [`crate::calibration::interpolate_calsols`])
+    pub aocalsols_interp: Option<CalsolInterp>,
+    /// How to smooth `aocalsols_in` before they are applied (see
+    /// [`crate::calibration::smooth_calsols`])
+    pub aocalsols_smoothing: CalsolSmoothing,
+    /// Optional path to write the smoothed calibration solutions to
+    pub aocalsols_smoothed_out: Option<PathBuf>,
+    /// Optional path to a CSV or JSON layout file overriding the tile positions, cable lengths
+    /// and flags in the metafits (see [`layout::apply_layout`])
+    pub layout_in: Option<PathBuf>,
+    /// Descriptions of the overrides from `layout_in` which were applied to the metafits, to be
+    /// recorded in the output history
+    pub layout_overrides: Vec<String>,
 
     // out
     /// Optional .uvfits output path
@@ -48,6 +78,11 @@ pub struct IOContext {
     pub ms_out: Option<PathBuf>,
     /// Optional .mwaf flag file path template (see `io::mwaf::FlagFileSet`)
     pub flag_template: Option<String>,
+    /// Optional .csv flag occupancy output path (see `occupancy::FlagOccupancy`)
+    pub occupancy_out: Option<PathBuf>,
+    /// Optional path to write the passband gains derived from the autocorrelations to (see
+    /// [`passband::write_passband_gains`])
+    pub passband_gains_out: Option<PathBuf>,
 }
 
 impl IOContext {
@@ -86,6 +121,109 @@ pub trait ReadableVis: Sync + Send {
     ) -> Result<(), IOError>;
 }
 
+/// The container has visibilities which have already been converted from gpubox files, e.g. a
+/// uvfits file or measurement set, and can be read without mwalib.
+///
+/// The visibilities are read with dimensions `[timestep][chan][baseline]`, where the timesteps,
+/// channels and baselines are those in the container.
+pub trait VisRead {
+    /// The path to the container.
+    fn get_path(&self) -> &Path;
+
+    /// The (zero-indexed) antenna index pairs of each baseline in the container.
+    fn get_ant_pairs(&self) -> &[(usize, usize)];
+
+    /// The phase centre of the visibilities.
+    fn get_phase_centre(&self) -> RADec;
+
+    /// The shape of the jones, weight and flag arrays for all of the visibilities in the
+    /// container.
+    fn get_shape(&self) -> (usize, usize, usize);
+
+    /// Create a [`VisContext`] describing the given range of timesteps in the container, with the
+    /// given averaging factors.
+    fn get_vis_ctx(
+        &self,
+        timestep_range: &Range<usize>,
+        avg_time: usize,
+        avg_freq: usize,
+    ) -> VisContext;
+
+    /// Read the visibilities of a range of timesteps into the provided arrays, where the
+    /// timestep axis matches `timestep_range`.
+    ///
+    /// Visibilities which are flagged or missing in the container are flagged, in addition to any
+    /// existing flags in `flag_array`.
+    ///
+    /// # Errors
+    ///
+    /// Can throw [`IOError`] if the arrays are the wrong shape, or there is an issue reading.
+    fn read_vis(
+        &self,
+        jones_array: ArrayViewMut3<Jones<f32>>,
+        weight_array: ArrayViewMut3<f32>,
+        flag_array: ArrayViewMut3<bool>,
+        timestep_range: &Range<usize>,
+    ) -> Result<(), IOError>;
+}
+
+/// Check that the jones, weight and flag arrays given to [`VisRead::read_vis`] have the
+/// expected shape.
+fn check_read_vis_shapes(
+    function: &'static str,
+    expected: (usize, usize, usize),
+    jones_array: &ArrayViewMut3<Jones<f32>>,
+    weight_array: &ArrayViewMut3<f32>,
+    flag_array: &ArrayViewMut3<bool>,
+) -> Result<(), IOError> {
+    for (argument, received) in [
+        ("jones_array", jones_array.dim()),
+        ("weight_array", weight_array.dim()),
+        ("flag_array", flag_array.dim()),
+    ] {
+        if received != expected {
+            return Err(IOError::BadArrayShape(BadArrayShape {
+                argument,
+                function,
+                expected: format!("{expected:?}"),
+                received: format!("{received:?}"),
+            }));
+        }
+    }
+    Ok(())
+}
+
+/// Flag, and zero the visibilities and weights of each `[timestep][baseline]` which is `missing`.
+fn flag_missing_vis(
+    missing: ArrayView2<bool>,
+    mut jones_array: ArrayViewMut3<Jones<f32>>,
+    mut weight_array: ArrayViewMut3<f32>,
+    mut flag_array: ArrayViewMut3<bool>,
+) {
+    for ((timestep_idx, baseline_idx), _) in missing.indexed_iter().filter(|(_, &m)| m) {
+        jones_array
+            .slice_mut(s![timestep_idx, .., baseline_idx])
+            .fill(Jones::default());
+        weight_array
+            .slice_mut(s![timestep_idx, .., baseline_idx])
+            .fill(0.0);
+        flag_array
+            .slice_mut(s![timestep_idx, .., baseline_idx])
+            .fill(true);
+    }
+}
+
+/// An HDU which could not be read from the gpubox files, and was flagged instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnreadableHdu {
+    /// The mwalib timestep index of the HDU
+    pub timestep_idx: usize,
+    /// The mwalib coarse channel index of the HDU
+    pub coarse_chan_idx: usize,
+    /// The error from mwalib when reading the HDU
+    pub error: String,
+}
+
 /// Read the visibilities for this selection into the jones array using mwalib,
 /// flag visiblities if they are not provided.
 ///
@@ -147,12 +285,58 @@ pub trait ReadableVis: Sync + Send {
 /// assert_ne!(dims_common, dims_good);
 /// ```
 pub fn read_mwalib(
+    vis_sel: &VisSelection,
+    corr_ctx: &CorrelatorContext,
+    jones_array: ArrayViewMut3<Jones<f32>>,
+    flag_array: ArrayViewMut3<bool>,
+    draw_progress: bool,
+) -> Result<(), SelectionError> {
+    read_mwalib_inner(
+        vis_sel,
+        corr_ctx,
+        jones_array,
+        flag_array,
+        false,
+        draw_progress,
+    )?;
+    Ok(())
+}
+
+/// Read the visibilities for this selection into the jones array using mwalib, like
+/// [`read_mwalib`], but flag visibilities in any HDU which can't be read (e.g. a truncated HDU
+/// or a bad checksum) instead of returning an error.
+///
+/// Returns the timesteps and coarse channels which could not be read.
+///
+/// # Errors
+///
+/// Can raise [`SelectionError::BadArrayShape`] if `jones_array` or `flag_array` does not match the
+/// expected shape of this selection.
+pub fn read_mwalib_tolerant(
+    vis_sel: &VisSelection,
+    corr_ctx: &CorrelatorContext,
+    jones_array: ArrayViewMut3<Jones<f32>>,
+    flag_array: ArrayViewMut3<bool>,
+    draw_progress: bool,
+) -> Result<Vec<UnreadableHdu>, SelectionError> {
+    read_mwalib_inner(
+        vis_sel,
+        corr_ctx,
+        jones_array,
+        flag_array,
+        true,
+        draw_progress,
+    )
+}
+
+fn read_mwalib_inner(
     vis_sel: &VisSelection,
     corr_ctx: &CorrelatorContext,
     mut jones_array: ArrayViewMut3<Jones<f32>>,
     mut flag_array: ArrayViewMut3<bool>,
+    tolerate_read_errors: bool,
     draw_progress: bool,
-) -> Result<(), SelectionError> {
+) -> Result<Vec<UnreadableHdu>, SelectionError> {
     let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
     let shape = vis_sel.get_shape(fine_chans_per_coarse);
     let (num_timesteps, _, _) = shape;
@@ -244,15 +428,16 @@ pub fn read_mwalib(
         );
 
     // Load HDUs from each coarse channel. arrays: [timestep][chan][baseline]
-    jones_array
+    let unreadable_hdus = jones_array
         .axis_chunks_iter_mut(Axis(1), fine_chans_per_coarse)
         .into_par_iter()
         .zip(flag_array.axis_chunks_iter_mut(Axis(1), fine_chans_per_coarse))
         .zip(vis_sel.coarse_chan_range.clone())
         .zip(read_progress)
-        .try_for_each(
+        .map(
             |(((mut jones_array, mut flag_array), coarse_chan_idx), progress)| {
                 progress.set_position(0);
+                let mut unreadable_hdus = vec![];
 
                 // buffer: [baseline][chan][pol][complex]
                 let mut hdu_buffer: Vec<f32> = vec![0.0; floats_per_hdu];
@@ -301,6 +486,21 @@ pub fn read_mwalib(
                             );
                             flag_array.fill(true);
                         }
+                        Err(e) if tolerate_read_errors => {
+                            warn!(
+                                "Flagging unreadable HDU @ ts={}, cc={}: {}",
+                                timestep_idx, coarse_chan_idx, e
+                            );
+                            // nothing was read, so clear any visibilities left in the (reused)
+                            // jones array.
+                            jones_array.fill(Jones::default());
+                            flag_array.fill(true);
+                            unreadable_hdus.push(UnreadableHdu {
+                                timestep_idx,
+                                coarse_chan_idx,
+                                error: e.to_string(),
+                            });
+                        }
                         Err(e) => return Err(e),
                     }
 
@@ -308,14 +508,129 @@ pub fn read_mwalib(
                     total_progress.inc(1);
                 }
                 progress.finish();
-                Ok(())
+                Ok(unreadable_hdus)
             },
-        )?;
+        )
+        .collect::<Result<Vec<_>, _>>()?;
 
     // We're done!
     total_progress.finish();
 
-    Ok(())
+    Ok(unreadable_hdus.into_iter().flatten().collect())
+}
+
+/// Scale the weights for this selection by the fraction of data received, as recorded in the
+/// MWAX weights HDUs, and flag any baseline and timestep where this falls below `min_weight`.
+///
+/// With a `min_weight` of zero, nothing is flagged unless its weight is negative or NaN,
+/// but baselines with no data received still get a weight of zero.
+///
+/// Each visibility is scaled by the smallest weight of its polarisations. Missing HDUs are
+/// skipped, since their visibilities are already flagged by [`read_mwalib`]. Legacy correlator
+/// observations have no weights HDUs, so their weights are left unchanged.
+///
+/// Returns the number of baselines which were flagged for having too little data, counted once for
+/// each timestep and coarse channel.
+///
+/// # Errors
+///
+/// Can raise [`SelectionError::BadArrayShape`] if `weight_array` or `flag_array` does not match
+/// the expected shape of this selection. Unless `tolerate_read_errors` is set, a weights HDU which
+/// can't be read raises [`SelectionError::Mwalib`], otherwise its visibilities are flagged.
+pub fn read_mwalib_weights(
+    vis_sel: &VisSelection,
+    corr_ctx: &CorrelatorContext,
+    mut weight_array: ArrayViewMut3<f32>,
+    mut flag_array: ArrayViewMut3<bool>,
+    min_weight: f32,
+    tolerate_read_errors: bool,
+) -> Result<usize, SelectionError> {
+    let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+    let shape = vis_sel.get_shape(fine_chans_per_coarse);
+
+    // check output array dimensions
+    if weight_array.dim() != shape {
+        return Err(SelectionError::BadArrayShape {
+            argument: "weight_array".to_string(),
+            function: "read_mwalib_weights".to_string(),
+            expected: format!("{shape:?}"),
+            received: format!("{:?}", weight_array.dim()),
+        });
+    };
+
+    if flag_array.dim() != shape {
+        return Err(SelectionError::BadArrayShape {
+            argument: "flag_array".to_string(),
+            function: "read_mwalib_weights".to_string(),
+            expected: format!("{shape:?}"),
+            received: format!("{:?}", flag_array.dim()),
+        });
+    };
+
+    if corr_ctx.mwa_version != mwalib::MWAVersion::CorrMWAXv2 {
+        return Ok(0);
+    }
+
+    let num_pols = corr_ctx.metafits_context.num_visibility_pols;
+
+    // arrays: [timestep][chan][baseline]
+    let num_flagged = weight_array
+        .axis_chunks_iter_mut(Axis(1), fine_chans_per_coarse)
+        .into_par_iter()
+        .zip(flag_array.axis_chunks_iter_mut(Axis(1), fine_chans_per_coarse))
+        .zip(vis_sel.coarse_chan_range.clone())
+        .map(|((mut weight_array, mut flag_array), coarse_chan_idx)| {
+            let mut num_flagged = 0;
+
+            // buffer: [baseline][pol]
+            let mut weights_buffer = vec![0.0; corr_ctx.num_timestep_coarse_chan_weight_floats];
+
+            // arrays: [chan][baseline]
+            for (mut weight_array, mut flag_array, timestep_idx) in izip!(
+                weight_array.outer_iter_mut(),
+                flag_array.outer_iter_mut(),
+                vis_sel.timestep_range.clone(),
+            ) {
+                match corr_ctx.read_weights_by_baseline_into_buffer(
+                    timestep_idx,
+                    coarse_chan_idx,
+                    weights_buffer.as_mut_slice(),
+                ) {
+                    Ok(()) => {}
+                    Err(mwalib::GpuboxError::NoDataForTimeStepCoarseChannel { .. }) => continue,
+                    Err(e) if tolerate_read_errors => {
+                        warn!(
+                            "Flagging HDU with unreadable weights @ ts={}, cc={}: {}",
+                            timestep_idx, coarse_chan_idx, e
+                        );
+                        flag_array.fill(true);
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                }
+
+                // arrays: [chan]
+                for (mut weight_array, mut flag_array, &baseline_idx) in izip!(
+                    weight_array.axis_iter_mut(Axis(1)),
+                    flag_array.axis_iter_mut(Axis(1)),
+                    vis_sel.baseline_idxs.iter()
+                ) {
+                    let weight = weights_buffer[baseline_idx * num_pols..][..num_pols]
+                        .iter()
+                        .copied()
+                        .fold(f32::INFINITY, f32::min);
+                    weight_array.map_inplace(|w| *w *= weight.max(0.0));
+                    if weight < min_weight || weight.is_nan() {
+                        num_flagged += 1;
+                        flag_array.fill(true);
+                    }
+                }
+            }
+            Ok(num_flagged)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(num_flagged.into_iter().sum())
 }
 
 /// Write the given ndarrays of flags and [`Jones`] matrix visibilities to a
@@ -599,9 +914,145 @@ mod tests {
     use approx::assert_abs_diff_eq;
     use marlu::{Complex, Jones};
 
-    use crate::{compare_jones, test_common::get_mwax_context, VisSelection};
+    use crate::{
+        compare_jones,
+        test_common::{get_mwax_context, get_mwax_data_paths},
+        CorrelatorContext, VisSelection,
+    };
+    use tempfile::tempdir;
+
+    use super::{read_mwalib, read_mwalib_tolerant, read_mwalib_weights};
+
+    // test read_mwalib_tolerant flags a truncated HDU instead of stopping
+    #[test]
+    fn test_read_tolerant_truncated_hdu() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+        let tmp_dir = tempdir().unwrap();
+        let gpufits_paths: Vec<_> = gpufits_paths
+            .iter()
+            .map(|path| {
+                let tmp_path = tmp_dir
+                    .path()
+                    .join(std::path::Path::new(path).file_name().unwrap());
+                std::fs::copy(path, &tmp_path).unwrap();
+                tmp_path
+            })
+            .collect();
+        // truncate the last visibility HDU in the last file.
+        let truncated_path = gpufits_paths.last().unwrap();
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(truncated_path)
+            .unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - 3 * 2880).unwrap();
+        drop(file);
+
+        let corr_ctx = CorrelatorContext::new(metafits_path, &gpufits_paths).unwrap();
+        let vis_sel = VisSelection::from_mwalib(&corr_ctx).unwrap();
+        let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+        let mut flag_array = vis_sel.allocate_flags(fine_chans_per_coarse).unwrap();
+        let mut jones_array = vis_sel.allocate_jones(fine_chans_per_coarse).unwrap();
+
+        assert!(read_mwalib(
+            &vis_sel,
+            &corr_ctx,
+            jones_array.view_mut(),
+            flag_array.view_mut(),
+            false,
+        )
+        .is_err());
+
+        flag_array.fill(false);
+        let unreadable_hdus = read_mwalib_tolerant(
+            &vis_sel,
+            &corr_ctx,
+            jones_array.view_mut(),
+            flag_array.view_mut(),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(unreadable_hdus.len(), 1);
+        let hdu = &unreadable_hdus[0];
+        let ts_idx = hdu.timestep_idx - vis_sel.timestep_range.start;
+        let cc_idx = hdu.coarse_chan_idx - vis_sel.coarse_chan_range.start;
+        for (chan_idx, chan_flags) in flag_array
+            .index_axis(ndarray::Axis(0), ts_idx)
+            .outer_iter()
+            .enumerate()
+        {
+            let in_hdu = chan_idx / fine_chans_per_coarse == cc_idx;
+            assert!(chan_flags.iter().all(|&flag| flag == in_hdu));
+        }
+    }
 
-    use super::read_mwalib;
+    // test read_mwalib_weights scales weights by, and flags baselines with, packet loss
+    #[test]
+    fn test_read_weights_packet_loss() {
+        let (metafits_path, gpufits_paths) = get_mwax_data_paths();
+        let tmp_dir = tempdir().unwrap();
+        let gpufits_paths: Vec<_> = gpufits_paths
+            .iter()
+            .map(|path| {
+                let tmp_path = tmp_dir
+                    .path()
+                    .join(std::path::Path::new(path).file_name().unwrap());
+                std::fs::copy(path, &tmp_path).unwrap();
+                tmp_path
+            })
+            .collect();
+        // overwrite the weights of the first HDU in the first file: [baseline][pol]
+        let mut fptr = marlu::fitsio::FitsFile::edit(&gpufits_paths[0]).unwrap();
+        let hdu = fptr.hdu(2).unwrap();
+        let weights = [0.9, 1.0, 1.0, 1.0, 0.5, 0.5, 0.5, 0.5, 1.0, 1.0, 1.0, 1.0];
+        hdu.write_section(&mut fptr, 0, weights.len(), &weights)
+            .unwrap();
+        drop(fptr);
+
+        let corr_ctx = CorrelatorContext::new(metafits_path, &gpufits_paths).unwrap();
+        let vis_sel = VisSelection::from_mwalib(&corr_ctx).unwrap();
+        let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+        let mut flag_array = vis_sel.allocate_flags(fine_chans_per_coarse).unwrap();
+        let mut weight_array = vis_sel.allocate_weights(fine_chans_per_coarse).unwrap();
+        weight_array.fill(2.0);
+
+        let num_flagged = read_mwalib_weights(
+            &vis_sel,
+            &corr_ctx,
+            weight_array.view_mut(),
+            flag_array.view_mut(),
+            0.6,
+            false,
+        )
+        .unwrap();
+        assert_eq!(num_flagged, 1);
+
+        for ((ts_idx, chan_idx, bl_idx), &weight) in weight_array.indexed_iter() {
+            let in_hdu = ts_idx == 0 && chan_idx < fine_chans_per_coarse;
+            let (expected_weight, expected_flag) = match bl_idx {
+                0 if in_hdu => (1.8, false),
+                1 if in_hdu => (1.0, true),
+                _ => (2.0, false),
+            };
+            assert_abs_diff_eq!(weight, expected_weight);
+            assert_eq!(flag_array[(ts_idx, chan_idx, bl_idx)], expected_flag);
+        }
+
+        // with no minimum weight, nothing is flagged.
+        flag_array.fill(false);
+        let num_flagged = read_mwalib_weights(
+            &vis_sel,
+            &corr_ctx,
+            weight_array.view_mut(),
+            flag_array.view_mut(),
+            0.0,
+            false,
+        )
+        .unwrap();
+        assert_eq!(num_flagged, 0);
+        assert!(!flag_array.iter().any(|&f| f));
+    }
 
     // test read_mwalib with bad vis_sel.baseline_idxs
     #[test]
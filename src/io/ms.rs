@@ -0,0 +1,683 @@
+//! Reading visibilities from measurement sets.
+//!
+//! This reads measurement sets written by Birli (via [`marlu::io::ms`]) and Cotter, so that
+//! already-converted data can be flagged, calibrated, averaged and written again. Only a single
+//! spectral window and field, with linear polarisations (XX, XY, YX, YY) are supported.
+//!
+//! Measurement sets of drift scans, where each timestep has a different phase centre, are given
+//! a field for each timestep after they are written, with [`write_drift_fields`].
+
+use std::{
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use marlu::{
+    hifitime::{Duration, Epoch},
+    precession::precess_time,
+    rubbl_casatables::{Complex, Table, TableError, TableOpenMode},
+    Jones, LatLngHeight, RADec, VisContext, XyzGeodetic, UVW,
+};
+use ndarray::prelude::*;
+
+use super::{
+    check_read_vis_shapes,
+    error::{IOError, IOError::BadMs},
+    flag_missing_vis, VisRead,
+};
+use crate::ndarray;
+
+/// The number of linear polarisations (XX, XY, YX, YY) in a measurement set visibility.
+const NUM_POLS: usize = 4;
+
+/// The casacore Stokes types of the linear polarisations, in the same order as a [`Jones`].
+const LINEAR_CORR_TYPES: [i32; NUM_POLS] = [9, 10, 11, 12];
+
+/// The columns of the main table which visibilities can be read from.
+pub const MS_DATA_COLUMNS: [&str; 2] = ["DATA", "CORRECTED_DATA"];
+
+/// Metadata about the visibilities in a measurement set, and the means to read them.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use birli::io::{ms::MsReader, VisRead};
+///
+/// let reader = MsReader::new("1297526432.ms", "CORRECTED_DATA").unwrap();
+/// let shape = reader.get_shape();
+/// let mut jones_array = birli::Array3::zeros(shape);
+/// let mut weight_array = birli::Array3::zeros(shape);
+/// let mut flag_array = birli::Array3::from_elem(shape, false);
+/// reader
+///     .read_vis(
+///         jones_array.view_mut(),
+///         weight_array.view_mut(),
+///         flag_array.view_mut(),
+///         &(0..shape.0),
+///     )
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct MsReader {
+    /// The path to the measurement set
+    pub path: PathBuf,
+    /// The column of the main table which visibilities are read from
+    pub data_column: String,
+    /// The centroid of each timestep in the measurement set, in ascending order
+    pub timestamps: Vec<Epoch>,
+    /// The duration of each timestep
+    pub int_time: Duration,
+    /// The number of channels in the spectral window
+    pub num_chans: usize,
+    /// The centre frequency of the first channel [Hz]
+    pub start_freq_hz: f64,
+    /// The bandwidth of each channel [Hz]
+    pub freq_resolution_hz: f64,
+    /// The name of each antenna in the `ANTENNA` table
+    pub antenna_names: Vec<String>,
+    /// The (zero-indexed) antenna index pairs of each baseline in the measurement set, in
+    /// ascending order
+    pub ant_pairs: Vec<(usize, usize)>,
+    /// The phase centre of the field
+    pub phase_centre: RADec,
+    /// The name of the field, if any
+    pub field_name: Option<String>,
+    /// Whether the main table has a `WEIGHT_SPECTRUM` column, otherwise `WEIGHT` is used
+    has_weight_spectrum: bool,
+    /// The timestep and baseline index of each row in the main table
+    row_idxs: Vec<(usize, usize)>,
+}
+
+impl MsReader {
+    /// Read the metadata of a measurement set from the `ANTENNA`, `SPECTRAL_WINDOW`, `FIELD` and
+    /// `POLARIZATION` tables, and determine which timestep and baseline each row of the main
+    /// table belongs to.
+    ///
+    /// `data_column` is the column of the main table to read visibilities from, one of
+    /// [`MS_DATA_COLUMNS`].
+    ///
+    /// # Errors
+    ///
+    /// Will throw [`IOError`] if the tables can't be read, `data_column` isn't present, or the
+    /// measurement set doesn't contain linearly polarised visibilities.
+    pub fn new<T: AsRef<Path>>(path: T, data_column: &str) -> Result<Self, IOError> {
+        let path = path.as_ref().to_path_buf();
+        let bad_ms = |message: String| BadMs {
+            ms_filename: path.clone(),
+            message,
+            source_file: file!(),
+            source_line: line!(),
+        };
+
+        let mut main_table = Table::open(&path, TableOpenMode::Read)?;
+        let column_names = main_table.column_names().map_err(TableError::from)?;
+        if !MS_DATA_COLUMNS.contains(&data_column)
+            || !column_names.iter().any(|name| name == data_column)
+        {
+            return Err(bad_ms(format!(
+                "no data column {data_column}, expected one of {MS_DATA_COLUMNS:?}"
+            )));
+        }
+        let has_weight_spectrum = column_names.iter().any(|name| name == "WEIGHT_SPECTRUM");
+
+        let mut pol_table = Table::open(path.join("POLARIZATION"), TableOpenMode::Read)?;
+        let corr_types: Vec<i32> = pol_table.get_cell_as_vec("CORR_TYPE", 0)?;
+        if corr_types != LINEAR_CORR_TYPES {
+            return Err(bad_ms(format!(
+                "expected CORR_TYPE {LINEAR_CORR_TYPES:?} (XX, XY, YX, YY), found {corr_types:?}"
+            )));
+        }
+
+        let mut spw_table = Table::open(path.join("SPECTRAL_WINDOW"), TableOpenMode::Read)?;
+        if spw_table.n_rows() != 1 {
+            return Err(bad_ms(format!(
+                "expected 1 spectral window, found {}",
+                spw_table.n_rows()
+            )));
+        }
+        let chan_freqs: Vec<f64> = spw_table.get_cell_as_vec("CHAN_FREQ", 0)?;
+        let chan_widths: Vec<f64> = spw_table.get_cell_as_vec("CHAN_WIDTH", 0)?;
+        let (start_freq_hz, freq_resolution_hz) = match (chan_freqs.first(), chan_widths.first()) {
+            (Some(&start_freq_hz), Some(&freq_resolution_hz)) => {
+                (start_freq_hz, freq_resolution_hz)
+            }
+            _ => return Err(bad_ms("spectral window has no channels".into())),
+        };
+
+        let mut field_table = Table::open(path.join("FIELD"), TableOpenMode::Read)?;
+        if field_table.n_rows() != 1 {
+            return Err(bad_ms(format!(
+                "expected 1 field, found {}",
+                field_table.n_rows()
+            )));
+        }
+        let phase_dir: Vec<f64> = field_table.get_cell_as_vec("PHASE_DIR", 0)?;
+        let phase_centre = match phase_dir.as_slice() {
+            [ra_rad, dec_rad] => RADec::from_radians(*ra_rad, *dec_rad),
+            _ => return Err(bad_ms(format!("unexpected PHASE_DIR {phase_dir:?}"))),
+        };
+        let field_name: String = field_table.get_cell("NAME", 0)?;
+
+        let mut ant_table = Table::open(path.join("ANTENNA"), TableOpenMode::Read)?;
+        let antenna_names: Vec<String> = ant_table.get_col_as_vec("NAME")?;
+
+        // Determine the timestep and baseline of each row.
+        let times: Vec<f64> = main_table.get_col_as_vec("TIME")?;
+        let ant1s: Vec<i32> = main_table.get_col_as_vec("ANTENNA1")?;
+        let ant2s: Vec<i32> = main_table.get_col_as_vec("ANTENNA2")?;
+        let row_timestamps = times
+            .iter()
+            .map(|&mjd_s| {
+                Epoch::from_mjd_utc(mjd_s / 86400.).round(Duration::from_milliseconds(1.0))
+            })
+            .collect::<Vec<_>>();
+        let mut row_ant_pairs = Vec::with_capacity(ant1s.len());
+        for (row_idx, (&ant1, &ant2)) in ant1s.iter().zip(ant2s.iter()).enumerate() {
+            match (usize::try_from(ant1), usize::try_from(ant2)) {
+                (Ok(ant1), Ok(ant2))
+                    if ant1 < antenna_names.len() && ant2 < antenna_names.len() =>
+                {
+                    row_ant_pairs.push((ant1, ant2));
+                }
+                _ => {
+                    return Err(bad_ms(format!(
+                        "row {row_idx} has invalid antennas ({ant1}, {ant2}) for {} antennas",
+                        antenna_names.len()
+                    )))
+                }
+            }
+        }
+
+        let mut timestamps = row_timestamps.clone();
+        timestamps.sort_unstable();
+        timestamps.dedup();
+        let mut ant_pairs = row_ant_pairs.clone();
+        ant_pairs.sort_unstable();
+        ant_pairs.dedup();
+        let row_idxs = row_timestamps
+            .iter()
+            .zip(row_ant_pairs.iter())
+            .map(|(timestamp, ant_pair)| {
+                (
+                    timestamps.binary_search(timestamp).unwrap(),
+                    ant_pairs.binary_search(ant_pair).unwrap(),
+                )
+            })
+            .collect();
+
+        if timestamps.is_empty() {
+            return Err(bad_ms("main table has no rows".into()));
+        }
+        let int_time_s: f64 = main_table.get_cell("INTERVAL", 0)?;
+
+        Ok(Self {
+            path,
+            data_column: data_column.to_string(),
+            timestamps,
+            int_time: Duration::from_seconds(int_time_s),
+            num_chans: chan_freqs.len(),
+            start_freq_hz,
+            freq_resolution_hz,
+            antenna_names,
+            ant_pairs,
+            phase_centre,
+            field_name: Some(field_name).filter(|name| !name.is_empty()),
+            has_weight_spectrum,
+            row_idxs,
+        })
+    }
+}
+
+impl VisRead for MsReader {
+    fn get_path(&self) -> &Path {
+        &self.path
+    }
+
+    fn get_ant_pairs(&self) -> &[(usize, usize)] {
+        &self.ant_pairs
+    }
+
+    fn get_phase_centre(&self) -> RADec {
+        self.phase_centre
+    }
+
+    fn get_shape(&self) -> (usize, usize, usize) {
+        (self.timestamps.len(), self.num_chans, self.ant_pairs.len())
+    }
+
+    fn get_vis_ctx(
+        &self,
+        timestep_range: &Range<usize>,
+        avg_time: usize,
+        avg_freq: usize,
+    ) -> VisContext {
+        VisContext {
+            num_sel_timesteps: timestep_range.len(),
+            start_timestamp: self.timestamps[timestep_range.start] - self.int_time / 2,
+            int_time: self.int_time,
+            num_sel_chans: self.num_chans,
+            start_freq_hz: self.start_freq_hz,
+            freq_resolution_hz: self.freq_resolution_hz,
+            sel_baselines: self.ant_pairs.clone(),
+            avg_time,
+            avg_freq,
+            num_vis_pols: NUM_POLS,
+        }
+    }
+
+    /// Visibilities which are flagged in any polarisation (`FLAG`), in flagged rows (`FLAG_ROW`),
+    /// or with a weight which is not positive are flagged. The weight of each visibility is its XX
+    /// weight from `WEIGHT_SPECTRUM`, or `WEIGHT` if that is not present.
+    fn read_vis(
+        &self,
+        mut jones_array: ArrayViewMut3<Jones<f32>>,
+        mut weight_array: ArrayViewMut3<f32>,
+        mut flag_array: ArrayViewMut3<bool>,
+        timestep_range: &Range<usize>,
+    ) -> Result<(), IOError> {
+        let shape = (timestep_range.len(), self.num_chans, self.ant_pairs.len());
+        check_read_vis_shapes(
+            "MsReader::read_vis",
+            shape,
+            &jones_array,
+            &weight_array,
+            &flag_array,
+        )?;
+
+        // Anything which isn't in the measurement set stays flagged.
+        let mut missing = Array2::from_elem((shape.0, shape.2), true);
+
+        let mut main_table = Table::open(&self.path, TableOpenMode::Read)?;
+        for (row_idx, &(timestep_idx, baseline_idx)) in self.row_idxs.iter().enumerate() {
+            if !timestep_range.contains(&timestep_idx) {
+                continue;
+            }
+            let timestep_idx = timestep_idx - timestep_range.start;
+            let row_idx = row_idx as u64;
+            let row_vis: Vec<Complex<f32>> =
+                main_table.get_cell_as_vec(&self.data_column, row_idx)?;
+            let row_flags: Vec<bool> = main_table.get_cell_as_vec("FLAG", row_idx)?;
+            let row_flagged: bool = main_table.get_cell("FLAG_ROW", row_idx)?;
+            let row_weights: Vec<f32> = if self.has_weight_spectrum {
+                main_table.get_cell_as_vec("WEIGHT_SPECTRUM", row_idx)?
+            } else {
+                let pol_weights: Vec<f32> = main_table.get_cell_as_vec("WEIGHT", row_idx)?;
+                pol_weights
+                    .iter()
+                    .copied()
+                    .cycle()
+                    .take(self.num_chans * NUM_POLS)
+                    .collect()
+            };
+            missing[(timestep_idx, baseline_idx)] = false;
+            for (chan_idx, (chan_vis, chan_flags, chan_weights)) in itertools::izip!(
+                row_vis.chunks_exact(NUM_POLS),
+                row_flags.chunks_exact(NUM_POLS),
+                row_weights.chunks_exact(NUM_POLS),
+            )
+            .enumerate()
+            {
+                let jones = &mut jones_array[(timestep_idx, chan_idx, baseline_idx)];
+                for (pol_idx, vis) in chan_vis.iter().enumerate() {
+                    jones[pol_idx].re = vis.re;
+                    jones[pol_idx].im = vis.im;
+                }
+                let flagged = row_flagged
+                    || chan_flags.iter().any(|&flag| flag)
+                    || chan_weights
+                        .iter()
+                        .any(|&weight| weight <= 0.0 || weight.is_nan());
+                weight_array[(timestep_idx, chan_idx, baseline_idx)] = chan_weights[0].abs();
+                flag_array[(timestep_idx, chan_idx, baseline_idx)] |= flagged;
+            }
+        }
+
+        flag_missing_vis(missing.view(), jones_array, weight_array, flag_array);
+
+        Ok(())
+    }
+}
+
+/// Give each averaged timestep of a measurement set written by
+/// [`marlu::io::ms::MeasurementSetWriter`] its own field, for drift scans where each timestep is
+/// phased to a different centre.
+///
+/// The writer only knows a single phase centre, so the `FIELD` table is extended to a row for each
+/// averaged timestep of `vis_ctx` with the phase centre in `phase_centres`, copying the rest of the
+/// first row. The `FIELD_ID` of each row in the main table is set to its timestep, and its `UVW`
+/// is recalculated for the phase centre of its timestep, the same way the writer calculates them.
+///
+/// # Errors
+///
+/// Will throw [`IOError::BadMs`] if the main table doesn't have a row for each averaged timestep
+/// and baseline of `vis_ctx`, or there isn't a phase centre for each averaged timestep. Will
+/// throw [`IOError::TableError`] or [`IOError::CasacoreError`] if the tables can't be read or
+/// written.
+pub fn write_drift_fields<P: AsRef<Path>>(
+    path: P,
+    vis_ctx: &VisContext,
+    phase_centres: &[RADec],
+    array_pos: LatLngHeight,
+    tile_xyzs: &[XyzGeodetic],
+    dut1: Duration,
+) -> Result<(), IOError> {
+    let path = path.as_ref();
+    let bad_ms = |message: String| BadMs {
+        ms_filename: path.to_path_buf(),
+        message,
+        source_file: file!(),
+        source_line: line!(),
+    };
+    let num_avg_timesteps = vis_ctx.num_avg_timesteps();
+    let num_baselines = vis_ctx.sel_baselines.len();
+    if phase_centres.len() != num_avg_timesteps {
+        return Err(bad_ms(format!(
+            "expected a phase centre for each of {num_avg_timesteps} timesteps, found {}",
+            phase_centres.len()
+        )));
+    }
+    let mut main_table = Table::open(path, TableOpenMode::ReadWrite)?;
+    if main_table.n_rows() != (num_avg_timesteps * num_baselines) as u64 {
+        return Err(bad_ms(format!(
+            "expected {num_avg_timesteps} timesteps of {num_baselines} baselines, found {} rows",
+            main_table.n_rows()
+        )));
+    }
+
+    let mut field_table = Table::open(path.join("FIELD"), TableOpenMode::ReadWrite)?;
+    if num_avg_timesteps > 1 {
+        field_table.add_rows(num_avg_timesteps - 1)?;
+        let mut field_row = field_table.get_row_writer()?;
+        field_table.read_row(&mut field_row, 0)?;
+        for field_idx in 1..num_avg_timesteps {
+            field_row.put(field_idx as u64)?;
+        }
+    }
+
+    for (timestep_idx, (centroid, &phase_centre)) in vis_ctx
+        .timeseries(true, true)
+        .zip(phase_centres)
+        .enumerate()
+    {
+        let field_idx = timestep_idx as u64;
+        let dir_info = array![[phase_centre.ra, phase_centre.dec]];
+        for col_name in ["DELAY_DIR", "PHASE_DIR", "REFERENCE_DIR"] {
+            field_table.put_cell(col_name, field_idx, &dir_info)?;
+        }
+        field_table.put_cell("TIME", field_idx, &centroid.to_mjd_utc_seconds())?;
+
+        let prec_info = precess_time(
+            array_pos.longitude_rad,
+            array_pos.latitude_rad,
+            phase_centre,
+            centroid,
+            dut1,
+        );
+        let tile_xyzs_prec = prec_info.precess_xyz(tile_xyzs);
+        for (baseline_idx, &(ant1, ant2)) in vis_ctx.sel_baselines.iter().enumerate() {
+            let row_idx = (timestep_idx * num_baselines + baseline_idx) as u64;
+            let uvw = UVW::from_xyz(
+                tile_xyzs_prec[ant1] - tile_xyzs_prec[ant2],
+                prec_info.hadec_j2000,
+            );
+            main_table.put_cell("UVW", row_idx, &vec![uvw.u, uvw.v, uvw.w])?;
+            main_table.put_cell("FIELD_ID", row_idx, &(timestep_idx as i32))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use marlu::{
+        hifitime::{Duration, Epoch},
+        io::{ms::MeasurementSetWriter, VisWrite},
+        rubbl_casatables::{Table, TableOpenMode},
+        Jones, LatLngHeight, ObsContext, RADec, VisContext, XyzGeodetic, ENH,
+    };
+    use ndarray::prelude::*;
+    use tempfile::tempdir;
+
+    use super::{write_drift_fields, MsReader};
+    use crate::{
+        approx::assert_abs_diff_eq,
+        io::{error::IOError, VisRead},
+        ndarray,
+    };
+
+    #[test]
+    fn test_read_ms_written_by_marlu() {
+        let tmp_dir = tempdir().unwrap();
+        let ms_path = tmp_dir.path().join("test.ms");
+
+        let num_ants = 3;
+        let sel_baselines = (0..num_ants)
+            .flat_map(|ant1| (ant1..num_ants).map(move |ant2| (ant1, ant2)))
+            .collect::<Vec<_>>();
+        let vis_ctx = VisContext {
+            num_sel_timesteps: 3,
+            start_timestamp: Epoch::from_gpst_seconds(1297526432.),
+            int_time: Duration::from_seconds(2.),
+            num_sel_chans: 4,
+            start_freq_hz: 167_000_000.,
+            freq_resolution_hz: 40_000.,
+            sel_baselines,
+            avg_time: 1,
+            avg_freq: 1,
+            num_vis_pols: 4,
+        };
+        let shape = vis_ctx.sel_dims();
+        let jones_array = Array3::from_shape_fn(shape, |(t, c, b)| {
+            let base = (t * 100 + c * 10 + b) as f32;
+            Jones::from([
+                base,
+                -base,
+                base + 0.1,
+                -base - 0.1,
+                base + 0.2,
+                -base - 0.2,
+                base + 0.3,
+                -base - 0.3,
+            ])
+        });
+        // flag the second channel of the first baseline in every timestep
+        let weight_array =
+            Array3::from_shape_fn(shape, |(_, c, b)| if c == 1 && b == 0 { -8. } else { 8. });
+
+        let phase_centre = RADec::from_degrees(15., -27.);
+        let array_pos = LatLngHeight::mwa();
+        let obs_ctx = ObsContext {
+            sched_start_timestamp: vis_ctx.start_timestamp,
+            sched_duration: vis_ctx.int_time * vis_ctx.num_sel_timesteps as f64,
+            name: Some("test_obs".into()),
+            field_name: Some("test_field".into()),
+            project_id: None,
+            observer: None,
+            phase_centre,
+            pointing_centre: None,
+            array_pos,
+            ant_positions_enh: vec![ENH::default(); num_ants],
+            ant_names: (0..num_ants).map(|i| format!("Tile{i:03}")).collect(),
+        };
+        let mut writer = MeasurementSetWriter::new(
+            &ms_path,
+            phase_centre,
+            array_pos,
+            vec![XyzGeodetic::default(); num_ants],
+            Duration::from_seconds(0.),
+            true,
+        );
+        writer.initialize(&vis_ctx, &obs_ctx, None).unwrap();
+        writer
+            .write_vis(jones_array.view(), weight_array.view(), &vis_ctx)
+            .unwrap();
+        writer.finalise().unwrap();
+
+        let reader = MsReader::new(&ms_path, "DATA").unwrap();
+        assert_eq!(reader.get_shape(), shape);
+        assert_eq!(reader.ant_pairs, vis_ctx.sel_baselines);
+        assert_eq!(reader.antenna_names, obs_ctx.ant_names);
+        assert_eq!(reader.field_name.as_deref(), Some("test_field"));
+        assert_eq!(reader.int_time, vis_ctx.int_time);
+        assert_eq!(
+            reader.timestamps,
+            vis_ctx.timeseries(false, true).collect::<Vec<_>>()
+        );
+        assert_abs_diff_eq!(reader.start_freq_hz, vis_ctx.start_freq_hz);
+        assert_abs_diff_eq!(reader.freq_resolution_hz, vis_ctx.freq_resolution_hz);
+        assert_abs_diff_eq!(reader.phase_centre.ra, phase_centre.ra, epsilon = 1e-10);
+        assert_abs_diff_eq!(reader.phase_centre.dec, phase_centre.dec, epsilon = 1e-10);
+
+        // read the last two timesteps
+        let timestep_range = 1..3;
+        let sel_shape = (timestep_range.len(), shape.1, shape.2);
+        let mut read_jones = Array3::zeros(sel_shape);
+        let mut read_weights = Array3::zeros(sel_shape);
+        let mut read_flags = Array3::from_elem(sel_shape, false);
+        reader
+            .read_vis(
+                read_jones.view_mut(),
+                read_weights.view_mut(),
+                read_flags.view_mut(),
+                &timestep_range,
+            )
+            .unwrap();
+
+        assert_abs_diff_eq!(
+            read_jones,
+            jones_array.slice(s![timestep_range.clone(), .., ..])
+        );
+        assert_abs_diff_eq!(read_weights, Array3::from_elem(sel_shape, 8.));
+        for ((_, c, b), &flag) in read_flags.indexed_iter() {
+            assert_eq!(flag, c == 1 && b == 0);
+        }
+
+        assert!(MsReader::new(&ms_path, "CORRECTED_DATA").is_err());
+    }
+
+    #[test]
+    fn test_write_drift_fields() {
+        let tmp_dir = tempdir().unwrap();
+        let num_ants = 3;
+        let vis_ctx = VisContext {
+            num_sel_timesteps: 3,
+            start_timestamp: Epoch::from_gpst_seconds(1297526432.),
+            int_time: Duration::from_seconds(2.),
+            num_sel_chans: 2,
+            start_freq_hz: 167_000_000.,
+            freq_resolution_hz: 40_000.,
+            sel_baselines: vec![(0, 1), (0, 2), (1, 2)],
+            avg_time: 1,
+            avg_freq: 1,
+            num_vis_pols: 4,
+        };
+        let shape = vis_ctx.sel_dims();
+        let array_pos = LatLngHeight::mwa();
+        let tile_xyzs = vec![
+            XyzGeodetic {
+                x: 0.,
+                y: 0.,
+                z: 0.,
+            },
+            XyzGeodetic {
+                x: 100.,
+                y: -20.,
+                z: 5.,
+            },
+            XyzGeodetic {
+                x: -30.,
+                y: 200.,
+                z: -10.,
+            },
+        ];
+        let dut1 = Duration::from_seconds(0.);
+        let phase_centres = [
+            RADec::from_degrees(10., -26.7),
+            RADec::from_degrees(10.5, -26.7),
+            RADec::from_degrees(11., -26.7),
+        ];
+        // write a measurement set phased to `phase_centre`, returning its path.
+        let write_ms = |name: &str, phase_centre: RADec| {
+            let ms_path = tmp_dir.path().join(name);
+            let obs_ctx = ObsContext {
+                sched_start_timestamp: vis_ctx.start_timestamp,
+                sched_duration: vis_ctx.int_time * vis_ctx.num_sel_timesteps as f64,
+                name: Some("test_obs".into()),
+                field_name: Some("test_field".into()),
+                project_id: None,
+                observer: None,
+                phase_centre,
+                pointing_centre: None,
+                array_pos,
+                ant_positions_enh: vec![ENH::default(); num_ants],
+                ant_names: (0..num_ants).map(|i| format!("Tile{i:03}")).collect(),
+            };
+            let mut writer = MeasurementSetWriter::new(
+                &ms_path,
+                phase_centre,
+                array_pos,
+                tile_xyzs.clone(),
+                dut1,
+                true,
+            );
+            writer.initialize(&vis_ctx, &obs_ctx, None).unwrap();
+            writer
+                .write_vis(
+                    Array3::from_elem(shape, Jones::identity()).view(),
+                    Array3::from_elem(shape, 1.).view(),
+                    &vis_ctx,
+                )
+                .unwrap();
+            writer.finalise().unwrap();
+            ms_path
+        };
+
+        let drift_path = write_ms("drift.ms", phase_centres[0]);
+        assert!(matches!(
+            write_drift_fields(
+                &drift_path,
+                &vis_ctx,
+                &phase_centres[..2],
+                array_pos,
+                &tile_xyzs,
+                dut1
+            ),
+            Err(IOError::BadMs { .. })
+        ));
+        write_drift_fields(
+            &drift_path,
+            &vis_ctx,
+            &phase_centres,
+            array_pos,
+            &tile_xyzs,
+            dut1,
+        )
+        .unwrap();
+
+        let mut field_table = Table::open(drift_path.join("FIELD"), TableOpenMode::Read).unwrap();
+        assert_eq!(field_table.n_rows(), 3);
+        for (field_idx, phase_centre) in phase_centres.iter().enumerate() {
+            let phase_dir: Vec<f64> = field_table
+                .get_cell_as_vec("PHASE_DIR", field_idx as u64)
+                .unwrap();
+            assert_abs_diff_eq!(phase_dir[0], phase_centre.ra, epsilon = 1e-10);
+            assert_abs_diff_eq!(phase_dir[1], phase_centre.dec, epsilon = 1e-10);
+            let name: String = field_table.get_cell("NAME", field_idx as u64).unwrap();
+            assert_eq!(name, "test_field");
+        }
+        let mut drift_table = Table::open(&drift_path, TableOpenMode::Read).unwrap();
+        let field_ids: Vec<i32> = drift_table.get_col_as_vec("FIELD_ID").unwrap();
+        assert_eq!(field_ids, vec![0, 0, 0, 1, 1, 1, 2, 2, 2]);
+
+        // each timestep has the same UVWs as if the whole observation was phased to its centre.
+        for (timestep_idx, &phase_centre) in phase_centres.iter().enumerate() {
+            let fixed_path = write_ms(&format!("fixed{timestep_idx}.ms"), phase_centre);
+            let mut fixed_table = Table::open(&fixed_path, TableOpenMode::Read).unwrap();
+            for row_idx in (timestep_idx * 3..(timestep_idx + 1) * 3).map(|row| row as u64) {
+                let drift_uvw: Vec<f64> = drift_table.get_cell_as_vec("UVW", row_idx).unwrap();
+                let fixed_uvw: Vec<f64> = fixed_table.get_cell_as_vec("UVW", row_idx).unwrap();
+                assert_abs_diff_eq!(drift_uvw.as_slice(), fixed_uvw.as_slice(), epsilon = 1e-9);
+            }
+        }
+    }
+}
@@ -0,0 +1,119 @@
+//! IO for coarse pfb passband gains, in the plain text subband passband format read by Cotter's
+//! `-sbpassband` option.
+//!
+//! Each line holds a fine channel index, followed by the gains of the XX, XY, YX and YY
+//! polarisations of that channel, separated by whitespace.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use super::error::IOError;
+
+/// Read passband gains for each fine channel of a coarse channel from `path`. Birli applies the
+/// same gain to every polarisation, so only the XX column is used.
+///
+/// # Errors
+///
+/// Will throw [`IOError::BadPassbandGains`] if a line doesn't have a channel index and four
+/// gains, the channel indices aren't consecutive from zero, or the file is empty.
+pub fn read_passband_gains<P: AsRef<Path>>(path: P) -> Result<Vec<f64>, IOError> {
+    let path = path.as_ref();
+    let bad_line = |line: usize, message: String| IOError::BadPassbandGains {
+        path: path.to_path_buf(),
+        line,
+        message,
+    };
+    let mut gains = vec![];
+    for (line_idx, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        if fields.is_empty() {
+            continue;
+        }
+        if fields.len() != 5 {
+            return Err(bad_line(
+                line_idx + 1,
+                format!("expected a channel index and 4 gains, got '{line}'"),
+            ));
+        }
+        match fields[0].parse::<usize>() {
+            Ok(chan) if chan == gains.len() => {}
+            _ => {
+                return Err(bad_line(
+                    line_idx + 1,
+                    format!(
+                        "expected channel index {}, got '{}'",
+                        gains.len(),
+                        fields[0]
+                    ),
+                ))
+            }
+        }
+        gains.push(fields[1].parse::<f64>().map_err(|_| {
+            bad_line(
+                line_idx + 1,
+                format!("could not parse '{}' as a gain", fields[1]),
+            )
+        })?);
+    }
+    if gains.is_empty() {
+        return Err(bad_line(0, "no gains found".into()));
+    }
+    Ok(gains)
+}
+
+/// Write passband gains for each fine channel of a coarse channel to `path`, with the same gain
+/// for every polarisation.
+///
+/// # Errors
+///
+/// Will throw [`IOError::IO`] if the file can't be written.
+pub fn write_passband_gains<P: AsRef<Path>>(path: P, gains: &[f64]) -> Result<(), IOError> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    for (chan, gain) in gains.iter().enumerate() {
+        writeln!(writer, "{chan} {gain:.11} {gain:.11} {gain:.11} {gain:.11}")?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::passband_gains::PFB_COTTER_2014_10KHZ;
+    use approx::assert_abs_diff_eq;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_read_cotter_passband_gains() {
+        let gains = read_passband_gains("tests/data/subband-passband-32ch-cotter.txt").unwrap();
+        assert_eq!(gains.len(), 32);
+        assert_abs_diff_eq!(gains[0], 0.50828905083);
+    }
+
+    #[test]
+    fn test_passband_gains_round_trip() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("passband.txt");
+        write_passband_gains(&path, PFB_COTTER_2014_10KHZ).unwrap();
+        let gains = read_passband_gains(&path).unwrap();
+        assert_eq!(gains.len(), PFB_COTTER_2014_10KHZ.len());
+        for (&gain, &expected) in gains.iter().zip(PFB_COTTER_2014_10KHZ) {
+            assert_abs_diff_eq!(gain, expected, epsilon = 1e-10);
+        }
+
+        std::fs::write(&path, "0 1 1 1 1\n2 1 1 1 1\n").unwrap();
+        assert!(matches!(
+            read_passband_gains(&path),
+            Err(IOError::BadPassbandGains { line: 2, .. })
+        ));
+        std::fs::write(&path, "0 1 1\n").unwrap();
+        assert!(matches!(
+            read_passband_gains(&path),
+            Err(IOError::BadPassbandGains { line: 1, .. })
+        ));
+    }
+}
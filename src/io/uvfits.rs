@@ -0,0 +1,498 @@
+//! Reading visibilities from uvfits files.
+//!
+//! This reads the random groups uvfits files written by Birli (via [`marlu::io::uvfits`]) and
+//! Cotter, so that already-converted data can be flagged, calibrated, averaged and written again.
+//! Only linear polarisations (XX, YY, XY, YX) are supported.
+
+use std::{
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use marlu::{
+    fitsio::{self, FitsFile},
+    fitsio_sys,
+    hifitime::{Duration, Epoch},
+    io::uvfits::decode_uvfits_baseline,
+    mwalib::{
+        _get_optional_fits_key, _get_required_fits_key, _open_fits, _open_hdu, fits_open,
+        fits_open_hdu, get_optional_fits_key, get_required_fits_key,
+    },
+    Jones, RADec, VisContext,
+};
+use ndarray::prelude::*;
+
+use super::{
+    check_read_vis_shapes,
+    error::{
+        IOError,
+        IOError::{BadUvfits, FitsIO},
+    },
+    flag_missing_vis, VisRead,
+};
+use crate::ndarray;
+
+/// The number of floats (real, imaginary, weight) for each polarisation in a uvfits visibility.
+const NUM_FLOATS_PER_POL: usize = 3;
+
+/// The number of linear polarisations (XX, YY, XY, YX) in a uvfits visibility.
+const NUM_POLS: usize = 4;
+
+/// The indices into a [`Jones`] of each uvfits polarisation, in the order XX, YY, XY, YX.
+const POL_JONES_IDXS: [usize; NUM_POLS] = [0, 3, 1, 2];
+
+/// Metadata about the visibilities in a uvfits file, and the means to read them.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use birli::io::{uvfits::UvfitsReader, VisRead};
+///
+/// let reader = UvfitsReader::new("1297526432.uvfits").unwrap();
+/// let shape = reader.get_shape();
+/// let mut jones_array = birli::Array3::zeros(shape);
+/// let mut weight_array = birli::Array3::zeros(shape);
+/// let mut flag_array = birli::Array3::from_elem(shape, false);
+/// reader
+///     .read_vis(
+///         jones_array.view_mut(),
+///         weight_array.view_mut(),
+///         flag_array.view_mut(),
+///         &(0..shape.0),
+///     )
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct UvfitsReader {
+    /// The path to the uvfits file
+    pub path: PathBuf,
+    /// The centroid of each timestep in the file, in ascending order
+    pub timestamps: Vec<Epoch>,
+    /// The duration of each timestep
+    pub int_time: Duration,
+    /// The number of channels in the file
+    pub num_chans: usize,
+    /// The centre frequency of the first channel [Hz]
+    pub start_freq_hz: f64,
+    /// The bandwidth of each channel [Hz]
+    pub freq_resolution_hz: f64,
+    /// The (zero-indexed) antenna index pairs of each baseline in the file, in ascending order
+    pub ant_pairs: Vec<(usize, usize)>,
+    /// The phase centre of the visibilities
+    pub phase_centre: RADec,
+    /// The name of the observation (`OBJECT`), if any
+    pub obs_name: Option<String>,
+    /// The timestep and baseline index of each row (random group) in the file
+    row_idxs: Vec<(usize, usize)>,
+}
+
+impl UvfitsReader {
+    /// Read the metadata of a uvfits file, and determine which timestep and baseline each
+    /// row in the file belongs to.
+    ///
+    /// # Errors
+    ///
+    /// Will throw [`IOError`] if the file can't be read, or it doesn't contain linearly polarised
+    /// visibilities with `BASELINE` and `DATE` group parameters.
+    pub fn new<T: AsRef<Path>>(path: T) -> Result<Self, IOError> {
+        let path = path.as_ref().to_path_buf();
+        let bad_uvfits = |message: String| BadUvfits {
+            uvfits_filename: path.clone(),
+            message,
+            source_file: file!(),
+            source_line: line!(),
+        };
+
+        let mut fptr = fits_open!(&path)?;
+        let hdu = fits_open_hdu!(&mut fptr, 0)?;
+
+        let num_floats_per_pol: usize = get_required_fits_key!(&mut fptr, &hdu, "NAXIS2")?;
+        let num_pols: usize = get_required_fits_key!(&mut fptr, &hdu, "NAXIS3")?;
+        if num_floats_per_pol != NUM_FLOATS_PER_POL || num_pols != NUM_POLS {
+            return Err(bad_uvfits(format!(
+                "expected {NUM_POLS} polarisations of {NUM_FLOATS_PER_POL} floats, \
+                found {num_pols} polarisations of {num_floats_per_pol} floats"
+            )));
+        }
+        let num_chans: usize = get_required_fits_key!(&mut fptr, &hdu, "NAXIS4")?;
+        let num_rows: usize = get_required_fits_key!(&mut fptr, &hdu, "GCOUNT")?;
+        let num_params: usize = get_required_fits_key!(&mut fptr, &hdu, "PCOUNT")?;
+
+        // Find the group parameters we care about, and how to scale them.
+        let mut baseline_param = None;
+        let mut date_params = vec![];
+        let mut inttim_param = None;
+        let mut date_zero_days = 0.0;
+        let mut param_scales = vec![1.0; num_params];
+        for (param_idx, param_scale) in param_scales.iter_mut().enumerate() {
+            let ptype: String =
+                get_required_fits_key!(&mut fptr, &hdu, &format!("PTYPE{}", param_idx + 1))?;
+            *param_scale =
+                get_optional_fits_key!(&mut fptr, &hdu, &format!("PSCAL{}", param_idx + 1))?
+                    .unwrap_or(1.0);
+            let pzero: f64 =
+                get_optional_fits_key!(&mut fptr, &hdu, &format!("PZERO{}", param_idx + 1))?
+                    .unwrap_or(0.0);
+            match ptype.trim() {
+                "BASELINE" => baseline_param = Some(param_idx),
+                "DATE" => {
+                    date_params.push(param_idx);
+                    date_zero_days += pzero;
+                }
+                "INTTIM" => inttim_param = Some(param_idx),
+                _ => {}
+            }
+        }
+        let baseline_param =
+            baseline_param.ok_or_else(|| bad_uvfits("no BASELINE group parameter".into()))?;
+        if date_params.is_empty() {
+            return Err(bad_uvfits("no DATE group parameter".into()));
+        }
+
+        // Read the baseline and date of each row.
+        let date_zero = Epoch::from_jde_utc(date_zero_days);
+        let mut group_params = vec![0.0_f32; num_params];
+        let mut row_ant_pairs = Vec::with_capacity(num_rows);
+        let mut row_timestamps = Vec::with_capacity(num_rows);
+        let mut int_time_secs = None;
+        for row_idx in 0..num_rows {
+            read_group_params(&mut fptr, row_idx, &mut group_params)?;
+            let baseline = group_params[baseline_param] as f64 * param_scales[baseline_param];
+            let (ant1, ant2) = decode_uvfits_baseline(baseline.round() as usize);
+            if ant1 == 0 || ant2 == 0 {
+                return Err(bad_uvfits(format!(
+                    "row {row_idx} has an invalid BASELINE {baseline}"
+                )));
+            }
+            row_ant_pairs.push((ant1 - 1, ant2 - 1));
+            let date_offset_days: f64 = date_params
+                .iter()
+                .map(|&param_idx| group_params[param_idx] as f64 * param_scales[param_idx])
+                .sum();
+            row_timestamps.push(
+                (date_zero + Duration::from_days(date_offset_days))
+                    .round(Duration::from_milliseconds(1.0)),
+            );
+            if let Some(inttim_param) = inttim_param {
+                int_time_secs
+                    .get_or_insert(group_params[inttim_param] as f64 * param_scales[inttim_param]);
+            }
+        }
+
+        let mut timestamps = row_timestamps.clone();
+        timestamps.sort_unstable();
+        timestamps.dedup();
+        let mut ant_pairs = row_ant_pairs.clone();
+        ant_pairs.sort_unstable();
+        ant_pairs.dedup();
+        let row_idxs = row_timestamps
+            .iter()
+            .zip(row_ant_pairs.iter())
+            .map(|(timestamp, ant_pair)| {
+                (
+                    timestamps.binary_search(timestamp).unwrap(),
+                    ant_pairs.binary_search(ant_pair).unwrap(),
+                )
+            })
+            .collect();
+
+        let int_time = match (int_time_secs, timestamps.as_slice()) {
+            (Some(secs), _) => Duration::from_seconds(secs),
+            (None, [first, second, ..]) => *second - *first,
+            (None, _) => {
+                return Err(bad_uvfits(
+                    "no INTTIM group parameter, and too few timesteps to infer it".into(),
+                ))
+            }
+        };
+
+        // Frequency axis.
+        let centre_freq_hz: f64 = get_required_fits_key!(&mut fptr, &hdu, "CRVAL4")?;
+        let freq_resolution_hz: f64 = get_required_fits_key!(&mut fptr, &hdu, "CDELT4")?;
+        let centre_freq_pix: f64 = get_required_fits_key!(&mut fptr, &hdu, "CRPIX4")?;
+        let start_freq_hz = centre_freq_hz + (1.0 - centre_freq_pix) * freq_resolution_hz;
+
+        // Phase centre, preferring OBSRA / OBSDEC.
+        let ra_deg = match get_optional_fits_key!(&mut fptr, &hdu, "OBSRA")? {
+            Some(ra_deg) => ra_deg,
+            None => get_required_fits_key!(&mut fptr, &hdu, "CRVAL5")?,
+        };
+        let dec_deg = match get_optional_fits_key!(&mut fptr, &hdu, "OBSDEC")? {
+            Some(dec_deg) => dec_deg,
+            None => get_required_fits_key!(&mut fptr, &hdu, "CRVAL6")?,
+        };
+        let obs_name: Option<String> = get_optional_fits_key!(&mut fptr, &hdu, "OBJECT")?;
+
+        Ok(Self {
+            path,
+            timestamps,
+            int_time,
+            num_chans,
+            start_freq_hz,
+            freq_resolution_hz,
+            ant_pairs,
+            phase_centre: RADec::from_degrees(ra_deg, dec_deg),
+            obs_name: obs_name.map(|name| name.trim().to_string()),
+            row_idxs,
+        })
+    }
+}
+
+impl VisRead for UvfitsReader {
+    fn get_path(&self) -> &Path {
+        &self.path
+    }
+
+    fn get_ant_pairs(&self) -> &[(usize, usize)] {
+        &self.ant_pairs
+    }
+
+    fn get_phase_centre(&self) -> RADec {
+        self.phase_centre
+    }
+
+    fn get_shape(&self) -> (usize, usize, usize) {
+        (self.timestamps.len(), self.num_chans, self.ant_pairs.len())
+    }
+
+    fn get_vis_ctx(
+        &self,
+        timestep_range: &Range<usize>,
+        avg_time: usize,
+        avg_freq: usize,
+    ) -> VisContext {
+        VisContext {
+            num_sel_timesteps: timestep_range.len(),
+            start_timestamp: self.timestamps[timestep_range.start] - self.int_time / 2,
+            int_time: self.int_time,
+            num_sel_chans: self.num_chans,
+            start_freq_hz: self.start_freq_hz,
+            freq_resolution_hz: self.freq_resolution_hz,
+            sel_baselines: self.ant_pairs.clone(),
+            avg_time,
+            avg_freq,
+            num_vis_pols: NUM_POLS,
+        }
+    }
+
+    /// Visibilities with a weight which is not positive in any polarisation are flagged. The
+    /// weight of each visibility is the absolute value of its XX weight.
+    fn read_vis(
+        &self,
+        mut jones_array: ArrayViewMut3<Jones<f32>>,
+        mut weight_array: ArrayViewMut3<f32>,
+        mut flag_array: ArrayViewMut3<bool>,
+        timestep_range: &Range<usize>,
+    ) -> Result<(), IOError> {
+        let shape = (timestep_range.len(), self.num_chans, self.ant_pairs.len());
+        check_read_vis_shapes(
+            "UvfitsReader::read_vis",
+            shape,
+            &jones_array,
+            &weight_array,
+            &flag_array,
+        )?;
+
+        // Anything which isn't in the file stays flagged.
+        let mut missing = Array2::from_elem((shape.0, shape.2), true);
+
+        let mut fptr = fits_open!(&self.path)?;
+        fits_open_hdu!(&mut fptr, 0)?;
+        let mut row_vis = vec![0.0_f32; self.num_chans * NUM_POLS * NUM_FLOATS_PER_POL];
+        for (row_idx, &(timestep_idx, baseline_idx)) in self.row_idxs.iter().enumerate() {
+            if !timestep_range.contains(&timestep_idx) {
+                continue;
+            }
+            let timestep_idx = timestep_idx - timestep_range.start;
+            read_group_vis(&mut fptr, row_idx, &mut row_vis)?;
+            missing[(timestep_idx, baseline_idx)] = false;
+            for (chan_idx, chan_vis) in row_vis
+                .chunks_exact(NUM_POLS * NUM_FLOATS_PER_POL)
+                .enumerate()
+            {
+                let jones = &mut jones_array[(timestep_idx, chan_idx, baseline_idx)];
+                let mut flagged = false;
+                for (pol_vis, &jones_idx) in chan_vis
+                    .chunks_exact(NUM_FLOATS_PER_POL)
+                    .zip(POL_JONES_IDXS.iter())
+                {
+                    jones[jones_idx].re = pol_vis[0];
+                    jones[jones_idx].im = pol_vis[1];
+                    flagged |= pol_vis[2] <= 0.0 || pol_vis[2].is_nan();
+                }
+                weight_array[(timestep_idx, chan_idx, baseline_idx)] = chan_vis[2].abs();
+                flag_array[(timestep_idx, chan_idx, baseline_idx)] |= flagged;
+            }
+        }
+
+        flag_missing_vis(missing.view(), jones_array, weight_array, flag_array);
+
+        Ok(())
+    }
+}
+
+/// Read the group parameters of a (zero-indexed) row of the primary HDU into `group_params`.
+fn read_group_params(
+    fptr: &mut FitsFile,
+    row_idx: usize,
+    group_params: &mut [f32],
+) -> Result<(), IOError> {
+    let mut status = 0;
+    unsafe {
+        // ffggpe = fits_read_grppar_flt
+        fitsio_sys::ffggpe(
+            fptr.as_raw(),             /* I - FITS file pointer                       */
+            1 + row_idx as i64,        /* I - group to read (1 = 1st group)           */
+            1,                         /* I - first vector element to read (1 = 1st)  */
+            group_params.len() as i64, /* I - number of values to read                */
+            group_params.as_mut_ptr(), /* O - array of values that are returned       */
+            &mut status,               /* IO - error status                           */
+        );
+    }
+    fitsio::errors::check_status(status).map_err(|e| FitsIO {
+        fits_error: e,
+        fits_filename: fptr.filename.clone(),
+        hdu_num: 0,
+        source_file: file!(),
+        source_line: line!(),
+    })
+}
+
+/// Read the visibilities of a (zero-indexed) row of the primary HDU into `vis`.
+fn read_group_vis(fptr: &mut FitsFile, row_idx: usize, vis: &mut [f32]) -> Result<(), IOError> {
+    let mut status = 0;
+    unsafe {
+        // ffgpve = fits_read_img_flt
+        fitsio_sys::ffgpve(
+            fptr.as_raw(),      /* I - FITS file pointer                       */
+            1 + row_idx as i64, /* I - group to read (1 = 1st group)           */
+            1,                  /* I - first vector element to read (1 = 1st)  */
+            vis.len() as i64,   /* I - number of values to read                */
+            0.0,                /* I - value for undefined pixels              */
+            vis.as_mut_ptr(),   /* O - array of values that are returned       */
+            &mut 0,             /* O - set to 1 if any values are null; else 0 */
+            &mut status,        /* IO - error status                           */
+        );
+    }
+    fitsio::errors::check_status(status).map_err(|e| FitsIO {
+        fits_error: e,
+        fits_filename: fptr.filename.clone(),
+        hdu_num: 0,
+        source_file: file!(),
+        source_line: line!(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use marlu::{
+        hifitime::{Duration, Epoch},
+        io::{uvfits::UvfitsWriter, VisWrite},
+        Jones, LatLngHeight, RADec, VisContext, XyzGeodetic,
+    };
+    use ndarray::prelude::*;
+    use tempfile::tempdir;
+
+    use super::UvfitsReader;
+    use crate::{approx::assert_abs_diff_eq, io::VisRead, ndarray};
+
+    #[test]
+    fn test_read_uvfits_written_by_marlu() {
+        let tmp_dir = tempdir().unwrap();
+        let uvfits_path = tmp_dir.path().join("test.uvfits");
+
+        let num_ants = 3;
+        let sel_baselines = (0..num_ants)
+            .flat_map(|ant1| (ant1..num_ants).map(move |ant2| (ant1, ant2)))
+            .collect::<Vec<_>>();
+        let vis_ctx = VisContext {
+            num_sel_timesteps: 3,
+            start_timestamp: Epoch::from_gpst_seconds(1297526432.),
+            int_time: Duration::from_seconds(2.),
+            num_sel_chans: 4,
+            start_freq_hz: 167_000_000.,
+            freq_resolution_hz: 40_000.,
+            sel_baselines,
+            avg_time: 1,
+            avg_freq: 1,
+            num_vis_pols: 4,
+        };
+        let shape = vis_ctx.sel_dims();
+        let jones_array = Array3::from_shape_fn(shape, |(t, c, b)| {
+            let base = (t * 100 + c * 10 + b) as f32;
+            Jones::from([
+                base,
+                -base,
+                base + 0.1,
+                -base - 0.1,
+                base + 0.2,
+                -base - 0.2,
+                base + 0.3,
+                -base - 0.3,
+            ])
+        });
+        // flag the second channel of the first baseline in every timestep
+        let weight_array =
+            Array3::from_shape_fn(shape, |(_, c, b)| if c == 1 && b == 0 { -8. } else { 8. });
+
+        let phase_centre = RADec::from_degrees(15., -27.);
+        let mut writer = UvfitsWriter::from_marlu(
+            &uvfits_path,
+            &vis_ctx,
+            LatLngHeight::mwa(),
+            phase_centre,
+            Duration::from_seconds(0.),
+            Some("test_obs"),
+            (0..num_ants).map(|i| format!("Tile{i:03}")).collect(),
+            vec![XyzGeodetic::default(); num_ants],
+            true,
+            None,
+        )
+        .unwrap();
+        writer
+            .write_vis(jones_array.view(), weight_array.view(), &vis_ctx)
+            .unwrap();
+        writer.finalise().unwrap();
+
+        let reader = UvfitsReader::new(&uvfits_path).unwrap();
+        assert_eq!(reader.get_shape(), shape);
+        assert_eq!(reader.ant_pairs, vis_ctx.sel_baselines);
+        assert_eq!(reader.obs_name.as_deref(), Some("test_obs"));
+        assert_eq!(reader.int_time, vis_ctx.int_time);
+        assert_eq!(
+            reader.timestamps,
+            vis_ctx.timeseries(false, true).collect::<Vec<_>>()
+        );
+        assert_abs_diff_eq!(reader.start_freq_hz, vis_ctx.start_freq_hz);
+        assert_abs_diff_eq!(reader.freq_resolution_hz, vis_ctx.freq_resolution_hz);
+        assert_abs_diff_eq!(reader.phase_centre.ra, phase_centre.ra, epsilon = 1e-10);
+        assert_abs_diff_eq!(reader.phase_centre.dec, phase_centre.dec, epsilon = 1e-10);
+
+        let read_vis_ctx = reader.get_vis_ctx(&(0..shape.0), 1, 1);
+        assert_eq!(read_vis_ctx.start_timestamp, vis_ctx.start_timestamp);
+
+        // read the last two timesteps
+        let timestep_range = 1..3;
+        let sel_shape = (timestep_range.len(), shape.1, shape.2);
+        let mut read_jones = Array3::zeros(sel_shape);
+        let mut read_weights = Array3::zeros(sel_shape);
+        let mut read_flags = Array3::from_elem(sel_shape, false);
+        reader
+            .read_vis(
+                read_jones.view_mut(),
+                read_weights.view_mut(),
+                read_flags.view_mut(),
+                &timestep_range,
+            )
+            .unwrap();
+
+        assert_abs_diff_eq!(
+            read_jones,
+            jones_array.slice(s![timestep_range.clone(), .., ..])
+        );
+        assert_abs_diff_eq!(read_weights, Array3::from_elem(sel_shape, 8.));
+        for ((_, c, b), &flag) in read_flags.indexed_iter() {
+            assert_eq!(flag, c == 1 && b == 0);
+        }
+    }
+}
@@ -167,15 +167,24 @@ use log::warn;
 pub mod io;
 pub use io::mwaf::FlagFileSet;
 pub mod corrections;
-pub use corrections::{correct_cable_lengths, correct_geometry, ScrunchType};
+pub use corrections::{
+    calc_uvws, correct_cable_lengths, correct_geometry, correct_geometry_per_timestep, ScrunchType,
+};
 pub mod calibration;
 pub mod flags;
 #[cfg(test)]
 pub use approx;
-pub use flags::{flag_to_weight_array, get_weight_factor, write_flags, FlagContext};
+pub use flags::{
+    flag_to_weight_array, get_weight_factor, set_new_flag_reasons, write_flags, FlagContext,
+    FlagReason, FlagThresholds,
+};
+pub mod occupancy;
 #[cfg(test)]
 pub use io::{write_ms, write_uvfits};
+pub use occupancy::{FlagOccupancy, FlagSource};
 pub mod passband_gains;
+pub mod sky_model;
+pub mod ssins;
 pub use marlu;
 pub use marlu::{
     mwalib,
@@ -185,6 +194,7 @@ pub use marlu::{
     selection::VisSelection,
     Complex, Jones,
 };
+pub use ssins::SsinsContext;
 
 mod error;
 pub use error::BirliError;
@@ -0,0 +1,655 @@
+//! Flag occupancy statistics, broken down by the stage of processing which raised each flag.
+//!
+//! The mwaf `CH_OCC` and `BL_OCC` HDUs only record the total occupancy of each coarse channel.
+//! [`FlagOccupancy`] accumulates flag counts for every chunk that passes through Birli, so that a
+//! summary can be shown (and written to disk) once processing is finished.
+//!
+//! If a flag reason array is available, [`FlagOccupancy::add_reasons`] also counts the visibilities
+//! flagged for each [`FlagReason`]. Unlike flag sources, a visibility can have several reasons.
+
+use std::path::Path;
+
+use crate::{
+    flags::FlagReason,
+    io::{error::IOError, UnreadableHdu},
+    marlu::{mwalib::CorrelatorContext, ndarray::prelude::*},
+    VisSelection,
+};
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "cli")] {
+        use prettytable::{format as prettyformat, Cell, Row, Table};
+    }
+}
+
+/// The stages of processing which can flag a visibility.
+///
+/// A visibility is only attributed to the first source which flagged it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlagSource {
+    /// Flags derived from the observation metadata, e.g. antennas flagged in the metafits, missing
+    /// coarse channels and timesteps, quack time and DC channels.
+    Metafits,
+    /// Additional flags requested by the user.
+    User,
+    /// Flags for HDUs which were not provided in the gpubox files, or could not be read.
+    MissingHdu,
+    /// Flags for baselines with packet loss in the MWAX weights HDUs.
+    PacketLoss,
+    /// Flags raised by `AOFlagger`.
+    AOFlagger,
+    /// Any other flags raised during preprocessing, e.g. bad values, zero digital gains, SSINS,
+    /// occupancy thresholds, bad autocorrelations and `NaN` calibration solutions.
+    Preprocessing,
+}
+
+impl FlagSource {
+    /// All the flag sources, in the order they are applied.
+    pub const ALL: [Self; 6] = [
+        Self::Metafits,
+        Self::User,
+        Self::MissingHdu,
+        Self::PacketLoss,
+        Self::AOFlagger,
+        Self::Preprocessing,
+    ];
+
+    /// A short name for this flag source, used in table headers.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Metafits => "metafits",
+            Self::User => "user",
+            Self::MissingHdu => "missing_hdu",
+            Self::PacketLoss => "packet_loss",
+            Self::AOFlagger => "aoflagger",
+            Self::Preprocessing => "preprocessing",
+        }
+    }
+}
+
+/// Counts of flagged visibilities for a selection, by [`FlagSource`].
+///
+/// The count arrays are all `[column][index]`, where the column is a [`FlagSource`] followed by a
+/// [`FlagReason`], and the index is the selected timestep, the selected fine channel or the mwalib
+/// antenna index.
+#[derive(Debug, Clone)]
+pub struct FlagOccupancy {
+    /// The mwalib timestep index of the first selected timestep.
+    timestep_offset: usize,
+    /// The number of fine channels in each coarse channel.
+    fine_chans_per_coarse: usize,
+    /// The antenna pairs of each selected baseline.
+    ant_pairs: Vec<(usize, usize)>,
+    /// The number of selected baselines which contain each antenna.
+    ant_baseline_counts: Vec<u64>,
+    /// The receiver channel number of each selected coarse channel.
+    rec_chan_numbers: Vec<usize>,
+    /// The name of each antenna.
+    tile_names: Vec<String>,
+    /// Whether any flag reasons have been added.
+    has_reasons: bool,
+    /// HDUs which could not be read, and were flagged.
+    unreadable_hdus: Vec<UnreadableHdu>,
+    /// The number of flags in each selected timestep, `[column][timestep]`.
+    timestep_counts: Array2<u64>,
+    /// The number of flags in each selected fine channel, `[column][channel]`.
+    chan_counts: Array2<u64>,
+    /// The number of flags in each baseline containing an antenna, `[column][antenna]`.
+    ant_counts: Array2<u64>,
+}
+
+impl FlagOccupancy {
+    /// Create a new, empty [`FlagOccupancy`] for the given selection.
+    pub fn new(corr_ctx: &CorrelatorContext, vis_sel: &VisSelection) -> Self {
+        let meta_ctx = &corr_ctx.metafits_context;
+        let fine_chans_per_coarse = meta_ctx.num_corr_fine_chans_per_coarse;
+        let num_columns = FlagSource::ALL.len() + FlagReason::ALL.len();
+        let ant_pairs = vis_sel.get_ant_pairs(meta_ctx);
+        let mut ant_baseline_counts = vec![0; meta_ctx.num_ants];
+        for &(ant1, ant2) in &ant_pairs {
+            ant_baseline_counts[ant1] += 1;
+            if ant1 != ant2 {
+                ant_baseline_counts[ant2] += 1;
+            }
+        }
+        Self {
+            timestep_offset: vis_sel.timestep_range.start,
+            fine_chans_per_coarse,
+            ant_pairs,
+            ant_baseline_counts,
+            rec_chan_numbers: corr_ctx.coarse_chans[vis_sel.coarse_chan_range.clone()]
+                .iter()
+                .map(|cc| cc.rec_chan_number)
+                .collect(),
+            tile_names: meta_ctx
+                .antennas
+                .iter()
+                .map(|a| a.tile_name.clone())
+                .collect(),
+            has_reasons: false,
+            unreadable_hdus: vec![],
+            timestep_counts: Array2::zeros((num_columns, vis_sel.timestep_range.len())),
+            chan_counts: Array2::zeros((
+                num_columns,
+                vis_sel.coarse_chan_range.len() * fine_chans_per_coarse,
+            )),
+            ant_counts: Array2::zeros((num_columns, meta_ctx.num_ants)),
+        }
+    }
+
+    /// Attribute every flag which is set in `new_flags` to `source`.
+    ///
+    /// `new_flags` is `[timestep][channel][baseline]` for a chunk of the selection starting at
+    /// mwalib timestep index `first_timestep`.
+    pub fn add(&mut self, source: FlagSource, new_flags: ArrayView3<bool>, first_timestep: usize) {
+        self.add_inner(source as usize, new_flags.dim(), first_timestep, |idx| {
+            new_flags[idx]
+        });
+    }
+
+    /// Attribute every flag which is set in `flags`, but not in `prev_flags` to `source`.
+    ///
+    /// This is used to count the flags raised by a stage of processing, given a copy of the flags
+    /// from before that stage. Both arrays are `[timestep][channel][baseline]` for a chunk of the
+    /// selection starting at mwalib timestep index `first_timestep`.
+    pub fn add_new(
+        &mut self,
+        source: FlagSource,
+        prev_flags: ArrayView3<bool>,
+        flags: ArrayView3<bool>,
+        first_timestep: usize,
+    ) {
+        assert_eq!(prev_flags.dim(), flags.dim());
+        self.add_inner(source as usize, flags.dim(), first_timestep, |idx| {
+            flags[idx] && !prev_flags[idx]
+        });
+    }
+
+    /// Attribute every flag which is set in `flags`, but not in `prev_flags`, to
+    /// [`FlagSource::AOFlagger`] if `flag_reasons` records that `AOFlagger` raised it, and to
+    /// [`FlagSource::Preprocessing`] otherwise.
+    ///
+    /// Flag reasons are only recorded by the stage which first flagged a visibility, so this
+    /// separates `AOFlagger` from the stages of preprocessing either side of it. All three arrays
+    /// are `[timestep][channel][baseline]` for a chunk of the selection starting at mwalib
+    /// timestep index `first_timestep`.
+    pub fn add_new_preprocessing(
+        &mut self,
+        prev_flags: ArrayView3<bool>,
+        flags: ArrayView3<bool>,
+        flag_reasons: ArrayView3<u16>,
+        first_timestep: usize,
+    ) {
+        assert_eq!(prev_flags.dim(), flags.dim());
+        assert_eq!(flag_reasons.dim(), flags.dim());
+        for (source, is_aoflagger) in [
+            (FlagSource::AOFlagger, true),
+            (FlagSource::Preprocessing, false),
+        ] {
+            self.add_inner(source as usize, flags.dim(), first_timestep, |idx| {
+                flags[idx]
+                    && !prev_flags[idx]
+                    && FlagReason::AOFlagger.is_set(flag_reasons[idx]) == is_aoflagger
+            });
+        }
+    }
+
+    /// Count the visibilities flagged for each [`FlagReason`] in a flag reason array.
+    ///
+    /// `flag_reasons` is `[timestep][channel][baseline]` for a chunk of the selection starting at
+    /// mwalib timestep index `first_timestep`.
+    pub fn add_reasons(&mut self, flag_reasons: ArrayView3<u16>, first_timestep: usize) {
+        self.has_reasons = true;
+        for (reason_idx, reason) in FlagReason::ALL.into_iter().enumerate() {
+            self.add_inner(
+                FlagSource::ALL.len() + reason_idx,
+                flag_reasons.dim(),
+                first_timestep,
+                |idx| reason.is_set(flag_reasons[idx]),
+            );
+        }
+    }
+
+    /// Record HDUs which could not be read, so that they are included in the report.
+    ///
+    /// The flags for these HDUs should still be attributed to [`FlagSource::MissingHdu`].
+    pub fn add_unreadable_hdus(&mut self, unreadable_hdus: &[UnreadableHdu]) {
+        self.unreadable_hdus.extend_from_slice(unreadable_hdus);
+    }
+
+    /// The HDUs which could not be read, and were flagged.
+    pub fn unreadable_hdus(&self) -> &[UnreadableHdu] {
+        &self.unreadable_hdus
+    }
+
+    /// Count the visibilities in a chunk where `is_flagged` is true in `column`.
+    fn add_inner<F>(
+        &mut self,
+        column: usize,
+        dim: (usize, usize, usize),
+        first_timestep: usize,
+        is_flagged: F,
+    ) where
+        F: Fn((usize, usize, usize)) -> bool,
+    {
+        let ts_offset = first_timestep - self.timestep_offset;
+        let (num_timesteps, num_chans, num_baselines) = dim;
+        for ts_idx in 0..num_timesteps {
+            for chan_idx in 0..num_chans {
+                let mut chan_count = 0;
+                for (bl_idx, &(ant1, ant2)) in self.ant_pairs[..num_baselines].iter().enumerate() {
+                    if !is_flagged((ts_idx, chan_idx, bl_idx)) {
+                        continue;
+                    }
+                    chan_count += 1;
+                    self.ant_counts[[column, ant1]] += 1;
+                    if ant1 != ant2 {
+                        self.ant_counts[[column, ant2]] += 1;
+                    }
+                }
+                self.chan_counts[[column, chan_idx]] += chan_count;
+                self.timestep_counts[[column, ts_offset + ts_idx]] += chan_count;
+            }
+        }
+    }
+
+    fn num_timesteps(&self) -> u64 {
+        self.timestep_counts.len_of(Axis(1)) as u64
+    }
+
+    fn num_chans(&self) -> u64 {
+        self.chan_counts.len_of(Axis(1)) as u64
+    }
+
+    fn num_baselines(&self) -> u64 {
+        self.ant_pairs.len() as u64
+    }
+
+    /// The total number of flags raised by each source.
+    pub fn total_counts(&self) -> Vec<u64> {
+        self.timestep_counts
+            .slice(s![..FlagSource::ALL.len(), ..])
+            .sum_axis(Axis(1))
+            .to_vec()
+    }
+
+    /// The total number of visibilities flagged for each reason.
+    pub fn total_reason_counts(&self) -> Vec<u64> {
+        self.timestep_counts
+            .slice(s![FlagSource::ALL.len().., ..])
+            .sum_axis(Axis(1))
+            .to_vec()
+    }
+
+    /// The names of the count columns, with reason columns only if any reasons were added.
+    fn column_names(&self) -> Vec<String> {
+        let mut names: Vec<_> = FlagSource::ALL
+            .iter()
+            .map(|source| source.name().to_string())
+            .collect();
+        if self.has_reasons {
+            names.extend(
+                FlagReason::ALL
+                    .iter()
+                    .map(|reason| format!("reason_{}", reason.name())),
+            );
+        }
+        names
+    }
+
+    /// The total number of visibilities in the selection.
+    pub fn total_samples(&self) -> u64 {
+        self.num_timesteps() * self.num_chans() * self.num_baselines()
+    }
+
+    /// Rows of `(axis, index, name, samples, counts by column)` for each timestep, coarse channel,
+    /// fine channel and antenna, followed by a row for the whole selection.
+    fn rows(&self) -> Vec<(&'static str, usize, String, u64, Vec<u64>)> {
+        let num_timesteps = self.num_timesteps();
+        let num_chans = self.num_chans();
+        let num_baselines = self.num_baselines();
+        let num_coarse_chans = self.rec_chan_numbers.len() as u64;
+        let fine_chans_per_coarse = self.fine_chans_per_coarse;
+        let mut rows = vec![];
+
+        for (ts_idx, counts) in self.timestep_counts.axis_iter(Axis(1)).enumerate() {
+            let timestep_idx = self.timestep_offset + ts_idx;
+            rows.push((
+                "timestep",
+                timestep_idx,
+                format!("ts{timestep_idx}"),
+                num_chans * num_baselines,
+                counts.to_vec(),
+            ));
+        }
+        for (cc_idx, (counts, rec_chan_number)) in self
+            .chan_counts
+            .axis_chunks_iter(Axis(1), fine_chans_per_coarse)
+            .zip(&self.rec_chan_numbers)
+            .enumerate()
+        {
+            rows.push((
+                "coarse_chan",
+                cc_idx,
+                format!("ch{rec_chan_number}"),
+                num_timesteps * fine_chans_per_coarse as u64 * num_baselines,
+                counts.sum_axis(Axis(1)).to_vec(),
+            ));
+        }
+        for fine_chan_idx in 0..fine_chans_per_coarse {
+            let counts = self
+                .chan_counts
+                .slice(s![.., fine_chan_idx..;fine_chans_per_coarse])
+                .sum_axis(Axis(1));
+            rows.push((
+                "fine_chan",
+                fine_chan_idx,
+                format!("fc{fine_chan_idx}"),
+                num_timesteps * num_coarse_chans * num_baselines,
+                counts.to_vec(),
+            ));
+        }
+        for (ant_idx, (counts, &ant_baselines)) in self
+            .ant_counts
+            .axis_iter(Axis(1))
+            .zip(&self.ant_baseline_counts)
+            .enumerate()
+        {
+            // skip antennas which are not in the selection.
+            if ant_baselines == 0 {
+                continue;
+            }
+            rows.push((
+                "antenna",
+                ant_idx,
+                self.tile_names[ant_idx].clone(),
+                num_timesteps * num_chans * ant_baselines,
+                counts.to_vec(),
+            ));
+        }
+        rows.push((
+            "total",
+            0,
+            "total".into(),
+            self.total_samples(),
+            self.timestep_counts.sum_axis(Axis(1)).to_vec(),
+        ));
+        let num_columns = self.column_names().len();
+        for (_, _, _, _, counts) in &mut rows {
+            counts.truncate(num_columns);
+        }
+        rows
+    }
+
+    /// Write the occupancy of each timestep, coarse channel, fine channel and antenna to a CSV
+    /// file, with a column for the total occupancy and for each [`FlagSource`], followed by a
+    /// column for each [`FlagReason`] if any reasons were added.
+    ///
+    /// # Errors
+    ///
+    /// Will error with [`IOError::IO`] if the file can't be written.
+    pub fn write_csv<T: AsRef<Path>>(&self, path: T) -> Result<(), IOError> {
+        let mut writer = csv::Writer::from_path(path).map_err(std::io::Error::from)?;
+        let header = ["axis", "index", "name", "samples", "total"]
+            .into_iter()
+            .map(String::from)
+            .chain(self.column_names());
+        writer.write_record(header).map_err(std::io::Error::from)?;
+        for (axis, index, name, samples, counts) in self.rows() {
+            let record = [
+                axis.to_string(),
+                index.to_string(),
+                name,
+                samples.to_string(),
+                occupancy(total_count(&counts), samples).to_string(),
+            ]
+            .into_iter()
+            .chain(
+                counts
+                    .iter()
+                    .map(|&count| occupancy(count, samples).to_string()),
+            );
+            writer.write_record(record).map_err(std::io::Error::from)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Write the mwalib timestep and coarse channel index of each unreadable HDU to a CSV file,
+    /// along with the error from mwalib. Every visibility of these HDUs is flagged as a missing
+    /// HDU.
+    ///
+    /// # Errors
+    ///
+    /// Will error with [`IOError::IO`] if the file can't be written.
+    pub fn write_unreadable_hdus_csv<T: AsRef<Path>>(&self, path: T) -> Result<(), IOError> {
+        let mut writer = csv::Writer::from_path(path).map_err(std::io::Error::from)?;
+        writer
+            .write_record(["timestep_idx", "coarse_chan_idx", "error"])
+            .map_err(std::io::Error::from)?;
+        for hdu in &self.unreadable_hdus {
+            writer
+                .write_record([
+                    hdu.timestep_idx.to_string(),
+                    hdu.coarse_chan_idx.to_string(),
+                    hdu.error.clone(),
+                ])
+                .map_err(std::io::Error::from)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// The number of flagged visibilities in a row of counts, from the [`FlagSource`] columns.
+fn total_count(counts: &[u64]) -> u64 {
+    counts[..FlagSource::ALL.len()].iter().sum()
+}
+
+fn occupancy(count: u64, samples: u64) -> f64 {
+    if samples == 0 {
+        0.
+    } else {
+        count as f64 / samples as f64
+    }
+}
+
+#[cfg(feature = "cli")]
+impl std::fmt::Display for FlagOccupancy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let rows = self.rows();
+        for (axis, title) in [
+            ("timestep", "timestep"),
+            ("coarse_chan", "coarse channel"),
+            ("fine_chan", "fine channel"),
+            ("antenna", "antenna"),
+            ("total", "observation"),
+        ] {
+            let mut table = Table::new();
+            table.set_format(*prettyformat::consts::FORMAT_CLEAN);
+            table.set_titles(Row::new(
+                ["".to_string(), "total".to_string()]
+                    .into_iter()
+                    .chain(self.column_names())
+                    .map(|name| Cell::new(&name))
+                    .collect(),
+            ));
+            for (_, _, name, samples, counts) in rows.iter().filter(|row| row.0 == axis) {
+                table.add_row(Row::new(
+                    [
+                        format!("{name}:"),
+                        format!("{:6.2}%", occupancy(total_count(counts), *samples) * 100.),
+                    ]
+                    .into_iter()
+                    .chain(
+                        counts
+                            .iter()
+                            .map(|&count| format!("{:6.2}%", occupancy(count, *samples) * 100.)),
+                    )
+                    .map(|s| Cell::new(&s).style_spec("r"))
+                    .collect(),
+                ));
+            }
+            writeln!(f, "Flag occupancy by {title}:\n{table}")?;
+        }
+        if !self.unreadable_hdus.is_empty() {
+            let mut table = Table::new();
+            table.set_format(*prettyformat::consts::FORMAT_CLEAN);
+            table.set_titles(Row::new(
+                ["timestep", "coarse chan", "error"]
+                    .into_iter()
+                    .map(Cell::new)
+                    .collect(),
+            ));
+            for hdu in &self.unreadable_hdus {
+                table.add_row(Row::new(vec![
+                    Cell::new(&hdu.timestep_idx.to_string()).style_spec("r"),
+                    Cell::new(&hdu.coarse_chan_idx.to_string()).style_spec("r"),
+                    Cell::new(&hdu.error),
+                ]));
+            }
+            writeln!(f, "Unreadable HDUs which were flagged:\n{table}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FlagOccupancy, FlagSource};
+    use crate::{io::UnreadableHdu, test_common::get_mwax_context, FlagReason, VisSelection};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_occupancy_counts_by_source() {
+        let corr_ctx = get_mwax_context();
+        let vis_sel = VisSelection::from_mwalib(&corr_ctx).unwrap();
+        let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+        let mut occupancy = FlagOccupancy::new(&corr_ctx, &vis_sel);
+
+        // flag the whole first timestep as metafits
+        let mut new_flags = vis_sel.allocate_flags(fine_chans_per_coarse).unwrap();
+        new_flags.index_axis_mut(ndarray::Axis(0), 0).fill(true);
+        occupancy.add(
+            FlagSource::Metafits,
+            new_flags.view(),
+            vis_sel.timestep_range.start,
+        );
+
+        // flag a single visibility on the cross-correlation in the second chunk as user.
+        let mut new_flags = new_flags.slice(ndarray::s![1.., .., ..]).to_owned();
+        new_flags.fill(false);
+        new_flags[(0, 1, 1)] = true;
+        occupancy.add(
+            FlagSource::User,
+            new_flags.view(),
+            vis_sel.timestep_range.start + 1,
+        );
+
+        // only flags which weren't already set are attributed to aoflagger, or the rest of
+        // preprocessing.
+        let prev_flags = new_flags.clone();
+        let mut flag_reasons = ndarray::Array3::<u16>::zeros(new_flags.dim());
+        new_flags[(0, 1, 1)] = true;
+        flag_reasons[(0, 1, 1)] = FlagReason::AOFlagger.bit();
+        new_flags[(0, 2, 1)] = true;
+        flag_reasons[(0, 2, 1)] = FlagReason::AOFlagger.bit();
+        new_flags[(0, 3, 1)] = true;
+        flag_reasons[(0, 3, 1)] = FlagReason::Ssins.bit();
+        occupancy.add_new_preprocessing(
+            prev_flags.view(),
+            new_flags.view(),
+            flag_reasons.view(),
+            vis_sel.timestep_range.start + 1,
+        );
+
+        let (num_timesteps, num_chans, num_baselines) = vis_sel.get_shape(fine_chans_per_coarse);
+        assert_eq!(
+            occupancy.total_samples(),
+            (num_timesteps * num_chans * num_baselines) as u64
+        );
+        assert_eq!(
+            occupancy.total_counts(),
+            vec![(num_chans * num_baselines) as u64, 1, 0, 0, 1, 1]
+        );
+        assert_eq!(occupancy.timestep_counts[[FlagSource::User as usize, 1]], 1);
+        assert_eq!(occupancy.chan_counts[[FlagSource::User as usize, 1]], 1);
+        assert_eq!(
+            occupancy.chan_counts[[FlagSource::AOFlagger as usize, 2]],
+            1
+        );
+        assert_eq!(
+            occupancy.chan_counts[[FlagSource::Preprocessing as usize, 3]],
+            1
+        );
+        // baseline 1 is (0, 1), so both antennas see the user flag.
+        assert_eq!(occupancy.ant_counts[[FlagSource::User as usize, 0]], 1);
+        assert_eq!(occupancy.ant_counts[[FlagSource::User as usize, 1]], 1);
+
+        let tmp_dir = tempdir().unwrap();
+        let csv_path = tmp_dir.path().join("occupancy.csv");
+        occupancy.write_csv(&csv_path).unwrap();
+        let csv = std::fs::read_to_string(&csv_path).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(
+            lines.next().unwrap(),
+            "axis,index,name,samples,total,metafits,user,missing_hdu,packet_loss,aoflagger,\
+             preprocessing"
+        );
+        let total_line = csv.lines().last().unwrap();
+        assert!(total_line.starts_with("total,0,total,"));
+
+        // unreadable HDUs are written to their own file, with the error quoted.
+        occupancy.add_unreadable_hdus(&[UnreadableHdu {
+            timestep_idx: 1,
+            coarse_chan_idx: 0,
+            error: "truncated, \"short\" read".into(),
+        }]);
+        let hdus_path = tmp_dir.path().join("unreadable_hdus.csv");
+        occupancy.write_unreadable_hdus_csv(&hdus_path).unwrap();
+        let csv = std::fs::read_to_string(&hdus_path).unwrap();
+        assert_eq!(
+            csv.lines().collect::<Vec<_>>(),
+            vec![
+                "timestep_idx,coarse_chan_idx,error",
+                "1,0,\"truncated, \"\"short\"\" read\"",
+            ]
+        );
+        let mut reader = csv::Reader::from_path(&hdus_path).unwrap();
+        let record = reader.records().next().unwrap().unwrap();
+        assert_eq!(&record[2], "truncated, \"short\" read");
+    }
+
+    #[test]
+    fn test_occupancy_counts_by_reason() {
+        let corr_ctx = get_mwax_context();
+        let vis_sel = VisSelection::from_mwalib(&corr_ctx).unwrap();
+        let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+        let mut occupancy = FlagOccupancy::new(&corr_ctx, &vis_sel);
+
+        let mut flag_reasons =
+            ndarray::Array3::<u16>::zeros(vis_sel.get_shape(fine_chans_per_coarse));
+        flag_reasons[(0, 0, 1)] = FlagReason::Timestep.bit() | FlagReason::AOFlagger.bit();
+        flag_reasons[(1, 0, 1)] = FlagReason::AOFlagger.bit();
+        occupancy.add_reasons(flag_reasons.view(), vis_sel.timestep_range.start);
+
+        assert_eq!(
+            occupancy.total_reason_counts(),
+            vec![1, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0]
+        );
+        // reasons are not included in the totals by source.
+        assert_eq!(occupancy.total_counts(), vec![0, 0, 0, 0, 0, 0]);
+
+        let tmp_dir = tempdir().unwrap();
+        let csv_path = tmp_dir.path().join("occupancy.csv");
+        occupancy.write_csv(&csv_path).unwrap();
+        let csv = std::fs::read_to_string(&csv_path).unwrap();
+        assert_eq!(
+            csv.lines().next().unwrap(),
+            "axis,index,name,samples,total,metafits,user,missing_hdu,packet_loss,aoflagger,\
+             preprocessing,reason_timestep,reason_channel,reason_antenna,reason_missing_hdu,reason_aoflagger,\
+             reason_calibration,reason_occupancy,reason_ssins,reason_bad_value,reason_digital_gains,\
+             reason_packet_loss,reason_bad_autos"
+        );
+    }
+}
@@ -6547,3 +6547,62 @@ pub const PFB_COTTER_2014_10KHZ: &[f64] = &[
     0.5095006003,
     0.5025463233,
 ];
+
+lazy_static::lazy_static! {
+    /// [`PFB_JAKE_2022_200HZ`] without the power aliased from the neighbouring coarse channels,
+    /// for observations made with the oversampled coarse channeliser.
+    ///
+    /// This is a model, not a measurement of the oversampled channeliser, so it is only applied
+    /// with `--passband-gains jake-oversampled`, and `auto` applies no passband correction to
+    /// oversampled observations until measured gains are available.
+    ///
+    /// Each critically sampled gain is the power response of the coarse channel, `A^2`, plus the
+    /// power aliased from its neighbour at the mirror frequency about the coarse channel edge. The
+    /// amplitude responses of neighbouring channels sum to one (which is why the gains are 0.5 at
+    /// the edges), so each gain is `A^2 + (1 - A)^2`, and `A = (1 + sqrt(2 * gain - 1)) / 2`.
+    ///
+    /// The oversampled channeliser samples each coarse channel at 32/25 of the coarse channel
+    /// width, so this aliasing falls outside the critically sampled band which MWAX keeps, leaving
+    /// only `A^2`. This assumes that the oversampled channeliser uses the same prototype filter.
+    pub static ref PFB_JAKE_2022_200HZ_OVERSAMPLED: Vec<f64> = unalias_gains(PFB_JAKE_2022_200HZ);
+}
+
+/// Remove the power aliased from the neighbouring coarse channels from critically sampled gains,
+/// see [`struct@PFB_JAKE_2022_200HZ_OVERSAMPLED`].
+fn unalias_gains(gains: &[f64]) -> Vec<f64> {
+    gains
+        .iter()
+        .map(|&gain| {
+            let amplitude = (1. + (2. * gain - 1.).max(0.).sqrt()) / 2.;
+            amplitude * amplitude
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_abs_diff_eq;
+
+    use super::{PFB_JAKE_2022_200HZ, PFB_JAKE_2022_200HZ_OVERSAMPLED};
+
+    #[test]
+    fn test_oversampled_gains_remove_aliasing() {
+        let gains = PFB_JAKE_2022_200HZ_OVERSAMPLED.as_slice();
+        assert_eq!(gains.len(), PFB_JAKE_2022_200HZ.len());
+        // the centre of the coarse channel is unchanged, and the amplitude response at the edges
+        // is half of it.
+        assert_abs_diff_eq!(gains[gains.len() / 2], 1.);
+        assert_abs_diff_eq!(gains[0].sqrt(), 0.5, epsilon = 1e-2);
+        assert_abs_diff_eq!(gains[gains.len() - 1].sqrt(), 0.5, epsilon = 1e-2);
+        // adding back the aliased power from the mirror frequency gives the critically sampled
+        // gains.
+        for (&gain, &critical_gain) in gains.iter().zip(PFB_JAKE_2022_200HZ) {
+            assert!(gain <= critical_gain);
+            assert_abs_diff_eq!(
+                gain + (1. - gain.sqrt()).powi(2),
+                critical_gain,
+                epsilon = 1e-12
+            );
+        }
+    }
+}
@@ -1,16 +1,29 @@
 //! Crate for preprocessing visibilities
 use crate::{
     calibration::apply_di_calsol,
-    correct_cable_lengths, correct_geometry,
-    corrections::{correct_coarse_passband_gains, correct_digital_gains, ScrunchType},
-    marlu::{mwalib::CorrelatorContext, ndarray::prelude::*, Jones, LatLngHeight, RADec},
+    correct_cable_lengths, correct_geometry, correct_geometry_per_timestep,
+    corrections::{
+        correct_coarse_passband_gains, correct_digital_gains, derive_passband_gains,
+        normalise_by_autos, select_autos, ScrunchType,
+    },
+    flags::{
+        apply_radiometer_weights, flag_bad_values, set_new_flag_reasons, FlagReason, FlagThresholds,
+    },
+    io::read_mwalib_tolerant,
+    marlu::{
+        io::error::BadArrayShape, mwalib::CorrelatorContext, ndarray::prelude::*, Jones,
+        LatLngHeight, RADec, VisContext,
+    },
+    ssins::SsinsContext,
     with_increment_duration, BirliError, VisSelection,
 };
 use cfg_if::cfg_if;
 use derive_builder::Builder;
-use log::trace;
+use itertools::{izip, Itertools};
+use log::{trace, warn};
 use std::{
     fmt::{Debug, Display},
+    ops::Range,
     time::Duration,
 };
 
@@ -23,6 +36,53 @@ cfg_if! {
     }
 }
 
+/// The layout of a chunk of visibilities, for the preprocessing stages which don't need mwalib.
+///
+/// Visibilities which have already been converted are treated as a single coarse channel, with
+/// indices into the container instead of mwalib.
+#[derive(Debug, Clone)]
+pub(crate) struct ChunkContext {
+    /// The timestep, coarse channel and baseline indices of the chunk
+    pub vis_sel: VisSelection,
+    /// The number of fine channels in each coarse channel
+    pub fine_chans_per_coarse: usize,
+    /// The antenna pair of each baseline
+    pub ant_pairs: Vec<(usize, usize)>,
+    /// The centre frequency of each channel
+    pub freqs_hz: Vec<f64>,
+}
+
+impl ChunkContext {
+    /// The layout of the visibilities in `vis_sel`, from the correlator metadata.
+    pub fn from_mwalib(corr_ctx: &CorrelatorContext, vis_sel: &VisSelection) -> Self {
+        Self {
+            vis_sel: vis_sel.clone(),
+            fine_chans_per_coarse: corr_ctx.metafits_context.num_corr_fine_chans_per_coarse,
+            ant_pairs: vis_sel.get_ant_pairs(&corr_ctx.metafits_context),
+            freqs_hz: VisContext::from_mwalib(
+                corr_ctx,
+                &vis_sel.timestep_range,
+                &vis_sel.coarse_chan_range,
+                &vis_sel.baseline_idxs,
+                1,
+                1,
+            )
+            .frequencies_hz(),
+        }
+    }
+
+    /// The same layout, for a different range of timesteps.
+    pub fn with_timestep_range(&self, timestep_range: Range<usize>) -> Self {
+        Self {
+            vis_sel: VisSelection {
+                timestep_range,
+                ..self.vis_sel.clone()
+            },
+            ..self.clone()
+        }
+    }
+}
+
 /// Options for preprocessing a chunk of correlator data
 #[derive(Builder, Debug, Default, Clone)]
 pub struct PreprocessContext<'a> {
@@ -30,6 +90,14 @@ pub struct PreprocessContext<'a> {
     pub array_pos: LatLngHeight,
     /// The phase centre used for geometric corrections
     pub phase_centre: RADec,
+    /// The phase centre of each timestep of the observation for a drift scan (see
+    /// [`crate::corrections::drift_phase_centres`]), used instead of `phase_centre`
+    #[builder(default)]
+    pub drift_phase_centres: Option<Vec<RADec>>,
+
+    /// Whether to flag zero-valued and non-finite visibilities
+    #[builder(default = "true")]
+    pub flag_bad_values: bool,
 
     /// Whether cable length corrections are enabled
     #[builder(default = "true")]
@@ -39,17 +107,35 @@ pub struct PreprocessContext<'a> {
     pub correct_digital_gains: bool,
     /// the pfb passband gains to use for corrections
     pub passband_gains: Option<&'a [f64]>,
+    /// Whether to measure the pfb passband gains from the autocorrelations of the observation
+    /// instead (see [`PreprocessContext::measure_passband_gains`])
+    #[builder(default)]
+    pub empirical_passband: bool,
+    /// Whether to normalise visibilities by the autocorrelation amplitudes
+    #[builder(default)]
+    pub normalise_by_autos: bool,
     /// The calibration solutions to apply
     pub calsols: Option<Array2<Jones<f64>>>,
     /// Whether geometric corrections are enabled
     #[builder(default = "true")]
     pub correct_geometry: bool,
+    /// Whether to derive weights from the radiometer equation using autocorrelations
+    #[builder(default)]
+    pub radiometer_weights: bool,
 
     /// `AOFlagger` strategy path for flagging
     #[builder(default)]
     #[cfg(feature = "aoflagger")]
     pub aoflagger_strategy: Option<String>,
 
+    /// Options for flagging with Sky-Subtracted Incoherent Noise Spectra after `AOFlagger`
+    #[builder(default)]
+    pub ssins: Option<SsinsContext>,
+
+    /// Occupancy thresholds above which flags are extended after RFI flagging
+    #[builder(default)]
+    pub flag_thresholds: FlagThresholds,
+
     /// Whether to draw progress bars
     #[builder(default = "true")]
     pub draw_progress: bool,
@@ -57,6 +143,15 @@ pub struct PreprocessContext<'a> {
 
 impl Display for PreprocessContext<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} flag zero-valued and non-finite visibilities.",
+            if self.flag_bad_values {
+                "Will"
+            } else {
+                "Will not"
+            }
+        )?;
         writeln!(
             f,
             "{} correct cable lengths.",
@@ -75,10 +170,26 @@ impl Display for PreprocessContext<'_> {
                 "Will not"
             }
         )?;
+        if self.empirical_passband {
+            writeln!(
+                f,
+                "Will correct coarse pfb passband gains measured from the autocorrelations."
+            )?;
+        } else {
+            writeln!(
+                f,
+                "{} correct coarse pfb passband gains.",
+                if self.passband_gains.is_some() {
+                    "Will"
+                } else {
+                    "Will not"
+                }
+            )?;
+        }
         writeln!(
             f,
-            "{} correct coarse pfb passband gains.",
-            if self.passband_gains.is_some() {
+            "{} normalise by autocorrelation amplitudes.",
+            if self.normalise_by_autos {
                 "Will"
             } else {
                 "Will not"
@@ -93,10 +204,24 @@ impl Display for PreprocessContext<'_> {
                 }
             }
         }
+        if let Some(ssins) = &self.ssins {
+            writeln!(f, "Will flag with {ssins}")?;
+        } else {
+            writeln!(f, "Will not flag with SSINS")?;
+        }
+        write!(f, "{}", self.flag_thresholds)?;
+        match (self.correct_geometry, self.drift_phase_centres.as_ref()) {
+            (true, Some(_)) => writeln!(
+                f,
+                "Will correct geometry, phased to a separate centre at each timestep."
+            )?,
+            (true, None) => writeln!(f, "Will correct geometry.")?,
+            (false, _) => writeln!(f, "Will not correct geometry.")?,
+        }
         writeln!(
             f,
-            "{} correct geometry.",
-            if self.correct_geometry {
+            "{} derive weights from the radiometer equation.",
+            if self.radiometer_weights {
                 "Will"
             } else {
                 "Will not"
@@ -110,6 +235,11 @@ impl<'a> PreprocessContext<'a> {
     /// A one line description of the tasks preprocessing will do.
     pub fn as_comment(&self) -> String {
         [
+            if self.flag_bad_values {
+                Some("bad value flagging".to_string())
+            } else {
+                None
+            },
             if self.correct_cable_lengths {
                 Some("cable length corrections".to_string())
             } else {
@@ -120,17 +250,37 @@ impl<'a> PreprocessContext<'a> {
             } else {
                 None
             },
-            if self.passband_gains.is_some() {
+            if self.empirical_passband {
+                Some("empirical pfb gains".to_string())
+            } else if self.passband_gains.is_some() {
                 Some("pfb gains".to_string())
             } else {
                 None
             },
+            if self.normalise_by_autos {
+                Some("autocorrelation normalisation".to_string())
+            } else {
+                None
+            },
             #[cfg(feature = "aoflagger")]
             self.aoflagger_strategy
                 .as_ref()
                 .map(|strategy| format!("aoflagging with {strategy}")),
-            if self.correct_geometry {
-                Some("geometric corrections".to_string())
+            self.ssins
+                .as_ref()
+                .map(|ssins| format!("ssins at {} sigma", ssins.sigma)),
+            if self.flag_thresholds.is_empty() {
+                None
+            } else {
+                Some("occupancy thresholds".to_string())
+            },
+            match (self.correct_geometry, self.drift_phase_centres.as_ref()) {
+                (true, Some(_)) => Some("drift scan geometric corrections".to_string()),
+                (true, None) => Some("geometric corrections".to_string()),
+                (false, _) => None,
+            },
+            if self.radiometer_weights {
+                Some("radiometer weights".to_string())
             } else {
                 None
             },
@@ -155,13 +305,228 @@ impl<'a> PreprocessContext<'a> {
     /// TODO: more granular error types: `PreprocessingError` -> {`DigitalGainsError`, etc.}
     #[allow(clippy::too_many_arguments)]
     pub fn preprocess(
+        &self,
+        corr_ctx: &CorrelatorContext,
+        jones_array: ArrayViewMut3<Jones<f32>>,
+        weight_array: ArrayViewMut3<f32>,
+        flag_array: ArrayViewMut3<bool>,
+        vis_sel: &VisSelection,
+    ) -> Result<(), BirliError> {
+        self.preprocess_with_reasons(
+            corr_ctx,
+            jones_array,
+            weight_array,
+            flag_array,
+            None,
+            vis_sel,
+        )
+    }
+
+    /// Preprocess visibilities for a chunk of correlator data, recording the reason for any new
+    /// flags in a flag reason array (see [`FlagReason`]).
+    ///
+    /// # Arguments
+    /// * `corr_ctx` - [`marlu::mwalib::CorrelatorContext`]
+    /// * `jones_array` - Array of Jones visibilties
+    /// * `weight_array` - Array of weights associated with Jones visibilities
+    /// * `flag_array` - Array of flags associated with Jones visibilities
+    /// * `flag_reason_array` - Optional array of [`FlagReason`] bits associated with `flag_array`
+    ///
+    /// # Errors
+    /// will wrap errors from `correct_digital_gains`, `correct_coarse_passband_gains`
+    #[allow(clippy::too_many_arguments)]
+    pub fn preprocess_with_reasons(
         &self,
         corr_ctx: &CorrelatorContext,
         mut jones_array: ArrayViewMut3<Jones<f32>>,
         mut weight_array: ArrayViewMut3<f32>,
         mut flag_array: ArrayViewMut3<bool>,
+        mut flag_reason_array: Option<ArrayViewMut3<u16>>,
         vis_sel: &VisSelection,
     ) -> Result<(), BirliError> {
+        let chunk_ctx = ChunkContext::from_mwalib(corr_ctx, vis_sel);
+        let base_weight_array = self.correct_and_flag(
+            Some(corr_ctx),
+            jones_array.view_mut(),
+            weight_array.view_mut(),
+            flag_array.view_mut(),
+            flag_reason_array.as_mut().map(ArrayBase::view_mut),
+            &chunk_ctx,
+        )?;
+        self.phase(Some(corr_ctx), jones_array.view_mut(), &chunk_ctx);
+        self.calibrate_and_weight(
+            jones_array,
+            weight_array,
+            flag_array,
+            flag_reason_array,
+            &chunk_ctx,
+            base_weight_array.as_ref().map(Array3::view),
+        )
+    }
+
+    /// The first stage of [`PreprocessContext::preprocess_with_reasons`]: flag bad values, apply
+    /// the cable length, digital gain, passband and autocorrelation corrections, then flag with
+    /// aoflagger, SSINS and the occupancy thresholds.
+    ///
+    /// The corrections which need the correlator metadata are skipped without `corr_ctx`, for
+    /// visibilities which have already been converted.
+    ///
+    /// Returns the weights from before any corrections if radiometer weights are enabled, to be
+    /// passed to [`PreprocessContext::calibrate_and_weight`]. Extra timesteps can be included
+    /// either side of a chunk to give the flaggers more context, and then left out of the
+    /// rest of preprocessing.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn correct_and_flag(
+        &self,
+        corr_ctx: Option<&CorrelatorContext>,
+        mut jones_array: ArrayViewMut3<Jones<f32>>,
+        mut weight_array: ArrayViewMut3<f32>,
+        mut flag_array: ArrayViewMut3<bool>,
+        mut flag_reason_array: Option<ArrayViewMut3<u16>>,
+        chunk_ctx: &ChunkContext,
+    ) -> Result<Option<Array3<f32>>, BirliError> {
+        let ChunkContext {
+            vis_sel,
+            fine_chans_per_coarse,
+            ant_pairs,
+            freqs_hz,
+        } = chunk_ctx;
+        // the weights before any corrections, replaced by the radiometer weights at the end.
+        let base_weight_array = self.radiometer_weights.then(|| weight_array.to_owned());
+
+        if self.flag_bad_values {
+            trace!("flagging zero-valued and non-finite visibilities");
+            let prev_flag_array = flag_reason_array.as_ref().map(|_| flag_array.to_owned());
+            let counts = with_increment_duration!(
+                "flag",
+                flag_bad_values(
+                    jones_array.view_mut(),
+                    flag_array.view_mut(),
+                    *fine_chans_per_coarse
+                )?
+            );
+            for (coarse_chan_idx, count) in izip!(vis_sel.coarse_chan_range.clone(), counts) {
+                if count > 0 {
+                    warn!(
+                        "Flagged {} zero-valued or non-finite visibilities @ cc={}",
+                        count, coarse_chan_idx
+                    );
+                }
+            }
+            record_new_flag_reasons(
+                flag_reason_array.as_mut(),
+                prev_flag_array,
+                flag_array.view(),
+                FlagReason::BadValue,
+            )?;
+        }
+
+        if let Some(corr_ctx) = corr_ctx {
+            self.correct_mwalib(
+                corr_ctx,
+                jones_array.view_mut(),
+                weight_array.view_mut(),
+                flag_array.view_mut(),
+                flag_reason_array.as_mut(),
+                chunk_ctx,
+            )?;
+        }
+
+        if self.normalise_by_autos {
+            trace!("normalising by autocorrelations");
+            self.apply_auto_normalisation(
+                corr_ctx,
+                jones_array.view_mut(),
+                flag_array.view_mut(),
+                flag_reason_array.as_mut(),
+                chunk_ctx,
+            )?;
+        }
+
+        cfg_if! {
+            if #[cfg(feature = "aoflagger")] {
+                if let Some(strategy) = self.aoflagger_strategy.as_ref() {
+                    trace!("using aoflagger");
+                    let aoflagger = unsafe { cxx_aoflagger_new() };
+                    let prev_flag_array = flag_reason_array.as_ref().map(|_| flag_array.to_owned());
+                    with_increment_duration!(
+                        "flag",
+                        flag_jones_array_existing(
+                            &aoflagger,
+                            strategy,
+                            jones_array.view(),
+                            flag_array.view_mut(),
+                            true,
+                            self.draw_progress,
+                        )
+                    );
+                    record_new_flag_reasons(
+                        flag_reason_array.as_mut(),
+                        prev_flag_array,
+                        flag_array.view(),
+                        FlagReason::AOFlagger,
+                    )?;
+                }
+            }
+        }
+
+        if let Some(ssins) = self.ssins.as_ref() {
+            trace!("flagging with ssins");
+            let prev_flag_array = flag_reason_array.as_ref().map(|_| flag_array.to_owned());
+            with_increment_duration!(
+                "flag",
+                ssins.flag_jones_array(
+                    jones_array.view(),
+                    flag_array.view_mut(),
+                    ant_pairs,
+                    freqs_hz,
+                )?
+            );
+            record_new_flag_reasons(
+                flag_reason_array.as_mut(),
+                prev_flag_array,
+                flag_array.view(),
+                FlagReason::Ssins,
+            )?;
+        }
+
+        if !self.flag_thresholds.is_empty() {
+            trace!("extending flags above occupancy thresholds");
+            let prev_flag_array = flag_reason_array.as_ref().map(|_| flag_array.to_owned());
+            with_increment_duration!(
+                "flag",
+                self.flag_thresholds.extend_flags(
+                    flag_array.view_mut(),
+                    &vis_sel.timestep_range,
+                    vis_sel.coarse_chan_range.start * fine_chans_per_coarse,
+                    ant_pairs,
+                )?
+            );
+            record_new_flag_reasons(
+                flag_reason_array.as_mut(),
+                prev_flag_array,
+                flag_array.view(),
+                FlagReason::Occupancy,
+            )?;
+        }
+
+        Ok(base_weight_array)
+    }
+
+    /// The corrections which need the correlator metadata: cable lengths, digital gains and the
+    /// pfb passband.
+    #[allow(clippy::too_many_arguments)]
+    fn correct_mwalib(
+        &self,
+        corr_ctx: &CorrelatorContext,
+        mut jones_array: ArrayViewMut3<Jones<f32>>,
+        mut weight_array: ArrayViewMut3<f32>,
+        mut flag_array: ArrayViewMut3<bool>,
+        flag_reason_array: Option<&mut ArrayViewMut3<u16>>,
+        chunk_ctx: &ChunkContext,
+    ) -> Result<(), BirliError> {
+        let vis_sel = &chunk_ctx.vis_sel;
+
         if self.correct_cable_lengths {
             trace!("correcting cable lengths");
             with_increment_duration!(
@@ -176,23 +541,27 @@ impl<'a> PreprocessContext<'a> {
             );
         }
 
-        let sel_ant_pairs = vis_sel.get_ant_pairs(&corr_ctx.metafits_context);
-
         if self.correct_digital_gains {
             trace!("correcting digital gains");
+            let prev_flag_array = flag_reason_array.as_ref().map(|_| flag_array.to_owned());
             with_increment_duration!(
                 "correct_digital",
                 correct_digital_gains(
                     corr_ctx,
                     jones_array.view_mut(),
+                    flag_array.view_mut(),
                     &vis_sel.coarse_chan_range,
-                    &sel_ant_pairs,
+                    &chunk_ctx.ant_pairs,
                 )?
             );
+            record_new_flag_reasons(
+                flag_reason_array,
+                prev_flag_array,
+                flag_array.view(),
+                FlagReason::DigitalGains,
+            )?;
         }
 
-        let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
-
         // perform pfb passband gain corrections
         if let Some(passband_gains) = self.passband_gains {
             trace!("correcting pfb gains");
@@ -202,49 +571,69 @@ impl<'a> PreprocessContext<'a> {
                     jones_array.view_mut(),
                     weight_array.view_mut(),
                     passband_gains,
-                    fine_chans_per_coarse,
-                    &ScrunchType::from_mwa_version(corr_ctx.metafits_context.mwa_version.unwrap())?,
+                    chunk_ctx.fine_chans_per_coarse,
+                    &scrunch_type(corr_ctx)?,
                 )?
             );
         }
 
-        cfg_if! {
-            if #[cfg(feature = "aoflagger")] {
-                if let Some(strategy) = self.aoflagger_strategy.as_ref() {
-                    trace!("using aoflagger");
-                    let aoflagger = unsafe { cxx_aoflagger_new() };
-                    with_increment_duration!(
-                        "flag",
-                        flag_jones_array_existing(
-                            &aoflagger,
-                            strategy,
-                            jones_array.view(),
-                            flag_array.view_mut(),
-                            true,
-                            self.draw_progress,
-                        )
-                    );
-                }
-            }
-        }
+        Ok(())
+    }
 
-        if self.correct_geometry {
+    /// Correct geometric delays, phasing the visibilities to the phase centre, after
+    /// [`PreprocessContext::correct_and_flag`].
+    ///
+    /// Skipped without `corr_ctx`, for visibilities which have already been converted.
+    pub(crate) fn phase(
+        &self,
+        corr_ctx: Option<&CorrelatorContext>,
+        mut jones_array: ArrayViewMut3<Jones<f32>>,
+        chunk_ctx: &ChunkContext,
+    ) {
+        let vis_sel = &chunk_ctx.vis_sel;
+        if let (true, Some(corr_ctx)) = (self.correct_geometry, corr_ctx) {
             trace!("correcting geometric delays");
             with_increment_duration!(
                 "correct_geom",
-                correct_geometry(
-                    corr_ctx,
-                    jones_array.view_mut(),
-                    vis_sel,
-                    Some(self.array_pos),
-                    Some(self.phase_centre),
-                    self.draw_progress,
-                )
+                if let Some(phase_centres) = self.drift_phase_centres.as_ref() {
+                    correct_geometry_per_timestep(
+                        corr_ctx,
+                        jones_array.view_mut(),
+                        vis_sel,
+                        Some(self.array_pos),
+                        &phase_centres[vis_sel.timestep_range.clone()],
+                        self.draw_progress,
+                    );
+                } else {
+                    correct_geometry(
+                        corr_ctx,
+                        jones_array.view_mut(),
+                        vis_sel,
+                        Some(self.array_pos),
+                        Some(self.phase_centre),
+                        self.draw_progress,
+                    );
+                }
             );
         }
+    }
 
+    /// The last stage of [`PreprocessContext::preprocess_with_reasons`]: apply calibration
+    /// solutions and derive radiometer weights from `base_weight_array`.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn calibrate_and_weight(
+        &self,
+        mut jones_array: ArrayViewMut3<Jones<f32>>,
+        mut weight_array: ArrayViewMut3<f32>,
+        mut flag_array: ArrayViewMut3<bool>,
+        mut flag_reason_array: Option<ArrayViewMut3<u16>>,
+        chunk_ctx: &ChunkContext,
+        base_weight_array: Option<ArrayView3<f32>>,
+    ) -> Result<(), BirliError> {
+        let ant_pairs = &chunk_ctx.ant_pairs;
         if let Some(ref calsols) = self.calsols {
             trace!("applying calibration solutions");
+            let prev_flag_array = flag_reason_array.as_ref().map(|_| flag_array.to_owned());
             with_increment_duration!(
                 "calibrate",
                 apply_di_calsol(
@@ -252,13 +641,237 @@ impl<'a> PreprocessContext<'a> {
                     jones_array.view_mut(),
                     weight_array.view_mut(),
                     flag_array.view_mut(),
-                    &sel_ant_pairs,
+                    ant_pairs,
                 )?
             );
+            record_new_flag_reasons(
+                flag_reason_array.as_mut(),
+                prev_flag_array,
+                flag_array.view(),
+                FlagReason::Calibration,
+            )?;
+        }
+
+        if let Some(base_weight_array) = base_weight_array {
+            trace!("deriving weights from the radiometer equation");
+            weight_array.assign(&base_weight_array);
+            derive_radiometer_weights(
+                jones_array.view(),
+                weight_array.view_mut(),
+                flag_array.view_mut(),
+                flag_reason_array.as_mut(),
+                ant_pairs,
+            )?;
         }
 
         Ok(())
     }
+
+    /// Normalise visibilities by the autocorrelation amplitudes, using the autocorrelations in
+    /// `jones_array`, or reading them with `corr_ctx` if they aren't selected.
+    fn apply_auto_normalisation(
+        &self,
+        corr_ctx: Option<&CorrelatorContext>,
+        mut jones_array: ArrayViewMut3<Jones<f32>>,
+        mut flag_array: ArrayViewMut3<bool>,
+        flag_reason_array: Option<&mut ArrayViewMut3<u16>>,
+        chunk_ctx: &ChunkContext,
+    ) -> Result<(), BirliError> {
+        let ant_pairs = &chunk_ctx.ant_pairs;
+        let (auto_array, auto_ants) = match (select_autos(jones_array.view(), ant_pairs), corr_ctx)
+        {
+            (Some(autos), _) => autos,
+            (None, Some(corr_ctx)) => self.read_autos(corr_ctx, &chunk_ctx.vis_sel)?,
+            (None, None) => {
+                return Err(BirliError::BadArrayShape(BadArrayShape {
+                    argument: "jones_array",
+                    function: "PreprocessContext::apply_auto_normalisation",
+                    expected: "an autocorrelation for every antenna".into(),
+                    received: "missing autocorrelations".into(),
+                }))
+            }
+        };
+        let prev_flag_array = flag_reason_array.as_ref().map(|_| flag_array.to_owned());
+        let count = with_increment_duration!(
+            "correct_autos",
+            normalise_by_autos(
+                jones_array.view_mut(),
+                flag_array.view_mut(),
+                ant_pairs,
+                auto_array.view(),
+                &auto_ants,
+            )?
+        );
+        if count > 0 {
+            warn!("Flagged {count} visibilities with bad autocorrelations for normalisation");
+        }
+        record_new_flag_reasons(
+            flag_reason_array,
+            prev_flag_array,
+            flag_array.view(),
+            FlagReason::BadAutos,
+        )
+    }
+
+    /// Measure the coarse pfb passband gains from the autocorrelations of the selected antennas
+    /// which aren't flagged in `antenna_flags`, over every selected timestep and coarse channel
+    /// (see [`derive_passband_gains`]). Digital gains are corrected first if enabled, but no
+    /// passband correction is applied.
+    ///
+    /// # Errors
+    ///
+    /// will wrap errors from reading the autocorrelations, `correct_digital_gains` and
+    /// `derive_passband_gains`
+    pub fn measure_passband_gains(
+        &self,
+        corr_ctx: &CorrelatorContext,
+        vis_sel: &VisSelection,
+        antenna_flags: &[bool],
+    ) -> Result<Vec<f64>, BirliError> {
+        let prep_ctx = Self {
+            passband_gains: None,
+            ..self.clone()
+        };
+        let (auto_array, auto_ants) = prep_ctx.read_autos(corr_ctx, vis_sel)?;
+        let good_auto_idxs = auto_ants
+            .iter()
+            .positions(|&ant| !antenna_flags.get(ant).copied().unwrap_or(false))
+            .collect_vec();
+        Ok(derive_passband_gains(
+            auto_array.select(Axis(2), &good_auto_idxs).view(),
+            corr_ctx.metafits_context.num_corr_fine_chans_per_coarse,
+        )?)
+    }
+
+    /// Read the autocorrelations of the selected antennas, for a selection which doesn't include
+    /// them, with the same digital gain and passband corrections as the visibilities.
+    fn read_autos(
+        &self,
+        corr_ctx: &CorrelatorContext,
+        vis_sel: &VisSelection,
+    ) -> Result<(Array3<Jones<f32>>, Vec<usize>), BirliError> {
+        let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+        let auto_ants = vis_sel
+            .get_ant_pairs(&corr_ctx.metafits_context)
+            .into_iter()
+            .flat_map(|(ant1, ant2)| [ant1, ant2])
+            .unique()
+            .sorted()
+            .collect_vec();
+        let auto_sel = VisSelection {
+            baseline_idxs: auto_ants
+                .iter()
+                .map(|&ant| {
+                    corr_ctx
+                        .metafits_context
+                        .baselines
+                        .iter()
+                        .position(|bl| bl.ant1_index == ant && bl.ant2_index == ant)
+                        .ok_or_else(|| {
+                            BirliError::BadArrayShape(BadArrayShape {
+                                argument: "corr_ctx.metafits_context.baselines",
+                                function: "PreprocessContext::read_autos",
+                                expected: format!("an autocorrelation for antenna {ant}"),
+                                received: "none".into(),
+                            })
+                        })
+                })
+                .collect::<Result<_, _>>()?,
+            ..vis_sel.clone()
+        };
+        let mut auto_array = auto_sel.allocate_jones(fine_chans_per_coarse)?;
+        let mut flag_array = auto_sel.allocate_flags(fine_chans_per_coarse)?;
+        // unreadable HDUs are zero, so their visibilities are flagged by the normalisation.
+        read_mwalib_tolerant(
+            &auto_sel,
+            corr_ctx,
+            auto_array.view_mut(),
+            flag_array.view_mut(),
+            false,
+        )?;
+        if self.correct_digital_gains {
+            correct_digital_gains(
+                corr_ctx,
+                auto_array.view_mut(),
+                flag_array.view_mut(),
+                &auto_sel.coarse_chan_range,
+                &auto_sel.get_ant_pairs(&corr_ctx.metafits_context),
+            )?;
+        }
+        if let Some(passband_gains) = self.passband_gains {
+            let mut weight_array = auto_sel.allocate_weights(fine_chans_per_coarse)?;
+            correct_coarse_passband_gains(
+                auto_array.view_mut(),
+                weight_array.view_mut(),
+                passband_gains,
+                fine_chans_per_coarse,
+                &scrunch_type(corr_ctx)?,
+            )?;
+        }
+        Ok((auto_array, auto_ants))
+    }
+}
+
+/// The scrunch type to use for passband gains, from the correlator version in the metafits.
+fn scrunch_type(corr_ctx: &CorrelatorContext) -> Result<ScrunchType, BirliError> {
+    corr_ctx.metafits_context.mwa_version.map_or_else(
+        || {
+            Err(BirliError::BadMWAVersion {
+                message: "the metafits has no correlator version.".into(),
+                version: "None".into(),
+            })
+        },
+        ScrunchType::from_mwa_version,
+    )
+}
+
+/// Replace the weights with radiometer weights (see [`apply_radiometer_weights`]), recording the
+/// reason for any visibilities flagged for bad autocorrelations.
+fn derive_radiometer_weights(
+    jones_array: ArrayView3<Jones<f32>>,
+    weight_array: ArrayViewMut3<f32>,
+    mut flag_array: ArrayViewMut3<bool>,
+    flag_reason_array: Option<&mut ArrayViewMut3<u16>>,
+    sel_ant_pairs: &[(usize, usize)],
+) -> Result<(), BirliError> {
+    let prev_flag_array = flag_reason_array.as_ref().map(|_| flag_array.to_owned());
+    let count = with_increment_duration!(
+        "weights",
+        apply_radiometer_weights(
+            jones_array,
+            weight_array,
+            flag_array.view_mut(),
+            sel_ant_pairs,
+        )?
+    );
+    if count > 0 {
+        warn!("Flagged {count} visibilities with bad autocorrelations for weights");
+    }
+    record_new_flag_reasons(
+        flag_reason_array,
+        prev_flag_array,
+        flag_array.view(),
+        FlagReason::BadAutos,
+    )
+}
+
+/// Set the bit for `reason` in `flag_reason_array` for flags which are not in `prev_flag_array`,
+/// if reasons are being recorded (see [`set_new_flag_reasons`]).
+fn record_new_flag_reasons(
+    flag_reason_array: Option<&mut ArrayViewMut3<u16>>,
+    prev_flag_array: Option<Array3<bool>>,
+    flag_array: ArrayView3<bool>,
+    reason: FlagReason,
+) -> Result<(), BirliError> {
+    if let (Some(flag_reason_array), Some(prev_flag_array)) = (flag_reason_array, prev_flag_array) {
+        set_new_flag_reasons(
+            flag_reason_array.view_mut(),
+            prev_flag_array.view(),
+            flag_array,
+            reason,
+        )?;
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -275,11 +888,12 @@ mod tests {
     use tempfile::tempdir;
 
     use crate::{
+        approx::assert_abs_diff_eq,
         flag_to_weight_array,
         flags::get_weight_factor,
         io::{read_mwalib, write_uvfits},
         passband_gains::PFB_JAKE_2022_200HZ,
-        test_common::{compare_uvfits_with_csv, get_1254670392_avg_paths},
+        test_common::{compare_uvfits_with_csv, get_1254670392_avg_paths, get_mwax_context},
         FlagContext, VisSelection,
     };
 
@@ -375,6 +989,57 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_normalise_by_autos_without_autos_selected() {
+        let corr_ctx = get_mwax_context();
+        let prep_ctx = PreprocessContext {
+            array_pos: LatLngHeight::mwa(),
+            phase_centre: RADec::from_mwalib_phase_or_pointing(&corr_ctx.metafits_context),
+            correct_digital_gains: true,
+            passband_gains: Some(PFB_JAKE_2022_200HZ),
+            normalise_by_autos: true,
+            ..PreprocessContext::default()
+        };
+        let fine_chans_per_coarse = corr_ctx.metafits_context.num_corr_fine_chans_per_coarse;
+
+        // baseline 1 is the cross-correlation between antennas 0 and 1.
+        let preprocess = |baseline_idxs: Vec<usize>| {
+            let vis_sel = VisSelection {
+                baseline_idxs,
+                ..VisSelection::from_mwalib(&corr_ctx).unwrap()
+            };
+            let mut flag_array = vis_sel.allocate_flags(fine_chans_per_coarse).unwrap();
+            let mut jones_array = vis_sel.allocate_jones(fine_chans_per_coarse).unwrap();
+            let mut weight_array = vis_sel.allocate_weights(fine_chans_per_coarse).unwrap();
+            read_mwalib(
+                &vis_sel,
+                &corr_ctx,
+                jones_array.view_mut(),
+                flag_array.view_mut(),
+                false,
+            )
+            .unwrap();
+            prep_ctx
+                .preprocess(
+                    &corr_ctx,
+                    jones_array.view_mut(),
+                    weight_array.view_mut(),
+                    flag_array.view_mut(),
+                    &vis_sel,
+                )
+                .unwrap();
+            jones_array
+        };
+        let all_jones_array = preprocess(vec![0, 1, 2]);
+        let cross_jones_array = preprocess(vec![1]);
+
+        assert_abs_diff_eq!(
+            all_jones_array.slice(s![.., .., 1..2]),
+            cross_jones_array.view()
+        );
+        assert_abs_diff_eq!(all_jones_array[(0, 0, 0)][0].re, 1.0);
+    }
+
     #[test]
     pub fn test_handle_weird_mwa_versions() {
         let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();
@@ -434,5 +1099,17 @@ mod tests {
         );
 
         assert!(matches!(result, Err(BirliError::BadMWAVersion { .. })));
+
+        corr_ctx.metafits_context.mwa_version = None;
+
+        let result = prep_ctx.preprocess(
+            &corr_ctx,
+            jones_array.view_mut(),
+            weight_array.view_mut(),
+            flag_array.view_mut(),
+            &vis_sel,
+        );
+
+        assert!(matches!(result, Err(BirliError::BadMWAVersion { .. })));
     }
 }
@@ -0,0 +1,596 @@
+//! Simple sky models of point and Gaussian components, used to predict model visibilities for
+//! calibration.
+//!
+//! Sky models can be read from a whitespace-separated text file with one component per line:
+//!
+//! ```text
+//! # ra_deg dec_deg flux_jy [ref_freq_mhz spectral_index [major_arcsec minor_arcsec pa_deg]]
+//! 0.0  -27.0  10.0
+//! 1.5  -26.0   2.0  150.0  -0.8
+//! 2.0  -28.0   1.0  150.0  -0.8  120.0  60.0  45.0
+//! ```
+//!
+//! or from a JSON or YAML file with a list of components:
+//!
+//! ```json
+//! [
+//!   { "ra_deg": 0.0, "dec_deg": -27.0, "flux_jy": 10.0 },
+//!   {
+//!     "ra_deg": 2.0, "dec_deg": -28.0, "flux_jy": 1.0,
+//!     "ref_freq_mhz": 150.0, "spectral_index": -0.8,
+//!     "major_arcsec": 120.0, "minor_arcsec": 60.0, "pa_deg": 45.0
+//!   }
+//! ]
+//! ```
+//!
+//! ```yaml
+//! - ra_deg: 0.0
+//!   dec_deg: -27.0
+//!   flux_jy: 10.0
+//! ```
+//!
+//! Flux densities are apparent Stokes I; no primary beam is applied when predicting.
+
+use std::{
+    f64::consts::{LN_2, PI},
+    fs::read_to_string,
+    path::Path,
+};
+
+use marlu::{constants::VEL_C, io::error::BadArrayShape, Jones, RADec, UVW};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::ndarray::{parallel::prelude::*, ArrayView2, ArrayViewMut3, Axis};
+
+/// The reference frequency of components which don't specify one \[Hz\].
+pub const DEFAULT_REF_FREQ_HZ: f64 = 150e6;
+
+#[derive(Error, Debug)]
+/// Errors that can occur when reading a sky model.
+pub enum SkyModelError {
+    #[error("Couldn't read sky model {path}: {source}")]
+    /// When the sky model file can't be read
+    Read {
+        /// The path of the sky model
+        path: String,
+        /// The underlying error
+        source: std::io::Error,
+    },
+
+    #[error("Invalid sky model on line {line}: {message}")]
+    /// When a line of the sky model can't be parsed
+    Parse {
+        /// The line number (starting at 1)
+        line: usize,
+        /// Why the line couldn't be parsed
+        message: String,
+    },
+
+    #[error("Invalid JSON sky model: {0}")]
+    /// When a JSON sky model can't be parsed
+    Json(#[from] serde_json::Error),
+
+    #[error("Invalid YAML sky model: {0}")]
+    /// When a YAML sky model can't be parsed
+    Yaml(#[from] serde_yaml_ng::Error),
+
+    #[error("The sky model contains no components")]
+    /// When the sky model has no components
+    Empty,
+}
+
+/// The shape of a sky model component.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ComponentShape {
+    /// An unresolved point source.
+    Point,
+    /// An elliptical Gaussian.
+    Gaussian {
+        /// Full width at half maximum of the major axis \[radians\]
+        maj_rad: f64,
+        /// Full width at half maximum of the minor axis \[radians\]
+        min_rad: f64,
+        /// Position angle of the major axis, east of north \[radians\]
+        pa_rad: f64,
+    },
+}
+
+/// A single component of a sky model.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SkyComponent {
+    /// The position of the component
+    pub radec: RADec,
+    /// The Stokes I flux density at `ref_freq_hz` \[Jy\]
+    pub flux_jy: f64,
+    /// The frequency `flux_jy` is given at \[Hz\]
+    pub ref_freq_hz: f64,
+    /// The power-law spectral index of the component
+    pub spectral_index: f64,
+    /// The shape of the component
+    pub shape: ComponentShape,
+}
+
+impl SkyComponent {
+    /// The Stokes I flux density of the component at `freq_hz` \[Jy\].
+    pub fn flux_at(&self, freq_hz: f64) -> f64 {
+        self.flux_jy * (freq_hz / self.ref_freq_hz).powf(self.spectral_index)
+    }
+
+    /// The fraction of the component's flux density seen at `uvw`, in wavelengths.
+    fn envelope(&self, uvw: UVW) -> f64 {
+        match self.shape {
+            ComponentShape::Point => 1.,
+            ComponentShape::Gaussian {
+                maj_rad,
+                min_rad,
+                pa_rad,
+            } => {
+                let (s_pa, c_pa) = pa_rad.sin_cos();
+                let k_x = uvw.u * s_pa + uvw.v * c_pa;
+                let k_y = uvw.u * c_pa - uvw.v * s_pa;
+                (-PI * PI / (4. * LN_2)
+                    * (maj_rad.powi(2) * k_x.powi(2) + min_rad.powi(2) * k_y.powi(2)))
+                .exp()
+            }
+        }
+    }
+}
+
+/// A sky model made up of point and Gaussian components.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SkyModel {
+    /// The components of the sky model
+    pub components: Vec<SkyComponent>,
+}
+
+impl SkyModel {
+    /// Read a sky model from a file, as JSON if the extension is `.json`, YAML if it is `.yaml` or
+    /// `.yml`, otherwise as text.
+    ///
+    /// # Errors
+    ///
+    /// Can raise [`SkyModelError`] if the file can't be read or parsed, or has no components.
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self, SkyModelError> {
+        let path = path.as_ref();
+        let contents = read_to_string(path).map_err(|source| SkyModelError::Read {
+            path: path.display().to_string(),
+            source,
+        })?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("json") => Self::from_json(&contents),
+            Some(ext) if ext.eq_ignore_ascii_case("yaml") || ext.eq_ignore_ascii_case("yml") => {
+                Self::from_yaml(&contents)
+            }
+            _ => Self::from_text(&contents),
+        }
+    }
+
+    /// Parse a sky model from text, with one component per line of whitespace-separated
+    /// `ra_deg dec_deg flux_jy [ref_freq_mhz spectral_index [major_arcsec minor_arcsec pa_deg]]`.
+    /// Blank lines and anything after a `#` are ignored.
+    ///
+    /// # Errors
+    ///
+    /// Can raise [`SkyModelError`] if a line can't be parsed, or there are no components.
+    pub fn from_text(contents: &str) -> Result<Self, SkyModelError> {
+        let mut components = vec![];
+        for (line_idx, line) in contents.lines().enumerate() {
+            let line = strip_comment(line);
+            if line.is_empty() {
+                continue;
+            }
+            let values = line
+                .split_whitespace()
+                .map(|value| parse_value(line_idx + 1, "value", value))
+                .collect::<Result<Vec<_>, _>>()?;
+            let component = match values[..] {
+                [ra, dec, flux] => new_component(ra, dec, flux, None, None, None),
+                [ra, dec, flux, freq, index] => {
+                    new_component(ra, dec, flux, Some(freq), Some(index), None)
+                }
+                [ra, dec, flux, freq, index, maj, min, pa] => {
+                    new_component(ra, dec, flux, Some(freq), Some(index), Some((maj, min, pa)))
+                }
+                _ => {
+                    return Err(SkyModelError::Parse {
+                        line: line_idx + 1,
+                        message: format!("expected 3, 5 or 8 values, found {}", values.len()),
+                    })
+                }
+            };
+            components.push(component);
+        }
+        Self::new(components)
+    }
+
+    /// Parse a sky model from a JSON list of components, each an object with the keys `ra_deg`,
+    /// `dec_deg` and `flux_jy`, and optionally `ref_freq_mhz`, `spectral_index`, and
+    /// `major_arcsec`, `minor_arcsec` and `pa_deg` for Gaussians. The list may be nested under a
+    /// top-level `components` key.
+    ///
+    /// # Errors
+    ///
+    /// Can raise [`SkyModelError`] if the JSON can't be parsed, a component is missing a key or
+    /// has an unknown one, or there are no components.
+    pub fn from_json(contents: &str) -> Result<Self, SkyModelError> {
+        let components: Vec<SerdeComponent> =
+            if let serde_json::Value::Object(_) = serde_json::from_str(contents)? {
+                serde_json::from_str::<SerdeSkyModel>(contents)?.components
+            } else {
+                serde_json::from_str(contents)?
+            };
+        Self::new(components.into_iter().map(|SerdeComponent(c)| c).collect())
+    }
+
+    /// Parse a sky model from a YAML list of components, with the same keys as
+    /// [`SkyModel::from_json`]. The list may be nested under a top-level `components` key.
+    ///
+    /// # Errors
+    ///
+    /// Can raise [`SkyModelError`] if the YAML can't be parsed, a component is missing a key or
+    /// has an unknown one, or there are no components.
+    pub fn from_yaml(contents: &str) -> Result<Self, SkyModelError> {
+        let components: Vec<SerdeComponent> =
+            if let serde_yaml_ng::Value::Mapping(_) = serde_yaml_ng::from_str(contents)? {
+                serde_yaml_ng::from_str::<SerdeSkyModel>(contents)?.components
+            } else {
+                serde_yaml_ng::from_str(contents)?
+            };
+        Self::new(components.into_iter().map(|SerdeComponent(c)| c).collect())
+    }
+
+    fn new(components: Vec<SkyComponent>) -> Result<Self, SkyModelError> {
+        if components.is_empty() {
+            return Err(SkyModelError::Empty);
+        }
+        Ok(Self { components })
+    }
+
+    /// Predict the model visibilities of the sky model, with dimensions
+    /// `[timestep][channel][baseline]`, given the UVWs of each baseline in metres with dimensions
+    /// `[timestep][baseline]`, and the frequency of each channel.
+    ///
+    /// Visibilities are phased to `phase_centre`, with the same sign convention as
+    /// [`crate::correct_geometry`]. Each component contributes its Stokes I flux density to the
+    /// XX and YY polarisations.
+    ///
+    /// # Errors
+    ///
+    /// Can raise [`BadArrayShape`] if the shapes of `model_array`, `uvws` and `freqs_hz` don't
+    /// match.
+    pub fn predict(
+        &self,
+        mut model_array: ArrayViewMut3<Jones<f32>>,
+        uvws: ArrayView2<UVW>,
+        freqs_hz: &[f64],
+        phase_centre: RADec,
+    ) -> Result<(), BadArrayShape> {
+        let model_dims = model_array.dim();
+        let expected_dims = (uvws.dim().0, freqs_hz.len(), uvws.dim().1);
+        if model_dims != expected_dims {
+            return Err(BadArrayShape {
+                argument: "model_array",
+                function: "SkyModel::predict",
+                expected: format!("{expected_dims:?}"),
+                received: format!("{model_dims:?}"),
+            });
+        }
+
+        let lmns = self
+            .components
+            .iter()
+            .map(|component| component.radec.to_lmn(phase_centre))
+            .collect::<Vec<_>>();
+
+        model_array
+            .axis_iter_mut(Axis(0))
+            .into_par_iter()
+            .zip(uvws.axis_iter(Axis(0)))
+            .for_each(|(mut model_array, uvws)| {
+                for (mut model_array, &freq_hz) in model_array.axis_iter_mut(Axis(0)).zip(freqs_hz)
+                {
+                    let fluxes = self
+                        .components
+                        .iter()
+                        .map(|component| component.flux_at(freq_hz))
+                        .collect::<Vec<_>>();
+                    let lambda_m = VEL_C / freq_hz;
+                    for (model, &uvw) in model_array.iter_mut().zip(uvws) {
+                        let uvw = UVW {
+                            u: uvw.u / lambda_m,
+                            v: uvw.v / lambda_m,
+                            w: uvw.w / lambda_m,
+                        };
+                        let vis = self.components.iter().zip(&lmns).zip(&fluxes).fold(
+                            marlu::Complex::new(0., 0.),
+                            |acc, ((component, lmn), &flux)| {
+                                acc + marlu::Complex::from_polar(
+                                    flux * component.envelope(uvw),
+                                    lmn.dot(uvw),
+                                )
+                            },
+                        );
+                        let zero = marlu::Complex::new(0., 0.);
+                        *model = Jones::<f32>::from(Jones::<f64>::from([vis, zero, zero, vis]));
+                    }
+                }
+            });
+        Ok(())
+    }
+}
+
+/// A JSON or YAML sky model with its list of components under a `components` key.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SerdeSkyModel {
+    components: Vec<SerdeComponent>,
+}
+
+/// A sky model component as written in JSON or YAML, checked when it is deserialized.
+#[derive(Deserialize)]
+#[serde(try_from = "RawSerdeComponent")]
+struct SerdeComponent(SkyComponent);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawSerdeComponent {
+    ra_deg: f64,
+    dec_deg: f64,
+    flux_jy: f64,
+    ref_freq_mhz: Option<f64>,
+    spectral_index: Option<f64>,
+    major_arcsec: Option<f64>,
+    minor_arcsec: Option<f64>,
+    pa_deg: Option<f64>,
+}
+
+impl TryFrom<RawSerdeComponent> for SerdeComponent {
+    type Error = String;
+
+    fn try_from(raw: RawSerdeComponent) -> Result<Self, Self::Error> {
+        let gaussian = match (raw.major_arcsec, raw.minor_arcsec) {
+            (Some(maj), Some(min)) => Some((maj, min, raw.pa_deg.unwrap_or(0.))),
+            (None, None) => None,
+            _ => return Err("Gaussian components need both major_arcsec and minor_arcsec".into()),
+        };
+        Ok(Self(new_component(
+            raw.ra_deg,
+            raw.dec_deg,
+            raw.flux_jy,
+            raw.ref_freq_mhz,
+            raw.spectral_index,
+            gaussian,
+        )))
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    line.split('#').next().unwrap_or_default().trim()
+}
+
+fn parse_value(line: usize, key: &str, value: &str) -> Result<f64, SkyModelError> {
+    value.parse().map_err(|_| SkyModelError::Parse {
+        line,
+        message: format!("couldn't parse {key} \"{value}\" as a number"),
+    })
+}
+
+fn new_component(
+    ra_deg: f64,
+    dec_deg: f64,
+    flux_jy: f64,
+    ref_freq_mhz: Option<f64>,
+    spectral_index: Option<f64>,
+    gaussian: Option<(f64, f64, f64)>,
+) -> SkyComponent {
+    SkyComponent {
+        radec: RADec::from_degrees(ra_deg, dec_deg),
+        flux_jy,
+        ref_freq_hz: ref_freq_mhz.map_or(DEFAULT_REF_FREQ_HZ, |freq_mhz| freq_mhz * 1e6),
+        spectral_index: spectral_index.unwrap_or(0.),
+        shape: gaussian.map_or(ComponentShape::Point, |(maj_arcsec, min_arcsec, pa_deg)| {
+            ComponentShape::Gaussian {
+                maj_rad: (maj_arcsec / 3600.).to_radians(),
+                min_rad: (min_arcsec / 3600.).to_radians(),
+                pa_rad: pa_deg.to_radians(),
+            }
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_abs_diff_eq;
+    use marlu::{constants::VEL_C, Complex};
+    use ndarray::{Array2, Array3};
+
+    use super::*;
+
+    #[test]
+    fn test_text_matches_json_and_yaml() {
+        let text = "# a comment\n\
+            0.0 -27.0 10.0\n\
+            \n\
+            1.5 -26.0 2.0 150.0 -0.8 # trailing comment\n\
+            2.0 -28.0 1.0 200.0 -0.7 120.0 60.0 45.0\n";
+        let json = r#"{"components": [
+            {"ra_deg": 0.0, "dec_deg": -27.0, "flux_jy": 10.0},
+            {"ra_deg": 1.5, "dec_deg": -26.0, "flux_jy": 2.0, "spectral_index": -0.8},
+            {
+                "ra_deg": 2.0, "dec_deg": -28.0, "flux_jy": 1.0, "ref_freq_mhz": 200,
+                "spectral_index": -0.7, "major_arcsec": 120, "minor_arcsec": 60, "pa_deg": 45
+            }
+        ]}"#;
+        let yaml = "\
+- ra_deg: 0.0
+  dec_deg: -27.0
+  flux_jy: 10.0
+- {ra_deg: 1.5, dec_deg: -26.0, flux_jy: 2.0, spectral_index: -0.8}
+- ra_deg: 2.0
+  dec_deg: -28.0
+  flux_jy: 1.0
+  ref_freq_mhz: 200
+  spectral_index: -0.7
+  major_arcsec: 120
+  minor_arcsec: 60
+  pa_deg: 45
+";
+        let from_text = SkyModel::from_text(text).unwrap();
+        let from_json = SkyModel::from_json(json).unwrap();
+        assert_eq!(from_text, from_json);
+        assert_eq!(from_text, SkyModel::from_yaml(yaml).unwrap());
+        assert_eq!(
+            from_text,
+            SkyModel::from_yaml(&format!("components:\n{yaml}")).unwrap()
+        );
+        assert_eq!(from_text.components.len(), 3);
+        assert_eq!(from_text.components[0].shape, ComponentShape::Point);
+        assert_abs_diff_eq!(
+            from_text.components[1].flux_at(300e6),
+            2.0 * 2.0_f64.powf(-0.8)
+        );
+        assert!(matches!(
+            from_text.components[2].shape,
+            ComponentShape::Gaussian { .. }
+        ));
+    }
+
+    #[test]
+    fn test_invalid_sky_models() {
+        assert!(matches!(
+            SkyModel::from_text("0.0 -27.0"),
+            Err(SkyModelError::Parse { line: 1, .. })
+        ));
+        assert!(matches!(
+            SkyModel::from_text("\n0.0 -27.0 ten"),
+            Err(SkyModelError::Parse { line: 2, .. })
+        ));
+        assert!(matches!(
+            SkyModel::from_text("# nothing here"),
+            Err(SkyModelError::Empty)
+        ));
+        assert!(matches!(
+            SkyModel::from_json(r#"[{"ra_deg": 0.0, "flux_jy": 1.0}]"#),
+            Err(SkyModelError::Json(_))
+        ));
+        assert!(matches!(
+            SkyModel::from_json(
+                r#"[{"ra_deg": 0.0, "dec_deg": 0.0, "flux_jy": 1.0, "fluxx": 1.0}]"#
+            ),
+            Err(SkyModelError::Json(_))
+        ));
+        assert!(matches!(
+            SkyModel::from_json(
+                r#"[{"ra_deg": 0.0, "dec_deg": 0.0, "flux_jy": 1.0, "major_arcsec": 1}]"#
+            ),
+            Err(SkyModelError::Json(_))
+        ));
+        assert!(matches!(
+            SkyModel::from_json(r#"{"components": []}"#),
+            Err(SkyModelError::Empty)
+        ));
+        assert!(matches!(
+            SkyModel::from_yaml("- ra_deg: 0.0\n  flux_jy: 1.0\n"),
+            Err(SkyModelError::Yaml(_))
+        ));
+        assert!(matches!(
+            SkyModel::from_yaml("components: []\n"),
+            Err(SkyModelError::Empty)
+        ));
+    }
+
+    /// The format is chosen by the extension, ignoring case.
+    #[test]
+    fn test_read_by_extension() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let yaml = "- ra_deg: 0.0\n  dec_deg: -27.0\n  flux_jy: 10.0\n";
+        let expected = SkyModel::from_text("0.0 -27.0 10.0").unwrap();
+        for name in ["sky.yaml", "sky.yml", "sky.YML"] {
+            let path = tmp_dir.path().join(name);
+            std::fs::write(&path, yaml).unwrap();
+            assert_eq!(SkyModel::read(&path).unwrap(), expected);
+        }
+        let path = tmp_dir.path().join("sky.json");
+        std::fs::write(
+            &path,
+            r#"[{"ra_deg": 0.0, "dec_deg": -27.0, "flux_jy": 10.0}]"#,
+        )
+        .unwrap();
+        assert_eq!(SkyModel::read(&path).unwrap(), expected);
+        let path = tmp_dir.path().join("sky.txt");
+        std::fs::write(&path, "0.0 -27.0 10.0\n").unwrap();
+        assert_eq!(SkyModel::read(&path).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_predict() {
+        let phase_centre = RADec::from_degrees(0.0, -27.0);
+        let offset = RADec::from_degrees(1.0, -27.0);
+        let model = SkyModel {
+            components: vec![
+                new_component(0.0, -27.0, 3.0, None, None, None),
+                new_component(1.0, -27.0, 2.0, Some(100.0), Some(-1.0), None),
+            ],
+        };
+        let freqs_hz = [100e6, 200e6];
+        let uvws = Array2::from_shape_fn((1, 2), |(_, bl)| UVW {
+            u: 100. * bl as f64,
+            v: -50. * bl as f64,
+            w: 10. * bl as f64,
+        });
+        let mut model_array = Array3::from_elem((1, 2, 2), Jones::default());
+        model
+            .predict(model_array.view_mut(), uvws.view(), &freqs_hz, phase_centre)
+            .unwrap();
+
+        // a zero-length baseline sees the total flux density
+        assert_abs_diff_eq!(model_array[(0, 0, 0)][0], Complex::new(5.0, 0.0));
+        assert_abs_diff_eq!(model_array[(0, 1, 0)][3], Complex::new(4.0, 0.0));
+        assert_abs_diff_eq!(model_array[(0, 1, 0)][1], Complex::new(0.0, 0.0));
+
+        let lmn = offset.to_lmn(phase_centre);
+        let lambda_m = VEL_C / 200e6;
+        let uvw = UVW {
+            u: 100. / lambda_m,
+            v: -50. / lambda_m,
+            w: 10. / lambda_m,
+        };
+        let expected = Complex::new(3.0, 0.0) + Complex::from_polar(1.0, lmn.dot(uvw));
+        assert_abs_diff_eq!(
+            Complex::<f64>::new(
+                model_array[(0, 1, 1)][0].re as f64,
+                model_array[(0, 1, 1)][0].im as f64
+            ),
+            expected,
+            epsilon = 1e-5
+        );
+
+        // a Gaussian is resolved out on long baselines
+        let model = SkyModel {
+            components: vec![new_component(
+                0.0,
+                -27.0,
+                1.0,
+                None,
+                None,
+                Some((600.0, 600.0, 0.0)),
+            )],
+        };
+        let uvws = Array2::from_shape_fn((1, 2), |(_, bl)| UVW {
+            u: 1e4 * bl as f64,
+            v: 0.,
+            w: 0.,
+        });
+        model
+            .predict(model_array.view_mut(), uvws.view(), &freqs_hz, phase_centre)
+            .unwrap();
+        assert_abs_diff_eq!(model_array[(0, 0, 0)][0].re, 1.0);
+        assert!(model_array[(0, 0, 1)][0].norm() < 1e-3);
+
+        let mut bad_array = Array3::from_elem((1, 3, 2), Jones::default());
+        assert!(model
+            .predict(bad_array.view_mut(), uvws.view(), &freqs_hz, phase_centre)
+            .is_err());
+    }
+}
@@ -0,0 +1,479 @@
+//! Flagging faint broadband RFI with Sky-Subtracted Incoherent Noise Spectra (SSINS).
+//!
+//! `AOFlagger` looks at each baseline separately, so RFI which is too faint to see on a single
+//! baseline, like distant digital TV, can be missed. SSINS subtracts consecutive timesteps to remove
+//! the sky, then averages the amplitude of the difference over all baselines. RFI which is
+//! common to many baselines stands out in this spectrum, and is found by testing it against a set
+//! of shapes in frequency.
+//!
+//! See [Wilensky et al. (2019)](https://doi.org/10.1088/1538-3873/ab3cad) for details.
+
+use std::fmt::Display;
+
+use itertools::izip;
+use log::{info, warn};
+use marlu::{io::error::BadArrayShape, Jones};
+
+use crate::{
+    ndarray::{parallel::prelude::*, prelude::*},
+    BirliError,
+};
+
+/// The frequency ranges [Hz] of the Australian digital TV channels within the MWA band.
+pub const DTV_SHAPES_HZ: [(&str, f64, f64); 8] = [
+    ("TV6", 174e6, 181e6),
+    ("TV7", 181e6, 188e6),
+    ("TV8", 188e6, 195e6),
+    ("TV9", 195e6, 202e6),
+    ("TV10", 202e6, 209e6),
+    ("TV11", 209e6, 216e6),
+    ("TV12", 216e6, 223e6),
+    ("TV13", 223e6, 230e6),
+];
+
+/// The number of polarisations in a [`Jones`] visibility.
+const NUM_POLS: usize = 4;
+
+/// The scale factor from the median absolute deviation to the standard deviation of a normal
+/// distribution.
+const MAD_TO_SIGMA: f64 = 1.4826;
+
+/// Options for flagging with Sky-Subtracted Incoherent Noise Spectra.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SsinsContext {
+    /// The significance, in standard deviations, above which a shape is flagged.
+    pub sigma: f64,
+    /// Named frequency ranges [Hz] to test, as well as single channels (`narrow`) and the whole
+    /// band (`streak`).
+    pub shapes: Vec<(String, f64, f64)>,
+}
+
+impl Default for SsinsContext {
+    fn default() -> Self {
+        Self {
+            sigma: 5.0,
+            shapes: DTV_SHAPES_HZ
+                .iter()
+                .map(|&(name, start_hz, end_hz)| (name.to_string(), start_hz, end_hz))
+                .collect(),
+        }
+    }
+}
+
+impl Display for SsinsContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "SSINS at {} sigma, with shapes narrow, streak",
+            self.sigma
+        )?;
+        for (name, _, _) in &self.shapes {
+            write!(f, ", {name}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A region of the incoherent noise spectrum which was flagged by SSINS.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SsinsEvent {
+    /// The index of the first of the two timesteps which were differenced, relative to the start
+    /// of the flag array. Both timesteps are flagged.
+    pub timestep_idx: usize,
+    /// The name of the shape which was flagged: `narrow`, `streak` or one of
+    /// [`SsinsContext::shapes`].
+    pub shape: String,
+    /// The channel indices which were flagged, relative to the start of the flag array.
+    pub chan_idxs: Vec<usize>,
+    /// The significance of the shape, in standard deviations.
+    pub sigma: f64,
+}
+
+impl SsinsContext {
+    /// Flag broadband and narrowband RFI in `flag_array` which is common to all baselines.
+    ///
+    /// The incoherent noise spectrum is the amplitude of the difference between consecutive
+    /// timesteps, averaged over the unflagged cross-correlations, for each polarisation. The mean
+    /// of each channel over time is subtracted, and it is normalised by a robust estimate of its
+    /// standard deviation. Then the most significant single channel, whole band or named shape in
+    /// any timestep is found, and if it is more than [`SsinsContext::sigma`] from zero, it is
+    /// flagged and excluded from the statistics. This repeats until nothing is above the
+    /// threshold. Each flagged region is flagged on all baselines for both timesteps in the
+    /// difference.
+    ///
+    /// # Arguments
+    ///
+    /// - `jones_array` - `[timestep][channel][baseline]` visibilities
+    /// - `flag_array` - `[timestep][channel][baseline]` flags, both used and updated
+    /// - `ant_pairs` - the antenna indices of each baseline
+    /// - `freqs_hz` - the centre frequency of each channel
+    ///
+    /// # Errors
+    ///
+    /// Can throw error if the shapes of the arguments don't match.
+    pub fn flag_jones_array(
+        &self,
+        jones_array: ArrayView3<Jones<f32>>,
+        mut flag_array: ArrayViewMut3<bool>,
+        ant_pairs: &[(usize, usize)],
+        freqs_hz: &[f64],
+    ) -> Result<Vec<SsinsEvent>, BirliError> {
+        let shape = jones_array.dim();
+        for (argument, received) in [
+            ("flag_array", flag_array.dim()),
+            ("ant_pairs", (shape.0, shape.1, ant_pairs.len())),
+            ("freqs_hz", (shape.0, freqs_hz.len(), shape.2)),
+        ] {
+            if received != shape {
+                return Err(BirliError::BadArrayShape(BadArrayShape {
+                    argument,
+                    function: "SsinsContext::flag_jones_array",
+                    expected: format!("{shape:?}"),
+                    received: format!("{received:?}"),
+                }));
+            }
+        }
+        let (num_timesteps, num_chans, _) = shape;
+        if num_timesteps < 2 {
+            warn!(
+                "SSINS needs at least 2 timesteps, skipping a chunk of {num_timesteps}. Use a \
+                larger --time-chunk or --chunk-overlap to flag every timestep."
+            );
+            return Ok(vec![]);
+        }
+
+        let (spectrum, mut mask) =
+            incoherent_noise_spectrum(jones_array, flag_array.view(), ant_pairs);
+
+        // the channel indices of each shape, skipping any which are outside the band.
+        let mut shapes = vec![("streak".to_string(), (0..num_chans).collect::<Vec<_>>())];
+        for (name, start_hz, end_hz) in &self.shapes {
+            let chan_idxs = freqs_hz
+                .iter()
+                .enumerate()
+                .filter(|(_, &freq_hz)| freq_hz >= *start_hz && freq_hz < *end_hz)
+                .map(|(chan_idx, _)| chan_idx)
+                .collect::<Vec<_>>();
+            if !chan_idxs.is_empty() {
+                shapes.push((name.clone(), chan_idxs));
+            }
+        }
+
+        let mut events = vec![];
+        while let Some(z_scores) = z_scores(spectrum.view(), mask.view()) {
+            let event = match self.most_significant(z_scores.view(), mask.view(), &shapes) {
+                Some(event) => event,
+                None => break,
+            };
+            for &chan_idx in &event.chan_idxs {
+                mask[(event.timestep_idx, chan_idx)] = true;
+            }
+            events.push(event);
+        }
+
+        for event in &events {
+            for &chan_idx in &event.chan_idxs {
+                flag_array
+                    .slice_mut(s![event.timestep_idx..event.timestep_idx + 2, chan_idx, ..])
+                    .fill(true);
+            }
+        }
+
+        if !events.is_empty() {
+            let mut shape_counts = vec![];
+            for event in &events {
+                match shape_counts
+                    .iter_mut()
+                    .find(|(name, _)| *name == event.shape)
+                {
+                    Some((_, count)) => *count += 1,
+                    None => shape_counts.push((event.shape.clone(), 1)),
+                }
+            }
+            info!(
+                "SSINS flagged {} regions: {}",
+                events.len(),
+                shape_counts
+                    .iter()
+                    .map(|(name, count)| format!("{count} {name}"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+
+        Ok(events)
+    }
+
+    /// Find the most significant shape in `z_scores` which is above the threshold.
+    fn most_significant(
+        &self,
+        z_scores: ArrayView3<f64>,
+        mask: ArrayView2<bool>,
+        shapes: &[(String, Vec<usize>)],
+    ) -> Option<SsinsEvent> {
+        let mut result: Option<SsinsEvent> = None;
+        let mut consider = |timestep_idx: usize, shape: &str, chan_idxs: Vec<usize>, z: f64| {
+            if z.abs() > self.sigma && result.as_ref().map_or(true, |r| z.abs() > r.sigma.abs()) {
+                result = Some(SsinsEvent {
+                    timestep_idx,
+                    shape: shape.to_string(),
+                    chan_idxs,
+                    sigma: z,
+                });
+            }
+        };
+        for (timestep_idx, (timestep_z, timestep_mask)) in
+            izip!(z_scores.outer_iter(), mask.outer_iter()).enumerate()
+        {
+            for pol_idx in 0..NUM_POLS {
+                let pol_z = timestep_z.index_axis(Axis(1), pol_idx);
+                // narrowband: a single channel
+                for (chan_idx, (&z, &masked)) in izip!(pol_z, timestep_mask).enumerate() {
+                    if !masked {
+                        consider(timestep_idx, "narrow", vec![chan_idx], z);
+                    }
+                }
+                // broadband: the mean of the unmasked channels in the shape, scaled by the
+                // square root of their number, so that it is in units of sigma.
+                for (name, chan_idxs) in shapes {
+                    let unmasked = chan_idxs
+                        .iter()
+                        .copied()
+                        .filter(|&chan_idx| !timestep_mask[chan_idx])
+                        .collect::<Vec<_>>();
+                    if unmasked.len() < 2 {
+                        continue;
+                    }
+                    let sum: f64 = unmasked.iter().map(|&chan_idx| pol_z[chan_idx]).sum();
+                    let z = sum / (unmasked.len() as f64).sqrt();
+                    consider(timestep_idx, name, unmasked, z);
+                }
+            }
+        }
+        result
+    }
+}
+
+/// Build the incoherent noise spectrum, `[timestep][channel][pol]`, from the mean amplitude of the
+/// difference between consecutive timesteps over the unflagged cross-correlations, along with a
+/// `[timestep][channel]` mask of where no baselines were unflagged.
+fn incoherent_noise_spectrum(
+    jones_array: ArrayView3<Jones<f32>>,
+    flag_array: ArrayView3<bool>,
+    ant_pairs: &[(usize, usize)],
+) -> (Array3<f64>, Array2<bool>) {
+    let (num_timesteps, num_chans, _) = jones_array.dim();
+    let mut spectrum = Array3::<f64>::zeros((num_timesteps - 1, num_chans, NUM_POLS));
+    let mut mask = Array2::from_elem((num_timesteps - 1, num_chans), false);
+    spectrum
+        .outer_iter_mut()
+        .into_par_iter()
+        .zip(mask.outer_iter_mut())
+        .enumerate()
+        .for_each(|(timestep_idx, (mut spectrum, mut mask))| {
+            let window = s![timestep_idx..timestep_idx + 2, .., ..];
+            let jones_window = jones_array.slice(window);
+            let flag_window = flag_array.slice(window);
+            for (mut chan_spectrum, masked, chan_jones, chan_flags) in izip!(
+                spectrum.outer_iter_mut(),
+                mask.iter_mut(),
+                jones_window.axis_iter(Axis(1)),
+                flag_window.axis_iter(Axis(1)),
+            ) {
+                let mut count = 0_usize;
+                for (&(ant1, ant2), jones, flags) in izip!(
+                    ant_pairs,
+                    chan_jones.axis_iter(Axis(1)),
+                    chan_flags.axis_iter(Axis(1)),
+                ) {
+                    if ant1 == ant2 || flags[0] || flags[1] {
+                        continue;
+                    }
+                    let diff = jones[1] - jones[0];
+                    for (value, pol) in izip!(chan_spectrum.iter_mut(), diff.iter()) {
+                        *value += pol.norm() as f64;
+                    }
+                    count += 1;
+                }
+                if count == 0 {
+                    *masked = true;
+                } else {
+                    chan_spectrum /= count as f64;
+                }
+            }
+        });
+    (spectrum, mask)
+}
+
+/// Subtract the mean of each channel over time from the spectrum, and normalise by a robust
+/// estimate of the standard deviation of each polarisation, ignoring masked samples. Masked
+/// samples are zero. Returns `None` if there is nothing left to normalise.
+fn z_scores(spectrum: ArrayView3<f64>, mask: ArrayView2<bool>) -> Option<Array3<f64>> {
+    let (_, num_chans, num_pols) = spectrum.dim();
+    let mut z_scores = spectrum.to_owned();
+    for chan_idx in 0..num_chans {
+        let chan_mask = mask.index_axis(Axis(1), chan_idx);
+        let mut chan_z = z_scores.index_axis_mut(Axis(1), chan_idx);
+        let count = chan_mask.iter().filter(|&&masked| !masked).count();
+        for mut pol_z in chan_z.axis_iter_mut(Axis(1)) {
+            let sum: f64 = izip!(pol_z.iter(), chan_mask.iter())
+                .filter(|(_, &masked)| !masked)
+                .map(|(&value, _)| value)
+                .sum();
+            let mean = if count > 0 { sum / count as f64 } else { 0.0 };
+            for (value, &masked) in izip!(pol_z.iter_mut(), chan_mask.iter()) {
+                *value = if masked { 0.0 } else { *value - mean };
+            }
+        }
+    }
+    for pol_idx in 0..num_pols {
+        let mut pol_z = z_scores.index_axis_mut(Axis(2), pol_idx);
+        let mut deviations = izip!(pol_z.iter(), mask.iter())
+            .filter(|(_, &masked)| !masked)
+            .map(|(&value, _)| value.abs())
+            .collect::<Vec<_>>();
+        if deviations.is_empty() {
+            return None;
+        }
+        let mid = deviations.len() / 2;
+        let (_, &mut median, _) = deviations.select_nth_unstable_by(mid, f64::total_cmp);
+        let sigma = median * MAD_TO_SIGMA;
+        if sigma <= 0.0 || !sigma.is_finite() {
+            return None;
+        }
+        pol_z /= sigma;
+    }
+    Some(z_scores)
+}
+
+#[cfg(test)]
+mod tests {
+    use marlu::{Complex, Jones};
+    use ndarray::prelude::*;
+
+    use super::SsinsContext;
+    use crate::ndarray;
+
+    /// A deterministic approximately normal random number generator, by summing uniforms from a
+    /// xorshift generator.
+    struct Noise(u64);
+
+    impl Noise {
+        fn next(&mut self) -> f32 {
+            (0..12)
+                .map(|_| {
+                    self.0 ^= self.0 << 13;
+                    self.0 ^= self.0 >> 7;
+                    self.0 ^= self.0 << 17;
+                    (self.0 >> 40) as f32 / (1_u64 << 24) as f32
+                })
+                .sum::<f32>()
+                - 6.0
+        }
+
+        fn next_jones(&mut self) -> Jones<f32> {
+            Jones::from([
+                Complex::new(self.next(), self.next()),
+                Complex::new(self.next(), self.next()),
+                Complex::new(self.next(), self.next()),
+                Complex::new(self.next(), self.next()),
+            ])
+        }
+    }
+
+    #[test]
+    fn test_ssins_flags_faint_dtv() {
+        let num_ants = 16;
+        let ant_pairs = (0..num_ants)
+            .flat_map(|ant1| (ant1..num_ants).map(move |ant2| (ant1, ant2)))
+            .collect::<Vec<_>>();
+        // 1 MHz channels from 170 MHz, so channels 11..18 are in TV7.
+        let freqs_hz = (0..32)
+            .map(|i| 170.5e6 + i as f64 * 1e6)
+            .collect::<Vec<_>>();
+        let shape = (20, freqs_hz.len(), ant_pairs.len());
+
+        let mut noise = Noise(0x2545_f491_4f6c_dd1d);
+        let sky = Array2::from_shape_fn((shape.1, shape.2), |(c, b)| {
+            Jones::identity() * (10.0 + (c * b) as f32 % 7.0)
+        });
+        let mut jones_array = Array3::from_shape_fn(shape, |(_, c, b)| sky[(c, b)]);
+        jones_array.mapv_inplace(|jones| jones + noise.next_jones());
+
+        // faint DTV in timestep 12, no brighter than the noise on a single baseline.
+        for c in 11..18 {
+            for jones in jones_array.slice_mut(s![12, c, ..]) {
+                *jones += Jones::identity() * Complex::new(1.2, 0.9);
+            }
+        }
+
+        let mut flag_array = Array3::from_elem(shape, false);
+        let events = SsinsContext::default()
+            .flag_jones_array(
+                jones_array.view(),
+                flag_array.view_mut(),
+                &ant_pairs,
+                &freqs_hz,
+            )
+            .unwrap();
+
+        // both differences which include timestep 12 are flagged as TV7.
+        let tv7_events = events
+            .iter()
+            .filter(|event| event.shape == "TV7")
+            .collect::<Vec<_>>();
+        assert_eq!(tv7_events.len(), 2, "{events:?}");
+        assert_eq!(tv7_events[0].chan_idxs, (11..18).collect::<Vec<_>>());
+        assert!(flag_array.slice(s![11..14, 11..18, ..]).iter().all(|&f| f));
+
+        // nothing else is flagged, except perhaps by chance.
+        let num_flagged = flag_array.iter().filter(|&&f| f).count();
+        assert!(num_flagged < 4 * 7 * shape.2, "{events:?}");
+        assert!(!flag_array[(0, 0, 0)]);
+    }
+
+    #[test]
+    fn test_ssins_ignores_flagged_and_short() {
+        let ant_pairs = vec![(0, 0), (0, 1), (1, 1)];
+        let freqs_hz = vec![150e6, 151e6];
+
+        // a single timestep can't be differenced.
+        let jones_array = Array3::from_elem((1, 2, 3), Jones::identity());
+        let mut flag_array = Array3::from_elem((1, 2, 3), false);
+        let events = SsinsContext::default()
+            .flag_jones_array(
+                jones_array.view(),
+                flag_array.view_mut(),
+                &ant_pairs,
+                &freqs_hz,
+            )
+            .unwrap();
+        assert!(events.is_empty());
+
+        // huge values on flagged visibilities don't matter.
+        let mut jones_array = Array3::from_elem((4, 2, 3), Jones::identity());
+        jones_array[(2, 0, 1)] *= 1e6;
+        let mut flag_array = Array3::from_elem((4, 2, 3), false);
+        flag_array[(2, 0, 1)] = true;
+        let events = SsinsContext::default()
+            .flag_jones_array(
+                jones_array.view(),
+                flag_array.view_mut(),
+                &ant_pairs,
+                &freqs_hz,
+            )
+            .unwrap();
+        assert!(events.is_empty());
+        assert_eq!(flag_array.iter().filter(|&&f| f).count(), 1);
+
+        // the wrong number of frequencies is an error.
+        assert!(SsinsContext::default()
+            .flag_jones_array(
+                jones_array.view(),
+                flag_array.view_mut(),
+                &ant_pairs,
+                &freqs_hz[..1],
+            )
+            .is_err());
+    }
+}
@@ -102,6 +102,111 @@ pub const fn get_mwax_data_paths() -> (&'static str, [&'static str; 4]) {
     (metafits_path, gpufits_paths)
 }
 
+/// Copy the MWAX test metafits into `dir` with the `OVERSAMP` key set, to mock an observation
+/// made with the oversampled coarse channeliser. It can be used with the gpubox files from
+/// [`get_mwax_data_paths`], which only contain the critically sampled band, like MWAX produces.
+pub fn get_mwax_oversampled_metafits(dir: &Path) -> PathBuf {
+    let (metafits_path, _) = get_mwax_data_paths();
+    let oversampled_path = dir.join("1297526432_oversampled.metafits");
+    std::fs::copy(metafits_path, &oversampled_path).unwrap();
+    let mut fptr = fitsio::FitsFile::edit(&oversampled_path).unwrap();
+    let hdu = fptr.primary_hdu().unwrap();
+    hdu.write_key(&mut fptr, "OVERSAMP", 1).unwrap();
+    oversampled_path
+}
+
+/// Write a mock MWAX observation made with the oversampled coarse channeliser into `dir`, with
+/// 10.24kHz fine channels and `num_fine_chans` fine channels in each visibility HDU of the gpubox
+/// files (zero-valued). The critically sampled 1.28MHz band has 125 fine channels at this
+/// resolution, and the oversampled 1.6384MHz band that the receivers produce has 160.
+pub fn get_mwax_oversampled_layout_paths(
+    dir: &Path,
+    num_fine_chans: usize,
+) -> (PathBuf, Vec<PathBuf>) {
+    let (_, gpufits_paths) = get_mwax_data_paths();
+    let oversampled_metafits_path = get_mwax_oversampled_metafits(dir);
+    let mut fptr = fitsio::FitsFile::edit(&oversampled_metafits_path).unwrap();
+    let num_coarse_chans: i64 = {
+        let hdu = fptr.primary_hdu().unwrap();
+        let nchans: i64 = hdu.read_key(&mut fptr, "NCHANS").unwrap();
+        let finechan: f64 = hdu.read_key(&mut fptr, "FINECHAN").unwrap();
+        nchans / (1280. / finechan).round() as i64
+    };
+    update_primary_keys(
+        &mut fptr,
+        &[("FINECHAN", 10.24)],
+        &[("NCHANS", num_coarse_chans * num_fine_chans as i64)],
+    );
+    drop(fptr);
+    let oversampled_gpufits_paths = gpufits_paths
+        .iter()
+        .map(|gpufits_path| {
+            let gpufits_path = Path::new(gpufits_path);
+            let oversampled_path = dir.join(gpufits_path.file_name().unwrap());
+            std::fs::copy(gpufits_path, &oversampled_path).unwrap();
+            let mut fptr = fitsio::FitsFile::edit(&oversampled_path).unwrap();
+            update_primary_keys(
+                &mut fptr,
+                &[("FINECHAN", 10.24)],
+                &[("NFINECHS", num_fine_chans as i64)],
+            );
+            // visibility HDUs are [baseline][fine chan][pol][real, imag], weights HDUs follow them.
+            let num_hdus = fptr.iter().count();
+            for hdu_idx in (1..num_hdus).step_by(2) {
+                let hdu = fptr.hdu(hdu_idx).unwrap();
+                let num_baselines = match hdu.info {
+                    fitsio::hdu::HduInfo::ImageInfo { ref shape, .. } => shape[0],
+                    _ => panic!("gpubox HDU {hdu_idx} isn't an image"),
+                };
+                hdu.resize(&mut fptr, &[num_baselines, num_fine_chans * 4 * 2])
+                    .unwrap();
+            }
+            oversampled_path
+        })
+        .collect();
+    (oversampled_metafits_path, oversampled_gpufits_paths)
+}
+
+/// Overwrite the values of existing keys in the primary HDU. `write_key` appends a second card
+/// instead, which readers ignore in favour of the first.
+fn update_primary_keys(
+    fptr: &mut fitsio::FitsFile,
+    dbl_keys: &[(&str, f64)],
+    int_keys: &[(&str, i64)],
+) {
+    fptr.primary_hdu().unwrap();
+    let mut status = 0;
+    for &(key, value) in dbl_keys {
+        let c_key = std::ffi::CString::new(key).unwrap();
+        unsafe {
+            // ffukyd = fits_update_key_dbl
+            fitsio_sys::ffukyd(
+                fptr.as_raw(),
+                c_key.as_ptr(),
+                value,
+                -15,
+                std::ptr::null_mut(),
+                &mut status,
+            );
+        }
+        fits_check_status(status).unwrap();
+    }
+    for &(key, value) in int_keys {
+        let c_key = std::ffi::CString::new(key).unwrap();
+        unsafe {
+            // ffukyj = fits_update_key_lng
+            fitsio_sys::ffukyj(
+                fptr.as_raw(),
+                c_key.as_ptr(),
+                value,
+                std::ptr::null_mut(),
+                &mut status,
+            );
+        }
+        fits_check_status(status).unwrap();
+    }
+}
+
 #[allow(dead_code)]
 pub(crate) fn get_1254670392_avg_context() -> CorrelatorContext {
     let (metafits_path, gpufits_paths) = get_1254670392_avg_paths();